@@ -1,37 +1,31 @@
-use env_logger;
 use log::*;
 use std::net::*;
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
-use usbip;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 #[tokio::main]
 async fn main() {
     env_logger::init();
-    let handler =
-        Arc::new(Mutex::new(Box::new(usbip::cdc::UsbCdcAcmHandler::new())
-            as Box<dyn usbip::UsbInterfaceHandler + Send>));
-    let server = usbip::UsbIpServer::new_simulated(vec![usbip::UsbDevice::new(0).with_interface(
+    let acm = usbip::cdc::UsbCdcAcmHandler::new();
+    let mut serial = acm.stream();
+    let server = usbip::UsbIpServer::new_simulated(vec![usbip::UsbDevice::new(0).with_handler(
         usbip::ClassCode::CDC as u8,
         usbip::cdc::CDC_ACM_SUBCLASS,
         0x00,
         "Test CDC ACM",
-        usbip::cdc::UsbCdcAcmHandler::endpoints(),
-        handler.clone(),
+        acm,
     )]);
     let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 3240);
-    tokio::spawn(usbip::server(addr, server));
+    tokio::spawn(usbip::server(addr, Arc::new(server)));
 
+    // echo what the host writes to the tty
+    let mut buf = [0u8; 512];
     loop {
-        // sleep 1s
-        tokio::time::sleep(Duration::new(1, 0)).await;
-        let mut handler = handler.lock().unwrap();
-        if let Some(acm) = handler
-            .as_any()
-            .downcast_mut::<usbip::cdc::UsbCdcAcmHandler>()
-        {
-            acm.tx_buffer.push(b'a');
-            info!("Simulate a char input");
+        let len = serial.read(&mut buf).await.unwrap();
+        if len == 0 {
+            break;
         }
+        info!("Echo {:?}", String::from_utf8_lossy(&buf[..len]));
+        serial.write_all(&buf[..len]).await.unwrap();
     }
 }
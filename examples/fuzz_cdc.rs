@@ -0,0 +1,45 @@
+//! Replay URBs decoded from files, or stdin, on a CDC ACM device
+//!
+//! The same body fits a cargo-fuzz target:
+//!
+//! ```ignore
+//! fuzz_target!(|bytes: &[u8]| {
+//!     let urbs = usbip::TraceUrb::parse_all(bytes);
+//!     tokio::runtime::Runtime::new().unwrap().block_on(device().replay(urbs));
+//! });
+//! ```
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+
+fn device() -> usbip::UsbDevice {
+    usbip::UsbDevice::new(0).with_interface(
+        usbip::ClassCode::CDC as u8,
+        usbip::cdc::CDC_ACM_SUBCLASS,
+        0x00,
+        "Fuzzed CDC ACM",
+        usbip::cdc::UsbCdcAcmHandler::endpoints(),
+        Arc::new(Mutex::new(Box::new(usbip::cdc::UsbCdcAcmHandler::new())
+            as Box<dyn usbip::UsbInterfaceHandler + Send>)),
+    )
+}
+
+#[tokio::main]
+async fn main() -> std::io::Result<()> {
+    env_logger::init();
+    let mut inputs = vec![];
+    for path in std::env::args().skip(1) {
+        inputs.push(std::fs::read(path)?);
+    }
+    if inputs.is_empty() {
+        let mut bytes = vec![];
+        std::io::stdin().read_to_end(&mut bytes)?;
+        inputs.push(bytes);
+    }
+    for bytes in inputs {
+        let urbs = usbip::TraceUrb::parse_all(&bytes);
+        for reply in device().replay(urbs).await {
+            println!("{} {:02x?}", reply.status, reply.data);
+        }
+    }
+    Ok(())
+}
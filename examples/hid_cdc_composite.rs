@@ -0,0 +1,71 @@
+use log::*;
+use std::net::*;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+    let hid_handler = Arc::new(Mutex::new(
+        Box::new(usbip::hid::UsbHidKeyboardHandler::new_keyboard())
+            as Box<dyn usbip::UsbInterfaceHandler + Send>,
+    ));
+    let acm_handler =
+        Arc::new(Mutex::new(Box::new(usbip::cdc::UsbCdcAcmHandler::new())
+            as Box<dyn usbip::UsbInterfaceHandler + Send>));
+    let device = usbip::UsbDevice::new(0)
+        .with_interface(
+            usbip::ClassCode::HID as u8,
+            usbip::hid::HID_SUBCLASS_BOOT,
+            usbip::hid::HID_PROTOCOL_KEYBOARD,
+            "Test HID",
+            vec![usbip::UsbEndpoint {
+                address: 0x83,         // IN, the CDC ACM interface uses 0x81 and 0x82
+                attributes: 0x03,      // Interrupt
+                max_packet_size: 0x08, // 8 bytes
+                interval: 10,
+            }],
+            hid_handler.clone(),
+        )
+        .with_interface(
+            usbip::ClassCode::CDC as u8,
+            usbip::cdc::CDC_ACM_SUBCLASS,
+            0x00,
+            "Test CDC ACM",
+            usbip::cdc::UsbCdcAcmHandler::endpoints(),
+            acm_handler.clone(),
+        )
+        .with_interface_association(
+            1,
+            1,
+            usbip::ClassCode::CDC as u8,
+            usbip::cdc::CDC_ACM_SUBCLASS,
+            0x00,
+            "Test CDC Function",
+        );
+    let server = usbip::UsbIpServer::new_simulated(vec![device]);
+    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 3240);
+    tokio::spawn(usbip::server(addr, Arc::new(server)));
+
+    loop {
+        // sleep 1s
+        tokio::time::sleep(Duration::new(1, 0)).await;
+        let mut handler = hid_handler.lock().unwrap();
+        if let Some(hid) = handler
+            .as_any()
+            .downcast_mut::<usbip::hid::UsbHidKeyboardHandler>()
+        {
+            hid.pending_key_events
+                .push_back(usbip::hid::UsbHidKeyboardReport::from_ascii(b'1'));
+            info!("Simulate a key event");
+        }
+        let mut handler = acm_handler.lock().unwrap();
+        if let Some(acm) = handler
+            .as_any()
+            .downcast_mut::<usbip::cdc::UsbCdcAcmHandler>()
+        {
+            acm.tx_buffer.push(b'a');
+            info!("Simulate a char input");
+        }
+    }
+}
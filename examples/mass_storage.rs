@@ -0,0 +1,27 @@
+use std::net::*;
+use std::sync::{Arc, Mutex};
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+    // the files of a directory given as argument, read only, or else
+    // 16 MiB of 512 byte blocks
+    let disk = match std::env::args().nth(1) {
+        Some(dir) => usbip::msc::RamDisk::from_dir(dir).expect("cannot read directory"),
+        None => usbip::msc::RamDisk::new(512, 32768),
+    };
+    let handler = Arc::new(Mutex::new(Box::new(usbip::AsyncHandler::new(
+        usbip::msc::UsbMassStorageHandler::new(Arc::new(disk)),
+    ))
+        as Box<dyn usbip::UsbInterfaceHandler + Send>));
+    let server = usbip::UsbIpServer::new_simulated(vec![usbip::UsbDevice::new(0).with_interface(
+        usbip::ClassCode::MassStorage as u8,
+        usbip::msc::MSC_SCSI_SUBCLASS,
+        usbip::msc::MSC_BULK_ONLY_PROTOCOL,
+        "Test Mass Storage",
+        usbip::msc::UsbMassStorageHandler::endpoints(),
+        handler,
+    )]);
+    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 3240);
+    usbip::server(addr, Arc::new(server)).await;
+}
@@ -0,0 +1,74 @@
+use log::*;
+use std::any::Any;
+use std::net::*;
+use std::sync::{Arc, Mutex};
+
+/// bRequest of the vendor request which wakes the device up
+const VENDOR_INIT: u8 = 0x01;
+
+/// A device whose bulk IN endpoint stays silent until it gets [VENDOR_INIT]
+#[derive(Default)]
+struct InitGatedHandler {
+    initialized: bool,
+    counter: u8,
+}
+
+impl usbip::UsbInterfaceHandler for InitGatedHandler {
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn handle_urb(
+        &mut self,
+        _interface: &usbip::UsbInterface,
+        _ep: usbip::UsbEndpoint,
+        setup: Option<usbip::SetupPacket>,
+        _req: &[u8],
+    ) -> std::io::Result<Vec<u8>> {
+        if let Some(setup) = setup {
+            // vendor request to the interface
+            if (setup.request_type, setup.request) == (0b01000001, VENDOR_INIT) {
+                info!("Initialized");
+                self.initialized = true;
+                return Ok(vec![]);
+            }
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                format!("unhandled request {:x?}", setup),
+            ));
+        }
+        if !self.initialized {
+            // no data until initialized
+            return Ok(vec![]);
+        }
+        self.counter = self.counter.wrapping_add(1);
+        Ok(vec![self.counter])
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+    let handler = Arc::new(Mutex::new(
+        Box::new(InitGatedHandler::default()) as Box<dyn usbip::UsbInterfaceHandler + Send>
+    ));
+    let server = usbip::UsbIpServer::new_simulated(vec![usbip::UsbDevice::new(0).with_interface(
+        usbip::ClassCode::VendorSpecific as u8,
+        0x00,
+        0x00,
+        "Test Init Gated",
+        vec![usbip::UsbEndpoint {
+            address: 0x81,        // IN
+            attributes: 0x02,     // Bulk
+            max_packet_size: 512, // 512 bytes
+            interval: 0,
+        }],
+        handler,
+    )]);
+    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 3240);
+    usbip::server(addr, Arc::new(server)).await;
+}
@@ -0,0 +1,540 @@
+//! Implement a USB Audio Class 1.0 speaker or microphone
+//!
+//! The device has an Audio Control interface describing an input and an
+//! output terminal, and an Audio Streaming interface carrying PCM on an
+//! isochronous endpoint at alternate setting 1. Setting 0 has no endpoint,
+//! hosts select it while not streaming.
+//!
+//! A speaker receives on an asynchronous OUT endpoint and tells the host the
+//! rate of its clock on an explicit feedback endpoint, a microphone sends on
+//! an asynchronous IN endpoint, varying the packet size to match its rate.
+//! Endpoint descriptors are the standard 7 byte ones, without bRefresh and
+//! bSynchAddress: hosts like Linux then take the second endpoint of the
+//! setting as the feedback endpoint.
+use super::*;
+
+// reference: https://www.usb.org/sites/default/files/audio10.pdf
+
+/// Sub class code for Audio Streaming, with [ClassCode::Audio]
+pub const AUDIO_STREAMING_SUBCLASS: u8 = 0x02;
+
+/// Descriptor type of class specific interface descriptors
+const CS_INTERFACE: u8 = 0x24;
+/// Descriptor type of class specific endpoint descriptors
+const CS_ENDPOINT: u8 = 0x25;
+
+/// wTerminalType of USB streaming terminals
+const TERMINAL_USB_STREAMING: u16 = 0x0101;
+/// wTerminalType of microphones
+const TERMINAL_MICROPHONE: u16 = 0x0201;
+/// wTerminalType of speakers
+const TERMINAL_SPEAKER: u16 = 0x0301;
+
+/// Receives the PCM data of each packet from the host, frames back to back
+pub type PcmSink = Box<dyn FnMut(&[u8]) + Send>;
+/// Fills the PCM data of each packet to the host, frames back to back
+pub type PcmSource = Box<dyn FnMut(&mut [u8]) + Send>;
+
+/// Direction of the audio stream
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AudioStream {
+    /// Audio from the host, played on a speaker
+    Speaker,
+    /// Audio to the host, recorded from a microphone
+    Microphone,
+}
+
+/// A PCM format with a single sample rate, Type I format of UAC1
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AudioFormat {
+    /// bNrChannels
+    pub channels: u8,
+    /// bSubframeSize: bytes of one sample of one channel
+    pub subframe_size: u8,
+    /// bBitResolution: bits used in a subframe
+    pub bit_resolution: u8,
+    /// tSamFreq in Hz
+    pub sample_rate: u32,
+}
+
+impl AudioFormat {
+    /// 16-bit PCM of `channels` channels at `sample_rate` Hz
+    pub fn pcm16(channels: u8, sample_rate: u32) -> Self {
+        Self {
+            channels,
+            subframe_size: 2,
+            bit_resolution: 16,
+            sample_rate,
+        }
+    }
+
+    /// Bytes of one sample of every channel
+    pub fn frame_size(&self) -> usize {
+        self.channels as usize * self.subframe_size as usize
+    }
+}
+
+/// A handler of the streaming setting of an Audio Streaming interface
+///
+/// PCM from the host goes to the sink, PCM to the host comes from the source,
+/// silence without one. Packets carry a millisecond of audio each.
+pub struct UsbAudioHandler {
+    pub stream: AudioStream,
+    pub format: AudioFormat,
+    /// Frames received from or sent to the host
+    pub frames: u64,
+    /// Rate of the device clock in Hz reported on the feedback endpoint of a
+    /// speaker, defaults to the sample rate
+    pub clock_rate: u32,
+    high_speed: bool,
+    sink: Option<PcmSink>,
+    source: Option<PcmSource>,
+    /// Thousandths of a frame not sent yet, so that 44.1kHz sends 44 and 45
+    /// frames in turn
+    residue: u32,
+}
+
+impl UsbAudioHandler {
+    pub fn new(stream: AudioStream, format: AudioFormat) -> Self {
+        Self {
+            stream,
+            format,
+            frames: 0,
+            clock_rate: format.sample_rate,
+            high_speed: true,
+            sink: None,
+            source: None,
+            residue: 0,
+        }
+    }
+
+    /// Pass the PCM of a speaker to `sink`
+    pub fn with_sink<F: FnMut(&[u8]) + Send + 'static>(mut self, sink: F) -> Self {
+        self.sink = Some(Box::new(sink));
+        self
+    }
+
+    /// Record the PCM of a microphone from `source`
+    pub fn with_source<F: FnMut(&mut [u8]) + Send + 'static>(mut self, source: F) -> Self {
+        self.source = Some(Box::new(source));
+        self
+    }
+
+    /// The data endpoint, followed by the feedback endpoint of a speaker
+    fn endpoints(&self) -> Vec<UsbEndpoint> {
+        // a packet every millisecond: 2^(4-1) microframes or 1 frame
+        let interval = if self.high_speed { 0x04 } else { 0x01 };
+        // one more frame than nominal, for rates not a multiple of 1kHz and
+        // clocks running fast
+        let max_packet_size =
+            ((self.format.sample_rate / 1000 + 1) as usize * self.format.frame_size()) as u16;
+        match self.stream {
+            AudioStream::Speaker => vec![
+                UsbEndpoint::new(
+                    0x01,
+                    EndpointAttributes::Isochronous,
+                    SyncType::Async,
+                    UsageType::Data,
+                    max_packet_size,
+                    interval,
+                ),
+                UsbEndpoint::new(
+                    0x81,
+                    EndpointAttributes::Isochronous,
+                    SyncType::NoSync,
+                    UsageType::Feedback,
+                    self.feedback().len() as u16,
+                    interval,
+                ),
+            ],
+            AudioStream::Microphone => vec![UsbEndpoint::new(
+                0x81,
+                EndpointAttributes::Isochronous,
+                SyncType::Async,
+                UsageType::Data,
+                max_packet_size,
+                interval,
+            )],
+        }
+    }
+
+    /// Frames per (micro)frame of the device clock: 16.16 per microframe at
+    /// high speed, 10.14 per frame at full speed
+    fn feedback(&self) -> Vec<u8> {
+        let rate = self.clock_rate as u64;
+        if self.high_speed {
+            (((rate << 16) / 8000) as u32).to_le_bytes().to_vec()
+        } else {
+            (((rate << 14) / 1000) as u32).to_le_bytes()[..3].to_vec()
+        }
+    }
+
+    /// Frames of the next millisecond of audio
+    fn next_packet_frames(&mut self) -> usize {
+        self.residue += self.format.sample_rate;
+        let frames = self.residue / 1000;
+        self.residue %= 1000;
+        frames as usize
+    }
+}
+
+impl UsbInterfaceHandler for UsbAudioHandler {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        ep: UsbEndpoint,
+        setup: Option<SetupPacket>,
+        req: &[u8],
+    ) -> Result<Vec<u8>> {
+        if ep.is_ep0() {
+            warn!("Audio streaming unsupported request {:x?}", setup);
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "unsupported audio streaming request",
+            ));
+        }
+
+        match (self.stream, ep.direction()) {
+            (AudioStream::Speaker, Direction::Out) => {
+                self.frames += (req.len() / self.format.frame_size()) as u64;
+                if let Some(sink) = &mut self.sink {
+                    sink(req);
+                }
+                Ok(vec![])
+            }
+            (AudioStream::Speaker, Direction::In) => Ok(self.feedback()),
+            (AudioStream::Microphone, Direction::In) => {
+                let frames = self.next_packet_frames();
+                let mut data = vec![0u8; frames * self.format.frame_size()];
+                if let Some(source) = &mut self.source {
+                    source(&mut data);
+                }
+                self.frames += frames as u64;
+                Ok(data)
+            }
+            (AudioStream::Microphone, Direction::Out) => Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "OUT transfer to a microphone",
+            )),
+        }
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        let terminal_link = match self.stream {
+            // input terminal, see UsbAudioControlHandler
+            AudioStream::Speaker => 0x01,
+            // output terminal
+            AudioStream::Microphone => 0x02,
+        };
+        let mut desc = vec![
+            0x07,          // bLength
+            CS_INTERFACE,  // bDescriptorType: CS_INTERFACE
+            0x01,          // bDescriptorSubtype: AS_GENERAL
+            terminal_link, // bTerminalLink
+            0x01,          // bDelay: 1 frame
+            0x01,
+            0x00,                       // wFormatTag: PCM
+            0x0B,                       // bLength
+            CS_INTERFACE,               // bDescriptorType: CS_INTERFACE
+            0x02,                       // bDescriptorSubtype: FORMAT_TYPE
+            0x01,                       // bFormatType: FORMAT_TYPE_I
+            self.format.channels,       // bNrChannels
+            self.format.subframe_size,  // bSubframeSize
+            self.format.bit_resolution, // bBitResolution
+            0x01,                       // bSamFreqType: one rate
+        ];
+        // tSamFreq
+        desc.extend_from_slice(&self.format.sample_rate.to_le_bytes()[..3]);
+        desc
+    }
+
+    fn get_class_specific_endpoint_descriptor(&self, endpoint: &UsbEndpoint) -> Vec<u8> {
+        if endpoint.usage_type() == Some(UsageType::Feedback) {
+            return vec![];
+        }
+        vec![
+            0x07,        // bLength
+            CS_ENDPOINT, // bDescriptorType: CS_ENDPOINT
+            0x01,        // bDescriptorSubtype: EP_GENERAL
+            0x00,        // bmAttributes: no sampling frequency control
+            0x00,        // bLockDelayUnits
+            0x00,
+            0x00, // wLockDelay
+        ]
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// A handler of the Audio Control interface of a [UsbAudioHandler]
+///
+/// Describes an input terminal 1 connected to an output terminal 2, one of
+/// them the USB streaming terminal. Unlike [midi::UsbAudioControlHandler],
+/// it has terminals, which audio streams need.
+#[derive(Clone)]
+pub struct UsbAudioControlHandler {
+    stream: AudioStream,
+    channels: u8,
+    streaming_interface: u8,
+}
+
+impl UsbAudioControlHandler {
+    pub fn new(stream: AudioStream, channels: u8, streaming_interface: u8) -> Self {
+        Self {
+            stream,
+            channels,
+            streaming_interface,
+        }
+    }
+}
+
+impl UsbInterfaceHandler for UsbAudioControlHandler {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        _ep: UsbEndpoint,
+        setup: Option<SetupPacket>,
+        _req: &[u8],
+    ) -> Result<Vec<u8>> {
+        warn!("Audio control unsupported request {:x?}", setup);
+        Err(std::io::Error::new(
+            std::io::ErrorKind::BrokenPipe,
+            "unsupported audio control request",
+        ))
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        let (input, output) = match self.stream {
+            AudioStream::Speaker => (TERMINAL_USB_STREAMING, TERMINAL_SPEAKER),
+            AudioStream::Microphone => (TERMINAL_MICROPHONE, TERMINAL_USB_STREAMING),
+        };
+        // left and right front for stereo, no spatial position otherwise
+        let channel_config: u16 = if self.channels == 2 { 0x0003 } else { 0x0000 };
+        let mut desc = vec![
+            0x09,         // bLength
+            CS_INTERFACE, // bDescriptorType: CS_INTERFACE
+            0x01,         // bDescriptorSubtype: HEADER
+            0x00,
+            0x01, // bcdADC: 1.00
+            0x00,
+            0x00,                     // wTotalLength: to be filled below
+            0x01,                     // bInCollection
+            self.streaming_interface, // baInterfaceNr
+            0x0C,                     // bLength
+            CS_INTERFACE,             // bDescriptorType: CS_INTERFACE
+            0x02,                     // bDescriptorSubtype: INPUT_TERMINAL
+            0x01,                     // bTerminalID
+        ];
+        desc.extend_from_slice(&input.to_le_bytes()); // wTerminalType
+        desc.extend_from_slice(&[
+            0x00,          // bAssocTerminal
+            self.channels, // bNrChannels
+        ]);
+        desc.extend_from_slice(&channel_config.to_le_bytes()); // wChannelConfig
+        desc.extend_from_slice(&[
+            0x00,         // iChannelNames
+            0x00,         // iTerminal
+            0x09,         // bLength
+            CS_INTERFACE, // bDescriptorType: CS_INTERFACE
+            0x03,         // bDescriptorSubtype: OUTPUT_TERMINAL
+            0x02,         // bTerminalID
+        ]);
+        desc.extend_from_slice(&output.to_le_bytes()); // wTerminalType
+        desc.extend_from_slice(&[
+            0x00, // bAssocTerminal
+            0x01, // bSourceID: the input terminal
+            0x00, // iTerminal
+        ]);
+        let len = desc.len() as u16;
+        desc[5] = len as u8;
+        desc[6] = (len >> 8) as u8;
+        desc
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// Add an Audio Control and an Audio Streaming interface streaming with `handler` to `device`
+///
+/// Endpoints are sized for the speed of `device`, set it before. Returns
+/// the device and the handler of the streaming setting.
+pub fn with_audio(
+    device: UsbDevice,
+    handler: UsbAudioHandler,
+) -> (UsbDevice, Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>>) {
+    let mut handler = handler;
+    handler.high_speed = device.speed == UsbSpeed::High as u32;
+    let streaming_interface = device.num_interfaces() as u8 + 1;
+    let control =
+        UsbAudioControlHandler::new(handler.stream, handler.format.channels, streaming_interface);
+    let endpoints = handler.endpoints();
+    let name = match handler.stream {
+        AudioStream::Speaker => "Speaker",
+        AudioStream::Microphone => "Microphone",
+    };
+    let handler = Arc::new(Mutex::new(
+        Box::new(handler) as Box<dyn UsbInterfaceHandler + Send>
+    ));
+    let device = device
+        .with_interface(
+            ClassCode::Audio as u8,
+            midi::AUDIO_CONTROL_SUBCLASS,
+            0x00,
+            "Audio Control",
+            vec![],
+            Arc::new(Mutex::new(
+                Box::new(control) as Box<dyn UsbInterfaceHandler + Send>
+            )),
+        )
+        // zero bandwidth setting
+        .with_interface(
+            ClassCode::Audio as u8,
+            AUDIO_STREAMING_SUBCLASS,
+            0x00,
+            name,
+            vec![],
+            FnHandler::new(|_, _, setup, _| {
+                warn!("Audio streaming unsupported request {:x?}", setup);
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::BrokenPipe,
+                    "unsupported audio streaming request",
+                ))
+            })
+            .into_handler(),
+        )
+        .with_alternate_setting(
+            ClassCode::Audio as u8,
+            AUDIO_STREAMING_SUBCLASS,
+            0x00,
+            name,
+            endpoints,
+            handler.clone(),
+        );
+    (device, handler)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn iso_packets(lengths: &[u32]) -> Vec<IsoPacketDescriptor> {
+        let mut offset = 0;
+        lengths
+            .iter()
+            .map(|length| {
+                let packet = IsoPacketDescriptor {
+                    offset,
+                    length: *length,
+                    ..Default::default()
+                };
+                offset += length;
+                packet
+            })
+            .collect()
+    }
+
+    /// SET_INTERFACE of the streaming interface to `setting`
+    async fn set_streaming(device: &UsbDevice, setting: u8) {
+        let ctx = ConnectionContext::default();
+        device
+            .handle_urb(
+                &ctx,
+                device.ep0_out,
+                None,
+                [0x01, 0x0B, setting, 0x00, 0x01, 0x00, 0x00, 0x00],
+                &[],
+            )
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn desc_verify() {
+        for stream in [AudioStream::Speaker, AudioStream::Microphone] {
+            for speed in [UsbSpeed::Full, UsbSpeed::High] {
+                let handler = UsbAudioHandler::new(stream, AudioFormat::pcm16(2, 48000));
+                let (device, _) = with_audio(UsbDevice::new(0).with_speed(speed), handler);
+                verify_descriptor(&device.configuration_descriptor());
+                assert_eq!(device.validate(), Ok(()));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn speaker_sink_and_feedback() {
+        let received = Arc::new(AtomicUsize::new(0));
+        let counter = received.clone();
+        let handler = UsbAudioHandler::new(AudioStream::Speaker, AudioFormat::pcm16(2, 48000))
+            .with_sink(move |pcm| {
+                counter.fetch_add(pcm.len(), Ordering::Relaxed);
+            });
+        let (device, handler) = with_audio(UsbDevice::new(0), handler);
+
+        set_streaming(&device, 1).await;
+
+        // 3ms of 48 frames of 4 bytes
+        let (ep, intf) = device.find_ep(0x01).unwrap();
+        let packets = iso_packets(&[192, 192, 192]);
+        let results = device
+            .handle_iso_urb(ep, intf, &packets, &[0x11; 3 * 192])
+            .unwrap();
+        assert!(results.iter().all(|result| result.status == 0));
+        assert_eq!(received.load(Ordering::Relaxed), 3 * 192);
+
+        // 6 frames per microframe in 16.16
+        let (ep, intf) = device.find_ep(0x81).unwrap();
+        let results = device
+            .handle_iso_urb(ep, intf, &iso_packets(&[4]), &[])
+            .unwrap();
+        assert_eq!(results[0].data, [0x00, 0x00, 0x06, 0x00]);
+
+        // a device clock running slow asks for less
+        {
+            let mut handler = handler.lock().unwrap();
+            let audio = handler.as_any().downcast_mut::<UsbAudioHandler>().unwrap();
+            assert_eq!(audio.frames, 3 * 48);
+            audio.clock_rate = 47996;
+        }
+        let results = device
+            .handle_iso_urb(ep, intf, &iso_packets(&[4]), &[])
+            .unwrap();
+        assert_eq!(
+            u32::from_le_bytes([
+                results[0].data[0],
+                results[0].data[1],
+                results[0].data[2],
+                results[0].data[3]
+            ]),
+            (47996 << 16) / 8000
+        );
+    }
+
+    #[tokio::test]
+    async fn microphone_source() {
+        let handler = UsbAudioHandler::new(AudioStream::Microphone, AudioFormat::pcm16(1, 44100))
+            .with_source(|pcm| pcm.fill(0x22));
+        let (device, handler) = with_audio(UsbDevice::new(0), handler);
+        set_streaming(&device, 1).await;
+
+        // 10ms of 44 or 45 frames of 2 bytes
+        let (ep, intf) = device.find_ep(0x81).unwrap();
+        assert_eq!(ep.max_packet_size, 90);
+        let packets = iso_packets(&[90; 10]);
+        let results = device.handle_iso_urb(ep, intf, &packets, &[]).unwrap();
+        let lengths: Vec<_> = results.iter().map(|result| result.data.len()).collect();
+        assert_eq!(lengths.iter().sum::<usize>(), 441 * 2);
+        assert!(lengths.iter().all(|len| *len == 88 || *len == 90));
+        assert!(results
+            .iter()
+            .all(|result| result.data.iter().all(|b| *b == 0x22)));
+
+        let mut handler = handler.lock().unwrap();
+        let audio = handler.as_any().downcast_mut::<UsbAudioHandler>().unwrap();
+        assert_eq!(audio.frames, 441);
+    }
+}
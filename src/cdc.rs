@@ -1,18 +1,125 @@
 //! Implement CDC(Communications) device
 use super::*;
 
+/// Called after the host changed the line coding or the control line state
+pub type AcmLineCallback = Box<dyn FnMut(&UsbCdcAcmHandler) + Send>;
+
 /// A handler of a CDC ACM(Abstract Control Model)
-#[derive(Clone)]
+///
+/// The host tty sets the line coding and the control line state, see
+/// [UsbCdcAcmHandler::baud_rate] and [UsbCdcAcmHandler::dtr]. To react to
+/// changes, e.g. reset like an Arduino when DTR drops, pass a callback to
+/// [UsbCdcAcmHandler::with_line_callback].
+///
+/// Bytes move with [UsbCdcAcmHandler::write_to_host] and
+/// [UsbCdcAcmHandler::take_from_host], or through an [AcmStream] from
+/// [UsbCdcAcmHandler::stream] once the handler is shared with the device.
 pub struct UsbCdcAcmHandler {
+    /// Sent to the host on the next bulk IN, before what was written with
+    /// [UsbCdcAcmHandler::write_to_host]
     pub tx_buffer: Vec<u8>,
+    /// Line coding last set by the host: dwDTERate, bCharFormat, bParityType, bDataBits
+    pub line_coding: [u8; 7],
+    /// wValue of the last SET_CONTROL_LINE_STATE: DTR in bit 0, RTS in bit 1
+    pub control_line_state: u16,
+    line_callback: Option<AcmLineCallback>,
+    pipe: Arc<Mutex<AcmPipe>>,
 }
 
 /// Sub class code for CDC ACM
 pub const CDC_ACM_SUBCLASS: u8 = 0x02;
 
+/// bRequest of SET_LINE_CODING
+pub const CDC_SET_LINE_CODING: u8 = 0x20;
+/// bRequest of GET_LINE_CODING
+pub const CDC_GET_LINE_CODING: u8 = 0x21;
+/// bRequest of SET_CONTROL_LINE_STATE
+pub const CDC_SET_CONTROL_LINE_STATE: u8 = 0x22;
+
 impl UsbCdcAcmHandler {
     pub fn new() -> Self {
-        Self { tx_buffer: vec![] }
+        Self {
+            tx_buffer: vec![],
+            // 115200 baud, 1 stop bit, no parity, 8 data bits
+            line_coding: [0x00, 0xC2, 0x01, 0x00, 0x00, 0x00, 0x08],
+            control_line_state: 0,
+            line_callback: None,
+            pipe: Arc::new(Mutex::new(AcmPipe::default())),
+        }
+    }
+
+    /// Queue `data` for the next bulk IN transfers
+    pub fn write_to_host(&mut self, data: &[u8]) {
+        self.pipe.lock().unwrap().to_host.extend(data);
+    }
+
+    /// Drain the bytes the host sent on bulk OUT so far
+    pub fn take_from_host(&mut self) -> Vec<u8> {
+        self.pipe.lock().unwrap().from_host.drain(..).collect()
+    }
+
+    /// The serial port as a stream, reading what the host sends and
+    /// writing to the host
+    ///
+    /// Reads wait for the host to send and end once the handler is
+    /// dropped. Shares its bytes with [UsbCdcAcmHandler::write_to_host] and
+    /// [UsbCdcAcmHandler::take_from_host].
+    pub fn stream(&self) -> AcmStream {
+        AcmStream {
+            pipe: self.pipe.clone(),
+        }
+    }
+
+    /// Call `callback` with the handler after each SET_LINE_CODING and
+    /// SET_CONTROL_LINE_STATE
+    pub fn with_line_callback<F: FnMut(&UsbCdcAcmHandler) + Send + 'static>(
+        mut self,
+        callback: F,
+    ) -> Self {
+        self.line_callback = Some(Box::new(callback));
+        self
+    }
+
+    /// dwDTERate of the line coding, in bits per second
+    pub fn baud_rate(&self) -> u32 {
+        u32::from_le_bytes([
+            self.line_coding[0],
+            self.line_coding[1],
+            self.line_coding[2],
+            self.line_coding[3],
+        ])
+    }
+
+    /// bCharFormat of the line coding: 0 for 1 stop bit, 1 for 1.5, 2 for 2
+    pub fn stop_bits(&self) -> u8 {
+        self.line_coding[4]
+    }
+
+    /// bParityType of the line coding: none, odd, even, mark or space from 0
+    pub fn parity(&self) -> u8 {
+        self.line_coding[5]
+    }
+
+    /// bDataBits of the line coding: 5, 6, 7, 8 or 16
+    pub fn data_bits(&self) -> u8 {
+        self.line_coding[6]
+    }
+
+    /// Whether the host asserts DTR, i.e. has the tty open
+    pub fn dtr(&self) -> bool {
+        self.control_line_state & 0x1 != 0
+    }
+
+    /// Whether the host asserts RTS
+    pub fn rts(&self) -> bool {
+        self.control_line_state & 0x2 != 0
+    }
+
+    fn line_changed(&mut self) {
+        if let Some(mut callback) = self.line_callback.take() {
+            callback(self);
+            self.line_callback = Some(callback);
+        }
     }
 
     pub fn endpoints() -> Vec<UsbEndpoint> {
@@ -42,15 +149,44 @@ impl UsbCdcAcmHandler {
     }
 }
 
+impl Default for UsbCdcAcmHandler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl UsbInterfaceHandler for UsbCdcAcmHandler {
     fn handle_urb(
         &mut self,
         _interface: &UsbInterface,
         ep: UsbEndpoint,
-        _setup: SetupPacket,
+        setup: Option<SetupPacket>,
         req: &[u8],
     ) -> Result<Vec<u8>> {
-        if ep.attributes == EndpointAttributes::Interrupt as u8 {
+        if let Some(setup) = setup {
+            // class requests to the interface
+            match (setup.request_type, setup.request) {
+                (0b00100001, CDC_SET_LINE_CODING) if req.len() >= 7 => {
+                    self.line_coding.copy_from_slice(&req[..7]);
+                    debug!("Set line coding {:02x?}", self.line_coding);
+                    self.line_changed();
+                }
+                (0b10100001, CDC_GET_LINE_CODING) => {
+                    return Ok(self.line_coding.to_vec());
+                }
+                (0b00100001, CDC_SET_CONTROL_LINE_STATE) => {
+                    debug!("Set control line state {:x}", setup.value);
+                    self.control_line_state = setup.value;
+                    self.line_changed();
+                }
+                _ => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::BrokenPipe,
+                        format!("unhandled cdc request {:x?}", setup),
+                    ));
+                }
+            }
+        } else if ep.transfer_type() == Some(EndpointAttributes::Interrupt) {
             // interrupt
             if let Direction::In = ep.direction() {
                 // interrupt in
@@ -63,14 +199,19 @@ impl UsbInterfaceHandler for UsbCdcAcmHandler {
                 info!(
                     "Got bulk out: {:?} \"{}\"",
                     req,
-                    String::from_utf8_lossy(&req)
+                    String::from_utf8_lossy(req)
                 );
+                let mut pipe = self.pipe.lock().unwrap();
+                pipe.from_host.extend(req);
+                if let Some(reader) = pipe.reader.take() {
+                    reader.wake();
+                }
                 return Ok(vec![]);
             } else {
                 // bulk in
                 // TODO: handle max packet size
-                let resp = self.tx_buffer.clone();
-                self.tx_buffer.clear();
+                let mut resp = std::mem::take(&mut self.tx_buffer);
+                resp.extend(self.pipe.lock().unwrap().to_host.drain(..));
                 return Ok(resp);
             }
         }
@@ -78,7 +219,7 @@ impl UsbInterfaceHandler for UsbCdcAcmHandler {
     }
 
     fn get_class_specific_descriptor(&self) -> Vec<u8> {
-        return vec![
+        vec![
             // Header
             0x05, // bFunctionLength
             0x24, // CS_INTERFACE
@@ -89,7 +230,421 @@ impl UsbInterfaceHandler for UsbCdcAcmHandler {
             0x24, // CS_INTERFACE
             0x02, // ACM
             0x00, // Capabilities
+        ]
+    }
+
+    /// Drop data not sent yet and go back to the default line coding, with
+    /// DTR and RTS dropped
+    fn reset(&mut self) {
+        let default = Self::new();
+        self.tx_buffer.clear();
+        self.pipe.lock().unwrap().to_host.clear();
+        self.line_coding = default.line_coding;
+        self.control_line_state = default.control_line_state;
+    }
+
+    fn endpoints(&self) -> Vec<UsbEndpoint> {
+        Self::endpoints()
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl Drop for UsbCdcAcmHandler {
+    fn drop(&mut self) {
+        let mut pipe = self.pipe.lock().unwrap();
+        pipe.closed = true;
+        if let Some(reader) = pipe.reader.take() {
+            reader.wake();
+        }
+    }
+}
+
+/// Bytes in flight between a [UsbCdcAcmHandler] and its [AcmStream]
+#[derive(Default)]
+struct AcmPipe {
+    to_host: VecDeque<u8>,
+    from_host: VecDeque<u8>,
+    /// Woken when the host sends or the handler is dropped
+    reader: Option<std::task::Waker>,
+    closed: bool,
+}
+
+/// The serial port of a [UsbCdcAcmHandler] as a stream, see [UsbCdcAcmHandler::stream]
+pub struct AcmStream {
+    pipe: Arc<Mutex<AcmPipe>>,
+}
+
+impl AsyncRead for AcmStream {
+    fn poll_read(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<Result<()>> {
+        let mut pipe = self.pipe.lock().unwrap();
+        if pipe.from_host.is_empty() {
+            if pipe.closed {
+                // end of stream
+                return std::task::Poll::Ready(Ok(()));
+            }
+            pipe.reader = Some(cx.waker().clone());
+            return std::task::Poll::Pending;
+        }
+        let len = pipe.from_host.len().min(buf.remaining());
+        let data: Vec<u8> = pipe.from_host.drain(..len).collect();
+        buf.put_slice(&data);
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for AcmStream {
+    /// Never waits, the bytes queue until the host reads them
+    fn poll_write(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+        data: &[u8],
+    ) -> std::task::Poll<Result<usize>> {
+        let mut pipe = self.pipe.lock().unwrap();
+        if pipe.closed {
+            return std::task::Poll::Ready(Err(std::io::Error::new(
+                ErrorKind::BrokenPipe,
+                "CDC ACM handler dropped",
+            )));
+        }
+        pipe.to_host.extend(data);
+        std::task::Poll::Ready(Ok(data.len()))
+    }
+
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
+/// Sub class code for CDC ECM(Ethernet Control Model)
+pub const CDC_ECM_SUBCLASS: u8 = 0x06;
+
+/// bRequest of SEND_ENCAPSULATED_COMMAND
+pub const CDC_SEND_ENCAPSULATED_COMMAND: u8 = 0x00;
+/// bRequest of GET_ENCAPSULATED_RESPONSE
+pub const CDC_GET_ENCAPSULATED_RESPONSE: u8 = 0x01;
+/// bRequest of SET_ETHERNET_MULTICAST_FILTERS
+pub const CDC_SET_ETHERNET_MULTICAST_FILTERS: u8 = 0x40;
+/// bRequest of SET_ETHERNET_PACKET_FILTER
+pub const CDC_SET_ETHERNET_PACKET_FILTER: u8 = 0x43;
+
+/// bNotification of NETWORK_CONNECTION
+const CDC_NETWORK_CONNECTION: u8 = 0x00;
+/// bNotification of CONNECTION_SPEED_CHANGE
+const CDC_CONNECTION_SPEED_CHANGE: u8 = 0x2A;
+
+/// wMaxSegmentSize: largest Ethernet frame, without FCS
+pub const ETHERNET_MAX_SEGMENT_SIZE: usize = 1514;
+
+/// Receives each Ethernet frame sent by the host
+pub type EthernetSink = Box<dyn FnMut(Vec<u8>) + Send>;
+/// Gives the next Ethernet frame to the host, if any
+pub type EthernetSource = Box<dyn FnMut() -> Option<Vec<u8>> + Send>;
+
+/// A handler of the data interface of a CDC ECM(Ethernet Control Model) function
+///
+/// Frames from the host go to the sink, frames to the host come from the
+/// source. Bulk IN transfers are NAKed while the source has no frame. Add it
+/// with [with_cdc_ecm], the host then brings up an interface like `usb0`
+/// with `mac_address`.
+pub struct UsbCdcEcmHandler {
+    /// MAC address of the interface of the host
+    pub mac_address: [u8; 6],
+    /// Frames received from the host
+    pub frames_out: u64,
+    /// Frames sent to the host
+    pub frames_in: u64,
+    sink: Option<EthernetSink>,
+    source: Option<EthernetSource>,
+    /// Packets of a frame from the host not ended by a short packet yet
+    partial: Vec<u8>,
+}
+
+impl UsbCdcEcmHandler {
+    pub fn new(mac_address: [u8; 6]) -> Self {
+        Self {
+            mac_address,
+            frames_out: 0,
+            frames_in: 0,
+            sink: None,
+            source: None,
+            partial: vec![],
+        }
+    }
+
+    /// Pass frames from the host to `sink`, they are dropped without one
+    pub fn with_sink<F: FnMut(Vec<u8>) + Send + 'static>(mut self, sink: F) -> Self {
+        self.sink = Some(Box::new(sink));
+        self
+    }
+
+    /// Send frames from `source` to the host
+    pub fn with_source<F: FnMut() -> Option<Vec<u8>> + Send + 'static>(
+        mut self,
+        source: F,
+    ) -> Self {
+        self.source = Some(Box::new(source));
+        self
+    }
+
+    /// Move frames through channels, e.g. to a thread reading and writing a TAP device
+    ///
+    /// Returns the handler, the sender of frames to the host and the
+    /// receiver of frames from the host.
+    pub fn channel(
+        mac_address: [u8; 6],
+    ) -> (
+        Self,
+        std::sync::mpsc::Sender<Vec<u8>>,
+        std::sync::mpsc::Receiver<Vec<u8>>,
+    ) {
+        let (to_host, source) = std::sync::mpsc::channel();
+        let (sink, from_host) = std::sync::mpsc::channel();
+        let handler = Self::new(mac_address)
+            .with_sink(move |frame| {
+                // the receiver is gone, nobody wants the frame
+                sink.send(frame).ok();
+            })
+            .with_source(move || source.try_recv().ok());
+        (handler, to_host, from_host)
+    }
+
+    pub fn endpoints() -> Vec<UsbEndpoint> {
+        vec![
+            // bulk in
+            UsbEndpoint {
+                address: 0x82,                              // IN
+                attributes: EndpointAttributes::Bulk as u8, // Bulk
+                max_packet_size: 512,                       // 512 bytes
+                interval: 0,
+            },
+            // bulk out
+            UsbEndpoint {
+                address: 0x02,                              // OUT
+                attributes: EndpointAttributes::Bulk as u8, // Bulk
+                max_packet_size: 512,                       // 512 bytes
+                interval: 0,
+            },
+        ]
+    }
+}
+
+impl UsbInterfaceHandler for UsbCdcEcmHandler {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        ep: UsbEndpoint,
+        setup: Option<SetupPacket>,
+        req: &[u8],
+    ) -> Result<Vec<u8>> {
+        if ep.transfer_type() != Some(EndpointAttributes::Bulk) {
+            warn!("CDC ECM unsupported transfer {:x?} {:x?}", ep, setup);
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "unsupported transfer",
+            ));
+        }
+        if let Direction::Out = ep.direction() {
+            // a short packet ends the frame, hosts pad or send a zero
+            // length packet after a frame of whole packets
+            self.partial.extend_from_slice(req);
+            if self.partial.len() > ETHERNET_MAX_SEGMENT_SIZE + 1 {
+                warn!("CDC ECM dropping oversized frame");
+                self.partial.clear();
+            } else if !req.len().is_multiple_of(ep.packet_size()) || req.is_empty() {
+                let frame = std::mem::take(&mut self.partial);
+                self.frames_out += 1;
+                if let Some(sink) = self.sink.as_mut() {
+                    sink(frame);
+                }
+            }
+            return Ok(vec![]);
+        }
+        match self.source.as_mut().and_then(|source| source()) {
+            Some(frame) => {
+                self.frames_in += 1;
+                Ok(frame)
+            }
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::WouldBlock,
+                "no frame to send",
+            )),
+        }
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    /// Drop a frame from the host cut short
+    fn reset(&mut self) {
+        self.partial.clear();
+    }
+
+    fn endpoints(&self) -> Vec<UsbEndpoint> {
+        Self::endpoints()
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// A handler of the communications interface of a CDC ECM function
+///
+/// It reports the link up at 100Mbps on its notification endpoint once,
+/// then NAKs. Packet and multicast filters are recorded but not applied,
+/// encapsulated commands are accepted and have empty responses.
+#[derive(Clone, Debug)]
+pub struct UsbCdcEcmControlHandler {
+    /// Interface number of the communications interface
+    pub control_interface: u8,
+    /// Interface number of the data interface
+    pub data_interface: u8,
+    /// String index of the MAC address
+    pub mac_string: u8,
+    /// bmPacketFilter last set by the host
+    pub packet_filter: u16,
+    notifications: VecDeque<Vec<u8>>,
+}
+
+impl UsbCdcEcmControlHandler {
+    pub fn new(control_interface: u8, data_interface: u8, mac_string: u8) -> Self {
+        let connection = vec![
+            0b10100001,             // bmRequestType
+            CDC_NETWORK_CONNECTION, // bNotification
+            0x01,                   // wValue: connected
+            0x00,
+            control_interface, // wIndex
+            0x00,
+            0x00, // wLength
+            0x00,
+        ];
+        let speed = 100_000_000u32;
+        let mut speed_change = vec![
+            0b10100001,                  // bmRequestType
+            CDC_CONNECTION_SPEED_CHANGE, // bNotification
+            0x00,                        // wValue
+            0x00,
+            control_interface, // wIndex
+            0x00,
+            0x08, // wLength
+            0x00,
+        ];
+        speed_change.extend_from_slice(&speed.to_le_bytes()); // DLBitRate
+        speed_change.extend_from_slice(&speed.to_le_bytes()); // ULBitRate
+        Self {
+            control_interface,
+            data_interface,
+            mac_string,
+            // directed, broadcast and all multicast
+            packet_filter: 0x000E,
+            notifications: vec![connection, speed_change].into(),
+        }
+    }
+
+    pub fn endpoints() -> Vec<UsbEndpoint> {
+        vec![
+            // notification
+            UsbEndpoint {
+                address: 0x81,                                   // IN
+                attributes: EndpointAttributes::Interrupt as u8, // Interrupt
+                max_packet_size: 16,                             // 16 bytes
+                interval: 10,
+            },
+        ]
+    }
+}
+
+impl UsbInterfaceHandler for UsbCdcEcmControlHandler {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        _ep: UsbEndpoint,
+        setup: Option<SetupPacket>,
+        req: &[u8],
+    ) -> Result<Vec<u8>> {
+        let setup = match setup {
+            Some(setup) => setup,
+            None => {
+                // notification
+                return self.notifications.pop_front().ok_or_else(|| {
+                    std::io::Error::new(std::io::ErrorKind::WouldBlock, "no notification")
+                });
+            }
+        };
+        match (setup.request_type, setup.request) {
+            (0b00100001, CDC_SEND_ENCAPSULATED_COMMAND) => {
+                debug!("Ignore encapsulated command {:02x?}", req);
+                Ok(vec![])
+            }
+            (0b10100001, CDC_GET_ENCAPSULATED_RESPONSE) => Ok(vec![]),
+            (0b00100001, CDC_SET_ETHERNET_MULTICAST_FILTERS) => {
+                debug!("Set multicast filters {:02x?}", req);
+                Ok(vec![])
+            }
+            (0b00100001, CDC_SET_ETHERNET_PACKET_FILTER) => {
+                self.packet_filter = setup.value;
+                debug!("Set packet filter {:x}", setup.value);
+                Ok(vec![])
+            }
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                format!("unhandled cdc ecm request {:x?}", setup),
+            )),
+        }
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        let mut desc = vec![
+            // Header
+            0x05, // bFunctionLength
+            0x24, // CS_INTERFACE
+            0x00, // Header
+            0x10,
+            0x01, // CDC 1.2
+            // Union
+            0x05,                   // bFunctionLength
+            0x24,                   // CS_INTERFACE
+            0x06,                   // Union
+            self.control_interface, // bControlInterface
+            self.data_interface,    // bSubordinateInterface0
+            // Ethernet Networking
+            0x0D,            // bFunctionLength
+            0x24,            // CS_INTERFACE
+            0x0F,            // Ethernet Networking
+            self.mac_string, // iMACAddress
+            0x00,
+            0x00,
+            0x00,
+            0x00, // bmEthernetStatistics: none
         ];
+        desc.extend_from_slice(&(ETHERNET_MAX_SEGMENT_SIZE as u16).to_le_bytes()); // wMaxSegmentSize
+        desc.extend_from_slice(&[
+            0x00, 0x00, // wNumberMCFilters: no perfect filtering
+            0x00, // bNumberPowerFilters
+        ]);
+        desc
+    }
+
+    fn endpoints(&self) -> Vec<UsbEndpoint> {
+        Self::endpoints()
     }
 
     fn as_any(&mut self) -> &mut dyn Any {
@@ -97,6 +652,66 @@ impl UsbInterfaceHandler for UsbCdcAcmHandler {
     }
 }
 
+/// Add the communications and data interfaces of a CDC ECM function moving frames with `handler`
+///
+/// The data interface has no endpoints at setting 0, the host selects
+/// setting 1 to bring the link up. Returns the device and the handler of
+/// setting 1.
+pub fn with_cdc_ecm(
+    device: UsbDevice,
+    handler: UsbCdcEcmHandler,
+) -> (UsbDevice, Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>>) {
+    let mut device = device;
+    let mac: String = handler
+        .mac_address
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect();
+    let mac_string = device.new_string(&mac).expect("add MAC address string");
+    let control_interface = device.num_interfaces() as u8;
+    let control =
+        UsbCdcEcmControlHandler::new(control_interface, control_interface + 1, mac_string);
+    let handler = Arc::new(Mutex::new(
+        Box::new(handler) as Box<dyn UsbInterfaceHandler + Send>
+    ));
+    let device = device
+        .with_interface(
+            ClassCode::CDC as u8,
+            CDC_ECM_SUBCLASS,
+            0x00,
+            "CDC Ethernet Control",
+            UsbCdcEcmControlHandler::endpoints(),
+            Arc::new(Mutex::new(
+                Box::new(control) as Box<dyn UsbInterfaceHandler + Send>
+            )),
+        )
+        // no traffic
+        .with_interface(
+            ClassCode::CDCData as u8,
+            0x00,
+            0x00,
+            "CDC Ethernet Data",
+            vec![],
+            FnHandler::new(|_, _, setup, _| {
+                warn!("CDC data unsupported request {:x?}", setup);
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::BrokenPipe,
+                    "unsupported cdc data request",
+                ))
+            })
+            .into_handler(),
+        )
+        .with_alternate_setting(
+            ClassCode::CDCData as u8,
+            0x00,
+            0x00,
+            "CDC Ethernet Data",
+            UsbCdcEcmHandler::endpoints(),
+            handler.clone(),
+        );
+    (device, handler)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -106,4 +721,221 @@ mod tests {
         let handler = UsbCdcAcmHandler::new();
         verify_descriptor(&handler.get_class_specific_descriptor());
     }
+
+    #[tokio::test]
+    async fn ecm_frames() {
+        let (handler, to_host, from_host) =
+            UsbCdcEcmHandler::channel([0x02, 0x00, 0x00, 0x00, 0x00, 0x01]);
+        let (device, _) = with_cdc_ecm(UsbDevice::new(0), handler);
+        verify_descriptor(&device.configuration_descriptor());
+        assert!(device.string_pool.values().any(|s| s == "020000000001"));
+
+        let frame: Vec<u8> = (0..600).map(|i| i as u8).collect();
+        to_host.send(frame.clone()).unwrap();
+        let urbs = vec![
+            // SET_INTERFACE(1, 1)
+            TraceUrb {
+                endpoint: 0x00,
+                setup: [0x01, 0x0B, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00],
+                ..TraceUrb::default()
+            },
+            // SET_ETHERNET_PACKET_FILTER
+            TraceUrb {
+                endpoint: 0x00,
+                setup: [0x21, 0x43, 0x0F, 0x00, 0x00, 0x00, 0x00, 0x00],
+                ..TraceUrb::default()
+            },
+            // a frame of two packets
+            TraceUrb {
+                endpoint: 0x02,
+                transfer_buffer_length: 512,
+                data: frame[..512].to_vec(),
+                ..TraceUrb::default()
+            },
+            TraceUrb {
+                endpoint: 0x02,
+                transfer_buffer_length: 88,
+                data: frame[512..].to_vec(),
+                ..TraceUrb::default()
+            },
+            TraceUrb {
+                endpoint: 0x82,
+                transfer_buffer_length: 1514,
+                ..TraceUrb::default()
+            },
+            TraceUrb {
+                endpoint: 0x82,
+                transfer_buffer_length: 1514,
+                ..TraceUrb::default()
+            },
+        ];
+        let notification = TraceUrb {
+            endpoint: 0x81,
+            transfer_buffer_length: 16,
+            ..TraceUrb::default()
+        };
+        let urbs = urbs.into_iter().chain(std::iter::repeat_n(notification, 3));
+        let replies = device.replay(urbs).await;
+        let statuses: Vec<_> = replies.iter().map(|reply| reply.status).collect();
+        assert_eq!(
+            statuses,
+            [0, 0, 0, 0, 0, -errno::ETIMEDOUT, 0, 0, -errno::ETIMEDOUT]
+        );
+        assert_eq!(from_host.try_recv().unwrap(), frame);
+        assert!(from_host.try_recv().is_err());
+        assert_eq!(replies[4].data, frame);
+        // link up, then 100Mbps both ways
+        assert_eq!(replies[6].data[..4], [0xA1, 0x00, 0x01, 0x00]);
+        assert_eq!(replies[7].data[1], 0x2A);
+        assert_eq!(replies[7].data[8..12], 100_000_000u32.to_le_bytes());
+    }
+
+    #[tokio::test]
+    async fn acm_reset_on_release() {
+        let device = UsbDevice::new(0).with_handler(
+            ClassCode::CDC as u8,
+            CDC_ACM_SUBCLASS,
+            0x00,
+            "Serial",
+            UsbCdcAcmHandler::new(),
+        );
+        let addresses: Vec<_> = device.interfaces[0]
+            .endpoints
+            .iter()
+            .map(|ep| ep.address)
+            .collect();
+        assert_eq!(addresses, [0x81, 0x82, 0x02]);
+        let server = Arc::new(UsbIpServer::new_simulated(vec![device]));
+        let set_line_coding = [0x21, 0x20, 0x00, 0x00, 0x00, 0x00, 0x07, 0x00];
+        let get_line_coding = [0xA1, 0x21, 0x00, 0x00, 0x00, 0x00, 0x07, 0x00];
+        let line_coding = [0x80, 0x25, 0x00, 0x00, 0x00, 0x00, 0x07];
+        let urbs = [
+            testing::CmdSubmit::control(1, set_line_coding, &line_coding),
+            testing::CmdSubmit::control(2, get_line_coding, &[]),
+        ];
+        let rets = testing::round_trip(server.clone(), "0", &urbs)
+            .await
+            .unwrap();
+        assert_eq!(rets[1].data, line_coding);
+
+        // the next client starts from the default line coding
+        let urbs = [testing::CmdSubmit::control(1, get_line_coding, &[])];
+        let rets = testing::round_trip(server, "0", &urbs).await.unwrap();
+        assert_eq!(rets[0].data, UsbCdcAcmHandler::new().line_coding);
+    }
+
+    #[tokio::test]
+    async fn acm_line_state() {
+        let changes = Arc::new(Mutex::new(vec![]));
+        let seen = changes.clone();
+        let acm = UsbCdcAcmHandler::new().with_line_callback(move |acm| {
+            seen.lock()
+                .unwrap()
+                .push((acm.baud_rate(), acm.dtr(), acm.rts()))
+        });
+        let device = UsbDevice::new(0).with_handler(
+            ClassCode::CDC as u8,
+            CDC_ACM_SUBCLASS,
+            0x00,
+            "Serial",
+            acm,
+        );
+        // 9600 baud, 2 stop bits, even parity, 7 data bits
+        let line_coding = vec![0x80, 0x25, 0x00, 0x00, 0x02, 0x02, 0x07];
+        let urbs = vec![
+            TraceUrb {
+                endpoint: 0x00,
+                setup: [0x21, 0x20, 0x00, 0x00, 0x00, 0x00, 0x07, 0x00],
+                transfer_buffer_length: 7,
+                data: line_coding.clone(),
+            },
+            // DTR and RTS
+            TraceUrb {
+                endpoint: 0x00,
+                setup: [0x21, 0x22, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00],
+                ..TraceUrb::default()
+            },
+            TraceUrb {
+                endpoint: 0x80,
+                setup: [0xA1, 0x21, 0x00, 0x00, 0x00, 0x00, 0x07, 0x00],
+                transfer_buffer_length: 7,
+                data: vec![],
+            },
+            // DTR dropped, e.g. the tty closed
+            TraceUrb {
+                endpoint: 0x00,
+                setup: [0x21, 0x22, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00],
+                ..TraceUrb::default()
+            },
+        ];
+        let replies = device.replay(urbs).await;
+        assert!(replies.iter().all(|reply| reply.status == 0));
+        assert_eq!(replies[2].data, line_coding);
+        assert_eq!(
+            *changes.lock().unwrap(),
+            [
+                (9600, false, false),
+                (9600, true, true),
+                (9600, false, true)
+            ]
+        );
+
+        let mut handler = device.interfaces[0].handler.lock().unwrap();
+        let acm = handler.as_any().downcast_mut::<UsbCdcAcmHandler>().unwrap();
+        assert_eq!((acm.stop_bits(), acm.parity(), acm.data_bits()), (2, 2, 7));
+        assert!(!acm.dtr() && acm.rts());
+    }
+
+    #[tokio::test]
+    async fn acm_stream() {
+        let acm = UsbCdcAcmHandler::new();
+        let mut serial = acm.stream();
+        let device = UsbDevice::new(0).with_handler(
+            ClassCode::CDC as u8,
+            CDC_ACM_SUBCLASS,
+            0x00,
+            "Serial",
+            acm,
+        );
+        let bulk_out = |data: &[u8]| TraceUrb {
+            endpoint: 0x02,
+            transfer_buffer_length: data.len() as u32,
+            data: data.to_vec(),
+            ..TraceUrb::default()
+        };
+        let bulk_in = TraceUrb {
+            endpoint: 0x82,
+            transfer_buffer_length: 64,
+            ..TraceUrb::default()
+        };
+
+        serial.write_all(b"login: ").await.unwrap();
+        let replies = device
+            .replay(vec![bulk_in.clone(), bulk_out(b"root\n")])
+            .await;
+        assert_eq!(replies[0].data, b"login: ");
+        let mut line = [0u8; 5];
+        serial.read_exact(&mut line).await.unwrap();
+        assert_eq!(&line, b"root\n");
+
+        // the handler queues alike
+        {
+            let mut handler = device.interfaces[0].handler.lock().unwrap();
+            let acm = handler.as_any().downcast_mut::<UsbCdcAcmHandler>().unwrap();
+            acm.write_to_host(b"# ");
+        }
+        let replies = device.replay(vec![bulk_in, bulk_out(b"exit\n")]).await;
+        assert_eq!(replies[0].data, b"# ");
+        {
+            let mut handler = device.interfaces[0].handler.lock().unwrap();
+            let acm = handler.as_any().downcast_mut::<UsbCdcAcmHandler>().unwrap();
+            assert_eq!(acm.take_from_host(), b"exit\n");
+        }
+
+        // the stream ends with the device
+        drop(device);
+        let mut rest = vec![];
+        serial.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+    }
 }
@@ -0,0 +1,381 @@
+//! Client side of the USB/IP protocol, see [UsbIpClient]
+use super::*;
+use tokio::net::TcpStream;
+
+/// An interface of a device listed by [UsbIpClient::list_devices]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UsbInterfaceInfo {
+    pub interface_class: u8,
+    pub interface_subclass: u8,
+    pub interface_protocol: u8,
+}
+
+/// A device exported by a USB/IP server, as described in OP_REP_DEVLIST and
+/// OP_REP_IMPORT
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UsbDeviceInfo {
+    pub path: String,
+    pub bus_id: String,
+    pub bus_num: u32,
+    pub dev_num: u32,
+    pub speed: u32,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub device_bcd: u16,
+    pub device_class: u8,
+    pub device_subclass: u8,
+    pub device_protocol: u8,
+    pub configuration_value: u8,
+    pub num_configurations: u8,
+    pub num_interfaces: u8,
+    /// Only listed by OP_REP_DEVLIST, empty for an imported device
+    pub interfaces: Vec<UsbInterfaceInfo>,
+}
+
+impl UsbDeviceInfo {
+    /// Read what [UsbDevice::write_dev] writes
+    async fn read_dev<T: AsyncReadExt + Unpin>(socket: &mut T) -> Result<Self> {
+        Ok(Self {
+            path: socket_read_fixed_string(socket, 256).await?,
+            bus_id: socket_read_fixed_string(socket, 32).await?,
+            bus_num: socket.read_u32().await?,
+            dev_num: socket.read_u32().await?,
+            speed: socket.read_u32().await?,
+            vendor_id: socket.read_u16().await?,
+            product_id: socket.read_u16().await?,
+            device_bcd: socket.read_u16().await?,
+            device_class: socket.read_u8().await?,
+            device_subclass: socket.read_u8().await?,
+            device_protocol: socket.read_u8().await?,
+            configuration_value: socket.read_u8().await?,
+            num_configurations: socket.read_u8().await?,
+            num_interfaces: socket.read_u8().await?,
+            interfaces: vec![],
+        })
+    }
+
+    /// Read what [UsbDevice::write_dev_with_interfaces] writes
+    async fn read_dev_with_interfaces<T: AsyncReadExt + Unpin>(socket: &mut T) -> Result<Self> {
+        let mut info = Self::read_dev(socket).await?;
+        for _ in 0..info.num_interfaces {
+            info.interfaces.push(UsbInterfaceInfo {
+                interface_class: socket.read_u8().await?,
+                interface_subclass: socket.read_u8().await?,
+                interface_protocol: socket.read_u8().await?,
+            });
+            // padding
+            socket.read_u8().await?;
+        }
+        Ok(info)
+    }
+}
+
+/// Read the header of an OP_REP_* reply to `code`, returning its status
+async fn read_reply_header<T: AsyncReadExt + Unpin>(socket: &mut T, code: u32) -> Result<u32> {
+    let version_code = socket.read_u32().await?;
+    if version_code != (USBIP_VERSION as u32) << 16 | code {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("unexpected reply {:08x}", version_code),
+        ));
+    }
+    socket.read_u32().await
+}
+
+/// A connection to a USB/IP server
+///
+/// Speaks the protocol of the kernel `usbip` tool, so tests can exercise a
+/// [UsbIpServer] end to end without root or the vhci driver.
+pub struct UsbIpClient<T: UsbIpSocket = TcpStream> {
+    socket: T,
+}
+
+impl UsbIpClient<TcpStream> {
+    /// Connect to the USB/IP server at `addr`
+    pub async fn connect(addr: SocketAddr) -> Result<Self> {
+        Ok(Self::new(TcpStream::connect(addr).await?))
+    }
+}
+
+impl<T: UsbIpSocket> UsbIpClient<T> {
+    /// Speak USB/IP over an already connected `socket`
+    pub fn new(socket: T) -> Self {
+        Self { socket }
+    }
+
+    /// List the exported devices with OP_REQ_DEVLIST
+    pub async fn list_devices(&mut self) -> Result<Vec<UsbDeviceInfo>> {
+        // OP_REQ_DEVLIST
+        self.socket.write_u32(0x01118005).await?;
+        self.socket.write_u32(0).await?;
+
+        // OP_REP_DEVLIST
+        let status = read_reply_header(&mut self.socket, 0x0005).await?;
+        if status != 0 {
+            return Err(std::io::Error::other(format!(
+                "device list failed with status {}",
+                status
+            )));
+        }
+        let count = self.socket.read_u32().await?;
+        let mut devices = vec![];
+        for _ in 0..count {
+            devices.push(UsbDeviceInfo::read_dev_with_interfaces(&mut self.socket).await?);
+        }
+        Ok(devices)
+    }
+
+    /// Import the device `bus_id` with OP_REQ_IMPORT
+    ///
+    /// Fails with [ErrorKind::NotFound] if the server does not export it,
+    /// [ErrorKind::ResourceBusy] if another client imported it and
+    /// [ErrorKind::PermissionDenied] if the server refused the import. The
+    /// connection then carries the URBs of the device.
+    pub async fn import(mut self, bus_id: &str) -> Result<ImportedDevice<T>> {
+        // OP_REQ_IMPORT
+        self.socket.write_u32(0x01118003).await?;
+        self.socket.write_u32(0).await?;
+        socket_write_fixed_string(&mut self.socket, bus_id, 32).await?;
+
+        // OP_REP_IMPORT, without a device if it failed
+        let status = read_reply_header(&mut self.socket, 0x0003).await?;
+        if status != 0 {
+            let kind = match status {
+                OP_STATUS_NOT_AVAILABLE => ErrorKind::PermissionDenied,
+                OP_STATUS_DEVICE_BUSY => ErrorKind::ResourceBusy,
+                _ => ErrorKind::NotFound,
+            };
+            return Err(std::io::Error::new(
+                kind,
+                format!("import of {} failed with status {}", bus_id, status),
+            ));
+        }
+        let info = UsbDeviceInfo::read_dev(&mut self.socket).await?;
+        Ok(ImportedDevice {
+            socket: self.socket,
+            info,
+            seq_num: 0,
+        })
+    }
+}
+
+/// Completion of a URB, from USBIP_RET_SUBMIT
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UrbResult {
+    /// 0 or a negative errno, e.g. `-errno::EPIPE` for a STALL
+    pub status: i32,
+    pub actual_length: u32,
+    /// Data of IN transfers
+    pub data: Vec<u8>,
+}
+
+/// A device imported by [UsbIpClient::import]
+pub struct ImportedDevice<T: UsbIpSocket = TcpStream> {
+    socket: T,
+    info: UsbDeviceInfo,
+    seq_num: u32,
+}
+
+impl<T: UsbIpSocket> ImportedDevice<T> {
+    /// The device as described by OP_REP_IMPORT
+    pub fn info(&self) -> &UsbDeviceInfo {
+        &self.info
+    }
+
+    /// Submit a URB to `endpoint` with USBIP_CMD_SUBMIT and wait for its
+    /// completion
+    ///
+    /// The direction of ep0 transfers is the one of bmRequestType in `setup`.
+    /// IN transfers ask for `length` bytes, OUT transfers send `out_data`.
+    /// Isochronous transfers are not supported.
+    pub async fn submit(
+        &mut self,
+        endpoint: u8,
+        setup: [u8; 8],
+        transfer_flags: u32,
+        length: u32,
+        out_data: &[u8],
+    ) -> Result<UrbResult> {
+        let number = endpoint & 0x7F;
+        let direction = if number == 0 {
+            setup[0] & 0x80
+        } else {
+            endpoint & 0x80
+        };
+        let (direction, transfer_buffer_length) = if direction != 0 {
+            (Direction::In, length)
+        } else {
+            (Direction::Out, out_data.len() as u32)
+        };
+        self.seq_num += 1;
+        let seq_num = self.seq_num;
+        let dev_id = self.info.bus_num << 16 | self.info.dev_num;
+
+        // USBIP_CMD_SUBMIT
+        let socket = &mut self.socket;
+        socket.write_u32(0x1).await?;
+        socket.write_u32(seq_num).await?;
+        socket.write_u32(dev_id).await?;
+        socket.write_u32(direction.to_usbip()).await?;
+        socket.write_u32(number as u32).await?;
+        socket.write_u32(transfer_flags).await?;
+        socket.write_u32(transfer_buffer_length).await?;
+        // start frame
+        socket.write_u32(0).await?;
+        socket.write_u32(ISO_NO_PACKETS).await?;
+        // interval
+        socket.write_u32(0).await?;
+        socket.write_all(&setup).await?;
+        if direction == Direction::Out {
+            socket.write_all(out_data).await?;
+        }
+
+        // USBIP_RET_SUBMIT
+        let command = socket.read_u32().await?;
+        let ret_seq_num = socket.read_u32().await?;
+        if command != 0x3 || ret_seq_num != seq_num {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "expected USBIP_RET_SUBMIT {}, got command {:x} {}",
+                    seq_num, command, ret_seq_num
+                ),
+            ));
+        }
+        // dev id, direction, ep
+        let mut ignored = [0u8; 3 * 4];
+        socket.read_exact(&mut ignored).await?;
+        let status = socket.read_i32().await?;
+        let actual_length = socket.read_u32().await?;
+        // start frame, number of packets, error count, setup
+        let mut ignored = [0u8; 3 * 4 + 8];
+        socket.read_exact(&mut ignored).await?;
+        let mut data = vec![];
+        if direction == Direction::In {
+            data.resize(actual_length as usize, 0);
+            socket.read_exact(&mut data).await?;
+        }
+        Ok(UrbResult {
+            status,
+            actual_length,
+            data,
+        })
+    }
+
+    /// Submit a control transfer to ep0, reading wLength bytes if it is IN
+    pub async fn control(&mut self, setup: [u8; 8], out_data: &[u8]) -> Result<UrbResult> {
+        let length = u16::from_le_bytes([setup[6], setup[7]]) as u32;
+        self.submit(0, setup, 0, length, out_data).await
+    }
+
+    /// Give back the connection
+    pub fn into_inner(self) -> T {
+        self.socket
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn list_import_submit() {
+        let handler = Arc::new(Mutex::new(
+            Box::new(cdc::UsbCdcAcmHandler::new()) as Box<dyn UsbInterfaceHandler + Send>
+        ));
+        let device = UsbDevice::new(0).with_vendor_id(0x1234).with_interface(
+            ClassCode::CDC as u8,
+            cdc::CDC_ACM_SUBCLASS,
+            0x00,
+            "Test CDC ACM",
+            cdc::UsbCdcAcmHandler::endpoints(),
+            handler,
+        );
+        let server = UsbIpServer::new_simulated(vec![device]);
+        let handle = spawn_server("127.0.0.1:0".parse().unwrap(), Arc::new(server))
+            .await
+            .unwrap();
+
+        let mut client = UsbIpClient::connect(handle.local_addr()).await.unwrap();
+        let devices = client.list_devices().await.unwrap();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].bus_id, "0");
+        assert_eq!(devices[0].vendor_id, 0x1234);
+        assert_eq!(
+            devices[0].interfaces,
+            [UsbInterfaceInfo {
+                interface_class: ClassCode::CDC as u8,
+                interface_subclass: cdc::CDC_ACM_SUBCLASS,
+                interface_protocol: 0x00,
+            }]
+        );
+
+        let mut device = client.import("0").await.unwrap();
+        assert_eq!(device.info().path, "/sys/device/usbip/0");
+        // GET_DESCRIPTOR device
+        let res = device
+            .control([0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x40, 0x00], &[])
+            .await
+            .unwrap();
+        assert_eq!(res.status, 0);
+        assert_eq!(res.actual_length, 18);
+        assert_eq!(res.data[8..10], 0x1234u16.to_le_bytes());
+        // bulk OUT to the ACM
+        let res = device.submit(0x02, [0; 8], 0, 0, b"hello").await.unwrap();
+        assert_eq!((res.status, res.actual_length), (0, 5));
+        // no such endpoint
+        let res = device.submit(0x85, [0; 8], 0, 8, &[]).await.unwrap();
+        assert_eq!(res.status, -errno::EPIPE);
+
+        let client = UsbIpClient::connect(handle.local_addr()).await.unwrap();
+        let err = client.import("1-1").await.err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        handle.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn in_memory_cdc() {
+        let handler = Arc::new(Mutex::new(
+            Box::new(cdc::UsbCdcAcmHandler::new()) as Box<dyn UsbInterfaceHandler + Send>
+        ));
+        let device = UsbDevice::new(0).with_interface(
+            ClassCode::CDC as u8,
+            cdc::CDC_ACM_SUBCLASS,
+            0x00,
+            "Test CDC ACM",
+            cdc::UsbCdcAcmHandler::endpoints(),
+            handler.clone(),
+        );
+        let server = Arc::new(UsbIpServer::new_simulated(vec![device]));
+        let (client, stream) = duplex_pair();
+        let serve = tokio::spawn(serve_stream(stream, server));
+
+        let mut device = UsbIpClient::new(client).import("0").await.unwrap();
+        // SET_LINE_CODING to 115200 8N1
+        let line_coding = [0x00, 0xC2, 0x01, 0x00, 0x00, 0x00, 0x08];
+        let res = device
+            .control(
+                [0x21, 0x20, 0x00, 0x00, 0x00, 0x00, 0x07, 0x00],
+                &line_coding,
+            )
+            .await
+            .unwrap();
+        assert_eq!(res.status, 0);
+        let res = device.submit(0x02, [0; 8], 0, 0, b"ping").await.unwrap();
+        assert_eq!((res.status, res.actual_length), (0, 4));
+        {
+            let mut handler = handler.lock().unwrap();
+            let acm = handler
+                .as_any()
+                .downcast_mut::<cdc::UsbCdcAcmHandler>()
+                .unwrap();
+            assert_eq!(acm.baud_rate(), 115200);
+            acm.tx_buffer = b"pong".to_vec();
+        }
+        let res = device.submit(0x82, [0; 8], 0, 64, &[]).await.unwrap();
+        assert_eq!(res.data, b"pong");
+
+        drop(device);
+        serve.await.unwrap().unwrap();
+    }
+}
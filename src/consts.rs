@@ -40,8 +40,14 @@ pub enum ClassCode {
     VendorSpecific = 0xFF,
 }
 
+/// Sub class code of [ClassCode::Misc] for common class
+pub const MISC_SUBCLASS_COMMON: u8 = 0x02;
+
+/// Protocol code of [ClassCode::Misc] for Interface Association Descriptor
+pub const MISC_PROTOCOL_IAD: u8 = 0x01;
+
 /// A list of defined USB endpoint attributes
-#[derive(Copy, Clone, Debug, FromPrimitive)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, FromPrimitive)]
 pub enum EndpointAttributes {
     Control = 0,
     Isochronous,
@@ -49,18 +55,156 @@ pub enum EndpointAttributes {
     Interrupt,
 }
 
+/// Synchronization type of isochronous endpoints, bits 3:2 of bmAttributes
+#[derive(Copy, Clone, Debug, PartialEq, Eq, FromPrimitive)]
+pub enum SyncType {
+    NoSync = 0,
+    Async,
+    Adaptive,
+    Sync,
+}
+
+/// Usage type of isochronous endpoints, bits 5:4 of bmAttributes
+#[derive(Copy, Clone, Debug, PartialEq, Eq, FromPrimitive)]
+pub enum UsageType {
+    Data = 0,
+    Feedback,
+    ImplicitFeedback,
+}
+
 /// USB endpoint direction: IN or OUT
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Direction {
     In,
     Out,
 }
 
+impl Direction {
+    /// Parse the direction field of a USB/IP header: 0 for OUT, 1 for IN
+    pub fn from_usbip(direction: u32) -> Self {
+        match direction {
+            USBIP_DIR_OUT => Direction::Out,
+            _ => Direction::In,
+        }
+    }
+
+    /// Encode as the direction field of a USB/IP header
+    pub fn to_usbip(self) -> u32 {
+        match self {
+            Direction::Out => USBIP_DIR_OUT,
+            Direction::In => USBIP_DIR_IN,
+        }
+    }
+}
+
+/// Value of the USB/IP direction field for OUT transfers
+pub const USBIP_DIR_OUT: u32 = 0;
+
+/// Value of the USB/IP direction field for IN transfers
+pub const USBIP_DIR_IN: u32 = 1;
+
+/// Transfer flag failing IN URBs which complete with less data than
+/// transfer_buffer_length with `-EREMOTEIO`
+pub const USBIP_URB_SHORT_NOT_OK: u32 = 0x0001;
+
+/// transfer_flags of a USB/IP CMD_SUBMIT, mirroring the kernel's URB flags
+///
+/// Honored by the server:
+/// - [TransferFlags::SHORT_NOT_OK]: short IN replies fail with `-EREMOTEIO`
+///
+/// Passed to handlers in [crate::ConnectionContext::transfer_flags] and left
+/// to them:
+/// - [TransferFlags::ZERO_PACKET]: a bulk OUT transfer whose length is a
+///   multiple of the packet size ends with a zero-length packet. Handlers
+///   which frame data by short packets see the end of a transfer here, not
+///   from an empty request. [crate::UsbHostHandler] sends the packet to the
+///   host device.
+/// - [TransferFlags::DIR_IN]: direction of the transfer buffer, which
+///   matches the direction field of the submit
+///
+/// Ignored, as they only concern memory and interrupts of the client host:
+/// [TransferFlags::ISO_ASAP], [TransferFlags::NO_TRANSFER_DMA_MAP],
+/// [TransferFlags::NO_INTERRUPT] and [TransferFlags::FREE_BUFFER].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct TransferFlags(pub u32);
+
+impl TransferFlags {
+    /// Fail IN transfers shorter than transfer_buffer_length
+    pub const SHORT_NOT_OK: Self = Self(USBIP_URB_SHORT_NOT_OK);
+    /// Schedule an iso transfer at the next free frame
+    pub const ISO_ASAP: Self = Self(0x0002);
+    /// Transfer buffer is already mapped for DMA
+    pub const NO_TRANSFER_DMA_MAP: Self = Self(0x0004);
+    /// Terminate a bulk OUT transfer with a zero-length packet if needed
+    pub const ZERO_PACKET: Self = Self(0x0040);
+    /// No completion interrupt is needed
+    pub const NO_INTERRUPT: Self = Self(0x0080);
+    /// Free the transfer buffer with the URB
+    pub const FREE_BUFFER: Self = Self(0x0100);
+    /// Transfer buffer is written by the device
+    pub const DIR_IN: Self = Self(0x0200);
+
+    /// Raw value as sent on the wire
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Whether all bits of `other` are set
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Direction of the transfer buffer
+    pub fn direction(self) -> Direction {
+        if self.contains(Self::DIR_IN) {
+            Direction::In
+        } else {
+            Direction::Out
+        }
+    }
+}
+
+impl std::ops::BitOr for TransferFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
 /// Emulated max packet size of EP0
 pub const EP0_MAX_PACKET_SIZE: u16 = 64;
 
+/// Linux errno values, USB/IP reports URB status as their negation
+pub mod errno {
+    /// No such file or directory: URB was unlinked synchronously
+    pub const ENOENT: i32 = 2;
+    /// No such device
+    pub const ENODEV: i32 = 19;
+    /// Invalid argument
+    pub const EINVAL: i32 = 22;
+    /// Broken pipe: endpoint stalled
+    pub const EPIPE: i32 = 32;
+    /// Invalid request descriptor: wLength and transfer length disagree
+    pub const EBADR: i32 = 53;
+    /// Protocol error
+    pub const EPROTO: i32 = 71;
+    /// Value too large: device sent more data than requested
+    pub const EOVERFLOW: i32 = 75;
+    /// Message too long: iso packet above the endpoint payload
+    pub const EMSGSIZE: i32 = 90;
+    /// Connection reset: URB was unlinked asynchronously
+    pub const ECONNRESET: i32 = 104;
+    /// Transport endpoint shut down: the server is shutting down
+    pub const ESHUTDOWN: i32 = 108;
+    /// Connection timed out
+    pub const ETIMEDOUT: i32 = 110;
+    /// Remote I/O error: short transfer with URB_SHORT_NOT_OK
+    pub const EREMOTEIO: i32 = 121;
+}
+
 /// A list of defined USB standard requests
-#[derive(Copy, Clone, Debug, FromPrimitive)]
+#[derive(Copy, Clone, Debug, PartialEq, FromPrimitive)]
 pub enum StandardRequest {
     GetStatus = 0,
     ClearFeature = 1,
@@ -70,8 +214,17 @@ pub enum StandardRequest {
     GetConfiguration = 8,
     SetConfiguration = 9,
     GetInterface = 0xA,
-    SetInterface = 0x11,
-    SynthFrame = 0x12,
+    SetInterface = 0xB,
+    SynthFrame = 0xC,
+}
+
+/// A list of defined USB standard feature selectors
+#[derive(Copy, Clone, Debug, PartialEq, Eq, FromPrimitive)]
+pub enum FeatureSelector {
+    EndpointHalt = 0,
+    DeviceRemoteWakeup = 1,
+    /// Electrical test modes, acknowledged as no-ops in emulation
+    TestMode = 2,
 }
 
 /// A list of defined USB descriptor types
@@ -89,4 +242,32 @@ pub enum DescriptorType {
     Debug = 0xA,
     InterfaceAssociation = 0xB,
     BOS = 0xF,
+    DeviceCapability = 0x10,
+}
+
+/// A list of defined device capability types in the BOS descriptor
+#[derive(Copy, Clone, Debug, FromPrimitive)]
+pub enum DeviceCapability {
+    Usb20Extension = 0x2,
+    SuperSpeedUsb = 0x3,
+    ContainerId = 0x4,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn direction_from_usbip() {
+        assert_eq!(Direction::from_usbip(0), Direction::Out);
+        assert_eq!(Direction::from_usbip(1), Direction::In);
+        assert_eq!(
+            Direction::from_usbip(Direction::In.to_usbip()),
+            Direction::In
+        );
+        assert_eq!(
+            Direction::from_usbip(Direction::Out.to_usbip()),
+            Direction::Out
+        );
+    }
 }
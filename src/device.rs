@@ -17,6 +17,7 @@ pub struct UsbDevice {
     pub configuration_value: u8,
     pub num_configurations: u8,
     pub interfaces: Vec<UsbInterface>,
+    pub interface_associations: Vec<UsbInterfaceAssociation>,
     pub(crate) ep0_in: UsbEndpoint,
     pub(crate) ep0_out: UsbEndpoint,
     // strings
@@ -25,6 +26,235 @@ pub struct UsbDevice {
     pub(crate) string_manufacturer: u8,
     pub(crate) string_product: u8,
     pub(crate) string_serial: u8,
+    // language of string_pool, PRIMARY_LANGID if unset
+    pub(crate) primary_language: Option<u16>,
+    // languages after the primary one, whose strings are in string_pool
+    pub(crate) other_languages: Vec<u16>,
+    pub(crate) translated_strings: HashMap<(u16, u8), String>,
+    pub(crate) string_language_fallback: StringLanguageFallback,
+    // configurations after the first, selected by SET_CONFIGURATION
+    pub(crate) other_configurations: Vec<UsbConfiguration>,
+    // interfaces of the first configuration by mode, see DescriptorMode
+    pub(crate) descriptor_mode: Option<DescriptorMode>,
+    pub(crate) mode_configurations: HashMap<u8, UsbConfiguration>,
+    // device to present instead on the next import
+    pub(crate) reenumeration: Option<Reenumeration>,
+    // set by the host, shared by all clones of this device
+    pub(crate) state: Arc<Mutex<UsbDeviceState>>,
+    // throughput limits, shared by all clones of this device
+    pub(crate) device_throttle: Option<Arc<Mutex<TokenBucket>>>,
+    pub(crate) endpoint_throttles: HashMap<u8, Arc<Mutex<TokenBucket>>>,
+    // random delay before each RET_SUBMIT, shared by all clones of this device
+    #[cfg(feature = "fault-injection")]
+    pub(crate) response_jitter: Option<Arc<Mutex<ResponseJitter>>>,
+    // each import gets forked handlers and fresh state
+    pub(crate) import_clones: bool,
+    // IN queues waking URBs pending on their endpoint
+    pub(crate) in_queues: HashMap<u8, InEndpointQueue>,
+    // latency injected before answering standard requests
+    pub(crate) enumeration_delays: HashMap<u8, Duration>,
+    // BOS
+    pub(crate) container_id: Option<[u8; 16]>,
+    pub(crate) usb20_extension: Option<u32>,
+    // power capabilities in bmAttributes of the configuration descriptor
+    pub(crate) self_powered: bool,
+    pub(crate) remote_wakeup: bool,
+    // injected errors, shared by all clones of this device
+    pub(crate) endpoint_errors: Arc<Mutex<HashMap<u8, EndpointError>>>,
+    // transfer counters, shared by all clones of this device
+    pub(crate) stats: DeviceStats,
+    // callbacks observing control requests
+    pub(crate) control_hooks: Vec<(ControlRequestMatcher, ControlRequestCallback)>,
+    // accepts SET_DESCRIPTOR, stalled without it
+    pub(crate) set_descriptor_handler: Option<SetDescriptorHandler>,
+    // set by UsbIpServer::unplug_device, shared by all clones of this device
+    pub(crate) removal: Arc<Removal>,
+    // set while a client imports it, shared by all clones of this device
+    pub(crate) imported: Arc<std::sync::atomic::AtomicBool>,
+}
+
+/// Claim of a client on an imported device, released when dropped
+///
+/// Releasing resets the handlers of the device, see
+/// [UsbInterfaceHandler::reset], before another client can claim it.
+pub(crate) struct ImportLease {
+    imported: Option<Arc<std::sync::atomic::AtomicBool>>,
+    handlers: Vec<Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>>>,
+}
+
+impl Drop for ImportLease {
+    fn drop(&mut self) {
+        for handler in &self.handlers {
+            // a handler which panicked is left as it is
+            if let Ok(mut handler) = handler.lock() {
+                handler.reset();
+            }
+        }
+        if let Some(imported) = &self.imported {
+            imported.store(false, std::sync::atomic::Ordering::SeqCst);
+        }
+    }
+}
+
+/// Marks a device as unplugged and wakes URBs waiting on it
+#[derive(Debug, Default)]
+pub(crate) struct Removal {
+    removed: std::sync::atomic::AtomicBool,
+    notify: tokio::sync::Notify,
+}
+
+impl Removal {
+    pub(crate) fn remove(&self) {
+        self.removed
+            .store(true, std::sync::atomic::Ordering::SeqCst);
+        self.notify.notify_waiters();
+    }
+
+    pub(crate) fn is_removed(&self) -> bool {
+        self.removed.load(std::sync::atomic::Ordering::SeqCst)
+    }
+
+    /// Complete once the device is removed
+    pub(crate) async fn wait(&self) {
+        let notified = self.notify.notified();
+        if !self.is_removed() {
+            notified.await;
+        }
+    }
+}
+
+/// Accept a descriptor written by SET_DESCRIPTOR, see [UsbDevice::with_set_descriptor]
+///
+/// Called with the descriptor type, descriptor index, language id and the
+/// descriptor. Return an error of kind [std::io::ErrorKind::BrokenPipe] to
+/// STALL the request.
+pub type SetDescriptorHandler = Arc<dyn Fn(u8, u8, u16, &[u8]) -> Result<()> + Send + Sync>;
+
+/// Default language id of the strings of a [UsbDevice], en-US
+///
+/// See [UsbDevice::with_primary_language] to change it.
+pub const PRIMARY_LANGID: u16 = 0x0409;
+
+/// Answer to a string request in a language the device does not list, see
+/// [UsbDevice::with_string_language_fallback]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StringLanguageFallback {
+    /// Return the string in the primary language, like most devices do
+    #[default]
+    Primary,
+    /// STALL the request
+    Stall,
+}
+
+/// State of a device changed by the host through standard requests
+#[derive(Clone, Debug, Default)]
+pub struct UsbDeviceState {
+    /// Active bConfigurationValue, 0 if not configured
+    pub configuration: u8,
+    /// Alternate setting selected by SET_INTERFACE, by interface number
+    pub alternate_settings: HashMap<u8, u8>,
+    /// Endpoints halted by SET_FEATURE(ENDPOINT_HALT)
+    pub halted_endpoints: std::collections::BTreeSet<u8>,
+    /// Remote wakeup enabled by SET_FEATURE(DEVICE_REMOTE_WAKEUP)
+    pub remote_wakeup: bool,
+    /// IN data beyond the transfer_buffer_length of a URB, by endpoint
+    /// address, served to the next URBs on the endpoint
+    pub in_remainders: HashMap<u8, Vec<u8>>,
+}
+
+/// How the host configured a device, see [UsbDevice::runtime_state]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UsbRuntimeState {
+    /// Active bConfigurationValue, 0 if not configured
+    pub configuration: u8,
+    /// Active alternate setting of each interface, indexed by interface number
+    pub alternate_settings: Vec<u8>,
+    /// Addresses of halted endpoints, ascending
+    pub halted_endpoints: Vec<u8>,
+    pub remote_wakeup: bool,
+}
+
+/// A trigger to replace a device with a different one, as if it re-enumerated
+///
+/// Some devices, e.g. DFU, present a different descriptor set after a reset.
+/// Attach it to a device with [UsbDevice::with_reenumeration] and give a clone
+/// to a handler, which calls [Reenumeration::trigger] when the device should
+/// change. The server swaps in the new device, keeping the bus id, before the
+/// next device list or import. Clients which already imported the old device
+/// keep talking to it.
+#[derive(Clone, Default)]
+pub struct Reenumeration {
+    next: Arc<Mutex<Option<UsbDevice>>>,
+}
+
+impl Reenumeration {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Present `device` instead from now on
+    pub fn trigger(&self, device: UsbDevice) {
+        *self.next.lock().unwrap() = Some(device);
+    }
+
+    pub(crate) fn take(&self) -> Option<UsbDevice> {
+        self.next.lock().unwrap().take()
+    }
+
+    pub(crate) fn is_triggered(&self) -> bool {
+        self.next.lock().unwrap().is_some()
+    }
+}
+
+/// A switch between interface sets of a device that reconfigures itself
+///
+/// Some devices change their interfaces on a vendor request, e.g. to expose a
+/// diagnostic function, without disconnecting. Attach it to a device with
+/// [UsbDevice::with_descriptor_mode] and give a clone to a handler, which
+/// calls [DescriptorMode::set] to switch. From then on descriptor reads,
+/// device lists and URBs see the interfaces of the new mode, mode 0 being
+/// the interfaces given to [UsbDevice::with_interface].
+///
+/// Unlike [Reenumeration], nothing tells the host: it keeps the descriptors
+/// it read during enumeration and the drivers it bound to them, until it
+/// reads them again, e.g. after a port reset. Use it to test how host software
+/// copes with that; to make the host enumerate the new interfaces, re-attach
+/// or use [Reenumeration] instead. The mode is shared by all imports, even
+/// with [UsbDevice::with_import_clones].
+#[derive(Clone, Debug, Default)]
+pub struct DescriptorMode {
+    current: Arc<Mutex<u8>>,
+}
+
+impl DescriptorMode {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Present the interfaces of `mode` from now on
+    pub fn set(&self, mode: u8) {
+        *self.current.lock().unwrap() = mode;
+    }
+
+    /// The current mode, 0 initially
+    pub fn get(&self) -> u8 {
+        *self.current.lock().unwrap()
+    }
+}
+
+/// A configuration other than the first one of a [UsbDevice]
+#[derive(Clone, Default)]
+pub struct UsbConfiguration {
+    pub configuration_value: u8,
+    pub string_configuration: u8,
+    pub interfaces: Vec<UsbInterface>,
+    pub interface_associations: Vec<UsbInterfaceAssociation>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct EndpointError {
+    status: i32,
+    once: bool,
 }
 
 impl UsbDevice {
@@ -51,15 +281,15 @@ impl UsbDevice {
             num_configurations: 1,
             ..Self::default()
         };
-        res.string_configuration = res.new_string("Default Configuration");
-        res.string_manufacturer = res.new_string("Manufacturer");
-        res.string_product = res.new_string("Product");
-        res.string_serial = res.new_string("Serial");
+        res.string_configuration = res.new_string("Default Configuration").unwrap();
+        res.string_manufacturer = res.new_string("Manufacturer").unwrap();
+        res.string_product = res.new_string("Product").unwrap();
+        res.string_serial = res.new_string("Serial").unwrap();
         res
     }
 
     pub fn with_interface(
-        mut self,
+        self,
         interface_class: u8,
         interface_subclass: u8,
         interface_protocol: u8,
@@ -67,214 +297,1308 @@ impl UsbDevice {
         endpoints: Vec<UsbEndpoint>,
         handler: Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>>,
     ) -> Self {
-        let string_interface = self.new_string(name);
-        let class_specific_descriptor = handler.lock().unwrap().get_class_specific_descriptor();
-        self.interfaces.push(UsbInterface {
+        self.try_with_interface(
             interface_class,
             interface_subclass,
             interface_protocol,
+            name,
             endpoints,
-            string_interface,
-            class_specific_descriptor,
             handler,
-        });
-        self
+        )
+        .expect("add interface")
     }
 
-    pub(crate) fn new_string(&mut self, s: &str) -> u8 {
-        for i in 1.. {
-            if self.string_pool.get(&i).is_none() {
-                self.string_pool.insert(i, s.to_string());
-                return i;
-            }
+    /// Add an interface served by `handler`, with the endpoints it reports
+    ///
+    /// Like [UsbDevice::with_interface], with the endpoints from
+    /// [UsbInterfaceHandler::endpoints], so they cannot go out of sync.
+    pub fn with_handler<H: UsbInterfaceHandler + Send + 'static>(
+        self,
+        interface_class: u8,
+        interface_subclass: u8,
+        interface_protocol: u8,
+        name: &str,
+        handler: H,
+    ) -> Self {
+        let endpoints = handler.endpoints();
+        self.with_interface(
+            interface_class,
+            interface_subclass,
+            interface_protocol,
+            name,
+            endpoints,
+            Arc::new(Mutex::new(Box::new(handler))),
+        )
+    }
+
+    /// Like [UsbDevice::with_interface], but fails instead of producing a corrupt
+    /// configuration descriptor when it would exceed 255 interfaces or the
+    /// 65535 bytes wTotalLength can describe, when no string descriptor index
+    /// is left for `name`, when an endpoint address is
+    /// already used by another interface or twice in `endpoints`, or when
+    /// an endpoint has the opposite direction of the one `handler` declares
+    pub fn try_with_interface(
+        self,
+        interface_class: u8,
+        interface_subclass: u8,
+        interface_protocol: u8,
+        name: &str,
+        endpoints: Vec<UsbEndpoint>,
+        handler: Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>>,
+    ) -> Result<Self> {
+        if self.num_interfaces() >= u8::MAX as usize {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "too many interfaces",
+            ));
         }
-        panic!("string poll exhausted")
+        self.push_interface(
+            interface_class,
+            interface_subclass,
+            interface_protocol,
+            0,
+            name,
+            endpoints,
+            handler,
+        )
     }
 
-    pub(crate) fn find_ep(&self, ep: u8) -> Option<(UsbEndpoint, Option<&UsbInterface>)> {
-        if ep == self.ep0_in.address {
-            Some((self.ep0_in, None))
-        } else if ep == self.ep0_out.address {
-            Some((self.ep0_out, None))
+    /// Add an alternate setting to the last added interface
+    ///
+    /// Settings are numbered from 1 in the order they are added. It does not
+    /// count as another interface in bNumInterfaces. Transfers to its endpoints
+    /// go to `handler`, control requests to the interface go to setting 0.
+    pub fn with_alternate_setting(
+        self,
+        interface_class: u8,
+        interface_subclass: u8,
+        interface_protocol: u8,
+        name: &str,
+        endpoints: Vec<UsbEndpoint>,
+        handler: Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>>,
+    ) -> Self {
+        self.try_with_alternate_setting(
+            interface_class,
+            interface_subclass,
+            interface_protocol,
+            name,
+            endpoints,
+            handler,
+        )
+        .expect("add alternate setting")
+    }
+
+    /// Like [UsbDevice::with_alternate_setting], but fails if there is no
+    /// interface yet or it already has 255 settings
+    pub fn try_with_alternate_setting(
+        self,
+        interface_class: u8,
+        interface_subclass: u8,
+        interface_protocol: u8,
+        name: &str,
+        endpoints: Vec<UsbEndpoint>,
+        handler: Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>>,
+    ) -> Result<Self> {
+        let alternate_setting = match self.interfaces.last() {
+            Some(intf) if intf.alternate_setting < u8::MAX => intf.alternate_setting + 1,
+            Some(_) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "too many alternate settings",
+                ))
+            }
+            None => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "no interface to add an alternate setting to",
+                ))
+            }
+        };
+        self.push_interface(
+            interface_class,
+            interface_subclass,
+            interface_protocol,
+            alternate_setting,
+            name,
+            endpoints,
+            handler,
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn push_interface(
+        mut self,
+        interface_class: u8,
+        interface_subclass: u8,
+        interface_protocol: u8,
+        alternate_setting: u8,
+        name: &str,
+        endpoints: Vec<UsbEndpoint>,
+        handler: Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>>,
+    ) -> Result<Self> {
+        // alternate settings of one interface may reuse its addresses,
+        // other interfaces may not, or their URBs would be misrouted
+        let others = if alternate_setting == 0 {
+            self.interfaces.len()
         } else {
-            for intf in &self.interfaces {
-                for endpoint in &intf.endpoints {
-                    if endpoint.address == ep {
-                        return Some((*endpoint, Some(intf)));
-                    }
-                }
+            self.interfaces
+                .iter()
+                .rposition(|intf| intf.alternate_setting == 0)
+                .unwrap_or(0)
+        };
+        if let Some(ep) = endpoints.iter().find(|ep| {
+            ep.is_ep0()
+                || self.interfaces[..others]
+                    .iter()
+                    .any(|intf| intf.endpoints.iter().any(|e| e.address == ep.address))
+        }) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("endpoint {:02x} already in use", ep.address),
+            ));
+        }
+        for (i, ep) in endpoints.iter().enumerate() {
+            if endpoints[..i].iter().any(|e| e.address == ep.address) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("endpoint {:02x} declared twice", ep.address),
+                ));
             }
-            None
         }
+        // an IN endpoint the handler serves as OUT, or the other way around
+        let declared = handler.lock().unwrap().endpoints();
+        if let Some((ep, expected)) = endpoints.iter().find_map(|ep| {
+            ep.declared_opposite(&declared)
+                .map(|expected| (ep, expected))
+        }) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "endpoint {:02x} has the opposite direction of {:02x} declared by its handler",
+                    ep.address, expected
+                ),
+            ));
+        }
+        let string_interface = self.new_string(name)?;
+        let (class_specific_descriptor, class_specific_endpoint_descriptors) = {
+            let handler = handler.lock().unwrap();
+            let endpoint_descriptors = endpoints
+                .iter()
+                .map(|ep| {
+                    (
+                        ep.address,
+                        handler.get_class_specific_endpoint_descriptor(ep),
+                    )
+                })
+                .filter(|(_, desc)| !desc.is_empty())
+                .collect();
+            (
+                handler.get_class_specific_descriptor(),
+                endpoint_descriptors,
+            )
+        };
+        self.interfaces.push(UsbInterface {
+            interface_class,
+            interface_subclass,
+            interface_protocol,
+            alternate_setting,
+            endpoints,
+            string_interface,
+            class_specific_descriptor,
+            class_specific_endpoint_descriptors,
+            handler,
+        });
+        let len = self.configuration_descriptor().len();
+        if len > u16::MAX as usize {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("configuration descriptor too large: {} bytes", len),
+            ));
+        }
+        Ok(self)
     }
 
-    pub(crate) async fn write_dev<T: AsyncReadExt + AsyncWriteExt + Unpin>(
-        &self,
-        socket: &mut T,
-    ) -> Result<()> {
-        socket_write_fixed_string(socket, &self.path, 256).await?;
-        socket_write_fixed_string(socket, &self.bus_id, 32).await?;
+    /// Number of interfaces, alternate settings count once as bNumInterfaces does
+    pub fn num_interfaces(&self) -> usize {
+        num_interfaces(&self.interfaces)
+    }
 
-        // fields
-        socket.write_u32(self.bus_num).await?;
-        socket.write_u32(self.dev_num).await?;
-        socket.write_u32(self.speed).await?;
-        socket.write_u16(self.vendor_id).await?;
-        socket.write_u16(self.product_id).await?;
-        socket.write_u16(self.device_bcd).await?;
-        socket.write_u8(self.device_class).await?;
-        socket.write_u8(self.device_subclass).await?;
-        socket.write_u8(self.device_protocol).await?;
-        socket.write_u8(self.configuration_value).await?;
-        socket.write_u8(self.num_configurations).await?;
-        socket.write_u8(self.interfaces.len() as u8).await?;
+    /// Interfaces of the configuration selected by SET_CONFIGURATION, those
+    /// of the first configuration while unconfigured
+    pub fn active_interfaces(&self) -> &[UsbInterface] {
+        let value = self.state.lock().unwrap().configuration;
+        self.other_configurations
+            .iter()
+            .find(|config| config.configuration_value == value)
+            .map_or(self.first_interfaces(), |config| &config.interfaces)
+    }
 
-        Ok(())
+    /// Configuration replacing the first one in the current [DescriptorMode]
+    fn mode_configuration(&self) -> Option<&UsbConfiguration> {
+        let mode = self.descriptor_mode.as_ref()?.get();
+        self.mode_configurations.get(&mode)
     }
 
-    pub(crate) async fn write_dev_with_interfaces<T: AsyncReadExt + AsyncWriteExt + Unpin>(
-        &self,
-        socket: &mut T,
-    ) -> Result<()> {
-        self.write_dev(socket).await?;
+    /// Interfaces of the first configuration in the current [DescriptorMode]
+    fn first_interfaces(&self) -> &[UsbInterface] {
+        self.mode_configuration()
+            .map_or(&self.interfaces, |config| &config.interfaces)
+    }
 
-        for interface in &self.interfaces {
-            socket.write_u8(interface.interface_class).await?;
-            socket.write_u8(interface.interface_subclass).await?;
-            socket.write_u8(interface.interface_protocol).await?;
-            // padding
-            socket.write_u8(0).await?;
+    /// Whether SET_CONFIGURATION can select configuration `value`, 0 unconfigures
+    fn has_configuration(&self, value: u8) -> bool {
+        value == 0
+            || value == self.configuration_value
+            || self
+                .other_configurations
+                .iter()
+                .any(|config| config.configuration_value == value)
+    }
+
+    /// Snapshot of the state set by the host
+    pub fn state(&self) -> UsbDeviceState {
+        self.state.lock().unwrap().clone()
+    }
+
+    /// Transfer statistics of endpoint `address`, including the direction bit
+    ///
+    /// Counted over all connections since the device was created. Endpoints
+    /// without any URB yet report zeros.
+    pub fn endpoint_stats(&self, address: u8) -> EndpointStats {
+        self.stats.snapshot(address)
+    }
+
+    /// Transfer statistics of every endpoint which saw a URB, by address
+    pub fn all_endpoint_stats(&self) -> Vec<(u8, EndpointStats)> {
+        self.stats.snapshot_all()
+    }
+
+    /// Dump the configuration, alternate settings, halted endpoints and remote
+    /// wakeup flag as set by the host, for debugging
+    pub fn runtime_state(&self) -> UsbRuntimeState {
+        let num_interfaces = num_interfaces(self.active_interfaces());
+        let state = self.state.lock().unwrap();
+        UsbRuntimeState {
+            configuration: state.configuration,
+            alternate_settings: (0..num_interfaces as u8)
+                .map(|i| state.alternate_settings.get(&i).copied().unwrap_or(0))
+                .collect(),
+            halted_endpoints: state.halted_endpoints.iter().copied().collect(),
+            remote_wakeup: state.remote_wakeup,
+        }
+    }
+
+    /// Whether endpoint `address` is halted, by the host with
+    /// SET_FEATURE(ENDPOINT_HALT) or after its handler stalled a transfer
+    ///
+    /// The halt lasts until CLEAR_FEATURE(ENDPOINT_HALT) or SET_CONFIGURATION.
+    /// EP0 is never reported halted, its STALLs only last one transfer.
+    pub fn is_endpoint_halted(&self, address: u8) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .halted_endpoints
+            .contains(&address)
+    }
+
+    /// Halt endpoint `address` as if its handler stalled a transfer
+    ///
+    /// Its transfers fail with `-EPIPE` until the host clears the halt, see
+    /// [UsbDevice::is_endpoint_halted]. Halting EP0 has no effect.
+    pub fn halt_endpoint(&self, address: u8) {
+        if address & 0x7F != 0 {
+            let mut state = self.state.lock().unwrap();
+            state.halted_endpoints.insert(address);
+            state.in_remainders.remove(&address);
         }
-        Ok(())
     }
 
-    pub(crate) async fn handle_urb<T: AsyncReadExt + AsyncWriteExt + Unpin>(
+    /// Keep `data`, which did not fit in an IN URB on `address`, for the
+    /// next URBs on it
+    pub(crate) fn stash_in_remainder(&self, address: u8, data: Vec<u8>) {
+        if !data.is_empty() {
+            let mut state = self.state.lock().unwrap();
+            state.in_remainders.entry(address).or_default().extend(data);
+        }
+    }
+
+    /// Serve an IN URB of `transfer_buffer_length` bytes on `address` from
+    /// a remainder of previous replies, if there is one
+    ///
+    /// The handler is not asked again until the remainder is used up, like
+    /// the FIFO of a device is drained by short reads.
+    pub(crate) fn take_in_remainder(
         &self,
-        socket: &mut T,
-        ep: UsbEndpoint,
-        intf: Option<&UsbInterface>,
+        address: u8,
         transfer_buffer_length: u32,
-        setup: [u8; 8],
-    ) -> Result<Vec<u8>> {
-        use DescriptorType::*;
-        use Direction::*;
-        use EndpointAttributes::*;
-        use StandardRequest::*;
+    ) -> Option<Vec<u8>> {
+        let mut state = self.state.lock().unwrap();
+        if state.halted_endpoints.contains(&address) {
+            return None;
+        }
+        let remainder = state.in_remainders.get_mut(&address)?;
+        let rest = remainder.split_off((transfer_buffer_length as usize).min(remainder.len()));
+        let data = std::mem::replace(remainder, rest);
+        if remainder.is_empty() {
+            state.in_remainders.remove(&address);
+        }
+        Some(data)
+    }
 
-        // parse setup
-        let setup_packet = SetupPacket::parse(&setup);
+    /// Whether interface `number` has alternate setting `alt`
+    fn has_alternate_setting(&self, number: u8, alt: u8) -> bool {
+        let mut i = None;
+        for intf in self.active_interfaces() {
+            if intf.alternate_setting == 0 {
+                i = Some(i.map_or(0, |i| i + 1));
+            }
+            if i == Some(number as usize) && intf.alternate_setting == alt {
+                return true;
+            }
+        }
+        false
+    }
 
-        // read data from socket for OUT
-        let out_data = if let Out = ep.direction() {
-            let mut data = vec![0u8; transfer_buffer_length as usize];
-            socket.read_exact(&mut data).await?;
-            data
-        } else {
-            vec![]
+    /// Whether a host could transfer on endpoint `address`: the device is
+    /// configured and the endpoint is in the selected setting of its interface
+    pub(crate) fn is_endpoint_enabled(&self, address: u8) -> bool {
+        let (configuration, alternate_settings) = {
+            let state = self.state.lock().unwrap();
+            (state.configuration, state.alternate_settings.clone())
         };
+        if configuration == 0 {
+            return false;
+        }
+        let mut number = None;
+        for intf in self.active_interfaces() {
+            if intf.alternate_setting == 0 {
+                number = Some(number.map_or(0, |n| n + 1));
+            }
+            let selected = alternate_settings
+                .get(&number.unwrap_or(0))
+                .copied()
+                .unwrap_or(0);
+            if intf.alternate_setting == selected
+                && intf.endpoints.iter().any(|ep| ep.address == address)
+            {
+                return true;
+            }
+        }
+        false
+    }
 
-        match (FromPrimitive::from_u8(ep.attributes), ep.direction()) {
-            (Some(Control), In) => {
-                // control in
-                debug!("Control IN setup={:x?}", setup_packet);
-                match (
-                    setup_packet.request_type,
-                    FromPrimitive::from_u8(setup_packet.request),
-                ) {
-                    (0b10000000, Some(GetDescriptor)) => {
-                        // high byte: type
-                        match FromPrimitive::from_u16(setup_packet.value >> 8) {
-                            Some(Device) => {
-                                debug!("Get device descriptor");
-                                let mut desc = vec![
-                                    0x12,         // bLength
-                                    Device as u8, // bDescriptorType: Device
-                                    0x10,
-                                    0x02,                              // bcdUSB: USB 2.1
-                                    self.device_class,                 // bDeviceClass
-                                    self.device_subclass,              // bDeviceSubClass
-                                    self.device_protocol,              // bDeviceProtocol
-                                    self.ep0_in.max_packet_size as u8, // bMaxPacketSize0
-                                    self.vendor_id as u8,              // idVendor
-                                    (self.vendor_id >> 8) as u8,
-                                    self.product_id as u8, // idProduct
-                                    (self.product_id >> 8) as u8,
-                                    self.device_bcd as u8, // bcdDevice
-                                    (self.device_bcd >> 8) as u8,
-                                    self.string_manufacturer, // iManufacturer
-                                    self.string_product,      // iProduct
-                                    self.string_serial,       // iSerial
-                                    self.num_configurations,
-                                ];
+    /// Make every transfer on endpoint `address` fail with USB/IP `status` until cleared
+    ///
+    /// `status` is a negated errno, e.g. `-errno::EPIPE` to emulate a STALL.
+    /// The setting is shared by all clones of this device, so it can be
+    /// changed while the device is exported.
+    pub fn set_endpoint_error(&self, address: u8, status: i32) {
+        self.endpoint_errors.lock().unwrap().insert(
+            address,
+            EndpointError {
+                status,
+                once: false,
+            },
+        );
+    }
 
-                                // requested len too short: wLength < real length
-                                if setup_packet.length < desc.len() as u16 {
-                                    desc.resize(setup_packet.length as usize, 0);
-                                }
-                                return Ok(desc);
-                            }
-                            Some(BOS) => {
-                                debug!("Get BOS descriptor");
-                                let mut desc = vec![
-                                    0x05,      // bLength
-                                    BOS as u8, // bDescriptorType: BOS
-                                    0x05, 0x00, // wTotalLength
-                                    0x00, // bNumCapabilities
-                                ];
+    /// Make only the next transfer on endpoint `address` fail with USB/IP `status`
+    pub fn set_endpoint_error_once(&self, address: u8, status: i32) {
+        self.endpoint_errors
+            .lock()
+            .unwrap()
+            .insert(address, EndpointError { status, once: true });
+    }
 
-                                // requested len too short: wLength < real length
-                                if setup_packet.length < desc.len() as u16 {
-                                    desc.resize(setup_packet.length as usize, 0);
+    /// Stop injecting errors on endpoint `address`
+    pub fn clear_endpoint_error(&self, address: u8) {
+        self.endpoint_errors.lock().unwrap().remove(&address);
+    }
+
+    /// Status to fail the next transfer on endpoint `address` with, if any
+    pub(crate) fn take_endpoint_error(&self, address: u8) -> Option<i32> {
+        let mut errors = self.endpoint_errors.lock().unwrap();
+        let error = *errors.get(&address)?;
+        if error.once {
+            errors.remove(&address);
+        }
+        Some(error.status)
+    }
+
+    /// Limit the throughput of all non-control endpoints to `bytes_per_sec`
+    ///
+    /// The server delays replies to stay under the limit, see [TokenBucket].
+    pub fn with_throughput_limit(mut self, bytes_per_sec: u64) -> Self {
+        self.device_throttle = Some(Arc::new(Mutex::new(TokenBucket::new(bytes_per_sec))));
+        self
+    }
+
+    /// Limit the throughput of endpoint `address` to `bytes_per_sec`
+    ///
+    /// Applies in addition to the device wide limit.
+    pub fn with_endpoint_throughput_limit(mut self, address: u8, bytes_per_sec: u64) -> Self {
+        self.endpoint_throttles.insert(
+            address,
+            Arc::new(Mutex::new(TokenBucket::new(bytes_per_sec))),
+        );
+        self
+    }
+
+    /// Delay each reply by a random duration between `min` and `max`
+    ///
+    /// Applies after throughput limits, to all endpoints including ep0. The
+    /// delays are reproducible for a given `seed`, see [ResponseJitter].
+    #[cfg(feature = "fault-injection")]
+    pub fn with_response_jitter(mut self, min: Duration, max: Duration, seed: u64) -> Self {
+        self.response_jitter = Some(Arc::new(Mutex::new(ResponseJitter::new(min, max, seed))));
+        self
+    }
+
+    /// Account `bytes` transferred on endpoint `address`, returning how long to
+    /// delay the reply to honor the throughput limits
+    pub(crate) fn throttle(&self, address: u8, bytes: usize) -> Duration {
+        if address & 0x7F == 0 {
+            return Duration::from_secs(0);
+        }
+        let device = self
+            .device_throttle
+            .as_ref()
+            .map(|bucket| bucket.lock().unwrap().consume(bytes));
+        let endpoint = self
+            .endpoint_throttles
+            .get(&address)
+            .map(|bucket| bucket.lock().unwrap().consume(bytes));
+        device.max(endpoint).unwrap_or_default()
+    }
+
+    /// Report the device as self-powered in bmAttributes and GET_STATUS
+    ///
+    /// Devices are bus-powered by default.
+    pub fn with_self_powered(mut self, self_powered: bool) -> Self {
+        self.self_powered = self_powered;
+        self
+    }
+
+    /// Report support for remote wakeup in bmAttributes
+    ///
+    /// Hosts only enable it with SET_FEATURE(DEVICE_REMOTE_WAKEUP) on devices
+    /// which report it, GET_STATUS then has the remote wakeup bit set.
+    pub fn with_remote_wakeup(mut self, remote_wakeup: bool) -> Self {
+        self.remote_wakeup = remote_wakeup;
+        self
+    }
+
+    /// bmAttributes of the configuration descriptors
+    fn configuration_attributes(&self) -> u8 {
+        // reserved bit 7 is always set
+        0x80 | (self.self_powered as u8) << 6 | (self.remote_wakeup as u8) << 5
+    }
+
+    /// Report a Container ID capability in the BOS descriptor
+    ///
+    /// The UUID identifies the physical device, Windows uses it to group
+    /// functions of a composite device into one device node.
+    pub fn with_container_id(mut self, container_id: [u8; 16]) -> Self {
+        self.container_id = Some(container_id);
+        self
+    }
+
+    /// Report LPM support in a USB 2.0 Extension capability of the BOS descriptor
+    ///
+    /// `baseline_besl` and `deep_besl` are the recommended Best Effort Service
+    /// Latency values (0 to 15), omitted from the descriptor when `None`. Link
+    /// power states do not exist over USB/IP, only the descriptor is emulated.
+    pub fn with_lpm(mut self, baseline_besl: Option<u8>, deep_besl: Option<u8>) -> Self {
+        // LPM, BESL and alternate HIRD definitions supported
+        let mut attributes = 0b0110;
+        if let Some(besl) = baseline_besl {
+            assert!(besl <= 0xF, "BESL out of range");
+            attributes |= 1 << 3 | (besl as u32) << 8;
+        }
+        if let Some(besl) = deep_besl {
+            assert!(besl <= 0xF, "BESL out of range");
+            attributes |= 1 << 4 | (besl as u32) << 12;
+        }
+        self.usb20_extension = Some(attributes);
+        self
+    }
+
+    /// Set string descriptor `index` to `s`, replacing any previous string
+    ///
+    /// Lets class specific descriptors and vendor requests reference strings
+    /// at fixed indices. Strings added later by the builders skip used indices.
+    pub fn add_string(&mut self, index: u8, s: &str) {
+        assert_ne!(index, 0, "string index 0 is reserved for language ids");
+        self.string_pool.insert(index, s.to_string());
+    }
+
+    /// Provide string `index` in language `langid` too, listing the language
+    /// in string descriptor 0
+    ///
+    /// Strings without a translation are returned in the primary language.
+    pub fn with_string_translation(mut self, langid: u16, index: u8, s: &str) -> Self {
+        assert_ne!(index, 0, "string index 0 is reserved for language ids");
+        if langid != self.primary_language() && !self.other_languages.contains(&langid) {
+            self.other_languages.push(langid);
+        }
+        self.translated_strings
+            .insert((langid, index), s.to_string());
+        self
+    }
+
+    /// Set the language of the strings given to the builders and
+    /// [UsbDevice::add_string], listed first in string descriptor 0
+    ///
+    /// Defaults to [PRIMARY_LANGID].
+    pub fn with_primary_language(mut self, langid: u16) -> Self {
+        let previous = self.primary_language();
+        self.other_languages.retain(|l| *l != langid);
+        if self.translated_strings.keys().any(|(l, _)| *l == previous)
+            && !self.other_languages.contains(&previous)
+        {
+            self.other_languages.push(previous);
+        }
+        self.primary_language = Some(langid);
+        self
+    }
+
+    fn primary_language(&self) -> u16 {
+        self.primary_language.unwrap_or(PRIMARY_LANGID)
+    }
+
+    /// Choose how to answer string requests in languages not listed in
+    /// string descriptor 0
+    ///
+    /// Defaults to [StringLanguageFallback::Primary]: real devices mostly
+    /// ignore wIndex, and hosts asking in an unlisted language, e.g. 0 by
+    /// some tools, still get a readable string that way.
+    pub fn with_string_language_fallback(mut self, fallback: StringLanguageFallback) -> Self {
+        self.string_language_fallback = fallback;
+        self
+    }
+
+    /// String `index` in language `langid`, see [UsbDevice::with_string_translation]
+    fn string(&self, index: u8, langid: u16) -> Result<&str> {
+        let listed = langid == self.primary_language() || self.other_languages.contains(&langid);
+        if !listed && self.string_language_fallback == StringLanguageFallback::Stall {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                format!("no language {:04x}", langid),
+            ));
+        }
+        self.translated_strings
+            .get(&(langid, index))
+            .or_else(|| self.string_pool.get(&index))
+            .map(|s| s.as_str())
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::BrokenPipe,
+                    format!("no string {}", index),
+                )
+            })
+    }
+
+    /// Let interface `interface` reference string `index` as iInterface
+    pub fn with_interface_string(mut self, interface: usize, index: u8) -> Self {
+        self.interfaces[interface].string_interface = index;
+        self
+    }
+
+    /// Let the configuration reference string `index` as iConfiguration
+    pub fn with_configuration_string(mut self, index: u8) -> Self {
+        self.string_configuration = index;
+        self
+    }
+
+    /// Set bConfigurationValue of the first configuration, 1 by default
+    ///
+    /// SET_CONFIGURATION then selects it with `value` only, other values the
+    /// device does not have STALL. 0 is reserved for the unconfigured state.
+    pub fn with_configuration_value(mut self, value: u8) -> Self {
+        assert_ne!(value, 0, "configuration value 0 means unconfigured");
+        self.configuration_value = value;
+        self
+    }
+
+    /// Add a configuration after the existing ones, built from `interfaces`
+    ///
+    /// Its bConfigurationValue is one above the highest so far, which is its
+    /// position when the first configuration keeps value 1. Once the host selects it with SET_CONFIGURATION, URBs
+    /// go to its interfaces instead of those of the first configuration.
+    /// Device lists always show the first configuration.
+    pub fn with_other_configuration(mut self, name: &str, interfaces: Vec<UsbInterface>) -> Self {
+        let string_configuration = self.new_string(name).expect("add configuration name");
+        self.num_configurations += 1;
+        let configuration_value = self
+            .other_configurations
+            .iter()
+            .map(|config| config.configuration_value)
+            .fold(self.configuration_value, u8::max)
+            + 1;
+        self.other_configurations.push(UsbConfiguration {
+            configuration_value,
+            string_configuration,
+            interfaces,
+            interface_associations: vec![],
+        });
+        self
+    }
+
+    /// Give each import its own copy of this device
+    ///
+    /// On import, handlers are replaced by their [UsbInterfaceHandler::fork]
+    /// and the device state starts over, so clients do not see each other's
+    /// changes. Handlers which do not fork stay shared. Without it, a device
+    /// is imported by one client at a time.
+    pub fn with_import_clones(mut self) -> Self {
+        self.import_clones = true;
+        self
+    }
+
+    /// Claim this device for one client, None if another client imported it
+    ///
+    /// Like a device bound to usbip-host, a device serves one client at a
+    /// time, as its handlers keep state, and its handlers are reset when the
+    /// client releases it. Devices with
+    /// [UsbDevice::with_import_clones] serve any number of clients.
+    pub(crate) fn lease(&self) -> Option<ImportLease> {
+        if self.import_clones {
+            return Some(ImportLease {
+                imported: None,
+                handlers: vec![],
+            });
+        }
+        let claimed = self.imported.compare_exchange(
+            false,
+            true,
+            std::sync::atomic::Ordering::SeqCst,
+            std::sync::atomic::Ordering::SeqCst,
+        );
+        claimed.ok()?;
+        let others = self.other_configurations.iter();
+        let modes = self.mode_configurations.values();
+        let interfaces = others
+            .chain(modes)
+            .flat_map(|config| config.interfaces.iter());
+        Some(ImportLease {
+            imported: Some(self.imported.clone()),
+            handlers: self
+                .interfaces
+                .iter()
+                .chain(interfaces)
+                .map(|intf| intf.handler.clone())
+                .collect(),
+        })
+    }
+
+    /// Copy of this device for one import, see [UsbDevice::with_import_clones]
+    pub(crate) fn import(&self) -> UsbDevice {
+        let mut device = self.clone();
+        if self.import_clones {
+            device.state = Default::default();
+            let others = device.other_configurations.iter_mut();
+            let modes = device.mode_configurations.values_mut();
+            let interfaces = others
+                .chain(modes)
+                .flat_map(|config| config.interfaces.iter_mut());
+            for intf in device.interfaces.iter_mut().chain(interfaces) {
+                let forked = intf.handler.lock().unwrap().fork();
+                if let Some(handler) = forked {
+                    intf.handler = Arc::new(Mutex::new(handler));
+                }
+            }
+        }
+        device
+    }
+
+    /// Complete URBs NAKed on endpoint `address` as soon as data is pushed to `queue`
+    pub fn with_in_queue(mut self, address: u8, queue: &InEndpointQueue) -> Self {
+        self.in_queues.insert(address, queue.clone());
+        self
+    }
+
+    /// Switch the interfaces of the first configuration with `mode`, see
+    /// [DescriptorMode]
+    pub fn with_descriptor_mode(mut self, mode: DescriptorMode) -> Self {
+        self.descriptor_mode = Some(mode);
+        self
+    }
+
+    /// Present `interfaces` in the first configuration while the
+    /// [DescriptorMode] is `mode`
+    ///
+    /// Modes without interfaces present those given to
+    /// [UsbDevice::with_interface].
+    pub fn with_mode_interfaces(mut self, mode: u8, interfaces: Vec<UsbInterface>) -> Self {
+        self.mode_configurations.insert(
+            mode,
+            UsbConfiguration {
+                configuration_value: self.configuration_value,
+                string_configuration: self.string_configuration,
+                interfaces,
+                interface_associations: vec![],
+            },
+        );
+        self
+    }
+
+    /// Let `reenumeration` replace this device, see [Reenumeration]
+    pub fn with_reenumeration(mut self, reenumeration: Reenumeration) -> Self {
+        self.reenumeration = Some(reenumeration);
+        self
+    }
+
+    /// Delay answering the standard `request`, e.g. GET_DESCRIPTOR, by `delay`
+    ///
+    /// Helps reproducing host bugs that only show up when a device is slow
+    /// during enumeration. Each stage can have its own delay.
+    pub fn with_enumeration_delay(mut self, request: StandardRequest, delay: Duration) -> Self {
+        self.enumeration_delays.insert(request as u8, delay);
+        self
+    }
+
+    /// Set the max packet size of endpoint 0, reported as bMaxPacketSize0
+    ///
+    /// Must be 8, 16, 32 or 64 for low, full and high speed devices.
+    pub fn with_max_packet_size0(self, max_packet_size: u8) -> Self {
+        self.try_with_max_packet_size0(max_packet_size)
+            .expect("set bMaxPacketSize0")
+    }
+
+    /// Like [UsbDevice::with_max_packet_size0], but fails on a size other
+    /// than 8, 16, 32 or 64
+    pub fn try_with_max_packet_size0(mut self, max_packet_size: u8) -> Result<Self> {
+        if ![8, 16, 32, 64].contains(&max_packet_size) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("invalid bMaxPacketSize0 {}", max_packet_size),
+            ));
+        }
+        self.ep0_in.max_packet_size = max_packet_size as u16;
+        self.ep0_out.max_packet_size = max_packet_size as u16;
+        Ok(self)
+    }
+
+    /// The max packet size of endpoint 0, shared by both directions
+    ///
+    /// 512 at super speed, where bMaxPacketSize0 holds its exponent instead.
+    pub fn max_packet_size0(&self) -> u16 {
+        self.ep0_in.max_packet_size
+    }
+
+    /// Set the speed reported in device lists and imports
+    ///
+    /// bcdUSB of the device descriptor and the max packet size of endpoint 0
+    /// follow: 8 bytes at low speed, 64 at full and high speed, 512 at super
+    /// speed. Call [UsbDevice::with_max_packet_size0] afterwards to change it.
+    pub fn with_speed(mut self, speed: UsbSpeed) -> Self {
+        let max_packet_size = match speed {
+            UsbSpeed::Low => 8,
+            UsbSpeed::Super | UsbSpeed::SuperPlus => 512,
+            _ => EP0_MAX_PACKET_SIZE,
+        };
+        self.speed = speed as u32;
+        self.ep0_in.max_packet_size = max_packet_size;
+        self.ep0_out.max_packet_size = max_packet_size;
+        self
+    }
+
+    /// bcdUSB of the device descriptor, following the speed
+    ///
+    /// High speed devices report USB 2.1, the lowest version hosts read the
+    /// BOS descriptor of. So do low and full speed devices with BOS
+    /// capabilities, the others report USB 1.1.
+    pub fn usb_version(&self) -> u16 {
+        if self.speed == UsbSpeed::Super as u32 || self.speed == UsbSpeed::SuperPlus as u32 {
+            0x0300
+        } else if self.speed == UsbSpeed::High as u32
+            || self.usb20_extension.is_some()
+            || self.container_id.is_some()
+        {
+            0x0210
+        } else {
+            0x0110
+        }
+    }
+
+    /// Duration of `interval` (micro)frames, the unit of the interval of a URB
+    ///
+    /// Microframes of 125us from high speed on, frames of 1ms below.
+    pub(crate) fn interval_duration(&self, interval: u32) -> Duration {
+        if self.speed == UsbSpeed::Low as u32 || self.speed == UsbSpeed::Full as u32 {
+            Duration::from_millis(interval as u64)
+        } else {
+            Duration::from_micros(interval as u64 * 125)
+        }
+    }
+
+    /// bMaxPacketSize0, an exponent of 2 from USB 3.0 on
+    ///
+    /// SuperSpeed ep0 always moves 512 bytes, so it is 9 even if only the
+    /// speed field was set and ep0 kept its high speed size.
+    fn max_packet_size0_field(&self) -> u8 {
+        if self.usb_version() >= 0x0300 {
+            9
+        } else {
+            self.max_packet_size0() as u8
+        }
+    }
+
+    /// Call `callback` whenever the host sends a control request matching `matcher`
+    ///
+    /// The callback runs before the request is handled and cannot change the
+    /// reply, use it to observe requests or trigger side effects. Several hooks
+    /// may fire for one request, in the order they were added. A request NAKed
+    /// by its handler fires again on every retry.
+    pub fn with_control_hook<F>(mut self, matcher: ControlRequestMatcher, callback: F) -> Self
+    where
+        F: Fn(&SetupPacket, &[u8]) + Send + Sync + 'static,
+    {
+        self.control_hooks.push((matcher, Arc::new(callback)));
+        self
+    }
+
+    /// Accept SET_DESCRIPTOR requests with `handler`
+    ///
+    /// Like most hardware, devices STALL SET_DESCRIPTOR unless they opt in.
+    /// The written descriptor is only passed to `handler`, descriptors served
+    /// by the device do not change.
+    pub fn with_set_descriptor<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(u8, u8, u16, &[u8]) -> Result<()> + Send + Sync + 'static,
+    {
+        self.set_descriptor_handler = Some(Arc::new(handler));
+        self
+    }
+
+    /// Pin the device to `bus_num` and `dev_num`, with bus id `<bus_num>-<dev_num>`
+    ///
+    /// The numbers are reported as is in device lists and imports, so the
+    /// host sees the same identity across server restarts, e.g. for udev rules
+    /// keyed on the topology. Keeping them unique is up to the caller.
+    pub fn with_bus_location(mut self, bus_num: u32, dev_num: u32) -> Self {
+        self.bus_num = bus_num;
+        self.dev_num = dev_num;
+        self.bus_id = format!("{}-{}", bus_num, dev_num);
+        self.path = format!("/sys/device/usbip/{}", self.bus_id);
+        self
+    }
+
+    /// Set bDeviceClass, bDeviceSubClass and bDeviceProtocol of the device descriptor
+    ///
+    /// Composite devices using interface associations should use
+    /// [ClassCode::Misc], [MISC_SUBCLASS_COMMON] and [MISC_PROTOCOL_IAD],
+    /// otherwise Windows does not load the composite driver for them.
+    pub fn with_device_class(mut self, class: u8, subclass: u8, protocol: u8) -> Self {
+        self.device_class = class;
+        self.device_subclass = subclass;
+        self.device_protocol = protocol;
+        self
+    }
+
+    /// Set idVendor of the device descriptor
+    pub fn with_vendor_id(mut self, vendor_id: u16) -> Self {
+        self.vendor_id = vendor_id;
+        self
+    }
+
+    /// Set idProduct of the device descriptor
+    pub fn with_product_id(mut self, product_id: u16) -> Self {
+        self.product_id = product_id;
+        self
+    }
+
+    /// Set bcdDevice of the device descriptor, e.g. 0x0102 for version 1.02
+    pub fn with_device_version(mut self, bcd: u16) -> Self {
+        self.device_bcd = bcd;
+        self
+    }
+
+    /// Set the iManufacturer string
+    pub fn with_manufacturer(mut self, manufacturer: &str) -> Self {
+        self.string_manufacturer = self.replace_string(self.string_manufacturer, manufacturer);
+        self
+    }
+
+    /// Set the iProduct string
+    pub fn with_product(mut self, product: &str) -> Self {
+        self.string_product = self.replace_string(self.string_product, product);
+        self
+    }
+
+    /// Set the iSerialNumber string
+    ///
+    /// Hosts tell apart devices of the same vendor and product by it, so give
+    /// each exported device its own.
+    pub fn with_serial(mut self, serial: &str) -> Self {
+        self.string_serial = self.replace_string(self.string_serial, serial);
+        self
+    }
+
+    /// Group `count` interfaces starting at `first_interface` into a function
+    /// using an Interface Association Descriptor
+    ///
+    /// This also switches the device descriptor to the Multi-Interface Function
+    /// class triple (0xEF/0x02/0x01) required by Windows for IAD composites.
+    pub fn with_interface_association(
+        mut self,
+        first_interface: u8,
+        count: u8,
+        function_class: u8,
+        function_subclass: u8,
+        function_protocol: u8,
+        name: &str,
+    ) -> Self {
+        let string_function = self.new_string(name).expect("add function name");
+        self.interface_associations.push(UsbInterfaceAssociation {
+            first_interface,
+            interface_count: count,
+            function_class,
+            function_subclass,
+            function_protocol,
+            string_function,
+        });
+        self.with_device_class(
+            ClassCode::Misc as u8,
+            MISC_SUBCLASS_COMMON,
+            MISC_PROTOCOL_IAD,
+        )
+    }
+
+    /// Build the configuration descriptor, including interface, class specific
+    /// and endpoint descriptors
+    pub(crate) fn configuration_descriptor(&self) -> Vec<u8> {
+        if let Some(config) = self.mode_configuration() {
+            return build_configuration_descriptor(
+                self.configuration_value,
+                self.string_configuration,
+                self.configuration_attributes(),
+                self.speed,
+                &config.interfaces,
+                &config.interface_associations,
+            );
+        }
+        build_configuration_descriptor(
+            self.configuration_value,
+            self.string_configuration,
+            self.configuration_attributes(),
+            self.speed,
+            &self.interfaces,
+            &self.interface_associations,
+        )
+    }
+
+    /// Build the configuration descriptor at `index`, whether active or not
+    pub(crate) fn configuration_descriptor_at(&self, index: u8) -> Option<Vec<u8>> {
+        if index == 0 {
+            return Some(self.configuration_descriptor());
+        }
+        self.other_configurations
+            .get(index as usize - 1)
+            .map(|config| {
+                build_configuration_descriptor(
+                    config.configuration_value,
+                    config.string_configuration,
+                    self.configuration_attributes(),
+                    self.speed,
+                    &config.interfaces,
+                    &config.interface_associations,
+                )
+            })
+    }
+
+    /// Build the BOS descriptor with its device capabilities
+    pub(crate) fn bos_descriptor(&self) -> Vec<u8> {
+        let mut desc = vec![
+            0x05,                      // bLength
+            DescriptorType::BOS as u8, // bDescriptorType: BOS
+            0x00,
+            0x00, // wTotalLength: to be filled below
+            0x00, // bNumCapabilities: to be filled below
+        ];
+        if let Some(attributes) = self.usb20_extension {
+            desc.extend_from_slice(&[
+                0x07,                                   // bLength
+                DescriptorType::DeviceCapability as u8, // bDescriptorType: Device Capability
+                DeviceCapability::Usb20Extension as u8, // bDevCapabilityType: USB 2.0 Extension
+            ]);
+            desc.extend_from_slice(&attributes.to_le_bytes()); // bmAttributes
+            desc[4] += 1;
+        }
+        if let Some(container_id) = self.container_id {
+            desc.extend_from_slice(&[
+                0x14,                                   // bLength
+                DescriptorType::DeviceCapability as u8, // bDescriptorType: Device Capability
+                DeviceCapability::ContainerId as u8,    // bDevCapabilityType: Container ID
+                0x00,                                   // bReserved
+            ]);
+            desc.extend_from_slice(&container_id); // ContainerID
+            desc[4] += 1;
+        }
+        let len = desc.len() as u16;
+        desc[2] = len as u8;
+        desc[3] = (len >> 8) as u8;
+        desc
+    }
+
+    /// Replace string `index`, or add a new one if `index` is 0
+    fn replace_string(&mut self, index: u8, s: &str) -> u8 {
+        if index == 0 {
+            return self.new_string(s).expect("add string");
+        }
+        self.string_pool.insert(index, s.to_string());
+        index
+    }
+
+    /// Add string `s` at the lowest free index, failing once all 255 are used
+    pub(crate) fn new_string(&mut self, s: &str) -> Result<u8> {
+        for i in 1..=u8::MAX {
+            if let std::collections::hash_map::Entry::Vacant(e) = self.string_pool.entry(i) {
+                e.insert(s.to_string());
+                return Ok(i);
+            }
+        }
+        Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "no string descriptor index left",
+        ))
+    }
+
+    /// Find endpoint `ep` and the interface it belongs to
+    ///
+    /// Alternate settings may reuse an address, the one selected by
+    /// SET_INTERFACE wins. An endpoint only found in another setting is still
+    /// returned, clients which skip SET_INTERFACE keep working unless
+    /// [crate::UsbIpServer::with_strict_submits] is set.
+    pub(crate) fn find_ep(&self, ep: u8) -> Option<(UsbEndpoint, Option<&UsbInterface>)> {
+        if ep == self.ep0_in.address {
+            Some((self.ep0_in, None))
+        } else if ep == self.ep0_out.address {
+            Some((self.ep0_out, None))
+        } else {
+            let alternate_settings = self.state.lock().unwrap().alternate_settings.clone();
+            let mut number = None;
+            let mut other_setting = None;
+            for intf in self.active_interfaces() {
+                if intf.alternate_setting == 0 {
+                    number = Some(number.map_or(0, |n| n + 1));
+                }
+                let selected = alternate_settings
+                    .get(&number.unwrap_or(0))
+                    .copied()
+                    .unwrap_or(0);
+                if let Some(endpoint) = intf.endpoints.iter().find(|e| e.address == ep) {
+                    if intf.alternate_setting == selected {
+                        return Some((*endpoint, Some(intf)));
+                    }
+                    other_setting.get_or_insert((*endpoint, Some(intf)));
+                }
+            }
+            other_setting
+        }
+    }
+
+    pub(crate) async fn write_dev<T: AsyncReadExt + AsyncWriteExt + Unpin>(
+        &self,
+        socket: &mut T,
+    ) -> Result<()> {
+        socket_write_fixed_string(socket, &self.path, 256).await?;
+        socket_write_fixed_string(socket, &self.bus_id, 32).await?;
+
+        // fields
+        socket.write_u32(self.bus_num).await?;
+        socket.write_u32(self.dev_num).await?;
+        socket.write_u32(self.speed).await?;
+        socket.write_u16(self.vendor_id).await?;
+        socket.write_u16(self.product_id).await?;
+        socket.write_u16(self.device_bcd).await?;
+        socket.write_u8(self.device_class).await?;
+        socket.write_u8(self.device_subclass).await?;
+        socket.write_u8(self.device_protocol).await?;
+        socket.write_u8(self.configuration_value).await?;
+        socket.write_u8(self.num_configurations).await?;
+        socket
+            .write_u8(num_interfaces(self.first_interfaces()) as u8)
+            .await?;
+
+        Ok(())
+    }
+
+    pub(crate) async fn write_dev_with_interfaces<T: AsyncReadExt + AsyncWriteExt + Unpin>(
+        &self,
+        socket: &mut T,
+    ) -> Result<()> {
+        self.write_dev(socket).await?;
+
+        for interface in self
+            .first_interfaces()
+            .iter()
+            .filter(|i| i.alternate_setting == 0)
+        {
+            socket.write_u8(interface.interface_class).await?;
+            socket.write_u8(interface.interface_subclass).await?;
+            socket.write_u8(interface.interface_protocol).await?;
+            // padding
+            socket.write_u8(0).await?;
+        }
+        Ok(())
+    }
+
+    /// Find the interface a control request is addressed to
+    ///
+    /// The target is in the low byte of wIndex, not the endpoint of the URB
+    /// which is always ep0: an interface number for interface recipients, an
+    /// endpoint address for endpoint recipients. Hub class requests to the
+    /// device or its ports go to the hub interface. Printer GET_DEVICE_ID has
+    /// the interface number in the high byte, and printer SOFT_RESET may have
+    /// the recipient other of printer class 1.0.
+    fn control_target(&self, setup: &SetupPacket) -> Option<&UsbInterface> {
+        let target = setup.index as u8;
+        let printer = match (setup.request_type, setup.request) {
+            (0xA1, printer::PRINTER_GET_DEVICE_ID) => Some(setup.index >> 8),
+            (0x23, printer::PRINTER_SOFT_RESET) => Some(setup.index & 0xFF),
+            _ => None,
+        };
+        if let Some(number) = printer {
+            let intf = self
+                .active_interfaces()
+                .iter()
+                .filter(|intf| intf.alternate_setting == 0)
+                .nth(number as usize)
+                .filter(|intf| intf.interface_class == ClassCode::Printer as u8);
+            if intf.is_some() {
+                return intf;
+            }
+        }
+        match setup.request_type & 0x1F {
+            0 | 3
+                if self.device_class == ClassCode::Hub as u8
+                    && setup.request_type & 0x60 == 0x20 =>
+            {
+                self.active_interfaces().first()
+            }
+            1 => self
+                .active_interfaces()
+                .iter()
+                .filter(|intf| intf.alternate_setting == 0)
+                .nth(target as usize),
+            2 => self
+                .active_interfaces()
+                .iter()
+                .find(|intf| intf.endpoints.iter().any(|ep| ep.address == target)),
+            _ => None,
+        }
+    }
+
+    /// Handle a URB on `ep`, answering standard requests and passing the
+    /// others to the handler of `intf`
+    ///
+    /// A stall becomes [UsbIpError::Stall], other errors, e.g. a NAK, are
+    /// [UsbIpError::Io].
+    pub(crate) async fn handle_urb(
+        &self,
+        ctx: &ConnectionContext,
+        ep: UsbEndpoint,
+        intf: Option<&UsbInterface>,
+        setup: [u8; 8],
+        out_data: &[u8],
+    ) -> std::result::Result<Vec<u8>, UsbIpError> {
+        self.dispatch_urb(ctx, ep, intf, setup, out_data)
+            .await
+            .map_err(UsbIpError::from_transfer)
+    }
+
+    async fn dispatch_urb(
+        &self,
+        ctx: &ConnectionContext,
+        ep: UsbEndpoint,
+        intf: Option<&UsbInterface>,
+        setup: [u8; 8],
+        out_data: &[u8],
+    ) -> Result<Vec<u8>> {
+        use DescriptorType::*;
+        use Direction::*;
+        use EndpointAttributes::*;
+        use StandardRequest::*;
+
+        // parse setup
+        let setup_packet = SetupPacket::parse(&setup);
+
+        if ep.is_ep0() {
+            for (matcher, callback) in &self.control_hooks {
+                if matcher.matches(&setup_packet) {
+                    callback(&setup_packet, out_data);
+                }
+            }
+        }
+
+        if ep.is_ep0() && setup_packet.request_type & 0x60 == 0 {
+            if let Some(delay) = self.enumeration_delays.get(&setup_packet.request) {
+                debug!("Delaying request {} by {:?}", setup_packet.request, delay);
+                tokio::time::sleep(*delay).await;
+            }
+        }
+
+        match (ep.transfer_type(), ep.direction()) {
+            (Some(Control), In) => {
+                // control in
+                debug!("Control IN setup={:x?}", setup_packet);
+                match (
+                    setup_packet.request_type,
+                    FromPrimitive::from_u8(setup_packet.request),
+                ) {
+                    (0b10000000, Some(GetDescriptor)) => {
+                        // high byte: type
+                        match FromPrimitive::from_u16(setup_packet.value >> 8) {
+                            Some(Device) => {
+                                debug!("Get device descriptor");
+                                let mut desc = vec![
+                                    0x12,                     // bLength
+                                    Device as u8,             // bDescriptorType: Device
+                                    self.usb_version() as u8, // bcdUSB
+                                    (self.usb_version() >> 8) as u8,
+                                    self.device_class,             // bDeviceClass
+                                    self.device_subclass,          // bDeviceSubClass
+                                    self.device_protocol,          // bDeviceProtocol
+                                    self.max_packet_size0_field(), // bMaxPacketSize0
+                                    self.vendor_id as u8,          // idVendor
+                                    (self.vendor_id >> 8) as u8,
+                                    self.product_id as u8, // idProduct
+                                    (self.product_id >> 8) as u8,
+                                    self.device_bcd as u8, // bcdDevice
+                                    (self.device_bcd >> 8) as u8,
+                                    self.string_manufacturer, // iManufacturer
+                                    self.string_product,      // iProduct
+                                    self.string_serial,       // iSerial
+                                    self.num_configurations,
+                                ];
+
+                                // requested len too short: wLength < real length
+                                if setup_packet.length < desc.len() as u16 {
+                                    desc.resize(setup_packet.length as usize, 0);
                                 }
                                 return Ok(desc);
                             }
-                            Some(Configuration) => {
-                                debug!("Get configuration descriptor");
-                                let mut desc = vec![
-                                    0x09,                // bLength
-                                    Configuration as u8, // bDescriptorType: Configuration
-                                    0x00,
-                                    0x00, // wTotalLength: to be filled below
-                                    self.interfaces.len() as u8, // bNumInterfaces
-                                    self.configuration_value, // bConfigurationValue
-                                    self.string_configuration, // iConfiguration
-                                    0x80, // bmAttributes Bus Powered
-                                    0x32, // bMaxPower 100mA
-                                ];
-                                for (i, intf) in self.interfaces.iter().enumerate() {
-                                    let mut intf_desc = vec![
-                                        0x09,                       // bLength
-                                        Interface as u8,            // bDescriptorType: Interface
-                                        i as u8,                    // bInterfaceNum
-                                        0x00,                       // bAlternateSettings
-                                        intf.endpoints.len() as u8, // bNumEndpoints
-                                        intf.interface_class,       // bInterfaceClass
-                                        intf.interface_subclass,    // bInterfaceSubClass
-                                        intf.interface_protocol,    // bInterfaceProtocol
-                                        intf.string_interface,      //iInterface
-                                    ];
-                                    // class specific endpoint
-                                    let mut specific = intf.class_specific_descriptor.clone();
-                                    intf_desc.append(&mut specific);
-                                    // endpoint descriptors
-                                    for endpoint in &intf.endpoints {
-                                        let mut ep_desc = vec![
-                                            0x07,                // bLength
-                                            Endpoint as u8,      // bDescriptorType: Endpoint
-                                            endpoint.address,    // bEndpointAddress
-                                            endpoint.attributes, // bmAttributes
-                                            endpoint.max_packet_size as u8,
-                                            (endpoint.max_packet_size >> 8) as u8, // wMaxPacketSize
-                                            endpoint.interval,                     // bInterval
-                                        ];
-                                        intf_desc.append(&mut ep_desc);
-                                    }
-                                    desc.append(&mut intf_desc);
+                            Some(BOS) => {
+                                debug!("Get BOS descriptor");
+                                let mut desc = self.bos_descriptor();
+
+                                // requested len too short: wLength < real length
+                                if setup_packet.length < desc.len() as u16 {
+                                    desc.resize(setup_packet.length as usize, 0);
                                 }
-                                // length
-                                let len = desc.len() as u16;
-                                desc[2] = len as u8;
-                                desc[3] = (len >> 8) as u8;
+                                return Ok(desc);
+                            }
+                            Some(Configuration) => {
+                                let index = setup_packet.value as u8;
+                                debug!("Get configuration descriptor {}", index);
+                                let mut desc =
+                                    self.configuration_descriptor_at(index).ok_or_else(|| {
+                                        std::io::Error::new(
+                                            std::io::ErrorKind::BrokenPipe,
+                                            format!("no configuration {}", index),
+                                        )
+                                    })?;
 
                                 // requested len too short: wLength < real length
                                 if setup_packet.length < desc.len() as u16 {
@@ -288,18 +1612,20 @@ impl UsbDevice {
                                 if index == 0 {
                                     // language ids
                                     let mut desc = vec![
-                                        4,                            // bLength
+                                        (4 + self.other_languages.len() * 2) as u8, // bLength
                                         DescriptorType::String as u8, // bDescriptorType
-                                        0x09,
-                                        0x04, // bLANGID, en-US
                                     ];
+                                    desc.extend_from_slice(&self.primary_language().to_le_bytes());
+                                    for langid in &self.other_languages {
+                                        desc.extend_from_slice(&langid.to_le_bytes());
+                                    }
                                     // requested len too short: wLength < real length
                                     if setup_packet.length < desc.len() as u16 {
                                         desc.resize(setup_packet.length as usize, 0);
                                     }
                                     return Ok(desc);
                                 } else {
-                                    let s = &self.string_pool[&index];
+                                    let s = self.string(index, setup_packet.index)?;
                                     let bytes: Vec<u16> = s.encode_utf16().collect();
                                     let mut desc = vec![
                                         (2 + bytes.len() * 2) as u8,  // bLength
@@ -317,19 +1643,29 @@ impl UsbDevice {
                                     return Ok(desc);
                                 }
                             }
+                            Some(DeviceQualifier) if self.usb_version() < 0x0200 => {
+                                // only high speed capable devices have one
+                                return Err(std::io::Error::new(
+                                    std::io::ErrorKind::BrokenPipe,
+                                    "no device qualifier below USB 2.0",
+                                ));
+                            }
                             Some(DeviceQualifier) => {
                                 debug!("Get device qualifier descriptor");
+                                // a super speed device describes its high
+                                // speed operation, with a 64 byte ep0
+                                let max_packet_size0 = self.max_packet_size0().min(64) as u8;
                                 let mut desc = vec![
                                     0x0A,                  // bLength
                                     DeviceQualifier as u8, // bDescriptorType: Device Qualifier
                                     0x10,
-                                    0x02,                              // bcdUSB USB 2.1
-                                    self.device_class,                 // bDeviceClass
-                                    self.device_subclass,              // bDeviceSUbClass
-                                    self.device_protocol,              // bDeviceProtocol
-                                    self.ep0_in.max_packet_size as u8, // bMaxPacketSize0
-                                    self.num_configurations,           // bNumConfigurations
-                                    0x00,                              // reserved
+                                    0x02,                    // bcdUSB USB 2.1
+                                    self.device_class,       // bDeviceClass
+                                    self.device_subclass,    // bDeviceSUbClass
+                                    self.device_protocol,    // bDeviceProtocol
+                                    max_packet_size0,        // bMaxPacketSize0
+                                    self.num_configurations, // bNumConfigurations
+                                    0x00,                    // reserved
                                 ];
 
                                 // requested len too short: wLength < real length
@@ -338,35 +1674,2069 @@ impl UsbDevice {
                                 }
                                 return Ok(desc);
                             }
-                            _ => {
-                                warn!("unknown desc type: {:x?}", setup_packet);
-                                return Ok(vec![]);
+                            _ => {
+                                warn!("unknown desc type: {:x?}", setup_packet);
+                                return Err(std::io::Error::new(
+                                    std::io::ErrorKind::BrokenPipe,
+                                    "unknown descriptor type",
+                                ));
+                            }
+                        }
+                    }
+                    (0b10000000, Some(GetConfiguration)) => {
+                        debug!("Get configuration");
+                        return Ok(vec![self.state.lock().unwrap().configuration]);
+                    }
+                    (0b10000001, Some(GetInterface)) => {
+                        let number = setup_packet.index as u8;
+                        debug!("Get interface {}", number);
+                        if !self.has_alternate_setting(number, 0) {
+                            return Err(std::io::Error::new(
+                                std::io::ErrorKind::BrokenPipe,
+                                format!("no interface {}", number),
+                            ));
+                        }
+                        let state = self.state.lock().unwrap();
+                        return Ok(vec![state
+                            .alternate_settings
+                            .get(&number)
+                            .copied()
+                            .unwrap_or(0)]);
+                    }
+                    (0b10000000, Some(GetStatus)) => {
+                        debug!("Get device status");
+                        let remote_wakeup = self.state.lock().unwrap().remote_wakeup;
+                        return Ok(vec![
+                            (remote_wakeup as u8) << 1 | self.self_powered as u8,
+                            0x00,
+                        ]);
+                    }
+                    (0b10000001, Some(GetStatus)) => {
+                        // reserved zeros, interfaces have no status of their own
+                        let number = setup_packet.index as u8;
+                        debug!("Get interface status {}", number);
+                        if !self.has_alternate_setting(number, 0) {
+                            return Err(std::io::Error::new(
+                                std::io::ErrorKind::BrokenPipe,
+                                format!("no interface {}", number),
+                            ));
+                        }
+                        return Ok(vec![0x00, 0x00]);
+                    }
+                    (0b10000010, Some(GetStatus)) => {
+                        debug!("Get endpoint status {:x}", setup_packet.index);
+                        let address = setup_packet.index as u8;
+                        if address & 0x7F != 0 && self.find_ep(address).is_none() {
+                            return Err(std::io::Error::new(
+                                std::io::ErrorKind::BrokenPipe,
+                                format!("no endpoint {:x}", address),
+                            ));
+                        }
+                        let state = self.state.lock().unwrap();
+                        let halted = state.halted_endpoints.contains(&(setup_packet.index as u8));
+                        return Ok(vec![halted as u8, 0x00]);
+                    }
+                    _ if self.control_target(&setup_packet).is_some() => {
+                        // to interface or endpoint
+                        let intf = self.control_target(&setup_packet).unwrap();
+                        let resp = {
+                            let mut handler = intf.handler.lock().unwrap();
+                            handler.handle_urb_async(ctx, intf, ep, Some(setup_packet), out_data)
+                        };
+                        return resp.await;
+                    }
+                    _ => {
+                        warn!("Unhandled control IN setup={:x?}", setup_packet);
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::BrokenPipe,
+                            "unhandled control in",
+                        ));
+                    }
+                }
+            }
+            (Some(Control), Out) => {
+                // control out
+                debug!("Control OUT setup={:x?}", setup_packet);
+                match (
+                    setup_packet.request_type,
+                    FromPrimitive::from_u8(setup_packet.request),
+                ) {
+                    (0b00000000, Some(SetConfiguration)) => {
+                        let value = setup_packet.value as u8;
+                        debug!("Set configuration {}", value);
+                        if !self.has_configuration(value) {
+                            return Err(std::io::Error::new(
+                                std::io::ErrorKind::BrokenPipe,
+                                format!("no configuration {}", value),
+                            ));
+                        }
+                        // resets alternate settings and halts
+                        *self.state.lock().unwrap() = UsbDeviceState {
+                            configuration: value,
+                            ..Default::default()
+                        };
+                    }
+                    (0b00000000, Some(SetFeature))
+                        if FromPrimitive::from_u16(setup_packet.value)
+                            == Some(FeatureSelector::TestMode) =>
+                    {
+                        // electrical test modes are meaningless over USB/IP,
+                        // acknowledge so that compliance tools carry on
+                        debug!("Set feature test mode {}", setup_packet.index >> 8);
+                    }
+                    (0b00000000, Some(request @ SetFeature))
+                    | (0b00000000, Some(request @ ClearFeature))
+                        if FromPrimitive::from_u16(setup_packet.value)
+                            == Some(FeatureSelector::DeviceRemoteWakeup) =>
+                    {
+                        let enable = request == SetFeature;
+                        debug!("Set remote wakeup {}", enable);
+                        self.state.lock().unwrap().remote_wakeup = enable;
+                    }
+                    (0b00000010, Some(request @ SetFeature))
+                    | (0b00000010, Some(request @ ClearFeature))
+                        if FromPrimitive::from_u16(setup_packet.value)
+                            == Some(FeatureSelector::EndpointHalt) =>
+                    {
+                        let address = setup_packet.index as u8;
+                        debug!("{:?} halt of endpoint {:x}", request, address);
+                        let mut state = self.state.lock().unwrap();
+                        if address & 0x7F == 0 {
+                            // a STALL of the default control pipe only lasts
+                            // until the next SETUP, nothing to remember
+                        } else if request == SetFeature {
+                            state.halted_endpoints.insert(address);
+                            state.in_remainders.remove(&address);
+                        } else {
+                            // resets the endpoint, data not read yet is lost
+                            state.halted_endpoints.remove(&address);
+                            state.in_remainders.remove(&address);
+                        }
+                    }
+                    (0b00000000, Some(SetDescriptor)) => {
+                        let desc_type = (setup_packet.value >> 8) as u8;
+                        let index = setup_packet.value as u8;
+                        debug!("Set descriptor type {} index {}", desc_type, index);
+                        match &self.set_descriptor_handler {
+                            Some(handler) => {
+                                handler(desc_type, index, setup_packet.index, out_data)?
+                            }
+                            None => {
+                                return Err(std::io::Error::new(
+                                    std::io::ErrorKind::BrokenPipe,
+                                    "SET_DESCRIPTOR not supported",
+                                ))
                             }
                         }
                     }
-                    _ if setup_packet.request_type & 0xF == 1 => {
-                        // to interface
-                        let intf = &self.interfaces[setup_packet.index as usize];
-                        let mut handler = intf.handler.lock().unwrap();
-                        let resp = handler.handle_urb(intf, ep, setup_packet, &out_data)?;
-                        return Ok(resp);
+                    (0b00000001, Some(SetInterface)) => {
+                        let number = setup_packet.index as u8;
+                        let alt = setup_packet.value as u8;
+                        debug!("Set interface {} alternate setting {}", number, alt);
+                        if !self.has_alternate_setting(number, alt) {
+                            return Err(std::io::Error::new(
+                                std::io::ErrorKind::BrokenPipe,
+                                format!("no alternate setting {} of interface {}", alt, number),
+                            ));
+                        }
+                        self.state
+                            .lock()
+                            .unwrap()
+                            .alternate_settings
+                            .insert(number, alt);
+                    }
+                    _ if self.control_target(&setup_packet).is_some() => {
+                        // to interface or endpoint
+                        let intf = self.control_target(&setup_packet).unwrap();
+                        let resp = {
+                            let mut handler = intf.handler.lock().unwrap();
+                            handler.handle_urb_async(ctx, intf, ep, Some(setup_packet), out_data)
+                        };
+                        return resp.await;
+                    }
+                    _ => {
+                        // the data stage was already read off the socket,
+                        // so the stream stays aligned
+                        warn!(
+                            "Unhandled control OUT setup={:x?}, discarding {} bytes",
+                            setup_packet,
+                            out_data.len()
+                        );
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::BrokenPipe,
+                            "unhandled control out",
+                        ));
                     }
-                    _ => unimplemented!("control in"),
                 }
             }
-            (Some(Control), Out) => {
-                // control out
-                debug!("Control OUT setup={:x?}", setup_packet);
-            }
             (Some(_), _) => {
                 // others
+                if self
+                    .state
+                    .lock()
+                    .unwrap()
+                    .halted_endpoints
+                    .contains(&ep.address)
+                {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::BrokenPipe,
+                        format!("endpoint {:x} halted", ep.address),
+                    ));
+                }
                 let intf = intf.unwrap();
-                let mut handler = intf.handler.lock().unwrap();
-                let resp = handler.handle_urb(intf, ep, setup_packet, &out_data)?;
-                return Ok(resp);
+                let resp = {
+                    let mut handler = intf.handler.lock().unwrap();
+                    handler.handle_urb_async(ctx, intf, ep, None, out_data)
+                };
+                let resp = resp.await;
+                if let Err(err) = &resp {
+                    if err.kind() == std::io::ErrorKind::BrokenPipe {
+                        // like a real device, stay halted until the host
+                        // clears it
+                        self.halt_endpoint(ep.address);
+                    }
+                }
+                return resp;
+            }
+            _ => {
+                warn!("Transfer to unknown endpoint {:02x}", ep.address);
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::BrokenPipe,
+                    format!("transfer to unknown endpoint {:02x}", ep.address),
+                ));
             }
-            _ => unimplemented!("transfer to {:?}", ep),
         }
         Ok(vec![])
     }
+
+    /// Pass an isochronous URB to the handler of the interface of `ep`
+    pub(crate) fn handle_iso_urb(
+        &self,
+        ep: UsbEndpoint,
+        intf: Option<&UsbInterface>,
+        packets: &[IsoPacketDescriptor],
+        out_data: &[u8],
+    ) -> Result<Vec<IsoPacketResult>> {
+        if self
+            .state
+            .lock()
+            .unwrap()
+            .halted_endpoints
+            .contains(&ep.address)
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                format!("endpoint {:x} halted", ep.address),
+            ));
+        }
+        let intf = intf.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("isochronous transfer to {:x}", ep.address),
+            )
+        })?;
+        let mut handler = intf.handler.lock().unwrap();
+        handler.handle_iso_urb(intf, ep, packets, out_data)
+    }
+}
+
+/// Number of interfaces in `interfaces`, without alternate settings
+fn num_interfaces(interfaces: &[UsbInterface]) -> usize {
+    interfaces
+        .iter()
+        .filter(|intf| intf.alternate_setting == 0)
+        .count()
+}
+
+fn build_configuration_descriptor(
+    configuration_value: u8,
+    string_configuration: u8,
+    attributes: u8,
+    speed: u32,
+    interfaces: &[UsbInterface],
+    interface_associations: &[UsbInterfaceAssociation],
+) -> Vec<u8> {
+    use DescriptorType::*;
+
+    // alternate settings share the number of the interface they follow
+    let num_interfaces = num_interfaces(interfaces);
+    let mut desc = vec![
+        0x09,                // bLength
+        Configuration as u8, // bDescriptorType: Configuration
+        0x00,
+        0x00,                 // wTotalLength: to be filled below
+        num_interfaces as u8, // bNumInterfaces
+        configuration_value,  // bConfigurationValue
+        string_configuration, // iConfiguration
+        attributes,           // bmAttributes
+        0x32,                 // bMaxPower 100mA
+    ];
+    let mut i = 0u8;
+    for (idx, intf) in interfaces.iter().enumerate() {
+        if intf.alternate_setting == 0 {
+            if idx > 0 {
+                i += 1;
+            }
+            // interface association precedes its first interface
+            for assoc in interface_associations {
+                if assoc.first_interface == i {
+                    desc.extend_from_slice(&assoc.descriptor());
+                }
+            }
+        }
+        let mut intf_desc = vec![
+            0x09,                       // bLength
+            Interface as u8,            // bDescriptorType: Interface
+            i,                          // bInterfaceNum
+            intf.alternate_setting,     // bAlternateSettings
+            intf.endpoints.len() as u8, // bNumEndpoints
+            intf.interface_class,       // bInterfaceClass
+            intf.interface_subclass,    // bInterfaceSubClass
+            intf.interface_protocol,    // bInterfaceProtocol
+            intf.string_interface,      //iInterface
+        ];
+        // class specific endpoint
+        let mut specific = intf.class_specific_descriptor.clone();
+        intf_desc.append(&mut specific);
+        // endpoint descriptors
+        for endpoint in &intf.endpoints {
+            let mut ep_desc = vec![
+                0x07,                // bLength
+                Endpoint as u8,      // bDescriptorType: Endpoint
+                endpoint.address,    // bEndpointAddress
+                endpoint.attributes, // bmAttributes
+                endpoint.max_packet_size as u8,
+                (endpoint.max_packet_size >> 8) as u8, // wMaxPacketSize
+                endpoint.descriptor_interval(speed),   // bInterval
+            ];
+            intf_desc.append(&mut ep_desc);
+            if let Some(specific) = intf
+                .class_specific_endpoint_descriptors
+                .get(&endpoint.address)
+            {
+                intf_desc.extend_from_slice(specific);
+            }
+        }
+        desc.append(&mut intf_desc);
+    }
+    // length
+    let len = desc.len() as u16;
+    desc[2] = len as u8;
+    desc[3] = (len >> 8) as u8;
+    desc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn get_descriptor(device: &UsbDevice, desc_type: DescriptorType) -> Vec<u8> {
+        get_descriptor_len(device, desc_type, 0xFFFF).await
+    }
+
+    async fn get_descriptor_len(
+        device: &UsbDevice,
+        desc_type: DescriptorType,
+        length: u16,
+    ) -> Vec<u8> {
+        device
+            .handle_urb(
+                &ConnectionContext::default(),
+                device.ep0_in,
+                None,
+                [
+                    0x80,
+                    0x06,
+                    0x00,
+                    desc_type as u8,
+                    0x00,
+                    0x00,
+                    length as u8,
+                    (length >> 8) as u8,
+                ],
+                &[],
+            )
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn iad_composite() {
+        let new_handler = || {
+            Arc::new(Mutex::new(
+                Box::new(cdc::UsbCdcAcmHandler::new()) as Box<dyn UsbInterfaceHandler + Send>
+            ))
+        };
+        let device = UsbDevice::new(0)
+            .with_interface(
+                ClassCode::CDC as u8,
+                cdc::CDC_ACM_SUBCLASS,
+                0x00,
+                "Test CDC ACM",
+                cdc::UsbCdcAcmHandler::endpoints(),
+                new_handler(),
+            )
+            .with_interface(
+                ClassCode::CDCData as u8,
+                0x00,
+                0x00,
+                "Test CDC Data",
+                vec![],
+                new_handler(),
+            )
+            .with_interface_association(
+                0,
+                2,
+                ClassCode::CDC as u8,
+                cdc::CDC_ACM_SUBCLASS,
+                0x00,
+                "Test CDC Function",
+            );
+
+        // device descriptor uses the IAD class triple
+        let desc = get_descriptor(&device, DescriptorType::Device).await;
+        assert_eq!(desc[4..7], [0xEF, 0x02, 0x01]);
+
+        // IAD is emitted right after the configuration descriptor
+        let desc = get_descriptor(&device, DescriptorType::Configuration).await;
+        verify_descriptor(&desc);
+        assert_eq!(
+            desc[9..17],
+            [
+                0x08,
+                DescriptorType::InterfaceAssociation as u8,
+                0x00,
+                0x02,
+                ClassCode::CDC as u8,
+                cdc::CDC_ACM_SUBCLASS,
+                0x00,
+                device.interface_associations[0].string_function
+            ]
+        );
+        assert_eq!(desc[18], DescriptorType::Interface as u8);
+    }
+
+    #[tokio::test]
+    async fn hid_cdc_composite() {
+        let hid = Arc::new(Mutex::new(
+            Box::new(hid::UsbHidKeyboardHandler::new_keyboard())
+                as Box<dyn UsbInterfaceHandler + Send>,
+        ));
+        let acm = Arc::new(Mutex::new(
+            Box::new(cdc::UsbCdcAcmHandler::new()) as Box<dyn UsbInterfaceHandler + Send>
+        ));
+        let hid_ep = UsbEndpoint {
+            address: 0x83,
+            attributes: EndpointAttributes::Interrupt as u8,
+            max_packet_size: 0x08,
+            interval: 10,
+        };
+        let device = UsbDevice::new(0)
+            .with_interface(
+                ClassCode::HID as u8,
+                0x00,
+                0x00,
+                "Test HID",
+                vec![hid_ep],
+                hid.clone(),
+            )
+            .with_interface(
+                ClassCode::CDC as u8,
+                cdc::CDC_ACM_SUBCLASS,
+                0x00,
+                "Test CDC ACM",
+                cdc::UsbCdcAcmHandler::endpoints(),
+                acm.clone(),
+            )
+            .with_interface_association(
+                1,
+                1,
+                ClassCode::CDC as u8,
+                cdc::CDC_ACM_SUBCLASS,
+                0x00,
+                "Test CDC Function",
+            );
+        let ctx = ConnectionContext::default();
+
+        // both interfaces enumerate
+        let desc = get_descriptor(&device, DescriptorType::Configuration).await;
+        verify_descriptor(&desc);
+        assert_eq!(count_interfaces(&desc), 2);
+        let mut classes = vec![];
+        let mut offset = 0;
+        while offset < desc.len() {
+            if desc[offset + 1] == DescriptorType::Interface as u8 {
+                classes.push(desc[offset + 5]);
+            }
+            offset += desc[offset] as usize;
+        }
+        assert_eq!(classes, [ClassCode::HID as u8, ClassCode::CDC as u8]);
+
+        // class requests go by wIndex
+        let report = device
+            .handle_urb(
+                &ctx,
+                device.ep0_in,
+                None,
+                [0x81, 0x06, 0x00, 0x22, 0x00, 0x00, 0xFF, 0x00],
+                &[],
+            )
+            .await
+            .unwrap();
+        assert_eq!(report[..4], [0x05, 0x01, 0x09, 0x06]);
+        let line_coding = device
+            .handle_urb(
+                &ctx,
+                device.ep0_in,
+                None,
+                [
+                    0xA1,
+                    cdc::CDC_GET_LINE_CODING,
+                    0x00,
+                    0x00,
+                    0x01,
+                    0x00,
+                    0x07,
+                    0x00,
+                ],
+                &[],
+            )
+            .await
+            .unwrap();
+        assert_eq!(line_coding.len(), 7);
+
+        // transfers go by endpoint address
+        {
+            let mut hid = hid.lock().unwrap();
+            let hid = hid
+                .as_any()
+                .downcast_mut::<hid::UsbHidKeyboardHandler>()
+                .unwrap();
+            hid.pending_key_events
+                .push_back(hid::UsbHidKeyboardReport::from_ascii(b'a'));
+            let mut acm = acm.lock().unwrap();
+            let acm = acm
+                .as_any()
+                .downcast_mut::<cdc::UsbCdcAcmHandler>()
+                .unwrap();
+            acm.tx_buffer.push(b'b');
+        }
+        let (ep, intf) = device.find_ep(0x83).unwrap();
+        let resp = device
+            .handle_urb(&ctx, ep, intf, [0; 8], &[])
+            .await
+            .unwrap();
+        assert_eq!(resp, [0, 0, 4, 0, 0, 0, 0, 0]);
+        let (ep, intf) = device.find_ep(0x82).unwrap();
+        let resp = device
+            .handle_urb(&ctx, ep, intf, [0; 8], &[])
+            .await
+            .unwrap();
+        assert_eq!(resp, [b'b']);
+
+        // HID on an endpoint of the CDC interface would be misrouted
+        let res = device.try_with_interface(
+            ClassCode::HID as u8,
+            0x00,
+            0x00,
+            "Test HID",
+            vec![UsbEndpoint {
+                address: 0x81,
+                ..hid_ep
+            }],
+            hid,
+        );
+        assert_eq!(res.err().unwrap().kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    struct LargeDescriptorHandler;
+
+    impl UsbInterfaceHandler for LargeDescriptorHandler {
+        fn get_class_specific_descriptor(&self) -> Vec<u8> {
+            // 0x101 descriptors of 0xFF bytes each, more than wTotalLength can hold
+            let mut desc = vec![0u8; 0x101 * 0xFF];
+            for chunk in desc.chunks_mut(0xFF) {
+                chunk[0] = 0xFF; // bLength
+                chunk[1] = 0x24; // CS_INTERFACE
+            }
+            desc
+        }
+
+        fn handle_urb(
+            &mut self,
+            _interface: &UsbInterface,
+            _ep: UsbEndpoint,
+            _setup: Option<SetupPacket>,
+            _req: &[u8],
+        ) -> Result<Vec<u8>> {
+            Ok(vec![])
+        }
+
+        fn as_any(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    #[test]
+    fn configuration_descriptor_overflow() {
+        let handler = Arc::new(Mutex::new(
+            Box::new(LargeDescriptorHandler) as Box<dyn UsbInterfaceHandler + Send>
+        ));
+        let res = UsbDevice::new(0).try_with_interface(
+            ClassCode::VendorSpecific as u8,
+            0x00,
+            0x00,
+            "Large",
+            vec![],
+            handler,
+        );
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn string_indexes_exhausted() {
+        let add = |device: UsbDevice| {
+            device.try_with_interface(
+                ClassCode::VendorSpecific as u8,
+                0x00,
+                0x00,
+                "Interface",
+                vec![],
+                FnHandler::new(|_, _, _, _| Ok(vec![])).into_handler(),
+            )
+        };
+        // 4 of the 255 indexes name the device and its configuration
+        let mut device = UsbDevice::new(0);
+        for _ in 0..251 {
+            device = add(device).unwrap();
+        }
+        let res = add(device);
+        assert_eq!(res.err().unwrap().kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn max_packet_size0_probe() {
+        let device = UsbDevice::new(0).with_max_packet_size0(8);
+        // host reads the first 8 bytes to learn bMaxPacketSize0
+        let probe = get_descriptor_len(&device, DescriptorType::Device, 8).await;
+        assert_eq!(probe.len(), 8);
+        assert_eq!(probe[7], 8);
+        // then reads the whole descriptor
+        let full = get_descriptor_len(&device, DescriptorType::Device, 0x12).await;
+        assert_eq!(full.len(), 0x12);
+        assert_eq!(full[..8], probe[..]);
+        // endpoint 0 uses the reported size in both directions
+        assert_eq!(device.ep0_in.max_packet_size, 8);
+        assert_eq!(device.ep0_out.max_packet_size, 8);
+        let qualifier = get_descriptor(&device, DescriptorType::DeviceQualifier).await;
+        assert_eq!(qualifier[7], 8);
+    }
+
+    #[test]
+    fn invalid_max_packet_size0() {
+        for size in [0, 9, 128, 255] {
+            let res = UsbDevice::new(0).try_with_max_packet_size0(size);
+            assert_eq!(res.err().unwrap().kind(), std::io::ErrorKind::InvalidInput);
+        }
+        let device = UsbDevice::new(0).try_with_max_packet_size0(16).unwrap();
+        assert_eq!(device.max_packet_size0(), 16);
+    }
+
+    #[tokio::test]
+    async fn device_speed() {
+        let full = UsbDevice::new(0).with_speed(UsbSpeed::Full);
+        let desc = get_descriptor(&full, DescriptorType::Device).await;
+        // bcdUSB, bMaxPacketSize0
+        assert_eq!(desc[2..4], [0x10, 0x01]);
+        assert_eq!(desc[7], 64);
+        // USB 1.1 devices have no device qualifier
+        let setup = [0x80, 0x06, 0x00, 0x06, 0x00, 0x00, 0x0A, 0x00];
+        let ctx = ConnectionContext::default();
+        let err = full
+            .handle_urb(&ctx, full.ep0_in, None, setup, &[])
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+
+        let low = UsbDevice::new(0).with_speed(UsbSpeed::Low);
+        let desc = get_descriptor(&low, DescriptorType::Device).await;
+        assert_eq!(desc[7], 8);
+        assert_eq!(low.validate(), Ok(()));
+
+        let high = UsbDevice::new(0).with_speed(UsbSpeed::High);
+        let desc = get_descriptor(&high, DescriptorType::Device).await;
+        assert_eq!(desc[2..4], [0x10, 0x02]);
+        assert_eq!(desc[7], 64);
+
+        // 2^9 = 512 bytes
+        let sup = UsbDevice::new(0).with_speed(UsbSpeed::Super);
+        let desc = get_descriptor(&sup, DescriptorType::Device).await;
+        assert_eq!(desc[2..4], [0x00, 0x03]);
+        assert_eq!(desc[7], 9);
+        assert_eq!(sup.max_packet_size0(), 512);
+        // the qualifier describes high speed operation
+        let qualifier = get_descriptor(&sup, DescriptorType::DeviceQualifier).await;
+        assert_eq!(qualifier[7], 64);
+        let mut sup = UsbDevice::new(0);
+        sup.speed = UsbSpeed::SuperPlus as u32;
+        let desc = get_descriptor(&sup, DescriptorType::Device).await;
+        assert_eq!(desc[7], 9);
+
+        // speed field of OP_REP_DEVLIST and OP_REP_IMPORT, after path and bus id
+        let mut socket = MockSocket::new(vec![]);
+        full.write_dev(&mut socket).await.unwrap();
+        assert_eq!(
+            socket.output[256 + 32 + 8..256 + 32 + 12],
+            (UsbSpeed::Full as u32).to_be_bytes()
+        );
+    }
+
+    #[tokio::test]
+    async fn composite_configuration_lengths() {
+        // class specific interface and endpoint descriptors of varying lengths,
+        // and an interface with two alternate settings
+        let (device, _) = midi::with_midi(UsbDevice::new(0), midi::MidiProtocol::Midi1);
+        let keyboard = Arc::new(Mutex::new(
+            Box::new(hid::UsbHidKeyboardHandler::new_keyboard())
+                as Box<dyn UsbInterfaceHandler + Send>,
+        ));
+        let endpoint = |address| UsbEndpoint {
+            address,
+            attributes: EndpointAttributes::Interrupt as u8,
+            max_packet_size: 8,
+            interval: 10,
+        };
+        let device = device
+            .with_interface(
+                ClassCode::HID as u8,
+                0x00,
+                0x00,
+                "Keyboard",
+                vec![endpoint(0x82)],
+                keyboard,
+            )
+            .with_interface(
+                ClassCode::VendorSpecific as u8,
+                0x00,
+                0x00,
+                "Idle",
+                vec![],
+                FnHandler::new(|_, _, _, _| Ok(vec![])).into_handler(),
+            )
+            .with_alternate_setting(
+                ClassCode::VendorSpecific as u8,
+                0x00,
+                0x00,
+                "Active",
+                vec![endpoint(0x83)],
+                FnHandler::new(|_, _, _, _| Ok(vec![])).into_handler(),
+            );
+        let config = device.configuration_descriptor();
+        verify_descriptor(&config);
+        assert_eq!(config[4], 4);
+
+        // OP_REP_DEVLIST lists the same interfaces
+        let mut socket = MockSocket::new(vec![]);
+        device.write_dev_with_interfaces(&mut socket).await.unwrap();
+        let num_interfaces = socket.output[256 + 32 + 12 + 6 + 5];
+        assert_eq!(num_interfaces, 4);
+        assert_eq!(socket.output.len(), 256 + 32 + 12 + 6 + 6 + 4 * 4);
+    }
+
+    #[tokio::test]
+    async fn bos_container_id() {
+        let device = UsbDevice::new(0);
+        let desc = get_descriptor(&device, DescriptorType::BOS).await;
+        assert_eq!(desc, [0x05, 0x0F, 0x05, 0x00, 0x00]);
+
+        let container_id = [
+            0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC, 0xDE, 0xF0, 0x0F, 0xED, 0xCB, 0xA9, 0x87, 0x65,
+            0x43, 0x21,
+        ];
+        let device = device.with_container_id(container_id);
+        let desc = get_descriptor(&device, DescriptorType::BOS).await;
+        verify_descriptor(&desc);
+        // wTotalLength and bNumCapabilities
+        assert_eq!(desc[2..5], [0x19, 0x00, 0x01]);
+        assert_eq!(desc[5..9], [0x14, 0x10, 0x04, 0x00]);
+        assert_eq!(desc[9..], container_id);
+    }
+
+    #[tokio::test]
+    async fn get_set_configuration() {
+        let device = UsbDevice::new(0);
+        let get_configuration = [0x80, 0x08, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00];
+        let set_configuration = [0x00, 0x09, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00];
+
+        // not configured after attach
+        let resp = device
+            .handle_urb(
+                &ConnectionContext::default(),
+                device.ep0_in,
+                None,
+                get_configuration,
+                &[],
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp, [0]);
+
+        device
+            .handle_urb(
+                &ConnectionContext::default(),
+                device.ep0_out,
+                None,
+                set_configuration,
+                &[],
+            )
+            .await
+            .unwrap();
+        let resp = device
+            .handle_urb(
+                &ConnectionContext::default(),
+                device.ep0_in,
+                None,
+                get_configuration,
+                &[],
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp, [1]);
+        assert_eq!(device.state().configuration, 1);
+    }
+
+    #[tokio::test]
+    async fn configuration_value() {
+        let device = UsbDevice::new(0).with_configuration_value(2);
+        let ctx = ConnectionContext::default();
+        let set_configuration = |value: u8| [0x00, 0x09, value, 0x00, 0x00, 0x00, 0x00, 0x00];
+
+        let desc = get_descriptor(&device, DescriptorType::Configuration).await;
+        // bConfigurationValue
+        assert_eq!(desc[5], 2);
+        device
+            .handle_urb(&ctx, device.ep0_out, None, set_configuration(2), &[])
+            .await
+            .unwrap();
+        assert_eq!(device.state().configuration, 2);
+        let err = device
+            .handle_urb(&ctx, device.ep0_out, None, set_configuration(1), &[])
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+        assert_eq!(device.state().configuration, 2);
+
+        // the next configuration does not reuse it
+        let device = device.with_other_configuration("Other", vec![]);
+        assert_eq!(device.other_configurations[0].configuration_value, 3);
+    }
+
+    #[tokio::test]
+    async fn set_feature_test_mode() {
+        let device = UsbDevice::new(0);
+        // SET_FEATURE(TEST_MODE), Test_Packet selector in the high byte of wIndex
+        let set_feature = [0x00, 0x03, 0x02, 0x00, 0x00, 0x04, 0x00, 0x00];
+        let resp = device
+            .handle_urb(
+                &ConnectionContext::default(),
+                device.ep0_out,
+                None,
+                set_feature,
+                &[],
+            )
+            .await
+            .unwrap();
+        assert!(resp.is_empty());
+    }
+
+    #[tokio::test]
+    async fn control_stall_lasts_one_transfer() {
+        let device = UsbDevice::new(0);
+        let ctx = ConnectionContext::default();
+        let get_device_descriptor = [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00];
+
+        // an unknown request stalls, the next one goes through
+        let err = testing::control(
+            &device,
+            &ctx,
+            [0x80, 0xFF, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00],
+            &[],
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+        assert_eq!(
+            testing::control(&device, &ctx, get_device_descriptor, &[])
+                .await
+                .unwrap()
+                .len(),
+            0x12
+        );
+
+        // SET_FEATURE(ENDPOINT_HALT) and CLEAR_FEATURE(ENDPOINT_HALT) on EP0
+        testing::control(
+            &device,
+            &ctx,
+            [0x02, 0x03, 0x00, 0x00, 0x80, 0x00, 0x00, 0x00],
+            &[],
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            testing::control(&device, &ctx, get_device_descriptor, &[])
+                .await
+                .unwrap()
+                .len(),
+            0x12
+        );
+        let get_status = [0x82, 0x00, 0x00, 0x00, 0x80, 0x00, 0x02, 0x00];
+        assert_eq!(
+            testing::control(&device, &ctx, get_status, &[])
+                .await
+                .unwrap(),
+            [0x00, 0x00]
+        );
+        testing::control(
+            &device,
+            &ctx,
+            [0x02, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+            &[],
+        )
+        .await
+        .unwrap();
+        assert!(device.runtime_state().halted_endpoints.is_empty());
+    }
+
+    #[tokio::test]
+    async fn endpoint_halt_introspection() {
+        let handler = Arc::new(Mutex::new(
+            Box::new(cdc::UsbCdcAcmHandler::new()) as Box<dyn UsbInterfaceHandler + Send>
+        ));
+        let device = UsbDevice::new(0).with_interface(
+            ClassCode::CDC as u8,
+            cdc::CDC_ACM_SUBCLASS,
+            0x00,
+            "Test CDC ACM",
+            cdc::UsbCdcAcmHandler::endpoints(),
+            handler,
+        );
+        let ctx = ConnectionContext::default();
+        let (ep, intf) = device.find_ep(0x82).unwrap();
+        assert!(!device.is_endpoint_halted(0x82));
+
+        // SET_FEATURE(ENDPOINT_HALT) on 0x82 stalls its transfers
+        device
+            .handle_urb(
+                &ctx,
+                device.ep0_out,
+                None,
+                [0x02, 0x03, 0x00, 0x00, 0x82, 0x00, 0x00, 0x00],
+                &[],
+            )
+            .await
+            .unwrap();
+        assert!(device.is_endpoint_halted(0x82));
+        assert!(!device.is_endpoint_halted(0x81));
+        let err = device
+            .handle_urb(&ctx, ep, intf, [0; 8], &[])
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+
+        // CLEAR_FEATURE(ENDPOINT_HALT) on 0x82
+        device
+            .handle_urb(
+                &ctx,
+                device.ep0_out,
+                None,
+                [0x02, 0x01, 0x00, 0x00, 0x82, 0x00, 0x00, 0x00],
+                &[],
+            )
+            .await
+            .unwrap();
+        assert!(!device.is_endpoint_halted(0x82));
+        device
+            .handle_urb(&ctx, ep, intf, [0; 8], &[])
+            .await
+            .unwrap();
+    }
+
+    /// Stalls the first transfer, answers the others
+    struct StallOnceHandler {
+        calls: usize,
+    }
+
+    impl UsbInterfaceHandler for StallOnceHandler {
+        fn get_class_specific_descriptor(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn handle_urb(
+            &mut self,
+            _interface: &UsbInterface,
+            _ep: UsbEndpoint,
+            _setup: Option<SetupPacket>,
+            _req: &[u8],
+        ) -> Result<Vec<u8>> {
+            self.calls += 1;
+            if self.calls == 1 {
+                return Err(std::io::Error::new(std::io::ErrorKind::BrokenPipe, "stall"));
+            }
+            Ok(vec![0x42])
+        }
+
+        fn as_any(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    #[tokio::test]
+    async fn handler_stall_halts_endpoint() {
+        let handler = Arc::new(Mutex::new(
+            Box::new(StallOnceHandler { calls: 0 }) as Box<dyn UsbInterfaceHandler + Send>
+        ));
+        let device = UsbDevice::new(0).with_interface(
+            ClassCode::VendorSpecific as u8,
+            0x00,
+            0x00,
+            "Stall",
+            vec![UsbEndpoint::new(
+                0x81,
+                EndpointAttributes::Bulk,
+                SyncType::NoSync,
+                UsageType::Data,
+                512,
+                0,
+            )],
+            handler.clone(),
+        );
+        let ctx = ConnectionContext::default();
+        let (ep, intf) = device.find_ep(0x81).unwrap();
+        let get_status = [0x82, 0x00, 0x00, 0x00, 0x81, 0x00, 0x02, 0x00];
+
+        // the stall halts the endpoint, later transfers stall without the handler
+        for _ in 0..2 {
+            let err = device
+                .handle_urb(&ctx, ep, intf, [0; 8], &[])
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+        }
+        assert!(device.is_endpoint_halted(0x81));
+        let status = device.handle_urb(&ctx, device.ep0_in, None, get_status, &[]);
+        assert_eq!(status.await.unwrap(), [0x01, 0x00]);
+
+        // CLEAR_FEATURE(ENDPOINT_HALT) on 0x81 recovers
+        device
+            .handle_urb(
+                &ctx,
+                device.ep0_out,
+                None,
+                [0x02, 0x01, 0x00, 0x00, 0x81, 0x00, 0x00, 0x00],
+                &[],
+            )
+            .await
+            .unwrap();
+        let status = device.handle_urb(&ctx, device.ep0_in, None, get_status, &[]);
+        assert_eq!(status.await.unwrap(), [0x00, 0x00]);
+        assert_eq!(
+            device
+                .handle_urb(&ctx, ep, intf, [0; 8], &[])
+                .await
+                .unwrap(),
+            [0x42]
+        );
+        let mut handler = handler.lock().unwrap();
+        let stall = handler.as_any().downcast_mut::<StallOnceHandler>().unwrap();
+        assert_eq!(stall.calls, 2);
+
+        // the device side can halt it too
+        drop(handler);
+        device.halt_endpoint(0x81);
+        assert!(device.is_endpoint_halted(0x81));
+    }
+
+    #[tokio::test]
+    async fn enumeration_delay() {
+        tokio::time::pause();
+        let device = UsbDevice::new(0)
+            .with_enumeration_delay(StandardRequest::GetDescriptor, Duration::from_millis(300));
+
+        let start = tokio::time::Instant::now();
+        let desc = get_descriptor(&device, DescriptorType::Device).await;
+        assert_eq!(desc.len(), 0x12);
+        assert!(start.elapsed() >= Duration::from_millis(300));
+
+        // other stages are not delayed
+        let start = tokio::time::Instant::now();
+        device
+            .handle_urb(
+                &ConnectionContext::default(),
+                device.ep0_in,
+                None,
+                [0x80, 0x08, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00],
+                &[],
+            )
+            .await
+            .unwrap();
+        assert_eq!(start.elapsed(), Duration::from_secs(0));
+    }
+
+    #[tokio::test]
+    async fn other_configuration_descriptor() {
+        let handler =
+            Arc::new(Mutex::new(Box::new(crate::cdc::UsbCdcAcmHandler::new())
+                as Box<dyn UsbInterfaceHandler + Send>));
+        let device = UsbDevice::new(0).with_other_configuration(
+            "Serial",
+            vec![UsbInterface {
+                interface_class: ClassCode::CDCData as u8,
+                interface_subclass: 0,
+                interface_protocol: 0,
+                alternate_setting: 0,
+                endpoints: crate::cdc::UsbCdcAcmHandler::endpoints(),
+                string_interface: 0,
+                class_specific_descriptor: vec![],
+                class_specific_endpoint_descriptors: HashMap::new(),
+                handler,
+            }],
+        );
+        assert_eq!(device.num_configurations, 2);
+
+        // only the first configuration is active
+        let ctx = ConnectionContext::default();
+        device
+            .handle_urb(
+                &ctx,
+                device.ep0_out,
+                None,
+                [0x00, 0x09, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00],
+                &[],
+            )
+            .await
+            .unwrap();
+        assert_eq!(device.state().configuration, 1);
+
+        let get_config = |index: u8| {
+            device.handle_urb(
+                &ctx,
+                device.ep0_in,
+                None,
+                [0x80, 0x06, index, 0x02, 0x00, 0x00, 0xFF, 0xFF],
+                &[],
+            )
+        };
+        let first = get_config(0).await.unwrap();
+        verify_descriptor(&first);
+        assert_eq!(first[4..6], [0, 1]);
+        let second = get_config(1).await.unwrap();
+        verify_descriptor(&second);
+        // bNumInterfaces, bConfigurationValue
+        assert_eq!(second[4..6], [1, 2]);
+        assert_eq!(second.len(), 9 + 9 + 7 * 3);
+
+        // out of range index stalls
+        let err = get_config(2).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+    }
+
+    #[tokio::test]
+    async fn switch_configuration() {
+        let handler =
+            Arc::new(Mutex::new(Box::new(crate::cdc::UsbCdcAcmHandler::new())
+                as Box<dyn UsbInterfaceHandler + Send>));
+        let device = UsbDevice::new(0).with_other_configuration(
+            "Serial",
+            vec![UsbInterface {
+                interface_class: ClassCode::CDCData as u8,
+                interface_subclass: 0,
+                interface_protocol: 0,
+                alternate_setting: 0,
+                endpoints: crate::cdc::UsbCdcAcmHandler::endpoints(),
+                string_interface: 0,
+                class_specific_descriptor: vec![],
+                class_specific_endpoint_descriptors: HashMap::new(),
+                handler,
+            }],
+        );
+        let ctx = ConnectionContext::default();
+        let set_config = |value: u8| {
+            device.handle_urb(
+                &ctx,
+                device.ep0_out,
+                None,
+                [0x00, 0x09, value, 0x00, 0x00, 0x00, 0x00, 0x00],
+                &[],
+            )
+        };
+        let get_config = || {
+            device.handle_urb(
+                &ctx,
+                device.ep0_in,
+                None,
+                [0x80, 0x08, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00],
+                &[],
+            )
+        };
+
+        // the first configuration has no endpoints
+        assert_eq!(get_config().await.unwrap(), [0]);
+        assert!(device.find_ep(0x82).is_none());
+
+        set_config(2).await.unwrap();
+        assert_eq!(get_config().await.unwrap(), [2]);
+        let (_, intf) = device.find_ep(0x82).unwrap();
+        assert_eq!(intf.unwrap().interface_class, ClassCode::CDCData as u8);
+        assert_eq!(device.runtime_state().alternate_settings, [0]);
+
+        // unknown configurations stall and keep the active one
+        let err = set_config(3).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+        assert_eq!(get_config().await.unwrap(), [2]);
+
+        set_config(1).await.unwrap();
+        assert!(device.find_ep(0x82).is_none());
+    }
+
+    /// Switches the descriptor mode to wValue on any request
+    struct ModeSwitchHandler(DescriptorMode);
+
+    impl UsbInterfaceHandler for ModeSwitchHandler {
+        fn get_class_specific_descriptor(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn handle_urb(
+            &mut self,
+            _interface: &UsbInterface,
+            _ep: UsbEndpoint,
+            setup: Option<SetupPacket>,
+            _req: &[u8],
+        ) -> Result<Vec<u8>> {
+            if let Some(setup) = setup {
+                self.0.set(setup.value as u8);
+            }
+            Ok(vec![])
+        }
+
+        fn as_any(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    #[tokio::test]
+    async fn descriptor_mode_switch() {
+        let mode = DescriptorMode::new();
+        let handler = Arc::new(Mutex::new(
+            Box::new(ModeSwitchHandler(mode.clone())) as Box<dyn UsbInterfaceHandler + Send>
+        ));
+        let vendor = UsbInterface {
+            interface_class: ClassCode::VendorSpecific as u8,
+            interface_subclass: 0,
+            interface_protocol: 0,
+            alternate_setting: 0,
+            endpoints: vec![],
+            string_interface: 0,
+            class_specific_descriptor: vec![],
+            class_specific_endpoint_descriptors: HashMap::new(),
+            handler,
+        };
+        let serial = UsbInterface {
+            interface_class: ClassCode::CDCData as u8,
+            endpoints: crate::cdc::UsbCdcAcmHandler::endpoints(),
+            ..vendor.clone()
+        };
+        let mut device = UsbDevice::new(0)
+            .with_descriptor_mode(mode.clone())
+            .with_mode_interfaces(1, vec![vendor.clone(), serial]);
+        device.interfaces.push(vendor);
+        let ctx = ConnectionContext::default();
+        let get_config_desc = || {
+            device.handle_urb(
+                &ctx,
+                device.ep0_in,
+                None,
+                [0x80, 0x06, 0x00, 0x02, 0x00, 0x00, 0xFF, 0x00],
+                &[],
+            )
+        };
+
+        let desc = get_config_desc().await.unwrap();
+        // bNumInterfaces
+        assert_eq!(desc[4], 1);
+        assert!(device.find_ep(0x82).is_none());
+
+        // vendor request to interface 0 switches to mode 1
+        device
+            .handle_urb(
+                &ctx,
+                device.ep0_out,
+                None,
+                [0x41, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00],
+                &[],
+            )
+            .await
+            .unwrap();
+        assert_eq!(mode.get(), 1);
+        let desc = get_config_desc().await.unwrap();
+        assert_eq!(desc[4], 2);
+        // the second interface descriptor follows the first one
+        assert_eq!(desc[9 + 9 + 5], ClassCode::CDCData as u8);
+        let (_, intf) = device.find_ep(0x82).unwrap();
+        assert_eq!(intf.unwrap().interface_class, ClassCode::CDCData as u8);
+
+        // and back, modes without interfaces present the default ones
+        mode.set(2);
+        assert_eq!(get_config_desc().await.unwrap()[4], 1);
+    }
+
+    /// Answers every request with its own id
+    struct IdHandler(u8);
+
+    impl UsbInterfaceHandler for IdHandler {
+        fn get_class_specific_descriptor(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn handle_urb(
+            &mut self,
+            _interface: &UsbInterface,
+            _ep: UsbEndpoint,
+            _setup: Option<SetupPacket>,
+            _req: &[u8],
+        ) -> Result<Vec<u8>> {
+            Ok(vec![self.0])
+        }
+
+        fn as_any(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    /// Answers after a delay with the request reversed
+    struct SlowEchoHandler;
+
+    impl AsyncUsbInterfaceHandler for SlowEchoHandler {
+        fn get_class_specific_descriptor(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn handle_urb(
+            &mut self,
+            _ctx: ConnectionContext,
+            _ep: UsbEndpoint,
+            _setup: Option<SetupPacket>,
+            req: Vec<u8>,
+        ) -> BoxFuture<'_, Result<Vec<u8>>> {
+            Box::pin(async move {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                Ok(req.into_iter().rev().collect())
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn async_handler() {
+        tokio::time::pause();
+        let ep = UsbEndpoint {
+            address: 0x01,
+            attributes: EndpointAttributes::Bulk as u8,
+            max_packet_size: 512,
+            interval: 0,
+        };
+        let device = UsbDevice::new(0).with_interface(
+            ClassCode::VendorSpecific as u8,
+            0x00,
+            0x00,
+            "Async",
+            vec![ep],
+            Arc::new(Mutex::new(
+                Box::new(AsyncHandler::new(SlowEchoHandler)) as Box<dyn UsbInterfaceHandler + Send>
+            )),
+        );
+        let ctx = ConnectionContext::default();
+        let intf = &device.interfaces[0];
+
+        // the interface is not locked while the handler awaits
+        let start = tokio::time::Instant::now();
+        let (resp, unlocked) = tokio::join!(
+            device.handle_urb(&ctx, ep, Some(intf), [0; 8], &[1, 2, 3]),
+            async {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                intf.handler.try_lock().is_ok()
+            }
+        );
+        assert_eq!(resp.unwrap(), [3, 2, 1]);
+        assert!(unlocked);
+        assert!(start.elapsed() >= Duration::from_millis(10));
+    }
+
+    #[tokio::test]
+    async fn route_class_request_by_windex() {
+        let mut device = UsbDevice::new(0);
+        for id in 0..2u8 {
+            device = device.with_interface(
+                ClassCode::VendorSpecific as u8,
+                0x00,
+                0x00,
+                "Function",
+                vec![UsbEndpoint {
+                    address: 0x81 + id,
+                    attributes: EndpointAttributes::Interrupt as u8,
+                    max_packet_size: 8,
+                    interval: 10,
+                }],
+                Arc::new(Mutex::new(
+                    Box::new(IdHandler(id)) as Box<dyn UsbInterfaceHandler + Send>
+                )),
+            );
+        }
+        let ctx = ConnectionContext::default();
+        let class_request = |request_type: u8, index: u16| {
+            device.handle_urb(
+                &ctx,
+                device.ep0_in,
+                None,
+                [
+                    request_type,
+                    0x81,
+                    0x00,
+                    0x00,
+                    index as u8,
+                    (index >> 8) as u8,
+                    0x01,
+                    0x00,
+                ],
+                &[],
+            )
+        };
+
+        // interface 1, entity id 2 in the high byte
+        assert_eq!(class_request(0xA1, 0x0201).await.unwrap(), [1]);
+        assert_eq!(class_request(0xA1, 0x0000).await.unwrap(), [0]);
+        // endpoint 0x82 belongs to interface 1
+        assert_eq!(class_request(0xA2, 0x0082).await.unwrap(), [1]);
+        // no such interface
+        let err = class_request(0xA1, 0x0005).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+
+        // standard GET_STATUS is answered by the device, not the handlers
+        let get_status = |request_type: u8, index: u8| {
+            device.handle_urb(
+                &ctx,
+                device.ep0_in,
+                None,
+                [request_type, 0x00, 0x00, 0x00, index, 0x00, 0x02, 0x00],
+                &[],
+            )
+        };
+        assert_eq!(get_status(0x81, 1).await.unwrap(), [0x00, 0x00]);
+        assert_eq!(get_status(0x82, 0x82).await.unwrap(), [0x00, 0x00]);
+        assert_eq!(get_status(0x82, 0x80).await.unwrap(), [0x00, 0x00]);
+        for (request_type, index) in [(0x81, 5), (0x82, 0x85)] {
+            let err = get_status(request_type, index).await.unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+        }
+    }
+
+    #[tokio::test]
+    async fn custom_string_indices() {
+        let mut device = UsbDevice::new(0).with_interface(
+            ClassCode::VendorSpecific as u8,
+            0x00,
+            0x00,
+            "Function",
+            vec![],
+            Arc::new(Mutex::new(
+                Box::new(IdHandler(0)) as Box<dyn UsbInterfaceHandler + Send>
+            )),
+        );
+        device.add_string(0x42, "Custom");
+        device.add_string(0x43, "Vendor Config");
+        let device = device
+            .with_interface_string(0, 0x42)
+            .with_configuration_string(0x43);
+
+        let config = device.configuration_descriptor();
+        // iConfiguration and iInterface
+        assert_eq!(config[6], 0x43);
+        assert_eq!(config[9 + 8], 0x42);
+
+        let ctx = ConnectionContext::default();
+        let get_string = |index: u8| {
+            device.handle_urb(
+                &ctx,
+                device.ep0_in,
+                None,
+                [0x80, 0x06, index, 0x03, 0x09, 0x04, 0xFF, 0x00],
+                &[],
+            )
+        };
+        let desc = get_string(0x42).await.unwrap();
+        assert_eq!(
+            desc,
+            [14, 0x03, b'C', 0, b'u', 0, b's', 0, b't', 0, b'o', 0, b'm', 0]
+        );
+        // unknown strings stall
+        let err = get_string(0x44).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+    }
+
+    #[tokio::test]
+    async fn device_identity() {
+        let device = UsbDevice::new(0)
+            .with_vendor_id(0x1234)
+            .with_product_id(0x5678)
+            .with_device_version(0x0102)
+            .with_manufacturer("Acme")
+            .with_product("Widget")
+            .with_serial("0001");
+
+        let desc = get_descriptor(&device, DescriptorType::Device).await;
+        // idVendor, idProduct, bcdDevice
+        assert_eq!(desc[8..14], [0x34, 0x12, 0x78, 0x56, 0x02, 0x01]);
+
+        let ctx = ConnectionContext::default();
+        let get_string = |index: u8| {
+            device.handle_urb(
+                &ctx,
+                device.ep0_in,
+                None,
+                [0x80, 0x06, index, 0x03, 0x09, 0x04, 0xFF, 0x00],
+                &[],
+            )
+        };
+        // language ids: en-US
+        assert_eq!(get_string(0).await.unwrap(), [4, 0x03, 0x09, 0x04]);
+        let strings = [(14, "Acme"), (15, "Widget"), (16, "0001")];
+        for (offset, expected) in strings.iter() {
+            let string = get_string(desc[*offset]).await.unwrap();
+            let utf16: Vec<u16> = string[2..]
+                .chunks(2)
+                .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                .collect();
+            assert_eq!(String::from_utf16(&utf16).unwrap(), *expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn string_languages() {
+        let device = UsbDevice::new(0)
+            .with_manufacturer("Acme")
+            .with_product("Widget");
+        let product = device.string_product;
+        let device = device.with_string_translation(0x0407, product, "Ding");
+        let get_string = |device: &UsbDevice, index: u8, langid: u16| {
+            let [low, high] = langid.to_le_bytes();
+            let setup = [0x80, 0x06, index, 0x03, low, high, 0xFF, 0x00];
+            let ctx = ConnectionContext::default();
+            let device = device.clone();
+            async move {
+                let desc = device
+                    .handle_urb(&ctx, device.ep0_in, None, setup, &[])
+                    .await?;
+                let utf16: Vec<u16> = desc[2..]
+                    .chunks(2)
+                    .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                    .collect();
+                Ok::<_, std::io::Error>(String::from_utf16(&utf16).unwrap())
+            }
+        };
+
+        // en-US and de-DE are listed
+        let langids = device
+            .handle_urb(
+                &ConnectionContext::default(),
+                device.ep0_in,
+                None,
+                [0x80, 0x06, 0x00, 0x03, 0x00, 0x00, 0xFF, 0x00],
+                &[],
+            )
+            .await
+            .unwrap();
+        assert_eq!(langids, [6, 0x03, 0x09, 0x04, 0x07, 0x04]);
+        assert_eq!(
+            get_string(&device, product, 0x0409).await.unwrap(),
+            "Widget"
+        );
+        assert_eq!(get_string(&device, product, 0x0407).await.unwrap(), "Ding");
+        // untranslated strings are in the primary language
+        let manufacturer = device.string_manufacturer;
+        assert_eq!(
+            get_string(&device, manufacturer, 0x0407).await.unwrap(),
+            "Acme"
+        );
+
+        // fr-FR is not listed: primary language by default, or STALL
+        assert_eq!(
+            get_string(&device, product, 0x040C).await.unwrap(),
+            "Widget"
+        );
+        let device = device.with_string_language_fallback(StringLanguageFallback::Stall);
+        let err = get_string(&device, product, 0x040C).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+        assert_eq!(get_string(&device, product, 0x0407).await.unwrap(), "Ding");
+
+        // a device made for another market, translated to en-US
+        let device = UsbDevice::new(0)
+            .with_primary_language(0x0407)
+            .with_product("Ding");
+        let product = device.string_product;
+        let device = device.with_string_translation(0x0409, product, "Widget");
+        let langids = device
+            .handle_urb(
+                &ConnectionContext::default(),
+                device.ep0_in,
+                None,
+                [0x80, 0x06, 0x00, 0x03, 0x00, 0x00, 0xFF, 0x00],
+                &[],
+            )
+            .await
+            .unwrap();
+        assert_eq!(langids, [6, 0x03, 0x07, 0x04, 0x09, 0x04]);
+        assert_eq!(get_string(&device, product, 0x0407).await.unwrap(), "Ding");
+        assert_eq!(
+            get_string(&device, product, 0x0409).await.unwrap(),
+            "Widget"
+        );
+        assert_eq!(get_string(&device, product, 0x040C).await.unwrap(), "Ding");
+    }
+
+    #[test]
+    fn alternate_settings_count_once() {
+        let handler = || {
+            Arc::new(Mutex::new(
+                Box::new(IdHandler(0)) as Box<dyn UsbInterfaceHandler + Send>
+            ))
+        };
+        let endpoint = |max_packet_size| UsbEndpoint {
+            address: 0x81,
+            attributes: EndpointAttributes::Isochronous as u8,
+            max_packet_size,
+            interval: 1,
+        };
+        let device = UsbDevice::new(0)
+            .with_interface(
+                ClassCode::VendorSpecific as u8,
+                0x00,
+                0x00,
+                "Idle",
+                vec![],
+                handler(),
+            )
+            .with_alternate_setting(
+                ClassCode::VendorSpecific as u8,
+                0x00,
+                0x00,
+                "Low",
+                vec![endpoint(256)],
+                handler(),
+            )
+            .with_alternate_setting(
+                ClassCode::VendorSpecific as u8,
+                0x00,
+                0x00,
+                "High",
+                vec![endpoint(1024)],
+                handler(),
+            )
+            .with_interface(
+                ClassCode::VendorSpecific as u8,
+                0x00,
+                0x00,
+                "Other",
+                vec![],
+                handler(),
+            );
+        assert_eq!(device.num_interfaces(), 2);
+
+        let config = device.configuration_descriptor();
+        verify_descriptor(&config);
+        // bNumInterfaces
+        assert_eq!(config[4], 2);
+        assert_eq!(count_interfaces(&config), 2);
+        // second alternate setting of interface 0
+        let alt = &config[9 + 9 + 7 + 9..];
+        assert_eq!(alt[1], DescriptorType::Interface as u8);
+        assert_eq!(alt[2], 0);
+        assert_eq!(alt[3], 2);
+        // the next interface is numbered 1
+        let other = &alt[9 + 7..];
+        assert_eq!(other[2], 1);
+        assert_eq!(other[3], 0);
+
+        assert!(UsbDevice::new(0)
+            .try_with_alternate_setting(0, 0, 0, "", vec![], handler())
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn find_ep_in_selected_setting() {
+        let handler = |id| {
+            Arc::new(Mutex::new(
+                Box::new(IdHandler(id)) as Box<dyn UsbInterfaceHandler + Send>
+            ))
+        };
+        let endpoint = |max_packet_size| UsbEndpoint {
+            address: 0x81,
+            attributes: EndpointAttributes::Isochronous as u8,
+            max_packet_size,
+            interval: 1,
+        };
+        let device = UsbDevice::new(0)
+            .with_interface(0xFF, 0, 0, "Zero bandwidth", vec![], handler(0))
+            .with_alternate_setting(0xFF, 0, 0, "Low", vec![endpoint(256)], handler(1))
+            .with_alternate_setting(0xFF, 0, 0, "High", vec![endpoint(1024)], handler(2));
+        let ctx = ConnectionContext::default();
+        // SET_CONFIGURATION(1)
+        testing::control(
+            &device,
+            &ctx,
+            [0x00, 0x09, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00],
+            &[],
+        )
+        .await
+        .unwrap();
+
+        // alt 0 has no endpoints, the first setting using the address answers
+        assert!(!device.is_endpoint_enabled(0x81));
+        let (ep, intf) = device.find_ep(0x81).unwrap();
+        assert_eq!(
+            (ep.max_packet_size, intf.unwrap().alternate_setting),
+            (256, 1)
+        );
+
+        for alt in [2u8, 1, 2] {
+            // SET_INTERFACE(0, alt)
+            testing::control(
+                &device,
+                &ctx,
+                [0x01, 0x0B, alt, 0x00, 0x00, 0x00, 0x00, 0x00],
+                &[],
+            )
+            .await
+            .unwrap();
+            // GET_INTERFACE(0)
+            let reply = testing::control(
+                &device,
+                &ctx,
+                [0x81, 0x0A, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00],
+                &[],
+            )
+            .await;
+            assert_eq!(reply.unwrap(), [alt]);
+            assert!(device.is_endpoint_enabled(0x81));
+            let (ep, intf) = device.find_ep(0x81).unwrap();
+            let intf = intf.unwrap();
+            assert_eq!(intf.alternate_setting, alt);
+            assert_eq!(ep.max_packet_size, 256 << (2 * (alt - 1)));
+            let reply = device
+                .handle_urb(&ctx, ep, Some(intf), [0; 8], &[])
+                .await
+                .unwrap();
+            assert_eq!(reply, [alt]);
+        }
+
+        // back to zero bandwidth
+        testing::control(
+            &device,
+            &ctx,
+            [0x01, 0x0B, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+            &[],
+        )
+        .await
+        .unwrap();
+        assert!(!device.is_endpoint_enabled(0x81));
+    }
+
+    #[tokio::test]
+    async fn power_status() {
+        assert_eq!(UsbDevice::new(0).configuration_descriptor()[7], 0x80);
+        let device = UsbDevice::new(0)
+            .with_self_powered(true)
+            .with_remote_wakeup(true);
+        assert_eq!(device.configuration_descriptor()[7], 0xE0);
+
+        let ctx = ConnectionContext::default();
+        let get_status = [0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00];
+        let status = || device.handle_urb(&ctx, device.ep0_in, None, get_status, &[]);
+        assert_eq!(status().await.unwrap(), [0x01, 0x00]);
+        // SET_FEATURE(DEVICE_REMOTE_WAKEUP) then CLEAR_FEATURE
+        for (request, expected) in [(0x03, [0x03, 0x00]), (0x01, [0x01, 0x00])] {
+            let setup = [0x00, request, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00];
+            device
+                .handle_urb(&ctx, device.ep0_out, None, setup, &[])
+                .await
+                .unwrap();
+            assert_eq!(status().await.unwrap(), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn runtime_state_follows_requests() {
+        let handler = || {
+            Arc::new(Mutex::new(
+                Box::new(IdHandler(0)) as Box<dyn UsbInterfaceHandler + Send>
+            ))
+        };
+        let ep = UsbEndpoint {
+            address: 0x81,
+            attributes: EndpointAttributes::Bulk as u8,
+            max_packet_size: 512,
+            interval: 0,
+        };
+        let device = UsbDevice::new(0)
+            .with_interface(0xFF, 0, 0, "", vec![], handler())
+            .with_interface(0xFF, 0, 0, "Idle", vec![], handler())
+            .with_alternate_setting(0xFF, 0, 0, "Streaming", vec![ep], handler());
+        let ctx = ConnectionContext::default();
+        assert_eq!(
+            device.runtime_state(),
+            UsbRuntimeState {
+                alternate_settings: vec![0, 0],
+                ..Default::default()
+            }
+        );
+
+        // SET_CONFIGURATION(1)
+        testing::control(
+            &device,
+            &ctx,
+            [0x00, 0x09, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00],
+            &[],
+        )
+        .await
+        .unwrap();
+        // SET_INTERFACE(1, 1)
+        testing::control(
+            &device,
+            &ctx,
+            [0x01, 0x0B, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00],
+            &[],
+        )
+        .await
+        .unwrap();
+        // SET_FEATURE(ENDPOINT_HALT) on 0x81
+        testing::control(
+            &device,
+            &ctx,
+            [0x02, 0x03, 0x00, 0x00, 0x81, 0x00, 0x00, 0x00],
+            &[],
+        )
+        .await
+        .unwrap();
+        // SET_FEATURE(DEVICE_REMOTE_WAKEUP)
+        testing::control(
+            &device,
+            &ctx,
+            [0x00, 0x03, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00],
+            &[],
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            device.runtime_state(),
+            UsbRuntimeState {
+                configuration: 1,
+                alternate_settings: vec![0, 1],
+                halted_endpoints: vec![0x81],
+                remote_wakeup: true,
+            }
+        );
+
+        // GET_INTERFACE(1) and GET_STATUS of the device and the endpoint
+        let get_interface = [0x81, 0x0A, 0x00, 0x00, 0x01, 0x00, 0x01, 0x00];
+        assert_eq!(
+            testing::control(&device, &ctx, get_interface, &[])
+                .await
+                .unwrap(),
+            [1]
+        );
+        let get_status = [0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00];
+        assert_eq!(
+            testing::control(&device, &ctx, get_status, &[])
+                .await
+                .unwrap(),
+            [0x02, 0x00]
+        );
+        let get_status = [0x82, 0x00, 0x00, 0x00, 0x81, 0x00, 0x02, 0x00];
+        assert_eq!(
+            testing::control(&device, &ctx, get_status, &[])
+                .await
+                .unwrap(),
+            [0x01, 0x00]
+        );
+
+        // a halted endpoint stalls until the halt is cleared
+        let intf = &device.interfaces[2];
+        let err = device
+            .handle_urb(&ctx, ep, Some(intf), [0; 8], &[])
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+        testing::control(
+            &device,
+            &ctx,
+            [0x02, 0x01, 0x00, 0x00, 0x81, 0x00, 0x00, 0x00],
+            &[],
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            device
+                .handle_urb(&ctx, ep, Some(intf), [0; 8], &[])
+                .await
+                .unwrap(),
+            [0]
+        );
+
+        // no such alternate setting
+        let err = testing::control(
+            &device,
+            &ctx,
+            [0x01, 0x0B, 0x02, 0x00, 0x01, 0x00, 0x00, 0x00],
+            &[],
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+
+        // SET_CONFIGURATION resets alternate settings and halts
+        testing::control(
+            &device,
+            &ctx,
+            [0x00, 0x09, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00],
+            &[],
+        )
+        .await
+        .unwrap();
+        assert_eq!(device.runtime_state().alternate_settings, [0, 0]);
+    }
+
+    #[tokio::test]
+    async fn control_hook_fires_on_match() {
+        let fired = Arc::new(Mutex::new(vec![]));
+        let recorded = fired.clone();
+        let device = UsbDevice::new(0).with_control_hook(
+            ControlRequestMatcher::new()
+                .with_request_type(0x00)
+                .with_request(StandardRequest::SetConfiguration as u8)
+                .with_value(1),
+            move |setup, _| recorded.lock().unwrap().push(setup.value),
+        );
+        let ctx = ConnectionContext::default();
+
+        // GET_CONFIGURATION, SET_CONFIGURATION(0) and SET_CONFIGURATION(1)
+        testing::control(
+            &device,
+            &ctx,
+            [0x80, 0x08, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00],
+            &[],
+        )
+        .await
+        .unwrap();
+        testing::control(
+            &device,
+            &ctx,
+            [0x00, 0x09, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+            &[],
+        )
+        .await
+        .unwrap();
+        assert!(fired.lock().unwrap().is_empty());
+        testing::control(
+            &device,
+            &ctx,
+            [0x00, 0x09, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00],
+            &[],
+        )
+        .await
+        .unwrap();
+        assert_eq!(*fired.lock().unwrap(), [1]);
+    }
+
+    #[tokio::test]
+    async fn set_descriptor() {
+        // SET_DESCRIPTOR(String, 4), language en-US
+        let setup = [0x00, 0x07, 0x04, 0x03, 0x09, 0x04, 0x04, 0x00];
+        let data = [0x04, 0x03, b'A', 0x00];
+        let ctx = ConnectionContext::default();
+
+        // stalled by default
+        let device = UsbDevice::new(0);
+        let err = device
+            .handle_urb(&ctx, device.ep0_out, None, setup, &data)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+
+        let written = Arc::new(Mutex::new(vec![]));
+        let recorded = written.clone();
+        let device = UsbDevice::new(0).with_set_descriptor(move |desc_type, index, lang, desc| {
+            recorded
+                .lock()
+                .unwrap()
+                .push((desc_type, index, lang, desc.to_vec()));
+            Ok(())
+        });
+        device
+            .handle_urb(&ctx, device.ep0_out, None, setup, &data)
+            .await
+            .unwrap();
+        assert_eq!(
+            *written.lock().unwrap(),
+            [(0x03, 0x04, 0x0409, data.to_vec())]
+        );
+    }
+
+    #[tokio::test]
+    async fn bos_lpm_besl() {
+        let device = UsbDevice::new(0).with_lpm(Some(0x4), Some(0xA));
+        let desc = get_descriptor(&device, DescriptorType::BOS).await;
+        verify_descriptor(&desc);
+        // wTotalLength and bNumCapabilities
+        assert_eq!(desc[2..5], [0x0C, 0x00, 0x01]);
+        assert_eq!(desc[5..8], [0x07, 0x10, 0x02]);
+        // LPM, BESL, both BESL values valid, baseline 4 and deep 10
+        assert_eq!(desc[8..12], [0x1E, 0xA4, 0x00, 0x00]);
+
+        let device = UsbDevice::new(0).with_lpm(None, None);
+        let desc = get_descriptor(&device, DescriptorType::BOS).await;
+        assert_eq!(desc[8..12], [0x06, 0x00, 0x00, 0x00]);
+    }
+
+    #[tokio::test]
+    async fn non_iso_urb_to_iso_endpoint() {
+        // an async iso endpoint, bmAttributes 0x05 like the audio streams
+        let ep = UsbEndpoint::new(
+            0x81,
+            EndpointAttributes::Isochronous,
+            SyncType::Async,
+            UsageType::Data,
+            192,
+            1,
+        );
+        assert_eq!(ep.attributes, 0x05);
+        let device = UsbDevice::new(0).with_interface(
+            ClassCode::Audio as u8,
+            0x02,
+            0x00,
+            "Iso",
+            vec![ep],
+            FnHandler::new(|_, _, _, _| Ok(vec![0x42])).into_handler(),
+        );
+        let server = Arc::new(UsbIpServer::new_simulated(vec![device]));
+        let urbs = [testing::CmdSubmit {
+            seq_num: 1,
+            endpoint: 0x81,
+            transfer_flags: 0,
+            transfer_buffer_length: 192,
+            interval: 0,
+            setup: [0; 8],
+            data: vec![],
+        }];
+        let rets = testing::round_trip(server, "0", &urbs).await.unwrap();
+        assert_eq!(rets[0].status, 0);
+        assert_eq!(rets[0].data, [0x42]);
+    }
 }
@@ -0,0 +1,573 @@
+//! Implement DFU(Device Firmware Upgrade) device
+//!
+//! The device starts in runtime mode with a DFU runtime interface. On
+//! DFU_DETACH it re-enumerates in DFU mode, see [Reenumeration], where it
+//! accepts a firmware download into memory.
+use super::*;
+
+// reference:
+// DFU 1.1: https://www.usb.org/sites/default/files/DFU_1.1.pdf
+
+/// Sub class code for DFU, with [ClassCode::ApplicationSpecific]
+pub const DFU_SUBCLASS: u8 = 0x01;
+/// Protocol code of the DFU interface in runtime mode
+pub const DFU_PROTOCOL_RUNTIME: u8 = 0x01;
+/// Protocol code of the DFU interface in DFU mode
+pub const DFU_PROTOCOL_DFU_MODE: u8 = 0x02;
+
+/// Default wTransferSize advertised in the functional descriptor
+pub const DFU_TRANSFER_SIZE: u16 = 1024;
+
+/// bitCanDnload of bmAttributes
+pub const DFU_CAN_DNLOAD: u8 = 0x01;
+/// bitCanUpload of bmAttributes
+pub const DFU_CAN_UPLOAD: u8 = 0x02;
+/// bitManifestationTolerant of bmAttributes
+pub const DFU_MANIFESTATION_TOLERANT: u8 = 0x04;
+/// bitWillDetach of bmAttributes
+pub const DFU_WILL_DETACH: u8 = 0x08;
+
+/// A list of defined DFU class specific requests
+#[derive(Copy, Clone, Debug, FromPrimitive)]
+pub enum DfuRequest {
+    Detach = 0,
+    Dnload,
+    Upload,
+    GetStatus,
+    ClrStatus,
+    GetState,
+    Abort,
+}
+
+/// A list of defined DFU device states
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DfuState {
+    AppIdle = 0,
+    AppDetach,
+    DfuIdle,
+    DfuDnloadSync,
+    DfuDnbusy,
+    DfuDnloadIdle,
+    DfuManifestSync,
+    DfuManifest,
+    DfuManifestWaitReset,
+    DfuUploadIdle,
+    DfuError,
+}
+
+/// A list of defined bStatus values of DFU_GETSTATUS
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DfuStatus {
+    Ok = 0,
+    ErrTarget,
+    ErrFile,
+    ErrWrite,
+    ErrErase,
+    ErrCheckErased,
+    ErrProg,
+    ErrVerify,
+    ErrAddress,
+    ErrNotDone,
+    ErrFirmware,
+    ErrVendor,
+    ErrUsbr,
+    ErrPor,
+    ErrUnknown,
+    ErrStalledPkt,
+}
+
+/// The DFU functional descriptor, the same in both modes
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct DfuFunctional {
+    /// bmAttributes, of `DFU_CAN_DNLOAD` and the like
+    pub attributes: u8,
+    /// wDetachTimeOut in milliseconds
+    pub detach_timeout: u16,
+    /// wTransferSize, the most bytes of a DFU_DNLOAD or DFU_UPLOAD
+    pub transfer_size: u16,
+}
+
+impl Default for DfuFunctional {
+    fn default() -> Self {
+        Self {
+            attributes: DFU_WILL_DETACH
+                | DFU_MANIFESTATION_TOLERANT
+                | DFU_CAN_UPLOAD
+                | DFU_CAN_DNLOAD,
+            detach_timeout: 255,
+            transfer_size: DFU_TRANSFER_SIZE,
+        }
+    }
+}
+
+impl DfuFunctional {
+    pub fn descriptor(&self) -> Vec<u8> {
+        vec![
+            0x09,            // bLength
+            0x21,            // bDescriptorType: DFU FUNCTIONAL
+            self.attributes, // bmAttributes
+            self.detach_timeout as u8,
+            (self.detach_timeout >> 8) as u8, // wDetachTimeOut
+            self.transfer_size as u8,
+            (self.transfer_size >> 8) as u8, // wTransferSize
+            0x10,
+            0x01, // bcdDFUVersion: 1.1
+        ]
+    }
+}
+
+/// Response to DFU_GETSTATUS
+fn status(status: DfuStatus, poll_timeout: u32, state: DfuState) -> Vec<u8> {
+    vec![
+        status as u8, // bStatus
+        poll_timeout as u8,
+        (poll_timeout >> 8) as u8,
+        (poll_timeout >> 16) as u8, // bwPollTimeout
+        state as u8,                // bState
+        0x00,                       // iString
+    ]
+}
+
+fn stall(msg: &str) -> Result<Vec<u8>> {
+    Err(std::io::Error::new(std::io::ErrorKind::BrokenPipe, msg))
+}
+
+/// A handler of the DFU interface in runtime mode
+pub struct UsbDfuRuntimeHandler {
+    reenumeration: Reenumeration,
+    dfu_device: UsbDevice,
+    functional: DfuFunctional,
+    state: DfuState,
+}
+
+impl UsbDfuRuntimeHandler {
+    /// Switch to `dfu_device` through `reenumeration` on DFU_DETACH
+    pub fn new(reenumeration: Reenumeration, dfu_device: UsbDevice) -> Self {
+        Self {
+            reenumeration,
+            dfu_device,
+            functional: DfuFunctional::default(),
+            state: DfuState::AppIdle,
+        }
+    }
+
+    /// Advertise `functional` instead of the default functional descriptor
+    pub fn with_functional(mut self, functional: DfuFunctional) -> Self {
+        self.functional = functional;
+        self
+    }
+
+    pub fn state(&self) -> DfuState {
+        self.state
+    }
+}
+
+impl UsbInterfaceHandler for UsbDfuRuntimeHandler {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        _ep: UsbEndpoint,
+        setup: Option<SetupPacket>,
+        _req: &[u8],
+    ) -> Result<Vec<u8>> {
+        let setup = match setup {
+            Some(setup) => setup,
+            None => return stall("DFU interface has no endpoints"),
+        };
+        match FromPrimitive::from_u8(setup.request) {
+            Some(DfuRequest::Detach) if self.state == DfuState::AppIdle => {
+                info!("DFU detach, re-enumerating in DFU mode");
+                self.state = DfuState::AppDetach;
+                self.reenumeration.trigger(self.dfu_device.clone());
+                Ok(vec![])
+            }
+            Some(DfuRequest::GetStatus) => Ok(status(DfuStatus::Ok, 0, self.state)),
+            Some(DfuRequest::GetState) => Ok(vec![self.state as u8]),
+            _ => {
+                warn!("DFU unsupported request in runtime mode {:x?}", setup);
+                stall("unsupported DFU request")
+            }
+        }
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        self.functional.descriptor()
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// A handler of the DFU interface in DFU mode, storing the downloaded firmware
+///
+/// Requests move it through the states of the DFU 1.1 state machine. A
+/// request not allowed in the current state is stalled and enters dfuERROR
+/// with errSTALLEDPKT, which only DFU_CLRSTATUS leaves. A block is written
+/// as soon as it is received: with a `poll_timeout`, DFU_GETSTATUS reports
+/// dfuDNBUSY first and the next request finds the block done.
+#[derive(Clone)]
+pub struct UsbDfuModeHandler {
+    /// Blocks downloaded since the last DFU_DNLOAD of block 0
+    pub firmware: Vec<u8>,
+    /// bwPollTimeout in milliseconds reported while a block or the
+    /// manifestation is in progress, at most 24 bits
+    pub poll_timeout: u32,
+    pub functional: DfuFunctional,
+    state: DfuState,
+    status: DfuStatus,
+}
+
+impl UsbDfuModeHandler {
+    pub fn new() -> Self {
+        Self {
+            firmware: vec![],
+            poll_timeout: 0,
+            functional: DfuFunctional::default(),
+            state: DfuState::DfuIdle,
+            status: DfuStatus::Ok,
+        }
+    }
+
+    /// Advertise `functional` instead of the default functional descriptor
+    ///
+    /// DFU_DNLOAD and DFU_UPLOAD are only accepted if its attributes allow
+    /// them, with at most wTransferSize bytes.
+    pub fn with_functional(mut self, functional: DfuFunctional) -> Self {
+        self.functional = functional;
+        self
+    }
+
+    /// Report `poll_timeout` milliseconds in DFU_GETSTATUS while busy
+    pub fn with_poll_timeout(mut self, poll_timeout: u32) -> Self {
+        assert!(poll_timeout <= 0xFF_FFFF, "bwPollTimeout has 24 bits");
+        self.poll_timeout = poll_timeout;
+        self
+    }
+
+    pub fn state(&self) -> DfuState {
+        self.state
+    }
+
+    pub fn status(&self) -> DfuStatus {
+        self.status
+    }
+
+    /// Fail with `status`, e.g. to test how the host handles a bad
+    /// firmware: the device enters dfuERROR until DFU_CLRSTATUS
+    pub fn fail(&mut self, status: DfuStatus) {
+        self.status = status;
+        self.state = DfuState::DfuError;
+    }
+
+    /// Stall a request not allowed in the current state
+    fn reject(&mut self, setup: &SetupPacket) -> Result<Vec<u8>> {
+        warn!("DFU request {:x?} not allowed in {:?}", setup, self.state);
+        if self.state != DfuState::DfuError && self.state != DfuState::DfuManifestWaitReset {
+            self.fail(DfuStatus::ErrStalledPkt);
+        }
+        stall("DFU request not allowed")
+    }
+}
+
+impl Default for UsbDfuModeHandler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl UsbInterfaceHandler for UsbDfuModeHandler {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        _ep: UsbEndpoint,
+        setup: Option<SetupPacket>,
+        req: &[u8],
+    ) -> Result<Vec<u8>> {
+        use DfuState::*;
+        let setup = match setup {
+            Some(setup) => setup,
+            None => return stall("DFU interface has no endpoints"),
+        };
+        let was_busy = self.state == DfuDnbusy;
+        if was_busy {
+            // requests only come after bwPollTimeout, by then the block is done
+            self.state = DfuDnloadSync;
+        }
+        let request = FromPrimitive::from_u8(setup.request);
+        match (request, self.state) {
+            (Some(DfuRequest::GetStatus), _) => {
+                let reported = match self.state {
+                    DfuDnloadSync if self.poll_timeout > 0 && !was_busy => DfuDnbusy,
+                    DfuDnloadSync => DfuDnloadIdle,
+                    DfuManifestSync => {
+                        info!("DFU downloaded {} bytes", self.firmware.len());
+                        if self.functional.attributes & DFU_MANIFESTATION_TOLERANT != 0 {
+                            DfuIdle
+                        } else {
+                            DfuManifestWaitReset
+                        }
+                    }
+                    state => state,
+                };
+                self.state = reported;
+                let poll_timeout = match reported {
+                    DfuDnbusy | DfuManifest => self.poll_timeout,
+                    _ => 0,
+                };
+                Ok(status(self.status, poll_timeout, reported))
+            }
+            (Some(DfuRequest::GetState), _) => Ok(vec![self.state as u8]),
+            (_, DfuManifestWaitReset) => self.reject(&setup),
+            (Some(DfuRequest::ClrStatus), DfuError) => {
+                self.status = DfuStatus::Ok;
+                self.state = DfuIdle;
+                Ok(vec![])
+            }
+            (_, DfuError) => self.reject(&setup),
+            (Some(DfuRequest::Abort), DfuIdle)
+            | (Some(DfuRequest::Abort), DfuDnloadIdle)
+            | (Some(DfuRequest::Abort), DfuUploadIdle) => {
+                self.state = DfuIdle;
+                Ok(vec![])
+            }
+            (Some(DfuRequest::Dnload), DfuIdle) | (Some(DfuRequest::Dnload), DfuDnloadIdle)
+                if self.functional.attributes & DFU_CAN_DNLOAD != 0
+                    && setup.length <= self.functional.transfer_size =>
+            {
+                if req.is_empty() {
+                    // end of download, not allowed before any block
+                    if self.state == DfuIdle {
+                        return self.reject(&setup);
+                    }
+                    self.state = DfuManifestSync;
+                } else {
+                    if setup.value == 0 {
+                        self.firmware.clear();
+                    }
+                    self.firmware.extend_from_slice(req);
+                    self.state = DfuDnloadSync;
+                }
+                Ok(vec![])
+            }
+            (Some(DfuRequest::Upload), DfuIdle) | (Some(DfuRequest::Upload), DfuUploadIdle)
+                if self.functional.attributes & DFU_CAN_UPLOAD != 0
+                    && setup.length <= self.functional.transfer_size =>
+            {
+                let start = setup.value as usize * setup.length as usize;
+                let end = (start + setup.length as usize).min(self.firmware.len());
+                let block = self.firmware[start.min(end)..end].to_vec();
+                // a short block ends the upload
+                self.state = if block.len() < setup.length as usize {
+                    DfuIdle
+                } else {
+                    DfuUploadIdle
+                };
+                Ok(block)
+            }
+            _ => self.reject(&setup),
+        }
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        self.functional.descriptor()
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// Add a DFU runtime interface to `device`
+///
+/// On DFU_DETACH the device is replaced by a DFU mode device with the same
+/// vendor and product id, exposing a single [UsbDfuModeHandler] interface.
+pub fn with_dfu_runtime(device: UsbDevice) -> UsbDevice {
+    with_dfu(device, UsbDfuModeHandler::new()).0
+}
+
+/// Add a DFU runtime interface to `device`, switching to `handler` in DFU mode
+///
+/// Like [with_dfu_runtime], both modes advertise the functional descriptor
+/// of `handler`. Returns the device and the DFU mode handler, to read the
+/// downloaded firmware from.
+pub fn with_dfu(
+    device: UsbDevice,
+    handler: UsbDfuModeHandler,
+) -> (UsbDevice, Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>>) {
+    let functional = handler.functional;
+    let handler = Arc::new(Mutex::new(
+        Box::new(handler) as Box<dyn UsbInterfaceHandler + Send>
+    ));
+    let mut dfu_device = UsbDevice::new(0).with_interface(
+        ClassCode::ApplicationSpecific as u8,
+        DFU_SUBCLASS,
+        DFU_PROTOCOL_DFU_MODE,
+        "DFU",
+        vec![],
+        handler.clone(),
+    );
+    dfu_device.vendor_id = device.vendor_id;
+    dfu_device.product_id = device.product_id;
+
+    let reenumeration = Reenumeration::new();
+    let runtime =
+        UsbDfuRuntimeHandler::new(reenumeration.clone(), dfu_device).with_functional(functional);
+    let device = device
+        .with_interface(
+            ClassCode::ApplicationSpecific as u8,
+            DFU_SUBCLASS,
+            DFU_PROTOCOL_RUNTIME,
+            "DFU Runtime",
+            vec![],
+            Arc::new(Mutex::new(
+                Box::new(runtime) as Box<dyn UsbInterfaceHandler + Send>
+            )),
+        )
+        .with_reenumeration(reenumeration);
+    (device, handler)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn desc_verify() {
+        verify_descriptor(&DfuFunctional::default().descriptor());
+    }
+
+    fn dfu_interface() -> UsbInterface {
+        UsbDevice::new(0)
+            .with_interface(
+                0,
+                0,
+                0,
+                "",
+                vec![],
+                Arc::new(Mutex::new(
+                    Box::new(UsbDfuModeHandler::new()) as Box<dyn UsbInterfaceHandler + Send>
+                )),
+            )
+            .interfaces
+            .remove(0)
+    }
+
+    fn request(request: DfuRequest, value: u16, length: u16) -> SetupPacket {
+        SetupPacket {
+            request_type: 0x21,
+            request: request as u8,
+            value,
+            index: 0,
+            length,
+        }
+    }
+
+    #[test]
+    fn download_and_upload() {
+        let mut handler = UsbDfuModeHandler::new();
+        let intf = dfu_interface();
+        let ep0 = UsbEndpoint::default();
+        let get_status = |handler: &mut UsbDfuModeHandler| {
+            handler
+                .handle_urb(&intf, ep0, Some(request(DfuRequest::GetStatus, 0, 6)), &[])
+                .unwrap()
+        };
+
+        handler
+            .handle_urb(
+                &intf,
+                ep0,
+                Some(request(DfuRequest::Dnload, 0, 3)),
+                &[1, 2, 3],
+            )
+            .unwrap();
+        assert_eq!(handler.state(), DfuState::DfuDnloadSync);
+        assert_eq!(get_status(&mut handler)[4], DfuState::DfuDnloadIdle as u8);
+        handler
+            .handle_urb(&intf, ep0, Some(request(DfuRequest::Dnload, 1, 2)), &[4, 5])
+            .unwrap();
+        get_status(&mut handler);
+        assert_eq!(handler.state(), DfuState::DfuDnloadIdle);
+        handler
+            .handle_urb(&intf, ep0, Some(request(DfuRequest::Dnload, 2, 0)), &[])
+            .unwrap();
+        assert_eq!(handler.state(), DfuState::DfuManifestSync);
+        get_status(&mut handler);
+        assert_eq!(handler.state(), DfuState::DfuIdle);
+        assert_eq!(handler.firmware, [1, 2, 3, 4, 5]);
+
+        let block = handler
+            .handle_urb(&intf, ep0, Some(request(DfuRequest::Upload, 1, 2)), &[])
+            .unwrap();
+        assert_eq!(block, [3, 4]);
+        assert_eq!(handler.state(), DfuState::DfuUploadIdle);
+        let block = handler
+            .handle_urb(&intf, ep0, Some(request(DfuRequest::Upload, 2, 2)), &[])
+            .unwrap();
+        assert_eq!(block, [5]);
+        assert_eq!(handler.state(), DfuState::DfuIdle);
+    }
+
+    #[test]
+    fn state_machine() {
+        let intf = dfu_interface();
+        let ep0 = UsbEndpoint::default();
+        let mut handler = UsbDfuModeHandler::new()
+            .with_functional(DfuFunctional {
+                attributes: DFU_CAN_DNLOAD,
+                detach_timeout: 0,
+                transfer_size: 4,
+            })
+            .with_poll_timeout(0x123);
+        let handle = |handler: &mut UsbDfuModeHandler, setup: SetupPacket, req: &[u8]| {
+            handler.handle_urb(&intf, ep0, Some(setup), req)
+        };
+
+        // the end of a download before any block, an upload the attributes
+        // do not allow and a block above wTransferSize are all stalled
+        for (setup, req) in [
+            (request(DfuRequest::Dnload, 0, 0), &[][..]),
+            (request(DfuRequest::Upload, 0, 4), &[][..]),
+            (request(DfuRequest::Dnload, 0, 5), &[0; 5][..]),
+        ] {
+            let err = handle(&mut handler, setup, req).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+            assert_eq!(handler.state(), DfuState::DfuError);
+            let status = handle(&mut handler, request(DfuRequest::GetStatus, 0, 6), &[]).unwrap();
+            assert_eq!(status[0], DfuStatus::ErrStalledPkt as u8);
+            assert_eq!(status[4], DfuState::DfuError as u8);
+            // only DFU_CLRSTATUS leaves dfuERROR
+            handle(&mut handler, request(DfuRequest::Abort, 0, 0), &[]).unwrap_err();
+            handle(&mut handler, request(DfuRequest::ClrStatus, 0, 0), &[]).unwrap();
+            assert_eq!(handler.state(), DfuState::DfuIdle);
+            assert_eq!(handler.status(), DfuStatus::Ok);
+        }
+
+        // a block keeps the device busy for bwPollTimeout
+        handle(
+            &mut handler,
+            request(DfuRequest::Dnload, 0, 4),
+            &[1, 2, 3, 4],
+        )
+        .unwrap();
+        let status = handle(&mut handler, request(DfuRequest::GetStatus, 0, 6), &[]).unwrap();
+        assert_eq!(
+            status,
+            [0x00, 0x23, 0x01, 0x00, DfuState::DfuDnbusy as u8, 0x00]
+        );
+        let status = handle(&mut handler, request(DfuRequest::GetStatus, 0, 6), &[]).unwrap();
+        assert_eq!(
+            status,
+            [0x00, 0x00, 0x00, 0x00, DfuState::DfuDnloadIdle as u8, 0x00]
+        );
+
+        // without manifestation tolerance only a reset leaves the last state
+        handle(&mut handler, request(DfuRequest::Dnload, 1, 0), &[]).unwrap();
+        handle(&mut handler, request(DfuRequest::GetStatus, 0, 6), &[]).unwrap();
+        assert_eq!(handler.state(), DfuState::DfuManifestWaitReset);
+        handle(&mut handler, request(DfuRequest::ClrStatus, 0, 0), &[]).unwrap_err();
+        assert_eq!(handler.state(), DfuState::DfuManifestWaitReset);
+        assert_eq!(handler.firmware, [1, 2, 3, 4]);
+    }
+}
@@ -1,4 +1,5 @@
 use super::*;
+use tokio::sync::Notify;
 
 /// Represent a USB endpoint
 #[derive(Clone, Copy, Debug, Default)]
@@ -14,6 +15,41 @@ pub struct UsbEndpoint {
 }
 
 impl UsbEndpoint {
+    /// Create an endpoint, assembling bmAttributes from its typed parts
+    ///
+    /// Sync and usage types are only meaningful for isochronous endpoints, use
+    /// [SyncType::NoSync] and [UsageType::Data] for the others.
+    pub fn new(
+        address: u8,
+        transfer_type: EndpointAttributes,
+        sync_type: SyncType,
+        usage_type: UsageType,
+        max_packet_size: u16,
+        interval: u8,
+    ) -> Self {
+        Self {
+            address,
+            attributes: transfer_type as u8 | (sync_type as u8) << 2 | (usage_type as u8) << 4,
+            max_packet_size,
+            interval,
+        }
+    }
+
+    /// Transfer type in bits 1:0 of bmAttributes
+    pub fn transfer_type(&self) -> Option<EndpointAttributes> {
+        FromPrimitive::from_u8(self.attributes & 0x3)
+    }
+
+    /// Synchronization type in bits 3:2 of bmAttributes
+    pub fn sync_type(&self) -> Option<SyncType> {
+        FromPrimitive::from_u8((self.attributes >> 2) & 0x3)
+    }
+
+    /// Usage type in bits 5:4 of bmAttributes
+    pub fn usage_type(&self) -> Option<UsageType> {
+        FromPrimitive::from_u8((self.attributes >> 4) & 0x3)
+    }
+
     pub(crate) fn direction(&self) -> Direction {
         if self.address & 0x80 != 0 {
             Direction::In
@@ -22,7 +58,194 @@ impl UsbEndpoint {
         }
     }
 
+    /// Set the additional transactions per microframe in bits 12:11 of
+    /// wMaxPacketSize, 0 to 2
+    ///
+    /// Only high speed interrupt and isochronous endpoints may have them,
+    /// e.g. 1024 bytes with 2 more transactions move 3072 bytes per
+    /// microframe.
+    pub fn with_additional_transactions(mut self, additional: u8) -> Self {
+        assert!(additional <= 2, "at most 2 additional transactions");
+        assert!(
+            matches!(
+                self.transfer_type(),
+                Some(EndpointAttributes::Interrupt) | Some(EndpointAttributes::Isochronous)
+            ),
+            "additional transactions on a non periodic endpoint"
+        );
+        self.max_packet_size = (self.max_packet_size & 0x7FF) | (additional as u16) << 11;
+        self
+    }
+
+    /// Bytes per packet, bits 10:0 of wMaxPacketSize
+    pub fn packet_size(&self) -> usize {
+        (self.max_packet_size & 0x7FF) as usize
+    }
+
+    /// Transactions per microframe, 1 unless set by
+    /// [UsbEndpoint::with_additional_transactions]
+    pub fn transactions(&self) -> usize {
+        ((self.max_packet_size >> 11) & 0x3) as usize + 1
+    }
+
+    /// Bytes per (micro)frame: the packet size times the transactions
+    pub fn max_payload(&self) -> usize {
+        self.packet_size() * self.transactions()
+    }
+
     pub fn is_ep0(&self) -> bool {
         self.address & 0x7F == 0
     }
 }
+
+/// A queue of data for an IN endpoint, cloning it shares the queue
+///
+/// A handler pops from it in [UsbInterfaceHandler::handle_urb], which NAKs
+/// while it is empty. Register it with [UsbDevice::with_in_queue] so that a
+/// pending URB completes as soon as data is pushed, instead of on the next
+/// retry.
+#[derive(Clone, Debug, Default)]
+pub struct InEndpointQueue {
+    data: Arc<Mutex<VecDeque<Vec<u8>>>>,
+    notify: Arc<Notify>,
+}
+
+impl InEndpointQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue `data` to be returned by one IN transfer, waking a pending URB
+    pub fn push(&self, data: Vec<u8>) {
+        self.data.lock().unwrap().push_back(data);
+        self.notify.notify_one();
+    }
+
+    /// Take the oldest data, or fail with [std::io::ErrorKind::WouldBlock] to NAK
+    pub fn pop(&self) -> Result<Vec<u8>> {
+        self.data
+            .lock()
+            .unwrap()
+            .pop_front()
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::WouldBlock, "no data queued"))
+    }
+
+    /// Take up to `max_len` bytes of the oldest data, leaving the rest of it
+    /// for the next transfers
+    ///
+    /// A handler passing the transfer_buffer_length of
+    /// [ConnectionContext] completes several queued URBs from one large
+    /// [InEndpointQueue::push]. Data of separate pushes is never merged, the
+    /// last part of each ends its URB as a short transfer.
+    pub fn pop_at_most(&self, max_len: usize) -> Result<Vec<u8>> {
+        let mut data = self.data.lock().unwrap();
+        let front = data
+            .front_mut()
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::WouldBlock, "no data queued"))?;
+        if front.len() > max_len {
+            let rest = front.split_off(max_len);
+            Ok(std::mem::replace(front, rest))
+        } else {
+            Ok(data.pop_front().unwrap())
+        }
+    }
+
+    pub(crate) fn is_ready(&self) -> bool {
+        !self.data.lock().unwrap().is_empty()
+    }
+
+    pub(crate) async fn ready(&self) {
+        if self.data.lock().unwrap().is_empty() {
+            self.notify.notified().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn isochronous_attributes() {
+        let ep = UsbEndpoint::new(
+            0x81,
+            EndpointAttributes::Isochronous,
+            SyncType::Async,
+            UsageType::Data,
+            192,
+            1,
+        );
+        assert_eq!(ep.attributes, 0b0000_0101);
+        let ep = UsbEndpoint::new(
+            0x01,
+            EndpointAttributes::Isochronous,
+            SyncType::Adaptive,
+            UsageType::ImplicitFeedback,
+            192,
+            1,
+        );
+        assert_eq!(ep.attributes, 0b0010_1001);
+        assert_eq!(ep.transfer_type(), Some(EndpointAttributes::Isochronous));
+        assert_eq!(ep.sync_type(), Some(SyncType::Adaptive));
+        assert_eq!(ep.usage_type(), Some(UsageType::ImplicitFeedback));
+    }
+
+    #[test]
+    fn additional_transactions() {
+        let ep = UsbEndpoint::new(
+            0x81,
+            EndpointAttributes::Isochronous,
+            SyncType::Async,
+            UsageType::Data,
+            1024,
+            1,
+        )
+        .with_additional_transactions(2);
+        assert_eq!(ep.max_packet_size, 0x1400);
+        assert_eq!((ep.packet_size(), ep.transactions()), (1024, 3));
+        assert_eq!(ep.max_payload(), 3072);
+        let ep = ep.with_additional_transactions(0);
+        assert_eq!(ep.max_packet_size, 1024);
+
+        // written as is into the endpoint descriptor
+        let device = UsbDevice::new(0).with_interface(
+            ClassCode::VendorSpecific as u8,
+            0x00,
+            0x00,
+            "",
+            vec![ep.with_additional_transactions(1)],
+            Arc::new(Mutex::new(
+                Box::new(UsbDescriptorOnlyHandler) as Box<dyn UsbInterfaceHandler + Send>
+            )),
+        );
+        let config = device.configuration_descriptor();
+        assert_eq!(config[9 + 9 + 4..9 + 9 + 6], [0x00, 0x0C]);
+    }
+
+    #[test]
+    #[should_panic(expected = "non periodic")]
+    fn additional_transactions_on_bulk() {
+        UsbEndpoint::new(
+            0x81,
+            EndpointAttributes::Bulk,
+            SyncType::NoSync,
+            UsageType::Data,
+            512,
+            0,
+        )
+        .with_additional_transactions(1);
+    }
+
+    #[test]
+    fn in_queue_pop_at_most() {
+        let queue = InEndpointQueue::new();
+        queue.push(vec![1, 2, 3, 4, 5]);
+        queue.push(vec![6]);
+        assert_eq!(queue.pop_at_most(2).unwrap(), [1, 2]);
+        assert_eq!(queue.pop_at_most(2).unwrap(), [3, 4]);
+        assert_eq!(queue.pop_at_most(2).unwrap(), [5]);
+        assert_eq!(queue.pop().unwrap(), [6]);
+        let err = queue.pop_at_most(2).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
+    }
+}
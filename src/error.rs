@@ -0,0 +1,129 @@
+//! Errors of connections and transfers, see [UsbIpError]
+use super::*;
+
+/// An error of a USB/IP connection or of a transfer on an imported device
+///
+/// Tells a broken transport apart from the device refusing a transfer.
+/// Interface handlers keep returning [std::io::Error], with the conventions
+/// of [UsbInterfaceHandler::handle_urb], and a stall from them becomes
+/// [UsbIpError::Stall].
+#[derive(Debug)]
+pub enum UsbIpError {
+    /// The transport failed, or a transfer failed otherwise, e.g. a NAK as
+    /// [std::io::ErrorKind::WouldBlock]
+    Io(std::io::Error),
+    /// The peer sent something USB/IP does not allow
+    Protocol(String),
+    /// No exported device has the bus id
+    DeviceNotFound(String),
+    /// The device has no endpoint at the address
+    EndpointNotFound(u8),
+    /// The endpoint stalled the transfer
+    Stall,
+    /// A command code neither USB/IP nor a custom op defines
+    UnsupportedCommand(u32),
+}
+
+impl UsbIpError {
+    /// Classify an error of a transfer: a stall or anything else
+    pub(crate) fn from_transfer(err: std::io::Error) -> Self {
+        if err.kind() == ErrorKind::BrokenPipe {
+            debug!("Stalled: {}", err);
+            UsbIpError::Stall
+        } else {
+            UsbIpError::Io(err)
+        }
+    }
+
+    /// The closest [std::io::ErrorKind], as if it were an [std::io::Error]
+    pub fn kind(&self) -> ErrorKind {
+        use UsbIpError::*;
+        match self {
+            Io(err) => err.kind(),
+            Protocol(_) | UnsupportedCommand(_) => ErrorKind::InvalidData,
+            DeviceNotFound(_) | EndpointNotFound(_) => ErrorKind::NotFound,
+            Stall => ErrorKind::BrokenPipe,
+        }
+    }
+
+    /// Status of a URB failing with this error, as in USBIP_RET_SUBMIT
+    pub fn status(&self) -> i32 {
+        use UsbIpError::*;
+        match self {
+            Stall | EndpointNotFound(_) => -errno::EPIPE,
+            DeviceNotFound(_) => -errno::ENODEV,
+            Io(err) => match err.kind() {
+                ErrorKind::NotConnected => -errno::ENODEV,
+                ErrorKind::ConnectionAborted => -errno::ESHUTDOWN,
+                ErrorKind::Interrupted => -errno::ECONNRESET,
+                ErrorKind::WouldBlock | ErrorKind::TimedOut => -errno::ETIMEDOUT,
+                _ => -errno::EPROTO,
+            },
+            Protocol(_) | UnsupportedCommand(_) => -errno::EPROTO,
+        }
+    }
+}
+
+impl std::fmt::Display for UsbIpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use UsbIpError::*;
+        match self {
+            Io(err) => write!(f, "{}", err),
+            Protocol(msg) => write!(f, "protocol error: {}", msg),
+            DeviceNotFound(bus_id) => write!(f, "device {:?} not found", bus_id),
+            EndpointNotFound(address) => write!(f, "endpoint {:02x} not found", address),
+            Stall => write!(f, "endpoint stalled"),
+            UnsupportedCommand(command) => write!(f, "unsupported command {:08x}", command),
+        }
+    }
+}
+
+impl std::error::Error for UsbIpError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            UsbIpError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for UsbIpError {
+    fn from(err: std::io::Error) -> Self {
+        UsbIpError::Io(err)
+    }
+}
+
+impl From<UsbIpError> for std::io::Error {
+    fn from(err: UsbIpError) -> Self {
+        match err {
+            UsbIpError::Io(err) => err,
+            err => std::io::Error::new(err.kind(), err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn io_round_trip() {
+        let err = UsbIpError::from_transfer(std::io::Error::new(ErrorKind::BrokenPipe, "stall"));
+        assert!(matches!(err, UsbIpError::Stall));
+        assert_eq!(err.status(), -errno::EPIPE);
+        let err = std::io::Error::from(err);
+        assert_eq!(err.kind(), ErrorKind::BrokenPipe);
+        assert_eq!(err.to_string(), "endpoint stalled");
+
+        let err = UsbIpError::from(std::io::Error::new(ErrorKind::WouldBlock, "nak"));
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+        assert_eq!(err.status(), -errno::ETIMEDOUT);
+        assert_eq!(std::io::Error::from(err).to_string(), "nak");
+
+        let err = UsbIpError::from_transfer(std::io::Error::new(ErrorKind::TimedOut, "timeout"));
+        assert_eq!(err.status(), -errno::ETIMEDOUT);
+
+        let err = UsbIpError::UnsupportedCommand(0x0111_80AA);
+        assert_eq!(err.to_string(), "unsupported command 011180aa");
+    }
+}
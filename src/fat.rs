@@ -0,0 +1,341 @@
+//! Build FAT16 images of host directories, see [msc::RamDisk::from_dir]
+use super::*;
+use std::collections::HashSet;
+
+const SECTOR_SIZE: usize = 512;
+const DIR_ENTRY_SIZE: usize = 32;
+const ROOT_ENTRIES: usize = 512;
+/// Fewer clusters make a FAT12 volume, more a FAT32 one
+const MIN_CLUSTERS: usize = 4085;
+const MAX_CLUSTERS: usize = 65524;
+/// Long file name entries hold 13 UTF-16 code units each
+const LFN_CHARS: usize = 13;
+
+const ATTR_LONG_NAME: u8 = 0x0F;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+/// 1980-01-01, timestamps of the host files are not kept
+const FAT_DATE: u16 = 0x0021;
+
+/// A file or directory to put in the image
+enum Node {
+    File { name: String, data: Vec<u8> },
+    Dir { name: String, children: Vec<Node> },
+}
+
+impl Node {
+    fn name(&self) -> &str {
+        match self {
+            Node::File { name, .. } | Node::Dir { name, .. } => name,
+        }
+    }
+}
+
+/// Read the files and directories under `path`, skipping symbolic links
+fn read_tree(path: &Path) -> Result<Vec<Node>> {
+    let mut entries = std::fs::read_dir(path)?.collect::<Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    let mut nodes = vec![];
+    for entry in entries {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            let children = read_tree(&entry.path())?;
+            nodes.push(Node::Dir { name, children });
+        } else if file_type.is_file() {
+            let data = std::fs::read(entry.path())?;
+            if data.len() > u32::MAX as usize {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("{} is too large for FAT", name),
+                ));
+            }
+            nodes.push(Node::File { name, data });
+        }
+    }
+    Ok(nodes)
+}
+
+/// Characters allowed in 8.3 names besides letters and digits
+fn is_short_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'()-@^_`{}~".contains(c)
+}
+
+/// Uppercase `s` with invalid characters replaced, and whether none were
+fn sanitize(s: &str) -> (String, bool) {
+    let mut lossless = true;
+    let mut res = String::new();
+    for c in s.chars() {
+        if is_short_name_char(c) {
+            res.push(c.to_ascii_uppercase());
+        } else {
+            lossless = false;
+            if c != ' ' && c != '.' {
+                res.push('_');
+            }
+        }
+    }
+    (res, lossless)
+}
+
+/// Base name and extension of an 8.3 name, and whether `name` fits without loss
+fn split_short_name(name: &str) -> (String, String, bool) {
+    let (stem, ext) = match name.rfind('.') {
+        Some(dot) if dot > 0 => (&name[..dot], &name[dot + 1..]),
+        _ => (name, ""),
+    };
+    let (stem, stem_lossless) = sanitize(stem);
+    let (ext, ext_lossless) = sanitize(ext);
+    let fits =
+        stem_lossless && ext_lossless && !stem.is_empty() && stem.len() <= 8 && ext.len() <= 3;
+    (stem, ext, fits)
+}
+
+/// Whether `name` needs long file name entries, it does unless it is a valid
+/// uppercase 8.3 name
+fn needs_long_name(name: &str) -> bool {
+    let (stem, ext, fits) = split_short_name(name);
+    let short = if ext.is_empty() {
+        stem
+    } else {
+        format!("{}.{}", stem, ext)
+    };
+    !fits || short != name
+}
+
+/// Number of directory entries `name` takes
+fn entry_slots(name: &str) -> usize {
+    if needs_long_name(name) {
+        1 + name.encode_utf16().count().div_ceil(LFN_CHARS)
+    } else {
+        1
+    }
+}
+
+/// Pick an 8.3 name for `name` not in `used`, like NAME~1.EXT if it does not fit
+fn short_name(name: &str, used: &mut HashSet<[u8; 11]>) -> [u8; 11] {
+    let (stem, ext, fits) = split_short_name(name);
+    let pack = |stem: &str| {
+        let mut short = [b' '; 11];
+        for (i, b) in stem.bytes().take(8).enumerate() {
+            short[i] = b;
+        }
+        for (i, b) in ext.bytes().take(3).enumerate() {
+            short[8 + i] = b;
+        }
+        short
+    };
+    if fits && used.insert(pack(&stem)) {
+        return pack(&stem);
+    }
+    for n in 1.. {
+        let tail = format!("~{}", n);
+        let base: String = stem.chars().take(8 - tail.len()).collect();
+        let short = pack(&format!("{}{}", base, tail));
+        if used.insert(short) {
+            return short;
+        }
+    }
+    unreachable!()
+}
+
+/// Checksum of a short name stored in its long file name entries
+fn short_name_checksum(short: &[u8; 11]) -> u8 {
+    short
+        .iter()
+        .fold(0u8, |sum, b| sum.rotate_right(1).wrapping_add(*b))
+}
+
+/// Long file name entries of `name`, in the order they are stored
+fn long_name_entries(name: &str, checksum: u8) -> Vec<[u8; DIR_ENTRY_SIZE]> {
+    let mut chars: Vec<u16> = name.encode_utf16().collect();
+    let count = chars.len().div_ceil(LFN_CHARS);
+    // NUL terminated unless it fills the last entry, then padded with 0xFFFF
+    if !chars.len().is_multiple_of(LFN_CHARS) {
+        chars.push(0);
+    }
+    chars.resize(count * LFN_CHARS, 0xFFFF);
+    let mut entries = vec![];
+    for (i, part) in chars.chunks(LFN_CHARS).enumerate().rev() {
+        let mut entry = [0u8; DIR_ENTRY_SIZE];
+        entry[0] = (i + 1) as u8 | if i + 1 == count { 0x40 } else { 0 };
+        entry[11] = ATTR_LONG_NAME;
+        entry[13] = checksum;
+        let offsets = (1..11)
+            .step_by(2)
+            .chain((14..26).step_by(2))
+            .chain((28..32).step_by(2));
+        for (offset, c) in offsets.zip(part) {
+            entry[offset..offset + 2].copy_from_slice(&c.to_le_bytes());
+        }
+        entries.push(entry);
+    }
+    entries
+}
+
+/// A short directory entry
+fn dir_entry(short: &[u8; 11], attributes: u8, cluster: u16, size: u32) -> [u8; DIR_ENTRY_SIZE] {
+    let mut entry = [0u8; DIR_ENTRY_SIZE];
+    entry[..11].copy_from_slice(short);
+    entry[11] = attributes;
+    // creation, last access and write date
+    for offset in [16, 18, 24] {
+        entry[offset..offset + 2].copy_from_slice(&FAT_DATE.to_le_bytes());
+    }
+    entry[26..28].copy_from_slice(&cluster.to_le_bytes());
+    entry[28..32].copy_from_slice(&size.to_le_bytes());
+    entry
+}
+
+/// Bytes of the entries of a subdirectory, including . and ..
+fn dir_len(children: &[Node]) -> usize {
+    let slots: usize = children.iter().map(|child| entry_slots(child.name())).sum();
+    (2 + slots) * DIR_ENTRY_SIZE
+}
+
+/// Clusters taken by `nodes` and everything below
+fn count_clusters(nodes: &[Node], cluster_size: usize) -> usize {
+    nodes
+        .iter()
+        .map(|node| match node {
+            Node::File { data, .. } => data.len().div_ceil(cluster_size),
+            Node::Dir { children, .. } => {
+                dir_len(children).div_ceil(cluster_size) + count_clusters(children, cluster_size)
+            }
+        })
+        .sum()
+}
+
+/// Allocates clusters one after the other and fills them
+struct Builder {
+    cluster_size: usize,
+    fat: Vec<u16>,
+    data: Vec<u8>,
+}
+
+impl Builder {
+    /// Allocate a chain for `bytes` and copy them in, returning its first
+    /// cluster, 0 if empty
+    fn store(&mut self, bytes: &[u8]) -> u16 {
+        let count = bytes.len().div_ceil(self.cluster_size);
+        if count == 0 {
+            return 0;
+        }
+        let first = self.fat.len();
+        for cluster in first..first + count - 1 {
+            self.fat.push(cluster as u16 + 1);
+        }
+        // end of chain
+        self.fat.push(0xFFFF);
+        let offset = (first - 2) * self.cluster_size;
+        self.data[offset..offset + bytes.len()].copy_from_slice(bytes);
+        first as u16
+    }
+
+    /// Store `children` and return the directory entries pointing to them
+    fn store_children(&mut self, children: &[Node], own: u16) -> Vec<u8> {
+        let mut used = HashSet::new();
+        let mut entries = vec![];
+        for child in children {
+            let (attributes, cluster, size) = match child {
+                Node::File { data, .. } => (ATTR_ARCHIVE, self.store(data), data.len() as u32),
+                Node::Dir { children, .. } => (ATTR_DIRECTORY, self.store_dir(children, own), 0),
+            };
+            let short = short_name(child.name(), &mut used);
+            if needs_long_name(child.name()) {
+                let checksum = short_name_checksum(&short);
+                for entry in long_name_entries(child.name(), checksum) {
+                    entries.extend_from_slice(&entry);
+                }
+            }
+            entries.extend_from_slice(&dir_entry(&short, attributes, cluster, size));
+        }
+        entries
+    }
+
+    /// Store a subdirectory of `parent`, 0 for the root directory
+    fn store_dir(&mut self, children: &[Node], parent: u16) -> u16 {
+        // reserve the clusters of the directory before those of its children
+        let own = self.store(&vec![0u8; dir_len(children)]);
+        let mut entries = vec![];
+        entries.extend_from_slice(&dir_entry(b".          ", ATTR_DIRECTORY, own, 0));
+        entries.extend_from_slice(&dir_entry(b"..         ", ATTR_DIRECTORY, parent, 0));
+        entries.extend(self.store_children(children, own));
+        let offset = (own as usize - 2) * self.cluster_size;
+        self.data[offset..offset + entries.len()].copy_from_slice(&entries);
+        own
+    }
+}
+
+/// Build a FAT16 image of 512 byte sectors holding the files under `path`
+///
+/// The image is a snapshot: later changes to the directory are not seen.
+pub(crate) fn fat16_image(path: &Path) -> Result<Vec<u8>> {
+    let nodes = read_tree(path)?;
+    let root_slots: usize = nodes.iter().map(|node| entry_slots(node.name())).sum();
+    if root_slots > ROOT_ENTRIES {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidInput,
+            "too many files in the root directory for FAT16",
+        ));
+    }
+    // the smallest clusters which keep the cluster count in FAT16 range
+    let (sectors_per_cluster, needed) = (0..7)
+        .map(|shift| {
+            let sectors_per_cluster = 1usize << shift;
+            let needed = count_clusters(&nodes, sectors_per_cluster * SECTOR_SIZE);
+            (sectors_per_cluster, needed)
+        })
+        .find(|(_, needed)| *needed <= MAX_CLUSTERS)
+        .ok_or_else(|| std::io::Error::new(ErrorKind::InvalidInput, "too much data for FAT16"))?;
+    let clusters = needed.max(MIN_CLUSTERS);
+    let cluster_size = sectors_per_cluster * SECTOR_SIZE;
+    let sectors_per_fat = ((clusters + 2) * 2).div_ceil(SECTOR_SIZE);
+    let root_sectors = ROOT_ENTRIES * DIR_ENTRY_SIZE / SECTOR_SIZE;
+    let data_start = 1 + 2 * sectors_per_fat + root_sectors;
+    let total_sectors = data_start + clusters * sectors_per_cluster;
+
+    let mut builder = Builder {
+        cluster_size,
+        // media descriptor, then end of chain
+        fat: vec![0xFFF8, 0xFFFF],
+        data: vec![0; clusters * cluster_size],
+    };
+    let root = builder.store_children(&nodes, 0);
+
+    let mut image = vec![0u8; total_sectors * SECTOR_SIZE];
+    let boot = &mut image[..SECTOR_SIZE];
+    boot[..3].copy_from_slice(&[0xEB, 0x3C, 0x90]); // jump over the BPB
+    boot[3..11].copy_from_slice(b"USBIP   "); // OEM name
+    boot[11..13].copy_from_slice(&(SECTOR_SIZE as u16).to_le_bytes()); // bytes per sector
+    boot[13] = sectors_per_cluster as u8;
+    boot[14..16].copy_from_slice(&1u16.to_le_bytes()); // reserved sectors
+    boot[16] = 2; // number of FATs
+    boot[17..19].copy_from_slice(&(ROOT_ENTRIES as u16).to_le_bytes());
+    if total_sectors <= u16::MAX as usize {
+        boot[19..21].copy_from_slice(&(total_sectors as u16).to_le_bytes());
+    } else {
+        boot[32..36].copy_from_slice(&(total_sectors as u32).to_le_bytes());
+    }
+    boot[21] = 0xF8; // media descriptor: fixed disk
+    boot[22..24].copy_from_slice(&(sectors_per_fat as u16).to_le_bytes());
+    boot[24..26].copy_from_slice(&32u16.to_le_bytes()); // sectors per track
+    boot[26..28].copy_from_slice(&64u16.to_le_bytes()); // heads
+    boot[36] = 0x80; // drive number
+    boot[38] = 0x29; // extended boot signature
+    boot[39..43].copy_from_slice(&0x5553_4250u32.to_le_bytes()); // volume serial
+    boot[43..54].copy_from_slice(b"NO NAME    "); // volume label
+    boot[54..62].copy_from_slice(b"FAT16   ");
+    boot[510..512].copy_from_slice(&[0x55, 0xAA]);
+
+    let mut fat: Vec<u8> = builder.fat.iter().flat_map(|e| e.to_le_bytes()).collect();
+    fat.resize(sectors_per_fat * SECTOR_SIZE, 0);
+    for copy in 0..2 {
+        let offset = (1 + copy * sectors_per_fat) * SECTOR_SIZE;
+        image[offset..offset + fat.len()].copy_from_slice(&fat);
+    }
+    let root_offset = (1 + 2 * sectors_per_fat) * SECTOR_SIZE;
+    image[root_offset..root_offset + root.len()].copy_from_slice(&root);
+    image[data_start * SECTOR_SIZE..].copy_from_slice(&builder.data);
+    Ok(image)
+}
@@ -0,0 +1,85 @@
+/// Reassemble messages split across multiple bulk OUT transfers
+///
+/// Protocols layered on bulk endpoints (e.g. SCSI CBW and data, USBTMC)
+/// split one logical message over several URBs. Feed every bulk OUT payload
+/// to [BulkMessageAssembler::push] and it returns each message once all of
+/// its bytes have arrived.
+#[derive(Clone)]
+pub struct BulkMessageAssembler {
+    buffer: Vec<u8>,
+    header_len: usize,
+    message_len: fn(&[u8]) -> usize,
+}
+
+impl BulkMessageAssembler {
+    /// Create an assembler for messages starting with a header of `header_len` bytes
+    ///
+    /// `message_len` is called with the header and returns the total length of
+    /// the message, including the header itself.
+    pub fn new(header_len: usize, message_len: fn(&[u8]) -> usize) -> Self {
+        Self {
+            buffer: vec![],
+            header_len,
+            message_len,
+        }
+    }
+
+    /// Append data of a bulk OUT transfer, returning messages completed by it
+    pub fn push(&mut self, data: &[u8]) -> Vec<Vec<u8>> {
+        self.buffer.extend_from_slice(data);
+        let mut messages = vec![];
+        while self.buffer.len() >= self.header_len {
+            // never shorter than the header, or we would loop forever
+            let len = (self.message_len)(&self.buffer[..self.header_len]).max(self.header_len);
+            if self.buffer.len() < len {
+                break;
+            }
+            let rest = self.buffer.split_off(len);
+            messages.push(std::mem::replace(&mut self.buffer, rest));
+        }
+        messages
+    }
+
+    /// Number of bytes received but not yet part of a complete message
+    pub fn pending(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Drop any partially received message
+    pub fn clear(&mut self) {
+        self.buffer.clear();
+    }
+}
+
+impl std::fmt::Debug for BulkMessageAssembler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BulkMessageAssembler")
+            .field("header_len", &self.header_len)
+            .field("pending", &self.buffer.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2-byte little endian length of payload, followed by payload
+    fn message_len(header: &[u8]) -> usize {
+        2 + (header[0] as usize | (header[1] as usize) << 8)
+    }
+
+    #[test]
+    fn reassemble_two_chunks() {
+        let mut assembler = BulkMessageAssembler::new(2, message_len);
+        assert!(assembler.push(&[0x05, 0x00, b'h', b'e']).is_empty());
+        assert_eq!(assembler.pending(), 4);
+        let messages = assembler.push(&[b'l', b'l', b'o', 0x01]);
+        assert_eq!(
+            messages,
+            vec![vec![0x05, 0x00, b'h', b'e', b'l', b'l', b'o']]
+        );
+        // start of next message is kept
+        assert_eq!(assembler.pending(), 1);
+    }
+}
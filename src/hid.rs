@@ -5,6 +5,25 @@ use super::*;
 // HID 1.11: https://www.usb.org/sites/default/files/documents/hid1_11.pdf
 // HID Usage Tables 1.12: https://www.usb.org/sites/default/files/documents/hut1_12v2.pdf
 
+/// bInterfaceSubClass of HID interfaces supporting the boot protocol,
+/// report protocol only interfaces use 0
+pub const HID_SUBCLASS_BOOT: u8 = 0x01;
+
+/// bInterfaceProtocol of a boot interface keyboard
+pub const HID_PROTOCOL_KEYBOARD: u8 = 0x01;
+
+/// bInterfaceProtocol of a boot interface mouse
+pub const HID_PROTOCOL_MOUSE: u8 = 0x02;
+
+/// Protocol selected by SET_PROTOCOL, wValue of the request
+#[derive(Copy, Clone, Debug, PartialEq, Eq, FromPrimitive)]
+pub enum HidProtocol {
+    /// Fixed report formats of the boot subclass, understood by BIOSes
+    Boot = 0,
+    /// Reports as declared by the report descriptor, selected at power up
+    Report = 1,
+}
+
 #[derive(Clone)]
 enum UsbHidKeyboardHandlerState {
     Idle,
@@ -16,13 +35,24 @@ enum UsbHidKeyboardHandlerState {
 pub struct UsbHidKeyboardHandler {
     pub report_descriptor: Vec<u8>,
     pub pending_key_events: VecDeque<UsbHidKeyboardReport>,
+    /// Reports sent as is, without an automatic key up after each of them
+    pub pending_reports: VecDeque<UsbHidKeyboardReport>,
+    /// Protocol selected by the host
+    ///
+    /// The report descriptor describes the boot report, so both protocols
+    /// send the same bytes.
+    pub protocol: HidProtocol,
+    /// Idle rate set by SET_IDLE, in units of 4ms, 0 for indefinite
+    pub idle_rate: u8,
+    /// Report last sent to the host, returned by GET_REPORT
+    pub current_report: UsbHidKeyboardReport,
     state: UsbHidKeyboardHandlerState,
 }
 
 /// A report of a HID keyboard
 ///
 /// For definition of key codes, see [HID Usage Tables](https://www.usb.org/sites/default/files/documents/hut1_12v2.pdf)
-#[derive(Clone)]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct UsbHidKeyboardReport {
     /// Key modifier
     pub modifier: u8,
@@ -31,6 +61,13 @@ pub struct UsbHidKeyboardReport {
 }
 
 impl UsbHidKeyboardReport {
+    /// Encode as the 8-byte boot keyboard input report
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut resp = vec![self.modifier, 0];
+        resp.extend_from_slice(&self.keys);
+        resp
+    }
+
     pub fn from_ascii(ascii: u8) -> UsbHidKeyboardReport {
         // TODO: casing
         let key = match ascii {
@@ -51,6 +88,10 @@ impl UsbHidKeyboardHandler {
     pub fn new_keyboard() -> Self {
         Self {
             pending_key_events: VecDeque::new(),
+            pending_reports: VecDeque::new(),
+            protocol: HidProtocol::Report,
+            idle_rate: 0,
+            current_report: UsbHidKeyboardReport::default(),
             state: UsbHidKeyboardHandlerState::Idle,
             report_descriptor: vec![
                 0x05, 0x01, // Usage Page (Generic Desktop)
@@ -82,6 +123,115 @@ impl UsbHidKeyboardHandler {
             ],
         }
     }
+
+    /// Queue a report of `modifiers` and the pressed `keys`, sent on the next
+    /// interrupt IN transfer
+    ///
+    /// Keys stay pressed until a report without them. More than 6 keys are
+    /// reported as [HID_KEY_ERROR_ROLL_OVER], as the boot protocol requires.
+    pub fn push_key_report(&mut self, modifiers: u8, keys: &[u8]) {
+        let mut report = UsbHidKeyboardReport {
+            modifier: modifiers,
+            keys: [0; 6],
+        };
+        if keys.len() > report.keys.len() {
+            report.keys = [HID_KEY_ERROR_ROLL_OVER; 6];
+        } else {
+            report.keys[..keys.len()].copy_from_slice(keys);
+        }
+        self.pending_reports.push_back(report);
+    }
+
+    /// The interrupt IN endpoint of a keyboard, 8 bytes every 10 frames
+    pub fn endpoints() -> Vec<UsbEndpoint> {
+        vec![UsbEndpoint::new(
+            0x81,
+            EndpointAttributes::Interrupt,
+            SyncType::NoSync,
+            UsageType::Data,
+            0x08,
+            10,
+        )]
+    }
+}
+
+/// Add a boot interface keyboard served by `handler` to `device`
+///
+/// The interface has [HID_SUBCLASS_BOOT] and [HID_PROTOCOL_KEYBOARD], which
+/// some BIOSes require to use it. Returns the device and the handler.
+pub fn with_boot_keyboard(
+    device: UsbDevice,
+    handler: UsbHidKeyboardHandler,
+) -> (UsbDevice, Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>>) {
+    let keyboard = UsbHidKeyboard::with_handler(handler);
+    (keyboard.add_to(device), keyboard.handler)
+}
+
+/// A boot protocol keyboard, shared with the device it is added to
+///
+/// Wraps a [UsbHidKeyboardHandler] behind the handle of its interface, so
+/// reports are pushed without downcasting the handler:
+///
+/// ```
+/// let keyboard = usbip::hid::UsbHidKeyboard::new();
+/// let device = keyboard.add_to(usbip::UsbDevice::new(0));
+/// // shift and 'a'
+/// keyboard.push_key_report(0x02, &[0x04]);
+/// keyboard.push_key_report(0x00, &[]);
+/// ```
+#[derive(Clone)]
+pub struct UsbHidKeyboard {
+    handler: Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>>,
+}
+
+impl UsbHidKeyboard {
+    pub fn new() -> Self {
+        Self::with_handler(UsbHidKeyboardHandler::new_keyboard())
+    }
+
+    fn with_handler(handler: UsbHidKeyboardHandler) -> Self {
+        Self {
+            handler: Arc::new(Mutex::new(Box::new(handler))),
+        }
+    }
+
+    /// Add the keyboard to `device` as a boot interface, see [with_boot_keyboard]
+    pub fn add_to(&self, device: UsbDevice) -> UsbDevice {
+        device.with_interface(
+            ClassCode::HID as u8,
+            HID_SUBCLASS_BOOT,
+            HID_PROTOCOL_KEYBOARD,
+            "Keyboard",
+            UsbHidKeyboardHandler::endpoints(),
+            self.handler.clone(),
+        )
+    }
+
+    /// See [UsbHidKeyboardHandler::push_key_report]
+    pub fn push_key_report(&self, modifiers: u8, keys: &[u8]) {
+        self.with_keyboard(|keyboard| keyboard.push_key_report(modifiers, keys))
+    }
+
+    /// Run `f` on the handler, e.g. to read the protocol selected by the host
+    pub fn with_keyboard<R>(&self, f: impl FnOnce(&mut UsbHidKeyboardHandler) -> R) -> R {
+        let mut handler = self.handler.lock().unwrap();
+        let keyboard = handler
+            .as_any()
+            .downcast_mut::<UsbHidKeyboardHandler>()
+            .expect("handler of a UsbHidKeyboard");
+        f(keyboard)
+    }
+
+    /// The handler of the interface, e.g. for [forward_keyboard_input]
+    pub fn handler(&self) -> Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>> {
+        self.handler.clone()
+    }
+}
+
+impl Default for UsbHidKeyboard {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl UsbInterfaceHandler for UsbHidKeyboardHandler {
@@ -89,12 +239,13 @@ impl UsbInterfaceHandler for UsbHidKeyboardHandler {
         &mut self,
         _interface: &UsbInterface,
         ep: UsbEndpoint,
-        setup: SetupPacket,
+        setup: Option<SetupPacket>,
         _req: &[u8],
     ) -> Result<Vec<u8>> {
         use StandardRequest::*;
-        if ep.is_ep0() {
+        if let Some(setup) = setup {
             // control transfers
+            let hid_request = FromPrimitive::from_u8(setup.request);
             match (setup.request_type, FromPrimitive::from_u8(setup.request)) {
                 (0b10000001, Some(GetDescriptor)) => {
                     // high byte: type
@@ -102,10 +253,43 @@ impl UsbInterfaceHandler for UsbHidKeyboardHandler {
                         Some(HidDescriptorType::Report) => {
                             return Ok(self.report_descriptor.clone());
                         }
-                        _ => unimplemented!("hid descriptor {:?}", setup),
+                        _ => {
+                            return Err(std::io::Error::new(
+                                std::io::ErrorKind::BrokenPipe,
+                                format!("unknown hid descriptor {:x?}", setup),
+                            ))
+                        }
                     }
                 }
-                _ => unimplemented!("hid request {:?}", setup),
+                (0b10100001, _) if hid_request == Some(HidRequest::GetReport) => {
+                    if setup.value >> 8 != HidReportType::Input as u16 {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::BrokenPipe,
+                            format!("keyboard has no such report {:x?}", setup),
+                        ));
+                    }
+                    return Ok(self.current_report.to_bytes());
+                }
+                (0b10100001, _) if hid_request == Some(HidRequest::GetIdle) => {
+                    return Ok(vec![self.idle_rate]);
+                }
+                (0b10100001, _) if hid_request == Some(HidRequest::GetProtocol) => {
+                    return Ok(vec![self.protocol as u8]);
+                }
+                (0b00100001, _) if hid_request == Some(HidRequest::SetProtocol) => {
+                    self.protocol = set_protocol(&setup)?;
+                    return Ok(vec![]);
+                }
+                (0b00100001, _) if hid_request == Some(HidRequest::SetIdle) => {
+                    self.idle_rate = (setup.value >> 8) as u8;
+                    return Ok(vec![]);
+                }
+                _ => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::BrokenPipe,
+                        format!("unknown hid request {:x?}", setup),
+                    ))
+                }
             }
         } else {
             // interrupt transfer
@@ -113,10 +297,15 @@ impl UsbInterfaceHandler for UsbHidKeyboardHandler {
                 // interrupt in
                 match self.state {
                     UsbHidKeyboardHandlerState::Idle => {
+                        if let Some(report) = self.pending_reports.pop_front() {
+                            let resp = report.to_bytes();
+                            self.current_report = report;
+                            return Ok(resp);
+                        }
                         if let Some(report) = self.pending_key_events.pop_front() {
-                            let mut resp = vec![report.modifier, 0];
-                            resp.extend_from_slice(&report.keys);
+                            let resp = report.to_bytes();
                             info!("HID key down");
+                            self.current_report = report;
                             self.state = UsbHidKeyboardHandlerState::KeyDown;
                             return Ok(resp);
                         }
@@ -124,6 +313,7 @@ impl UsbInterfaceHandler for UsbHidKeyboardHandler {
                     UsbHidKeyboardHandlerState::KeyDown => {
                         let resp = vec![0; 6];
                         info!("HID key up");
+                        self.current_report = UsbHidKeyboardReport::default();
                         self.state = UsbHidKeyboardHandlerState::Idle;
                         return Ok(resp);
                     }
@@ -134,17 +324,11 @@ impl UsbInterfaceHandler for UsbHidKeyboardHandler {
     }
 
     fn get_class_specific_descriptor(&self) -> Vec<u8> {
-        return vec![
-            0x09,                         // bLength
-            HidDescriptorType::Hid as u8, // bDescriptorType: HID
-            0x11,
-            0x01,                            // bcdHID 1.11
-            0x00,                            // bCountryCode
-            0x01,                            // bNumDescriptors
-            HidDescriptorType::Report as u8, // bDescriptorType[0] HID
-            self.report_descriptor.len() as u8,
-            (self.report_descriptor.len() >> 8) as u8, // wDescriptorLength[0]
-        ];
+        hid_descriptor(self.report_descriptor.len())
+    }
+
+    fn endpoints(&self) -> Vec<UsbEndpoint> {
+        Self::endpoints()
     }
 
     fn as_any(&mut self) -> &mut dyn Any {
@@ -152,6 +336,622 @@ impl UsbInterfaceHandler for UsbHidKeyboardHandler {
     }
 }
 
+/// A high level input event, see [HidInputTranslator]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HidInputEvent {
+    /// Key with the given usage ID of the Keyboard/Keypad page is pressed
+    KeyPress(u8),
+    /// Key with the given usage ID of the Keyboard/Keypad page is released
+    KeyRelease(u8),
+    /// Pointer moved relatively
+    PointerMotion { dx: i8, dy: i8 },
+    /// Wheel scrolled
+    Wheel(i8),
+    /// Mouse button (0 is the primary button) is pressed
+    ///
+    /// The boot mouse report has 8 buttons, 0..=7, others are ignored.
+    ButtonPress(u8),
+    /// Mouse button (0 is the primary button) is released, 0..=7 as above
+    ButtonRelease(u8),
+}
+
+/// A report of a HID boot protocol mouse
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UsbHidMouseReport {
+    /// Button bitmap
+    pub buttons: u8,
+    pub dx: i8,
+    pub dy: i8,
+    pub wheel: i8,
+}
+
+impl UsbHidMouseReport {
+    /// Encode as a boot mouse input report with wheel
+    pub fn to_bytes(&self) -> Vec<u8> {
+        vec![self.buttons, self.dx as u8, self.dy as u8, self.wheel as u8]
+    }
+}
+
+/// A HID report produced by [HidInputTranslator]
+#[derive(Clone, Debug)]
+pub enum HidReport {
+    Keyboard(UsbHidKeyboardReport),
+    Mouse(UsbHidMouseReport),
+}
+
+/// Usage ID of ErrorRollOver, reported in every key slot when too many keys are pressed
+pub const HID_KEY_ERROR_ROLL_OVER: u8 = 0x01;
+
+/// Bit of `button` in the mouse button bitmap, none for buttons above 7
+fn button_bit(button: u8) -> u8 {
+    1u8.checked_shl(button as u32).unwrap_or(0)
+}
+
+/// Translate [HidInputEvent]s into HID reports
+///
+/// Tracks pressed keys and buttons, so that every produced report describes
+/// the full current state as HID requires. Modifier keys (usage 0xE0 to 0xE7)
+/// go into the modifier byte. When more than 6 other keys are pressed, the
+/// report signals a rollover error until enough of them are released.
+#[derive(Clone, Debug, Default)]
+pub struct HidInputTranslator {
+    modifier: u8,
+    keys: Vec<u8>,
+    buttons: u8,
+}
+
+impl HidInputTranslator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Update the state with `event` and return the resulting report
+    pub fn translate(&mut self, event: HidInputEvent) -> HidReport {
+        use HidInputEvent::*;
+        match event {
+            KeyPress(key @ 0xE0..=0xE7) => self.modifier |= 1 << (key - 0xE0),
+            KeyRelease(key @ 0xE0..=0xE7) => self.modifier &= !(1 << (key - 0xE0)),
+            KeyPress(key) => {
+                if !self.keys.contains(&key) {
+                    self.keys.push(key);
+                }
+            }
+            KeyRelease(key) => self.keys.retain(|k| *k != key),
+            ButtonPress(button) => self.buttons |= button_bit(button),
+            ButtonRelease(button) => self.buttons &= !button_bit(button),
+            PointerMotion { dx, dy } => {
+                return HidReport::Mouse(UsbHidMouseReport {
+                    buttons: self.buttons,
+                    dx,
+                    dy,
+                    wheel: 0,
+                })
+            }
+            Wheel(wheel) => {
+                return HidReport::Mouse(UsbHidMouseReport {
+                    buttons: self.buttons,
+                    wheel,
+                    ..Default::default()
+                })
+            }
+        }
+        match event {
+            ButtonPress(_) | ButtonRelease(_) => HidReport::Mouse(UsbHidMouseReport {
+                buttons: self.buttons,
+                ..Default::default()
+            }),
+            _ => HidReport::Keyboard(self.keyboard_report()),
+        }
+    }
+
+    fn keyboard_report(&self) -> UsbHidKeyboardReport {
+        let mut keys = [0u8; 6];
+        if self.keys.len() > keys.len() {
+            keys = [HID_KEY_ERROR_ROLL_OVER; 6];
+        } else {
+            keys[..self.keys.len()].copy_from_slice(&self.keys);
+        }
+        UsbHidKeyboardReport {
+            modifier: self.modifier,
+            keys,
+        }
+    }
+}
+
+/// Feed input events received from `rx` to a [UsbHidKeyboardHandler] until the channel closes
+///
+/// Keyboard reports are queued with [UsbHidKeyboardHandler::push_key_report].
+/// Pointer and button events are dropped with a warning since the handler is
+/// a keyboard. Fails with [std::io::ErrorKind::InvalidInput] if `handler` is
+/// not a [UsbHidKeyboardHandler].
+pub async fn forward_keyboard_input(
+    mut rx: tokio::sync::mpsc::Receiver<HidInputEvent>,
+    handler: Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>>,
+) -> Result<()> {
+    let mut translator = HidInputTranslator::new();
+    while let Some(event) = rx.recv().await {
+        match translator.translate(event) {
+            HidReport::Keyboard(report) => {
+                let mut handler = handler.lock().unwrap();
+                let keyboard = handler
+                    .as_any()
+                    .downcast_mut::<UsbHidKeyboardHandler>()
+                    .ok_or_else(|| {
+                        std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            "handler is not a UsbHidKeyboardHandler",
+                        )
+                    })?;
+                keyboard.push_key_report(report.modifier, &report.keys);
+            }
+            HidReport::Mouse(_) => warn!("Dropped pointer event {:?} of a keyboard", event),
+        }
+    }
+    Ok(())
+}
+
+/// A list of defined HID class specific requests
+#[derive(Copy, Clone, Debug, PartialEq, Eq, FromPrimitive)]
+pub enum HidRequest {
+    GetReport = 0x01,
+    GetIdle = 0x02,
+    GetProtocol = 0x03,
+    SetReport = 0x09,
+    SetIdle = 0x0A,
+    SetProtocol = 0x0B,
+}
+
+/// Type of a HID report, high byte of wValue in GET_REPORT and SET_REPORT
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, FromPrimitive)]
+pub enum HidReportType {
+    Input = 1,
+    Output,
+    Feature,
+}
+
+/// Whether a report descriptor declares Report ID items
+///
+/// If it does, every report on the wire starts with its report ID.
+pub fn hid_uses_report_ids(report_descriptor: &[u8]) -> bool {
+    let mut offset = 0;
+    while offset < report_descriptor.len() {
+        let prefix = report_descriptor[offset];
+        if prefix == 0xFE {
+            // long item: bDataSize, bLongItemTag, data
+            let size = report_descriptor.get(offset + 1).copied().unwrap_or(0) as usize;
+            offset += 3 + size;
+            continue;
+        }
+        // Report ID global item with one byte of data
+        if prefix & 0xFC == 0x84 {
+            return true;
+        }
+        offset += 1 + [0, 1, 2, 4][(prefix & 0x3) as usize];
+    }
+    false
+}
+
+/// Flags of Input, Output and Feature items, Data, Array and Absolute are 0
+pub const HID_MAIN_CONSTANT: u32 = 0x01;
+pub const HID_MAIN_VARIABLE: u32 = 0x02;
+pub const HID_MAIN_RELATIVE: u32 = 0x04;
+pub const HID_MAIN_WRAP: u32 = 0x08;
+pub const HID_MAIN_NON_LINEAR: u32 = 0x10;
+pub const HID_MAIN_NO_PREFERRED: u32 = 0x20;
+pub const HID_MAIN_NULL_STATE: u32 = 0x40;
+pub const HID_MAIN_VOLATILE: u32 = 0x80;
+pub const HID_MAIN_BUFFERED_BYTES: u32 = 0x100;
+
+/// Type of a Collection item
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HidCollection {
+    Physical = 0x00,
+    Application,
+    Logical,
+    Report,
+    NamedArray,
+    UsageSwitch,
+    UsageModifier,
+}
+
+/// Assemble a report descriptor from short items
+///
+/// Each item takes the fewest data bytes holding its value: 1, 2 or 4.
+/// Values of logical and physical extents and unit exponents are signed, so
+/// e.g. Logical Maximum 255 takes two bytes. Collections must be balanced.
+///
+/// ```
+/// use usbip::hid::*;
+/// let report_descriptor = HidReportDescriptorBuilder::new()
+///     .usage_page(0x01) // Generic Desktop
+///     .usage(0x05) // Game Pad
+///     .collection(HidCollection::Application)
+///     .usage_page(0x09) // Button
+///     .usage_minimum(1)
+///     .usage_maximum(16)
+///     .logical_minimum(0)
+///     .logical_maximum(1)
+///     .report_size(1)
+///     .report_count(16)
+///     .input(HID_MAIN_VARIABLE)
+///     .end_collection()
+///     .build();
+/// assert_eq!(report_descriptor[..4], [0x05, 0x01, 0x09, 0x05]);
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct HidReportDescriptorBuilder {
+    bytes: Vec<u8>,
+    depth: usize,
+}
+
+impl HidReportDescriptorBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append a short item with unsigned data
+    ///
+    /// `item_type` is 0 for main, 1 for global and 2 for local items.
+    pub fn item(mut self, item_type: u8, tag: u8, data: u32) -> Self {
+        let len = match data {
+            0..=0xFF => 1,
+            0x100..=0xFFFF => 2,
+            _ => 4,
+        };
+        self.push_item(item_type, tag, &data.to_le_bytes()[..len]);
+        self
+    }
+
+    /// Append a short item with signed data
+    pub fn signed_item(mut self, item_type: u8, tag: u8, data: i32) -> Self {
+        let len = match data {
+            -0x80..=0x7F => 1,
+            -0x8000..=0x7FFF => 2,
+            _ => 4,
+        };
+        self.push_item(item_type, tag, &data.to_le_bytes()[..len]);
+        self
+    }
+
+    fn push_item(&mut self, item_type: u8, tag: u8, data: &[u8]) {
+        assert!(item_type < 3 && tag < 0x10, "not a short item");
+        let size = match data.len() {
+            0 => 0,
+            1 => 1,
+            2 => 2,
+            _ => 3,
+        };
+        self.bytes.push(tag << 4 | item_type << 2 | size);
+        self.bytes.extend_from_slice(data);
+    }
+
+    /// Input item with `HID_MAIN_*` flags
+    pub fn input(self, flags: u32) -> Self {
+        self.item(0, 0x8, flags)
+    }
+
+    /// Output item with `HID_MAIN_*` flags
+    pub fn output(self, flags: u32) -> Self {
+        self.item(0, 0x9, flags)
+    }
+
+    /// Feature item with `HID_MAIN_*` flags
+    pub fn feature(self, flags: u32) -> Self {
+        self.item(0, 0xB, flags)
+    }
+
+    pub fn collection(mut self, collection: HidCollection) -> Self {
+        self.depth += 1;
+        self.item(0, 0xA, collection as u32)
+    }
+
+    pub fn end_collection(mut self) -> Self {
+        assert!(self.depth > 0, "End Collection without Collection");
+        self.depth -= 1;
+        self.push_item(0, 0xC, &[]);
+        self
+    }
+
+    pub fn usage_page(self, usage_page: u16) -> Self {
+        self.item(1, 0x0, usage_page as u32)
+    }
+
+    pub fn logical_minimum(self, value: i32) -> Self {
+        self.signed_item(1, 0x1, value)
+    }
+
+    pub fn logical_maximum(self, value: i32) -> Self {
+        self.signed_item(1, 0x2, value)
+    }
+
+    pub fn physical_minimum(self, value: i32) -> Self {
+        self.signed_item(1, 0x3, value)
+    }
+
+    pub fn physical_maximum(self, value: i32) -> Self {
+        self.signed_item(1, 0x4, value)
+    }
+
+    pub fn unit_exponent(self, exponent: i32) -> Self {
+        self.signed_item(1, 0x5, exponent)
+    }
+
+    pub fn unit(self, unit: u32) -> Self {
+        self.item(1, 0x6, unit)
+    }
+
+    /// Report Size in bits
+    pub fn report_size(self, bits: u32) -> Self {
+        self.item(1, 0x7, bits)
+    }
+
+    /// Report ID, reports on the wire then start with it, see [hid_uses_report_ids]
+    pub fn report_id(self, id: u8) -> Self {
+        assert!(id != 0, "report ID 0 is reserved");
+        self.item(1, 0x8, id as u32)
+    }
+
+    pub fn report_count(self, count: u32) -> Self {
+        self.item(1, 0x9, count)
+    }
+
+    pub fn push(mut self) -> Self {
+        self.push_item(1, 0xA, &[]);
+        self
+    }
+
+    pub fn pop(mut self) -> Self {
+        self.push_item(1, 0xB, &[]);
+        self
+    }
+
+    /// Usage, a 32 bit value is an extended usage with the usage page in the high half
+    pub fn usage(self, usage: u32) -> Self {
+        self.item(2, 0x0, usage)
+    }
+
+    pub fn usage_minimum(self, usage: u32) -> Self {
+        self.item(2, 0x1, usage)
+    }
+
+    pub fn usage_maximum(self, usage: u32) -> Self {
+        self.item(2, 0x2, usage)
+    }
+
+    /// The report descriptor, panics if a collection is still open
+    pub fn build(self) -> Vec<u8> {
+        assert!(self.depth == 0, "unterminated collection");
+        self.bytes
+    }
+}
+
+/// A generic HID handler with any report descriptor
+///
+/// Keeps the last report of each type and report ID, answering GET_REPORT
+/// from it and updating it on SET_REPORT and interrupt OUT. When the report
+/// descriptor declares report IDs, reports are selected by the low byte of
+/// wValue and carry their ID as the first byte on the wire; otherwise the
+/// report ID is always 0 and reports are sent as is.
+///
+/// In the boot protocol, selected by SET_PROTOCOL, input reports go on the
+/// wire without their ID. Use [HID_SUBCLASS_BOOT] and [HID_PROTOCOL_KEYBOARD] or
+/// [HID_PROTOCOL_MOUSE] for the interface when the input reports follow the
+/// boot format, 0 and 0 otherwise.
+#[derive(Clone)]
+pub struct UsbHidHandler {
+    pub report_descriptor: Vec<u8>,
+    /// Last report by type and report ID, without the report ID prefix
+    pub reports: HashMap<(HidReportType, u8), Vec<u8>>,
+    /// Input reports to send on the interrupt IN endpoint, with their report ID
+    pub pending_input_reports: VecDeque<(u8, Vec<u8>)>,
+    /// Protocol selected by the host
+    pub protocol: HidProtocol,
+    /// Idle rates set by SET_IDLE by report ID, in units of 4ms, 0 for
+    /// indefinite; ID 0 holds the rate of reports without a rate of their own
+    ///
+    /// While no new input report is queued, the last one of an ID with a non
+    /// zero rate is sent again once the rate elapsed, other IN transfers NAK.
+    pub idle_rates: HashMap<u8, u8>,
+    /// When the last input report of each ID was sent
+    input_sent: HashMap<u8, tokio::time::Instant>,
+    report_ids: bool,
+}
+
+impl UsbHidHandler {
+    pub fn new(report_descriptor: Vec<u8>) -> Self {
+        Self {
+            report_ids: hid_uses_report_ids(&report_descriptor),
+            report_descriptor,
+            reports: HashMap::new(),
+            pending_input_reports: VecDeque::new(),
+            protocol: HidProtocol::Report,
+            idle_rates: HashMap::new(),
+            input_sent: HashMap::new(),
+        }
+    }
+
+    /// Idle rate of input report `id`, see [UsbHidHandler::idle_rates]
+    pub fn idle_rate(&self, id: u8) -> u8 {
+        self.idle_rates
+            .get(&id)
+            .or_else(|| self.idle_rates.get(&0))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// The input report whose idle rate elapsed the longest ago, if any
+    fn idle_report(&self) -> Option<u8> {
+        let now = tokio::time::Instant::now();
+        self.input_sent
+            .iter()
+            .filter_map(|(id, sent)| {
+                let rate = self.idle_rate(*id);
+                let due = *sent + Duration::from_millis(rate as u64 * 4);
+                (rate != 0 && due <= now).then_some((due, *id))
+            })
+            .min()
+            .map(|(_, id)| id)
+    }
+
+    /// Queue input report `id` to be sent, use 0 without report IDs
+    pub fn send_input_report(&mut self, id: u8, report: Vec<u8>) {
+        self.pending_input_reports.push_back((id, report));
+    }
+
+    /// Report `report` on the wire, prefixed with `id` if report IDs are used
+    fn encode(&self, id: u8, report: &[u8]) -> Vec<u8> {
+        let mut resp = vec![];
+        if self.report_ids && self.protocol == HidProtocol::Report {
+            resp.push(id);
+        }
+        resp.extend_from_slice(report);
+        resp
+    }
+
+    /// Split a report from the wire into its report ID and data
+    fn decode<'a>(&self, data: &'a [u8]) -> Result<(u8, &'a [u8])> {
+        if !self.report_ids {
+            return Ok((0, data));
+        }
+        match data.split_first() {
+            Some((id, report)) if *id != 0 => Ok((*id, report)),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "missing report ID",
+            )),
+        }
+    }
+}
+
+impl UsbInterfaceHandler for UsbHidHandler {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        ep: UsbEndpoint,
+        setup: Option<SetupPacket>,
+        req: &[u8],
+    ) -> Result<Vec<u8>> {
+        let stall = |msg: &str| Err(std::io::Error::new(std::io::ErrorKind::BrokenPipe, msg));
+        let setup = match setup {
+            Some(setup) => setup,
+            // interrupt transfer
+            None => {
+                return match ep.direction() {
+                    Direction::In => {
+                        let now = tokio::time::Instant::now();
+                        if let Some((id, report)) = self.pending_input_reports.pop_front() {
+                            let resp = self.encode(id, &report);
+                            self.reports.insert((HidReportType::Input, id), report);
+                            self.input_sent.insert(id, now);
+                            return Ok(resp);
+                        }
+                        // repeat an unchanged report once its idle rate elapsed
+                        let id = self.idle_report().ok_or_else(|| {
+                            std::io::Error::new(std::io::ErrorKind::WouldBlock, "no input report")
+                        })?;
+                        self.input_sent.insert(id, now);
+                        Ok(self.encode(id, &self.reports[&(HidReportType::Input, id)]))
+                    }
+                    Direction::Out => {
+                        let (id, report) = self.decode(req)?;
+                        self.reports
+                            .insert((HidReportType::Output, id), report.to_vec());
+                        Ok(vec![])
+                    }
+                };
+            }
+        };
+
+        if setup.request_type == 0b10000001 && setup.request == StandardRequest::GetDescriptor as u8
+        {
+            return match FromPrimitive::from_u16(setup.value >> 8) {
+                Some(HidDescriptorType::Report) => Ok(self.report_descriptor.clone()),
+                _ => stall("unsupported HID descriptor"),
+            };
+        }
+
+        // GET_REPORT and SET_REPORT: report type and report ID
+        let report_type: Option<HidReportType> = FromPrimitive::from_u16(setup.value >> 8);
+        let id = setup.value as u8;
+        let report_type = report_type.filter(|_| self.report_ids == (id != 0));
+        match (setup.request_type, FromPrimitive::from_u8(setup.request)) {
+            (0b10100001, Some(HidRequest::GetReport)) => {
+                let report_type = match report_type {
+                    Some(report_type) => report_type,
+                    None => return stall("bad report type or ID"),
+                };
+                match self.reports.get(&(report_type, id)) {
+                    Some(report) => {
+                        let mut resp = self.encode(id, report);
+                        resp.truncate(setup.length as usize);
+                        Ok(resp)
+                    }
+                    None => stall("no such report"),
+                }
+            }
+            (0b00100001, Some(HidRequest::SetReport)) => {
+                let report_type = match report_type {
+                    Some(report_type) => report_type,
+                    None => return stall("bad report type or ID"),
+                };
+                let (data_id, report) = self.decode(req)?;
+                if data_id != id {
+                    return stall("report ID differs from wValue");
+                }
+                self.reports.insert((report_type, id), report.to_vec());
+                Ok(vec![])
+            }
+            (0b10100001, Some(HidRequest::GetProtocol)) => Ok(vec![self.protocol as u8]),
+            (0b00100001, Some(HidRequest::SetProtocol)) => {
+                self.protocol = set_protocol(&setup)?;
+                Ok(vec![])
+            }
+            // report ID in the low byte of wValue, 0 for all reports
+            (0b10100001, Some(HidRequest::GetIdle)) => Ok(vec![self.idle_rate(id)]),
+            (0b00100001, Some(HidRequest::SetIdle)) => {
+                if id == 0 {
+                    self.idle_rates.clear();
+                }
+                self.idle_rates.insert(id, (setup.value >> 8) as u8);
+                Ok(vec![])
+            }
+            _ => {
+                warn!("HID unsupported request {:x?}", setup);
+                stall("unsupported HID request")
+            }
+        }
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        hid_descriptor(self.report_descriptor.len())
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// Protocol requested by SET_PROTOCOL `setup`, stalling unknown ones
+fn set_protocol(setup: &SetupPacket) -> Result<HidProtocol> {
+    FromPrimitive::from_u16(setup.value)
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::BrokenPipe, "unknown HID protocol"))
+}
+
+/// HID descriptor pointing to a single report descriptor of `report_descriptor_len` bytes
+fn hid_descriptor(report_descriptor_len: usize) -> Vec<u8> {
+    vec![
+        0x09,                         // bLength
+        HidDescriptorType::Hid as u8, // bDescriptorType: HID
+        0x11,
+        0x01,                            // bcdHID 1.11
+        0x00,                            // bCountryCode
+        0x01,                            // bNumDescriptors
+        HidDescriptorType::Report as u8, // bDescriptorType[0] HID
+        report_descriptor_len as u8,
+        (report_descriptor_len >> 8) as u8, // wDescriptorLength[0]
+    ]
+}
+
 /// A list of defined HID descriptor type
 #[derive(Copy, Clone, Debug, FromPrimitive)]
 pub enum HidDescriptorType {
@@ -169,4 +969,474 @@ mod tests {
         let handler = UsbHidKeyboardHandler::new_keyboard();
         verify_descriptor(&handler.get_class_specific_descriptor());
     }
+
+    #[tokio::test]
+    async fn forward_key_press_release() {
+        let handler =
+            Arc::new(Mutex::new(Box::new(UsbHidKeyboardHandler::new_keyboard())
+                as Box<dyn UsbInterfaceHandler + Send>));
+        let (tx, rx) = tokio::sync::mpsc::channel(4);
+        tx.send(HidInputEvent::KeyPress(0x04)).await.unwrap();
+        tx.send(HidInputEvent::KeyRelease(0x04)).await.unwrap();
+        drop(tx);
+        forward_keyboard_input(rx, handler.clone()).await.unwrap();
+
+        let intf = UsbDevice::new(0)
+            .with_interface(ClassCode::HID as u8, 0, 0, "HID", vec![], handler.clone())
+            .interfaces
+            .remove(0);
+        let ep = UsbEndpoint {
+            address: 0x81,
+            attributes: EndpointAttributes::Interrupt as u8,
+            max_packet_size: 0x08,
+            interval: 10,
+        };
+        let mut handler = handler.lock().unwrap();
+        let mut poll = || handler.handle_urb(&intf, ep, None, &[]).unwrap();
+        assert_eq!(poll(), [0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(poll(), [0x00; 8]);
+        assert_eq!(poll(), [] as [u8; 0]);
+    }
+
+    #[tokio::test]
+    async fn forward_to_other_handler() {
+        let handler = Arc::new(Mutex::new(
+            Box::new(UsbHidHandler::new(vec![])) as Box<dyn UsbInterfaceHandler + Send>
+        ));
+        let (tx, rx) = tokio::sync::mpsc::channel(4);
+        // a pointer event is dropped, a key press fails
+        tx.send(HidInputEvent::ButtonPress(0)).await.unwrap();
+        tx.send(HidInputEvent::KeyPress(0x04)).await.unwrap();
+        drop(tx);
+        let err = forward_keyboard_input(rx, handler).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn translate_modifier_and_rollover() {
+        let mut translator = HidInputTranslator::new();
+        // left shift
+        translator.translate(HidInputEvent::KeyPress(0xE1));
+        for key in 0x04..0x0B {
+            translator.translate(HidInputEvent::KeyPress(key));
+        }
+        match translator.translate(HidInputEvent::KeyPress(0x04)) {
+            HidReport::Keyboard(report) => {
+                assert_eq!(report.modifier, 0x02);
+                assert_eq!(report.keys, [HID_KEY_ERROR_ROLL_OVER; 6]);
+            }
+            _ => panic!("expected keyboard report"),
+        }
+        match translator.translate(HidInputEvent::KeyRelease(0x0A)) {
+            HidReport::Keyboard(report) => {
+                assert_eq!(report.keys, [0x04, 0x05, 0x06, 0x07, 0x08, 0x09])
+            }
+            _ => panic!("expected keyboard report"),
+        }
+    }
+
+    #[test]
+    fn translate_out_of_range_button() {
+        let mut translator = HidInputTranslator::new();
+        translator.translate(HidInputEvent::ButtonPress(7));
+        for button in [8, 9, 255] {
+            match translator.translate(HidInputEvent::ButtonPress(button)) {
+                HidReport::Mouse(report) => assert_eq!(report.buttons, 0x80),
+                _ => panic!("expected mouse report"),
+            }
+            match translator.translate(HidInputEvent::ButtonRelease(button)) {
+                HidReport::Mouse(report) => assert_eq!(report.buttons, 0x80),
+                _ => panic!("expected mouse report"),
+            }
+        }
+    }
+
+    fn hid_request(request_type: u8, request: HidRequest, value: u16) -> SetupPacket {
+        SetupPacket {
+            request_type,
+            request: request as u8,
+            value,
+            index: 0,
+            length: 0x40,
+        }
+    }
+
+    #[tokio::test]
+    async fn boot_keyboard() {
+        let (device, handler) =
+            with_boot_keyboard(UsbDevice::new(0), UsbHidKeyboardHandler::new_keyboard());
+        let config = device.configuration_descriptor();
+        verify_descriptor(&config);
+        let intf = &config[9..];
+        assert_eq!(intf[1], DescriptorType::Interface as u8);
+        // bInterfaceClass, bInterfaceSubClass, bInterfaceProtocol
+        assert_eq!(intf[5..8], [ClassCode::HID as u8, 1, 1]);
+
+        let ctx = ConnectionContext::default();
+        let intf = Some(&device.interfaces[0]);
+        let get_protocol = [0xA1, 0x03, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00];
+        let reply = device.handle_urb(&ctx, device.ep0_in, intf, get_protocol, &[]);
+        assert_eq!(reply.await.unwrap(), [HidProtocol::Report as u8]);
+        // SET_PROTOCOL(boot)
+        let set_protocol = [0x21, 0x0B, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+        let reply = device.handle_urb(&ctx, device.ep0_out, intf, set_protocol, &[]);
+        reply.await.unwrap();
+        let reply = device.handle_urb(&ctx, device.ep0_in, intf, get_protocol, &[]);
+        assert_eq!(reply.await.unwrap(), [HidProtocol::Boot as u8]);
+        let mut handler = handler.lock().unwrap();
+        let keyboard = handler
+            .as_any()
+            .downcast_mut::<UsbHidKeyboardHandler>()
+            .unwrap();
+        assert_eq!(keyboard.protocol, HidProtocol::Boot);
+    }
+
+    #[tokio::test]
+    async fn keyboard_reports() {
+        let keyboard = UsbHidKeyboard::new();
+        let device = keyboard.add_to(UsbDevice::new(0));
+        let ctx = ConnectionContext::default();
+        let intf = Some(&device.interfaces[0]);
+        let ep = UsbHidKeyboardHandler::endpoints()[0];
+        let get_report = [0xA1, 0x01, 0x00, 0x01, 0x00, 0x00, 0x08, 0x00];
+
+        keyboard.push_key_report(0x02, &[0x04, 0x05]);
+        let reply = device.handle_urb(&ctx, device.ep0_in, intf, get_report, &[]);
+        assert_eq!(reply.await.unwrap(), [0; 8]);
+        let reply = device.handle_urb(&ctx, ep, intf, [0; 8], &[]);
+        assert_eq!(reply.await.unwrap(), [0x02, 0x00, 0x04, 0x05, 0, 0, 0, 0]);
+        // GET_REPORT(input) returns the keys held down
+        let reply = device.handle_urb(&ctx, device.ep0_in, intf, get_report, &[]);
+        assert_eq!(reply.await.unwrap(), [0x02, 0x00, 0x04, 0x05, 0, 0, 0, 0]);
+
+        // too many keys roll over
+        keyboard.push_key_report(0x00, &[4, 5, 6, 7, 8, 9, 10]);
+        let reply = device.handle_urb(&ctx, ep, intf, [0; 8], &[]);
+        assert_eq!(reply.await.unwrap()[2..], [HID_KEY_ERROR_ROLL_OVER; 6]);
+
+        // SET_IDLE(500ms) then GET_IDLE
+        let set_idle = [0x21, 0x0A, 0x00, 0x7D, 0x00, 0x00, 0x00, 0x00];
+        let reply = device.handle_urb(&ctx, device.ep0_out, intf, set_idle, &[]);
+        reply.await.unwrap();
+        let get_idle = [0xA1, 0x02, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00];
+        let reply = device.handle_urb(&ctx, device.ep0_in, intf, get_idle, &[]);
+        assert_eq!(reply.await.unwrap(), [0x7D]);
+        assert_eq!(keyboard.with_keyboard(|keyboard| keyboard.idle_rate), 0x7D);
+
+        // a keyboard has no output report to get
+        let get_output = [0xA1, 0x01, 0x00, 0x02, 0x00, 0x00, 0x01, 0x00];
+        let reply = device.handle_urb(&ctx, device.ep0_in, intf, get_output, &[]);
+        assert!(matches!(reply.await, Err(UsbIpError::Stall)));
+    }
+
+    #[test]
+    fn boot_protocol_drops_report_id() {
+        let report_descriptor = HidReportDescriptorBuilder::new()
+            .usage_page(0x01)
+            .usage(0x02)
+            .collection(HidCollection::Application)
+            .report_id(1)
+            .report_size(8)
+            .report_count(3)
+            .input(HID_MAIN_VARIABLE | HID_MAIN_RELATIVE)
+            .end_collection()
+            .build();
+        let mut handler = UsbHidHandler::new(report_descriptor);
+        let intf = UsbDevice::new(0)
+            .with_interface(
+                ClassCode::HID as u8,
+                HID_SUBCLASS_BOOT,
+                HID_PROTOCOL_MOUSE,
+                "Mouse",
+                vec![],
+                Arc::new(Mutex::new(
+                    Box::new(handler.clone()) as Box<dyn UsbInterfaceHandler + Send>
+                )),
+            )
+            .interfaces
+            .remove(0);
+        let ep = UsbEndpoint::new(
+            0x81,
+            EndpointAttributes::Interrupt,
+            SyncType::NoSync,
+            UsageType::Data,
+            4,
+            10,
+        );
+        let ep0 = UsbEndpoint {
+            address: 0x00,
+            ..ep
+        };
+        handler.send_input_report(1, vec![1, 2, 3]);
+        let reply = handler.handle_urb(&intf, ep, None, &[]);
+        assert_eq!(reply.unwrap(), [1, 1, 2, 3]);
+
+        let set_boot = hid_request(0b00100001, HidRequest::SetProtocol, 0);
+        handler.handle_urb(&intf, ep0, Some(set_boot), &[]).unwrap();
+        handler.send_input_report(1, vec![1, 2, 3]);
+        let reply = handler.handle_urb(&intf, ep, None, &[]);
+        assert_eq!(reply.unwrap(), [1, 2, 3]);
+
+        // protocols other than boot and report stall
+        let set_unknown = hid_request(0b00100001, HidRequest::SetProtocol, 2);
+        let err = handler
+            .handle_urb(&intf, ep0, Some(set_unknown), &[])
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+        assert_eq!(handler.protocol, HidProtocol::Boot);
+    }
+
+    #[test]
+    fn report_descriptor_builder() {
+        let keyboard = HidReportDescriptorBuilder::new()
+            .usage_page(0x01)
+            .usage(0x06)
+            .collection(HidCollection::Application)
+            .usage_page(0x07)
+            .usage_minimum(0xE0)
+            .usage_maximum(0xE7)
+            .logical_minimum(0)
+            .logical_maximum(1)
+            .report_size(1)
+            .report_count(8)
+            .input(HID_MAIN_VARIABLE)
+            .report_count(1)
+            .report_size(8)
+            .input(HID_MAIN_CONSTANT)
+            .report_count(6)
+            .report_size(8)
+            .logical_minimum(0)
+            .logical_maximum(0x65)
+            .usage_page(0x07)
+            .usage_minimum(0x00)
+            .usage_maximum(0x65)
+            .input(0)
+            .end_collection()
+            .build();
+        assert_eq!(
+            keyboard,
+            UsbHidKeyboardHandler::new_keyboard().report_descriptor
+        );
+
+        // multi-byte data
+        let desc = HidReportDescriptorBuilder::new()
+            .usage_page(0xFF00)
+            .logical_minimum(-1)
+            .logical_maximum(255)
+            .physical_minimum(-32768)
+            .physical_maximum(70000)
+            .usage(0x0001_0030)
+            .report_id(2)
+            .feature(HID_MAIN_VARIABLE | HID_MAIN_BUFFERED_BYTES)
+            .build();
+        assert_eq!(
+            desc,
+            [
+                0x06, 0x00, 0xFF, // Usage Page (Vendor Defined)
+                0x15, 0xFF, // Logical Minimum (-1)
+                0x26, 0xFF, 0x00, // Logical Maximum (255)
+                0x36, 0x00, 0x80, // Physical Minimum (-32768)
+                0x47, 0x70, 0x11, 0x01, 0x00, // Physical Maximum (70000)
+                0x0B, 0x30, 0x00, 0x01, 0x00, // Usage (Generic Desktop X)
+                0x85, 0x02, // Report ID (2)
+                0xB2, 0x02, 0x01, // Feature (Variable, Buffered Bytes)
+            ]
+        );
+        assert!(hid_uses_report_ids(&desc));
+    }
+
+    #[test]
+    #[should_panic(expected = "unterminated collection")]
+    fn report_descriptor_unterminated_collection() {
+        HidReportDescriptorBuilder::new()
+            .collection(HidCollection::Physical)
+            .build();
+    }
+
+    #[test]
+    fn report_ids() {
+        let report_descriptor = vec![
+            0x06, 0x00, 0xFF, // Usage Page (Vendor Defined)
+            0x09, 0x01, // Usage (1)
+            0xA1, 0x01, // Collection (Application)
+            0x85, 0x01, // Report ID (1)
+            0x75, 0x08, // Report Size (8)
+            0x95, 0x02, // Report Count (2)
+            0x09, 0x02, // Usage (2)
+            0x81, 0x02, // Input
+            0x85, 0x02, // Report ID (2)
+            0x95, 0x01, // Report Count (1)
+            0x09, 0x03, // Usage (3)
+            0xB1, 0x02, // Feature
+            0xC0, // End collection
+        ];
+        assert!(hid_uses_report_ids(&report_descriptor));
+        let mut handler = UsbHidHandler::new(report_descriptor);
+        verify_descriptor(&handler.get_class_specific_descriptor());
+        let intf = UsbDevice::new(0)
+            .with_interface(
+                ClassCode::HID as u8,
+                0,
+                0,
+                "HID",
+                vec![],
+                Arc::new(Mutex::new(
+                    Box::new(UsbHidHandler::new(vec![])) as Box<dyn UsbInterfaceHandler + Send>
+                )),
+            )
+            .interfaces
+            .remove(0);
+        let ep0 = UsbEndpoint::default();
+        let ep_in = UsbEndpoint {
+            address: 0x81,
+            attributes: EndpointAttributes::Interrupt as u8,
+            max_packet_size: 0x08,
+            interval: 10,
+        };
+
+        // SET_REPORT(Feature, 2) carries the ID in its first byte
+        handler
+            .handle_urb(
+                &intf,
+                ep0,
+                Some(hid_request(0x21, HidRequest::SetReport, 0x0302)),
+                &[0x02, 0x55],
+            )
+            .unwrap();
+        assert_eq!(handler.reports[&(HidReportType::Feature, 2)], [0x55]);
+        let resp = handler
+            .handle_urb(
+                &intf,
+                ep0,
+                Some(hid_request(0xA1, HidRequest::GetReport, 0x0302)),
+                &[],
+            )
+            .unwrap();
+        assert_eq!(resp, [0x02, 0x55]);
+        // mismatched ID, and report ID 0 which is reserved
+        for (value, data) in [(0x0301, &[0x02, 0x55]), (0x0300, &[0x00, 0x55])].iter() {
+            let err = handler
+                .handle_urb(
+                    &intf,
+                    ep0,
+                    Some(hid_request(0x21, HidRequest::SetReport, *value)),
+                    *data,
+                )
+                .unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+        }
+
+        // interrupt IN reports are prefixed with their ID
+        handler.send_input_report(1, vec![0x10, 0x20]);
+        let resp = handler.handle_urb(&intf, ep_in, None, &[]).unwrap();
+        assert_eq!(resp, [0x01, 0x10, 0x20]);
+        let resp = handler
+            .handle_urb(
+                &intf,
+                ep0,
+                Some(hid_request(0xA1, HidRequest::GetReport, 0x0101)),
+                &[],
+            )
+            .unwrap();
+        assert_eq!(resp, [0x01, 0x10, 0x20]);
+        // nothing queued: NAK
+        let err = handler.handle_urb(&intf, ep_in, None, &[]).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
+    }
+
+    #[tokio::test]
+    async fn idle_and_protocol() {
+        tokio::time::pause();
+        let mut handler =
+            UsbHidHandler::new(UsbHidKeyboardHandler::new_keyboard().report_descriptor);
+        let intf = UsbDevice::new(0)
+            .with_interface(
+                ClassCode::HID as u8,
+                0,
+                0,
+                "HID",
+                vec![],
+                Arc::new(Mutex::new(
+                    Box::new(UsbHidHandler::new(vec![])) as Box<dyn UsbInterfaceHandler + Send>
+                )),
+            )
+            .interfaces
+            .remove(0);
+        let ep0 = UsbEndpoint::default();
+        let ep_in = UsbHidKeyboardHandler::endpoints()[0];
+        let request = |handler: &mut UsbHidHandler, request_type, request, value| {
+            handler.handle_urb(
+                &intf,
+                ep0,
+                Some(hid_request(request_type, request, value)),
+                &[],
+            )
+        };
+
+        // SET_IDLE(40ms, all reports) then GET_IDLE
+        request(&mut handler, 0x21, HidRequest::SetIdle, 0x0A00).unwrap();
+        assert_eq!(handler.idle_rates[&0], 0x0A);
+        let resp = request(&mut handler, 0xA1, HidRequest::GetIdle, 0x0000).unwrap();
+        assert_eq!(resp, [0x0A]);
+        // SET_PROTOCOL(boot) then GET_PROTOCOL
+        request(&mut handler, 0x21, HidRequest::SetProtocol, 0x0000).unwrap();
+        assert_eq!(handler.protocol, HidProtocol::Boot);
+        let resp = request(&mut handler, 0xA1, HidRequest::GetProtocol, 0x0000).unwrap();
+        assert_eq!(resp, [HidProtocol::Boot as u8]);
+
+        // the last report is repeated every 40ms while nothing new is queued
+        let report = vec![0x00, 0x00, 0x04, 0, 0, 0, 0, 0];
+        handler.send_input_report(0, report.clone());
+        let poll = |handler: &mut UsbHidHandler| handler.handle_urb(&intf, ep_in, None, &[]);
+        assert_eq!(poll(&mut handler).unwrap(), report);
+        tokio::time::advance(Duration::from_millis(39)).await;
+        let err = poll(&mut handler).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
+        tokio::time::advance(Duration::from_millis(1)).await;
+        assert_eq!(poll(&mut handler).unwrap(), report);
+        assert!(poll(&mut handler).is_err());
+
+        // an idle rate of 0 reports only changes
+        request(&mut handler, 0x21, HidRequest::SetIdle, 0x0000).unwrap();
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(poll(&mut handler).is_err());
+    }
+
+    #[test]
+    fn no_report_ids() {
+        let mut handler =
+            UsbHidHandler::new(UsbHidKeyboardHandler::new_keyboard().report_descriptor);
+        let intf = UsbDevice::new(0)
+            .with_interface(
+                ClassCode::HID as u8,
+                0,
+                0,
+                "HID",
+                vec![],
+                Arc::new(Mutex::new(
+                    Box::new(UsbHidHandler::new(vec![])) as Box<dyn UsbInterfaceHandler + Send>
+                )),
+            )
+            .interfaces
+            .remove(0);
+        let ep_in = UsbEndpoint {
+            address: 0x81,
+            attributes: EndpointAttributes::Interrupt as u8,
+            max_packet_size: 0x08,
+            interval: 10,
+        };
+        handler.send_input_report(0, vec![0x00, 0x00, 0x04, 0, 0, 0, 0, 0]);
+        let resp = handler.handle_urb(&intf, ep_in, None, &[]).unwrap();
+        assert_eq!(resp, [0x00, 0x00, 0x04, 0, 0, 0, 0, 0]);
+        // SET_REPORT(Output, 0), the LED report
+        handler
+            .handle_urb(
+                &intf,
+                UsbEndpoint::default(),
+                Some(hid_request(0x21, HidRequest::SetReport, 0x0200)),
+                &[0x01],
+            )
+            .unwrap();
+        assert_eq!(handler.reports[&(HidReportType::Output, 0)], [0x01]);
+    }
 }
@@ -0,0 +1,84 @@
+use super::*;
+use tokio::time::Instant;
+
+/// At most this many bytes of transfer data are kept per URB
+pub const URB_HISTORY_DATA_LEN: usize = 64;
+
+/// Histories of at most this many connections are kept, oldest dropped first
+pub const URB_HISTORY_CONNECTIONS: usize = 16;
+
+/// A URB as seen by the server, kept for post-mortem debugging
+#[derive(Clone, Debug)]
+pub struct UrbRecord {
+    /// When the reply was sent
+    pub timestamp: Instant,
+    pub direction: Direction,
+    /// Endpoint address, including the direction bit
+    pub endpoint: u8,
+    pub setup: [u8; 8],
+    /// OUT data or IN reply, truncated to [URB_HISTORY_DATA_LEN] bytes
+    pub data: Vec<u8>,
+    /// Status sent in RET_SUBMIT, or `-EPROTO` if the handler failed
+    pub status: i32,
+}
+
+/// The last URBs of a connection, see [UsbIpServer::with_urb_history]
+#[derive(Clone, Debug)]
+pub struct UrbHistory {
+    capacity: usize,
+    records: VecDeque<UrbRecord>,
+}
+
+impl UrbHistory {
+    /// Keep the last `capacity` URBs
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            records: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Record a URB, evicting the oldest one when full
+    pub fn push(&mut self, mut record: UrbRecord) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.records.len() == self.capacity {
+            self.records.pop_front();
+        }
+        record.data.truncate(URB_HISTORY_DATA_LEN);
+        self.records.push_back(record);
+    }
+
+    /// Recorded URBs, oldest first
+    pub fn records(&self) -> Vec<UrbRecord> {
+        self.records.iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evict_oldest() {
+        let mut history = UrbHistory::new(3);
+        for i in 0..5u8 {
+            history.push(UrbRecord {
+                timestamp: Instant::now(),
+                direction: Direction::In,
+                endpoint: 0x81,
+                setup: [0; 8],
+                data: vec![i; 100],
+                status: 0,
+            });
+        }
+        let records = history.records();
+        assert_eq!(records.len(), 3);
+        assert_eq!(
+            records.iter().map(|r| r.data[0]).collect::<Vec<_>>(),
+            [2, 3, 4]
+        );
+        assert_eq!(records[0].data.len(), URB_HISTORY_DATA_LEN);
+    }
+}
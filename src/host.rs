@@ -1,92 +1,734 @@
 //! Host USB
 use super::*;
+use rusb::UsbContext;
+
+/// Transfers of an opened host device used by [UsbHostHandler]
+///
+/// Implemented by libusb device handles, other implementations allow
+/// passthrough to be exercised without real hardware.
+pub trait HostDeviceHandle {
+    fn read_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> rusb::Result<usize>;
+
+    fn write_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &[u8],
+        timeout: Duration,
+    ) -> rusb::Result<usize>;
+
+    fn read_interrupt(
+        &self,
+        endpoint: u8,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> rusb::Result<usize>;
+
+    fn write_interrupt(&self, endpoint: u8, buf: &[u8], timeout: Duration) -> rusb::Result<usize>;
+
+    fn read_bulk(&self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> rusb::Result<usize>;
+
+    fn write_bulk(&self, endpoint: u8, buf: &[u8], timeout: Duration) -> rusb::Result<usize>;
+}
+
+impl<T: UsbContext> HostDeviceHandle for DeviceHandle<T> {
+    fn read_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> rusb::Result<usize> {
+        DeviceHandle::read_control(self, request_type, request, value, index, buf, timeout)
+    }
+
+    fn write_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &[u8],
+        timeout: Duration,
+    ) -> rusb::Result<usize> {
+        DeviceHandle::write_control(self, request_type, request, value, index, buf, timeout)
+    }
+
+    fn read_interrupt(
+        &self,
+        endpoint: u8,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> rusb::Result<usize> {
+        DeviceHandle::read_interrupt(self, endpoint, buf, timeout)
+    }
+
+    fn write_interrupt(&self, endpoint: u8, buf: &[u8], timeout: Duration) -> rusb::Result<usize> {
+        DeviceHandle::write_interrupt(self, endpoint, buf, timeout)
+    }
+
+    fn read_bulk(&self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> rusb::Result<usize> {
+        DeviceHandle::read_bulk(self, endpoint, buf, timeout)
+    }
+
+    fn write_bulk(&self, endpoint: u8, buf: &[u8], timeout: Duration) -> rusb::Result<usize> {
+        DeviceHandle::write_bulk(self, endpoint, buf, timeout)
+    }
+}
 
 /// A handler to pass requests to a USB device of the host
 #[derive(Clone)]
 pub struct UsbHostHandler {
-    handle: Arc<Mutex<DeviceHandle<GlobalContext>>>,
+    handle: Arc<Mutex<dyn HostDeviceHandle + Send>>,
+    timeout: Duration,
 }
 
+/// Default timeout of transfers to a host device, see [UsbHostHandler::with_timeout]
+pub const HOST_TRANSFER_TIMEOUT: Duration = Duration::from_secs(1);
+
 impl UsbHostHandler {
     pub fn new(handle: Arc<Mutex<DeviceHandle<GlobalContext>>>) -> Self {
-        Self { handle }
+        Self {
+            handle,
+            timeout: HOST_TRANSFER_TIMEOUT,
+        }
+    }
+
+    /// Pass requests to any [HostDeviceHandle] instead of a libusb device
+    pub fn from_handle(handle: Arc<Mutex<dyn HostDeviceHandle + Send>>) -> Self {
+        Self {
+            handle,
+            timeout: HOST_TRANSFER_TIMEOUT,
+        }
+    }
+
+    /// Give up on a transfer to the host device after `timeout`
+    ///
+    /// An interrupt IN transfer timing out is NAKed, the server retries it
+    /// in the next interval, so a shorter timeout holds the handler for
+    /// less time between reports without losing any.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
     }
 }
 
-impl UsbInterfaceHandler for UsbHostHandler {
-    fn handle_urb(
-        &mut self,
-        _interface: &UsbInterface,
-        ep: UsbEndpoint,
-        setup: SetupPacket,
-        req: &[u8],
-    ) -> Result<Vec<u8>> {
-        debug!(
-            "To host device: ep={:?} setup={:?} req={:?}",
-            ep, setup, req
-        );
-        let mut buffer = [0u8; 1024];
-        let timeout = std::time::Duration::new(1, 0);
-        let handle = self.handle.lock().unwrap();
-        if ep.attributes == EndpointAttributes::Control as u8 {
-            // control
-            if let Direction::In = ep.direction() {
-                // control in
-                if let Ok(len) = handle.read_control(
+/// Pass one URB to `handle`, blocking until the host device answers
+///
+/// IN transfers read up to `transfer_buffer_length` bytes, or one
+/// (micro)frame worth of packets if it is unknown. Control transfers read wLength bytes instead. Bulk OUT
+/// transfers with [TransferFlags::ZERO_PACKET] end with a zero-length packet
+/// when they fill their last packet. Interrupt IN transfers with no report
+/// within `timeout` are NAKed, other failures fail the URB, see
+/// [transfer_error].
+fn host_transfer(
+    handle: &dyn HostDeviceHandle,
+    ep: UsbEndpoint,
+    setup: Option<SetupPacket>,
+    req: &[u8],
+    transfer_buffer_length: Option<u32>,
+    transfer_flags: TransferFlags,
+    timeout: Duration,
+) -> Result<Vec<u8>> {
+    debug!(
+        "To host device: ep={:?} setup={:?} req={:?}",
+        ep, setup, req
+    );
+    let len = match setup {
+        Some(setup) => setup.length as usize,
+        None => transfer_buffer_length.map_or(ep.max_payload(), |len| len as usize),
+    };
+    let mut buffer = vec![0u8; len];
+    if let Some(setup) = setup {
+        // control
+        if let Direction::In = ep.direction() {
+            // control in
+            let len = handle
+                .read_control(
                     setup.request_type,
                     setup.request,
                     setup.value,
                     setup.index,
                     &mut buffer,
                     timeout,
-                ) {
-                    return Ok(Vec::from(&buffer[..len]));
-                }
-            } else {
-                // control out
-                handle
-                    .write_control(
-                        setup.request_type,
-                        setup.request,
-                        setup.value,
-                        setup.index,
-                        req,
-                        timeout,
-                    )
-                    .ok();
+                )
+                .map_err(transfer_error)?;
+            return Ok(Vec::from(&buffer[..len]));
+        } else {
+            // control out, the whole data stage was read with the URB
+            if req.len() != setup.length as usize {
+                warn!(
+                    "Control OUT data stage has {} bytes, wLength is {}",
+                    req.len(),
+                    setup.length
+                );
             }
-        } else if ep.attributes == EndpointAttributes::Interrupt as u8 {
-            // interrupt
-            if let Direction::In = ep.direction() {
-                // interrupt in
-                if let Ok(len) = handle.read_interrupt(ep.address, &mut buffer, timeout) {
+            handle
+                .write_control(
+                    setup.request_type,
+                    setup.request,
+                    setup.value,
+                    setup.index,
+                    req,
+                    timeout,
+                )
+                .map_err(transfer_error)?;
+        }
+    } else if ep.transfer_type() == Some(EndpointAttributes::Interrupt) {
+        // interrupt
+        if let Direction::In = ep.direction() {
+            // interrupt in
+            match handle.read_interrupt(ep.address, &mut buffer, timeout) {
+                Ok(len) => {
                     info!("intr in {:?}", &buffer[..len]);
                     return Ok(Vec::from(&buffer[..len]));
                 }
-            } else {
-                // interrupt out
-                handle.write_interrupt(ep.address, req, timeout).ok();
-            }
-        } else if ep.attributes == EndpointAttributes::Bulk as u8 {
-            // bulk
-            if let Direction::In = ep.direction() {
-                // bulk in
-                if let Ok(len) = handle.read_bulk(ep.address, &mut buffer, timeout) {
-                    return Ok(Vec::from(&buffer[..len]));
+                Err(rusb::Error::Timeout) => {
+                    return Err(std::io::Error::new(
+                        ErrorKind::WouldBlock,
+                        "no interrupt report yet",
+                    ));
                 }
-            } else {
-                // bulk out
-                handle.write_bulk(ep.address, req, timeout).ok();
+                Err(err) => return Err(transfer_error(err)),
             }
+        } else {
+            // interrupt out
+            handle
+                .write_interrupt(ep.address, req, timeout)
+                .map_err(transfer_error)?;
         }
-        Ok(vec![])
+    } else if ep.transfer_type() == Some(EndpointAttributes::Bulk) {
+        // bulk
+        if let Direction::In = ep.direction() {
+            // bulk in
+            let len = handle
+                .read_bulk(ep.address, &mut buffer, timeout)
+                .map_err(transfer_error)?;
+            return Ok(Vec::from(&buffer[..len]));
+        } else {
+            // bulk out
+            handle
+                .write_bulk(ep.address, req, timeout)
+                .map_err(transfer_error)?;
+            if transfer_flags.contains(TransferFlags::ZERO_PACKET)
+                && !req.is_empty()
+                && req.len().is_multiple_of(ep.packet_size())
+            {
+                handle
+                    .write_bulk(ep.address, &[], timeout)
+                    .map_err(transfer_error)?;
+            }
+        }
+    }
+    Ok(vec![])
+}
+
+/// Convert a failed transfer on the host device, see [UsbIpError::status]
+///
+/// A stall becomes [ErrorKind::BrokenPipe], a timeout
+/// [ErrorKind::TimedOut] and a device gone [ErrorKind::NotConnected].
+fn transfer_error(err: rusb::Error) -> std::io::Error {
+    let kind = match err {
+        rusb::Error::Pipe => ErrorKind::BrokenPipe,
+        rusb::Error::Timeout => ErrorKind::TimedOut,
+        rusb::Error::NoDevice => ErrorKind::NotConnected,
+        _ => ErrorKind::Other,
+    };
+    std::io::Error::new(kind, format!("host device transfer failed: {}", err))
+}
+
+impl UsbInterfaceHandler for UsbHostHandler {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        ep: UsbEndpoint,
+        setup: Option<SetupPacket>,
+        req: &[u8],
+    ) -> Result<Vec<u8>> {
+        let handle = self.handle.lock().unwrap();
+        host_transfer(
+            &*handle,
+            ep,
+            setup,
+            req,
+            None,
+            TransferFlags::default(),
+            self.timeout,
+        )
+    }
+
+    fn handle_urb_with_context(
+        &mut self,
+        ctx: &ConnectionContext,
+        _interface: &UsbInterface,
+        ep: UsbEndpoint,
+        setup: Option<SetupPacket>,
+        req: &[u8],
+    ) -> Result<Vec<u8>> {
+        let handle = self.handle.lock().unwrap();
+        host_transfer(
+            &*handle,
+            ep,
+            setup,
+            req,
+            ctx.transfer_buffer_length,
+            ctx.transfer_flags.unwrap_or_default(),
+            self.timeout,
+        )
+    }
+
+    /// Wait for the host device on the blocking thread pool, which keeps the
+    /// worker free for other connections
+    fn handle_urb_async(
+        &mut self,
+        ctx: &ConnectionContext,
+        _interface: &UsbInterface,
+        ep: UsbEndpoint,
+        setup: Option<SetupPacket>,
+        req: &[u8],
+    ) -> UrbFuture {
+        let handle = self.handle.clone();
+        let req = req.to_vec();
+        let transfer_buffer_length = ctx.transfer_buffer_length;
+        let transfer_flags = ctx.transfer_flags.unwrap_or_default();
+        let timeout = self.timeout;
+        Box::pin(async move {
+            tokio::task::spawn_blocking(move || {
+                let handle = handle.lock().unwrap();
+                host_transfer(
+                    &*handle,
+                    ep,
+                    setup,
+                    &req,
+                    transfer_buffer_length,
+                    transfer_flags,
+                    timeout,
+                )
+            })
+            .await
+            .map_err(std::io::Error::other)?
+        })
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// A handler of a device known only by its descriptors, stalling every transfer
+#[derive(Clone, Default)]
+pub struct UsbDescriptorOnlyHandler;
+
+impl UsbInterfaceHandler for UsbDescriptorOnlyHandler {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        ep: UsbEndpoint,
+        _setup: Option<SetupPacket>,
+        _req: &[u8],
+    ) -> Result<Vec<u8>> {
+        warn!("Transfer to descriptor only device on {:02x}", ep.address);
+        Err(std::io::Error::new(
+            ErrorKind::BrokenPipe,
+            "device exported with descriptors only",
+        ))
     }
 
     fn get_class_specific_descriptor(&self) -> Vec<u8> {
-        return vec![];
+        vec![]
     }
 
     fn as_any(&mut self) -> &mut dyn Any {
         self
     }
 }
+
+fn descriptor_only_handler() -> Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>> {
+    Arc::new(Mutex::new(
+        Box::new(UsbDescriptorOnlyHandler) as Box<dyn UsbInterfaceHandler + Send>
+    ))
+}
+
+/// Base of mirrored devices: endpoint 0 but no strings, unlike [UsbDevice::new]
+fn mirrored_device() -> UsbDevice {
+    let template = UsbDevice::new(0);
+    UsbDevice {
+        ep0_in: template.ep0_in,
+        ep0_out: template.ep0_out,
+        ..UsbDevice::default()
+    }
+}
+
+/// Encode a version decoded by rusb back to bcdDevice
+fn version_to_bcd(version: rusb::Version) -> u16 {
+    let major = version.major() as u16;
+    (major / 10) << 12
+        | (major % 10) << 8
+        | (version.minor() as u16) << 4
+        | version.sub_minor() as u16
+}
+
+fn invalid_descriptor(msg: &str) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, msg.to_string())
+}
+
+impl UsbDevice {
+    /// Mirror the descriptors of a host device without opening it
+    ///
+    /// Interfaces use [UsbDescriptorOnlyHandler] and string descriptors are
+    /// not available. Use [UsbDevice::attach_host_handle] once a handle could
+    /// be opened to pass transfers through.
+    ///
+    /// The device descriptor is served from the mirrored fields, so
+    /// [UsbDevice::with_vendor_id], [UsbDevice::with_product_id] and
+    /// [UsbDevice::with_device_version] present other ids to the client while
+    /// the rest of the descriptors and all transfers still come from the
+    /// host device. The driver the client binds then expects a device it
+    /// is not talking to: quirks keyed on the ids are applied or missed,
+    /// vendor requests may not match.
+    pub fn from_host_device(dev: &rusb::Device<GlobalContext>) -> Result<Self> {
+        let to_io = |err: rusb::Error| std::io::Error::other(err.to_string());
+        let desc = dev.device_descriptor().map_err(to_io)?;
+        let cfg = dev.active_config_descriptor().map_err(to_io)?;
+        let mut interfaces = vec![];
+        for intf in cfg.interfaces() {
+            // ignore alternate settings
+            let intf_desc = intf.descriptors().next().unwrap();
+            let mut endpoints = vec![];
+            let mut class_specific_endpoint_descriptors = HashMap::new();
+            for ep_desc in intf_desc.endpoint_descriptors() {
+                if let Some(extra) = ep_desc.extra() {
+                    class_specific_endpoint_descriptors.insert(ep_desc.address(), extra.to_vec());
+                }
+                endpoints.push(UsbEndpoint {
+                    address: ep_desc.address(),
+                    attributes: ep_desc.transfer_type() as u8,
+                    max_packet_size: ep_desc.max_packet_size(),
+                    interval: ep_desc.interval(),
+                });
+            }
+            interfaces.push(UsbInterface {
+                interface_class: intf_desc.class_code(),
+                interface_subclass: intf_desc.sub_class_code(),
+                interface_protocol: intf_desc.protocol_code(),
+                alternate_setting: 0,
+                endpoints,
+                string_interface: 0,
+                class_specific_descriptor: Vec::from(intf_desc.extra().unwrap_or(&[])),
+                class_specific_endpoint_descriptors,
+                handler: descriptor_only_handler(),
+            });
+        }
+        UsbDevice {
+            path: format!(
+                "/sys/bus/{}/{}/{}",
+                dev.bus_number(),
+                dev.address(),
+                dev.port_number()
+            ),
+            bus_id: format!(
+                "{}-{}-{}",
+                dev.bus_number(),
+                dev.address(),
+                dev.port_number()
+            ),
+            bus_num: dev.bus_number() as u32,
+            dev_num: dev.port_number() as u32,
+            speed: dev.speed() as u32,
+            vendor_id: desc.vendor_id(),
+            product_id: desc.product_id(),
+            device_bcd: version_to_bcd(desc.device_version()),
+            device_class: desc.class_code(),
+            device_subclass: desc.sub_class_code(),
+            device_protocol: desc.protocol_code(),
+            configuration_value: cfg.number(),
+            self_powered: cfg.self_powered(),
+            remote_wakeup: cfg.remote_wakeup(),
+            num_configurations: desc.num_configurations(),
+            interfaces,
+            ..mirrored_device()
+        }
+        .try_with_max_packet_size0(desc.max_packet_size())
+        .map_err(|_| invalid_descriptor("invalid bMaxPacketSize0"))
+    }
+
+    /// Pass transfers of a mirrored host device through `handle`
+    ///
+    /// Claims the interfaces and reads the manufacturer, product and serial
+    /// strings of the device.
+    pub fn attach_host_handle(&mut self, handle: Arc<Mutex<DeviceHandle<GlobalContext>>>) {
+        {
+            let mut handle = handle.lock().unwrap();
+            handle.set_auto_detach_kernel_driver(true).ok();
+            for i in 0..self.num_interfaces() {
+                handle.claim_interface(i as u8).ok();
+            }
+        }
+        for intf in self.interfaces.iter_mut() {
+            intf.handler = Arc::new(Mutex::new(Box::new(UsbHostHandler::new(handle.clone()))
+                as Box<dyn UsbInterfaceHandler + Send>));
+        }
+
+        // set strings
+        let desc = match handle.lock().unwrap().device().device_descriptor() {
+            Ok(desc) => desc,
+            Err(_) => return,
+        };
+        let read_string = |index: Option<u8>| {
+            index.and_then(|index| {
+                handle
+                    .lock()
+                    .unwrap()
+                    .read_string_descriptor_ascii(index)
+                    .ok()
+            })
+        };
+        if let Some(s) = read_string(desc.manufacturer_string_index()) {
+            self.string_manufacturer = self.new_string(&s).expect("add manufacturer string");
+        }
+        if let Some(s) = read_string(desc.product_string_index()) {
+            self.string_product = self.new_string(&s).expect("add product string");
+        }
+        if let Some(s) = read_string(desc.serial_number_string_index()) {
+            self.string_serial = self.new_string(&s).expect("add serial string");
+        }
+    }
+
+    /// Mirror a device from its raw device and configuration descriptors
+    ///
+    /// Only alternate setting 0 of each interface is kept. Interfaces use
+    /// [UsbDescriptorOnlyHandler], string indices are dropped. Ids can be
+    /// overridden as for [UsbDevice::from_host_device].
+    pub fn from_descriptors(device_desc: &[u8], config_desc: &[u8]) -> Result<Self> {
+        if device_desc.len() < 18 || device_desc[1] != DescriptorType::Device as u8 {
+            return Err(invalid_descriptor("bad device descriptor"));
+        }
+        if config_desc.len() < 9 || config_desc[1] != DescriptorType::Configuration as u8 {
+            return Err(invalid_descriptor("bad configuration descriptor"));
+        }
+        let mut device = UsbDevice {
+            device_bcd: u16::from_le_bytes([device_desc[12], device_desc[13]]),
+            device_class: device_desc[4],
+            device_subclass: device_desc[5],
+            device_protocol: device_desc[6],
+            vendor_id: u16::from_le_bytes([device_desc[8], device_desc[9]]),
+            product_id: u16::from_le_bytes([device_desc[10], device_desc[11]]),
+            num_configurations: device_desc[17],
+            configuration_value: config_desc[5],
+            self_powered: config_desc[7] & 0x40 != 0,
+            remote_wakeup: config_desc[7] & 0x20 != 0,
+            speed: UsbSpeed::High as u32,
+            ..mirrored_device()
+        }
+        .try_with_max_packet_size0(device_desc[7])
+        .map_err(|_| invalid_descriptor("invalid bMaxPacketSize0"))?;
+
+        if config_desc[4] as usize != count_interfaces(config_desc) {
+            return Err(invalid_descriptor("bNumInterfaces mismatch"));
+        }
+
+        // walk the descriptors following the configuration descriptor
+        let mut offset = config_desc[0] as usize;
+        let mut skipping = false;
+        while offset < config_desc.len() {
+            let len = config_desc[offset] as usize;
+            if len < 2 || offset + len > config_desc.len() {
+                return Err(invalid_descriptor("truncated descriptor"));
+            }
+            let desc = &config_desc[offset..offset + len];
+            offset += len;
+            match FromPrimitive::from_u8(desc[1]) {
+                Some(DescriptorType::Interface) if len >= 9 => {
+                    // ignore alternate settings
+                    skipping = desc[3] != 0;
+                    if !skipping {
+                        device.interfaces.push(UsbInterface {
+                            interface_class: desc[5],
+                            interface_subclass: desc[6],
+                            interface_protocol: desc[7],
+                            alternate_setting: 0,
+                            endpoints: vec![],
+                            string_interface: 0,
+                            class_specific_descriptor: vec![],
+                            class_specific_endpoint_descriptors: HashMap::new(),
+                            handler: descriptor_only_handler(),
+                        });
+                    }
+                }
+                Some(DescriptorType::InterfaceAssociation) if len >= 8 => {
+                    device.interface_associations.push(UsbInterfaceAssociation {
+                        first_interface: desc[2],
+                        interface_count: desc[3],
+                        function_class: desc[4],
+                        function_subclass: desc[5],
+                        function_protocol: desc[6],
+                        string_function: 0,
+                    });
+                }
+                Some(DescriptorType::Endpoint) if len >= 7 => {
+                    if let (false, Some(intf)) = (skipping, device.interfaces.last_mut()) {
+                        intf.endpoints.push(UsbEndpoint {
+                            address: desc[2],
+                            attributes: desc[3],
+                            max_packet_size: u16::from_le_bytes([desc[4], desc[5]]),
+                            interval: desc[6],
+                        });
+                    }
+                }
+                _ => {
+                    // class specific descriptors of the interface precede the
+                    // endpoints, those of an endpoint follow it
+                    if let (false, Some(intf)) = (skipping, device.interfaces.last_mut()) {
+                        match intf.endpoints.last() {
+                            None => intf.class_specific_descriptor.extend_from_slice(desc),
+                            Some(ep) => intf
+                                .class_specific_endpoint_descriptors
+                                .entry(ep.address)
+                                .or_default()
+                                .extend_from_slice(desc),
+                        }
+                    }
+                }
+            }
+        }
+        Ok(device)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn mirror_from_descriptors() {
+        let original = UsbDevice::new(0).with_interface(
+            ClassCode::CDC as u8,
+            crate::cdc::CDC_ACM_SUBCLASS,
+            0x00,
+            "Serial",
+            crate::cdc::UsbCdcAcmHandler::endpoints(),
+            Arc::new(Mutex::new(Box::new(crate::cdc::UsbCdcAcmHandler::new())
+                as Box<dyn UsbInterfaceHandler + Send>)),
+        );
+        let device_desc = original
+            .handle_urb(
+                &ConnectionContext::default(),
+                original.ep0_in,
+                None,
+                [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00],
+                &[],
+            )
+            .await
+            .unwrap();
+        let config_desc = original.configuration_descriptor();
+
+        let device = UsbDevice::from_descriptors(&device_desc, &config_desc).unwrap();
+        assert_eq!(device.interfaces.len(), original.interfaces.len());
+        for (mirrored, intf) in device.interfaces.iter().zip(&original.interfaces) {
+            assert_eq!(mirrored.interface_class, intf.interface_class);
+            assert_eq!(mirrored.endpoints.len(), intf.endpoints.len());
+            assert_eq!(
+                mirrored.class_specific_descriptor,
+                intf.class_specific_descriptor
+            );
+        }
+        assert_eq!(device.max_packet_size0(), original.max_packet_size0());
+
+        // descriptors are served, transfers stall
+        let mut expected = config_desc.clone();
+        for intf in 0..original.interfaces.len() {
+            // iInterface is dropped
+            let pos = expected
+                .windows(2)
+                .enumerate()
+                .filter(|(_, w)| w[0] == 9 && w[1] == DescriptorType::Interface as u8)
+                .nth(intf)
+                .unwrap()
+                .0;
+            expected[pos + 8] = 0;
+        }
+        assert_eq!(device.configuration_descriptor()[..4], expected[..4]);
+        assert_eq!(device.configuration_descriptor()[9..], expected[9..]);
+        let ep = device.interfaces[0].endpoints[0];
+        let err = device
+            .handle_urb(
+                &ConnectionContext::default(),
+                ep,
+                Some(&device.interfaces[0]),
+                [0; 8],
+                &[],
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::BrokenPipe);
+    }
+
+    #[tokio::test]
+    async fn override_mirrored_ids() {
+        let original = UsbDevice::new(0)
+            .with_vendor_id(0x1234)
+            .with_product_id(0x5678)
+            .with_device_version(0x0102)
+            .with_interface(
+                ClassCode::CDC as u8,
+                crate::cdc::CDC_ACM_SUBCLASS,
+                0x00,
+                "Serial",
+                crate::cdc::UsbCdcAcmHandler::endpoints(),
+                Arc::new(Mutex::new(Box::new(crate::cdc::UsbCdcAcmHandler::new())
+                    as Box<dyn UsbInterfaceHandler + Send>)),
+            );
+        let get_device_desc = |device: &UsbDevice| {
+            let device = device.clone();
+            async move {
+                device
+                    .handle_urb(
+                        &ConnectionContext::default(),
+                        device.ep0_in,
+                        None,
+                        [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00],
+                        &[],
+                    )
+                    .await
+                    .unwrap()
+            }
+        };
+        let device_desc = get_device_desc(&original).await;
+        let config_desc = original.configuration_descriptor();
+
+        let device = UsbDevice::from_descriptors(&device_desc, &config_desc)
+            .unwrap()
+            .with_vendor_id(0xABCD)
+            .with_product_id(0xEF01)
+            .with_device_version(0x0200);
+        assert_eq!(
+            (device.vendor_id, device.product_id, device.device_bcd),
+            (0xABCD, 0xEF01, 0x0200)
+        );
+        let spoofed = get_device_desc(&device).await;
+        // idVendor, idProduct and bcdDevice
+        assert_eq!(spoofed[8..14], [0xCD, 0xAB, 0x01, 0xEF, 0x00, 0x02]);
+        // everything else is mirrored
+        assert_eq!(spoofed[..8], device_desc[..8]);
+        assert_eq!(spoofed[14..], [0, 0, 0, device_desc[17]]);
+        assert_eq!(device.configuration_descriptor()[9..17], config_desc[9..17]);
+
+        assert_eq!(version_to_bcd(rusb::Version::from_bcd(0x1234)), 0x1234);
+    }
+}
@@ -0,0 +1,103 @@
+//! Scripted hotplug of devices
+use super::*;
+use std::time::Duration;
+
+/// An action on the exported device list
+pub enum HotplugEvent {
+    /// Export a new device
+    Add(Box<UsbDevice>),
+    /// Stop exporting the device with this bus id
+    Remove(String),
+}
+
+/// A timeline of devices being plugged and unplugged
+///
+/// Useful to reproduce hotplug scenarios deterministically in tests and demos:
+///
+/// ```ignore
+/// let timeline = HotplugTimeline::new()
+///     .add_device_at(Duration::from_secs(1), device)
+///     .remove_device_at(Duration::from_secs(5), "0");
+/// tokio::spawn(timeline.run(server.clone()));
+/// ```
+#[derive(Default)]
+pub struct HotplugTimeline {
+    events: Vec<(Duration, HotplugEvent)>,
+}
+
+impl HotplugTimeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `device` at `at` after the timeline starts
+    pub fn add_device_at(mut self, at: Duration, device: UsbDevice) -> Self {
+        self.events.push((at, HotplugEvent::Add(Box::new(device))));
+        self
+    }
+
+    /// Remove the device with `bus_id` at `at` after the timeline starts
+    pub fn remove_device_at(mut self, at: Duration, bus_id: &str) -> Self {
+        self.events
+            .push((at, HotplugEvent::Remove(bus_id.to_string())));
+        self
+    }
+
+    /// Execute the events on `server` at their scheduled time
+    ///
+    /// Events at the same time run in the order they were added.
+    pub async fn run(mut self, server: Arc<UsbIpServer>) {
+        let start = tokio::time::Instant::now();
+        // stable sort keeps insertion order for equal times
+        self.events.sort_by_key(|(at, _)| *at);
+        for (at, event) in self.events {
+            tokio::time::sleep_until(start + at).await;
+            match event {
+                HotplugEvent::Add(device) => {
+                    info!("Hotplug: add device {}", device.bus_id);
+                    if let Err(err) = server.add_device(*device).await {
+                        warn!("Hotplug: {}", err);
+                    }
+                }
+                HotplugEvent::Remove(bus_id) => {
+                    info!("Hotplug: remove device {}", bus_id);
+                    if let Err(err) = server.remove_device(&bus_id).await {
+                        warn!("Hotplug: {}", err);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn bus_ids(server: &UsbIpServer) -> Vec<String> {
+        server
+            .devices
+            .read()
+            .await
+            .iter()
+            .map(|d| d.bus_id.clone())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn run_timeline() {
+        tokio::time::pause();
+        let server = Arc::new(UsbIpServer::new_simulated(vec![UsbDevice::new(0)]));
+        let timeline = HotplugTimeline::new()
+            .remove_device_at(Duration::from_secs(2), "0")
+            .add_device_at(Duration::from_secs(1), UsbDevice::new(1));
+        tokio::spawn(timeline.run(server.clone()));
+
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(bus_ids(&server).await, ["0"]);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(bus_ids(&server).await, ["0", "1"]);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(bus_ids(&server).await, ["1"]);
+    }
+}
@@ -0,0 +1,333 @@
+//! Implement the hub class, USB 2.0 chapter 11
+use super::*;
+
+/// wPortStatus bit of a connected device
+pub const HUB_PORT_CONNECTION: u16 = 0x0001;
+/// wPortStatus bit of an enabled port
+pub const HUB_PORT_ENABLE: u16 = 0x0002;
+/// wPortStatus bit of a suspended port
+pub const HUB_PORT_SUSPEND: u16 = 0x0004;
+/// wPortStatus bit of a port in reset
+pub const HUB_PORT_RESET: u16 = 0x0010;
+/// wPortStatus bit of a powered port
+pub const HUB_PORT_POWER: u16 = 0x0100;
+/// wPortStatus bit of a low speed device
+pub const HUB_PORT_LOW_SPEED: u16 = 0x0200;
+/// wPortStatus bit of a high speed device
+pub const HUB_PORT_HIGH_SPEED: u16 = 0x0400;
+
+/// Descriptor type of the hub descriptor
+pub const HUB_DESCRIPTOR_TYPE: u8 = 0x29;
+
+/// Port feature selectors of SET_FEATURE and CLEAR_FEATURE
+const PORT_ENABLE: u16 = 1;
+const PORT_SUSPEND: u16 = 2;
+const PORT_RESET: u16 = 4;
+const PORT_POWER: u16 = 8;
+/// Change feature selectors start at C_PORT_CONNECTION, bit 0 of wPortChange
+const C_PORT_CONNECTION: u16 = 16;
+const C_PORT_RESET: u16 = 20;
+
+/// wPortStatus and wPortChange of a port
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HubPortStatus {
+    pub status: u16,
+    pub change: u16,
+}
+
+/// A handler of a hub, reporting port status changes to the host
+///
+/// Call [UsbHubHandler::connect_port] and [UsbHubHandler::disconnect_port] to
+/// plug devices into its downstream ports. The hub then reports the change on
+/// its status change endpoint until the host acknowledges it with
+/// CLEAR_FEATURE(C_PORT_CONNECTION), as real hubs do. USB/IP exports single
+/// devices, so the devices behind the ports are not reachable: this models
+/// what the host hub driver sees. The device needs the hub class, see
+/// [UsbHubHandler::device_class], for hub requests to reach the handler.
+#[derive(Clone)]
+pub struct UsbHubHandler {
+    pub ports: Vec<HubPortStatus>,
+}
+
+impl UsbHubHandler {
+    /// Create a hub with `num_ports` downstream ports, numbered from 1
+    pub fn new(num_ports: u8) -> Self {
+        Self {
+            ports: vec![HubPortStatus::default(); num_ports as usize],
+        }
+    }
+
+    /// Class, subclass and protocol of a high speed hub with a single TT
+    pub fn device_class() -> (u8, u8, u8) {
+        (ClassCode::Hub as u8, 0x00, 0x01)
+    }
+
+    /// The status change endpoint of a hub with `num_ports` ports
+    pub fn endpoints(num_ports: u8) -> Vec<UsbEndpoint> {
+        vec![UsbEndpoint {
+            address: 0x81,
+            attributes: EndpointAttributes::Interrupt as u8,
+            max_packet_size: bitmap_len(num_ports) as u16,
+            // 256ms at high speed
+            interval: 0x0C,
+        }]
+    }
+
+    /// Plug a device of `speed` into `port`, counting from 1
+    pub fn connect_port(&mut self, port: u8, speed: UsbSpeed) {
+        let state = &mut self.ports[port as usize - 1];
+        state.status &= !(HUB_PORT_LOW_SPEED | HUB_PORT_HIGH_SPEED);
+        state.status |= HUB_PORT_CONNECTION;
+        match speed {
+            UsbSpeed::Low => state.status |= HUB_PORT_LOW_SPEED,
+            UsbSpeed::High => state.status |= HUB_PORT_HIGH_SPEED,
+            _ => {}
+        }
+        // C_PORT_CONNECTION
+        state.change |= 0x0001;
+    }
+
+    /// Unplug the device in `port`, counting from 1
+    pub fn disconnect_port(&mut self, port: u8) {
+        let state = &mut self.ports[port as usize - 1];
+        state.status &= !(HUB_PORT_CONNECTION
+            | HUB_PORT_ENABLE
+            | HUB_PORT_SUSPEND
+            | HUB_PORT_LOW_SPEED
+            | HUB_PORT_HIGH_SPEED);
+        // C_PORT_CONNECTION
+        state.change |= 0x0001;
+    }
+
+    /// Status change bitmap: bit 0 for the hub, bit n for port n
+    fn status_change(&self) -> Vec<u8> {
+        let mut bitmap = vec![0u8; bitmap_len(self.ports.len() as u8)];
+        for (i, port) in self.ports.iter().enumerate() {
+            if port.change != 0 {
+                bitmap[(i + 1) / 8] |= 1 << ((i + 1) % 8);
+            }
+        }
+        bitmap
+    }
+
+    fn hub_descriptor(&self) -> Vec<u8> {
+        let num_ports = self.ports.len() as u8;
+        let len = bitmap_len(num_ports);
+        let mut desc = vec![
+            (7 + len * 2) as u8, // bDescLength
+            HUB_DESCRIPTOR_TYPE, // bDescriptorType
+            num_ports,           // bNbrPorts
+            0x09,                // wHubCharacteristics: per port power
+            0x00,                // and over-current protection
+            50,                  // bPwrOn2PwrGood: 100ms
+            0,                   // bHubContrCurrent
+        ];
+        // DeviceRemovable
+        desc.extend(vec![0x00; len]);
+        // PortPwrCtrlMask, all ones for USB 1.0 compatibility
+        desc.extend(vec![0xFF; len]);
+        desc
+    }
+
+    fn port(&mut self, setup: &SetupPacket) -> Result<&mut HubPortStatus> {
+        let port = setup.index as u8 as usize;
+        if port == 0 || port > self.ports.len() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                format!("no port {}", port),
+            ));
+        }
+        Ok(&mut self.ports[port - 1])
+    }
+}
+
+/// Bytes of a bitmap with one bit for the hub and each of `num_ports` ports
+fn bitmap_len(num_ports: u8) -> usize {
+    (num_ports as usize + 1).div_ceil(8)
+}
+
+impl UsbInterfaceHandler for UsbHubHandler {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        _ep: UsbEndpoint,
+        setup: Option<SetupPacket>,
+        _req: &[u8],
+    ) -> Result<Vec<u8>> {
+        let setup = match setup {
+            Some(setup) => setup,
+            None => {
+                // status change endpoint, NAK while nothing changed
+                let bitmap = self.status_change();
+                if bitmap.iter().all(|b| *b == 0) {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::WouldBlock,
+                        "no port status change",
+                    ));
+                }
+                return Ok(bitmap);
+            }
+        };
+
+        match (setup.request_type, setup.request) {
+            // GET_DESCRIPTOR of the hub descriptor
+            (0xA0, 0x06) if setup.value >> 8 == HUB_DESCRIPTOR_TYPE as u16 => {
+                let mut desc = self.hub_descriptor();
+                desc.truncate(setup.length as usize);
+                Ok(desc)
+            }
+            // GET_STATUS of the hub: local power, no over-current
+            (0xA0, 0x00) => Ok(vec![0x00; 4]),
+            // CLEAR_FEATURE of the hub: nothing ever changes
+            (0x20, 0x01) => Ok(vec![]),
+            // GET_STATUS of a port
+            (0xA3, 0x00) => {
+                let port = self.port(&setup)?;
+                let mut status = port.status.to_le_bytes().to_vec();
+                status.extend_from_slice(&port.change.to_le_bytes());
+                Ok(status)
+            }
+            // SET_FEATURE of a port
+            (0x23, 0x03) => {
+                let port = self.port(&setup)?;
+                match setup.value {
+                    PORT_RESET => {
+                        // completes right away, enabling a connected device
+                        if port.status & HUB_PORT_CONNECTION != 0 {
+                            port.status |= HUB_PORT_ENABLE;
+                        }
+                        port.change |= 1 << (C_PORT_RESET - C_PORT_CONNECTION);
+                    }
+                    PORT_SUSPEND => port.status |= HUB_PORT_SUSPEND,
+                    PORT_POWER => port.status |= HUB_PORT_POWER,
+                    _ => {}
+                }
+                Ok(vec![])
+            }
+            // CLEAR_FEATURE of a port
+            (0x23, 0x01) => {
+                let port = self.port(&setup)?;
+                match setup.value {
+                    PORT_ENABLE => port.status &= !HUB_PORT_ENABLE,
+                    PORT_SUSPEND => port.status &= !HUB_PORT_SUSPEND,
+                    PORT_POWER => port.status &= !HUB_PORT_POWER,
+                    C_PORT_CONNECTION..=C_PORT_RESET => {
+                        port.change &= !(1 << (setup.value - C_PORT_CONNECTION))
+                    }
+                    _ => {}
+                }
+                Ok(vec![])
+            }
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                format!("unhandled hub request {:x?}", setup),
+            )),
+        }
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn endpoints(&self) -> Vec<UsbEndpoint> {
+        Self::endpoints(self.ports.len() as u8)
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn port_connection_change() {
+        let handler = Arc::new(Mutex::new(
+            Box::new(UsbHubHandler::new(4)) as Box<dyn UsbInterfaceHandler + Send>
+        ));
+        let (class, subclass, protocol) = UsbHubHandler::device_class();
+        let device = UsbDevice::new(0)
+            .with_device_class(class, subclass, protocol)
+            .with_interface(
+                ClassCode::Hub as u8,
+                0x00,
+                0x00,
+                "Test Hub",
+                UsbHubHandler::endpoints(4),
+                handler.clone(),
+            );
+        let ctx = ConnectionContext::default();
+        let poll = || {
+            let (ep, intf) = device.find_ep(0x81).unwrap();
+            device.handle_urb(&ctx, ep, intf, [0; 8], &[])
+        };
+
+        // GET_DESCRIPTOR of the hub descriptor
+        let desc = testing::control(
+            &device,
+            &ctx,
+            [0xA0, 0x06, 0x00, 0x29, 0x00, 0x00, 0xFF, 0x00],
+            &[],
+        )
+        .await
+        .unwrap();
+        verify_descriptor(&desc);
+        assert_eq!(desc[2], 4);
+
+        // nothing changed yet
+        let err = poll().await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
+
+        handler
+            .lock()
+            .unwrap()
+            .as_any()
+            .downcast_mut::<UsbHubHandler>()
+            .unwrap()
+            .connect_port(2, UsbSpeed::High);
+        assert_eq!(poll().await.unwrap(), [0b0000_0100]);
+        // GET_STATUS of port 2
+        let status = testing::control(
+            &device,
+            &ctx,
+            [0xA3, 0x00, 0x00, 0x00, 0x02, 0x00, 0x04, 0x00],
+            &[],
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            status,
+            [
+                HUB_PORT_CONNECTION as u8,
+                (HUB_PORT_HIGH_SPEED >> 8) as u8,
+                0x01,
+                0x00
+            ]
+        );
+        // reported until CLEAR_FEATURE(C_PORT_CONNECTION)
+        assert_eq!(poll().await.unwrap(), [0b0000_0100]);
+        testing::control(
+            &device,
+            &ctx,
+            [0x23, 0x01, 0x10, 0x00, 0x02, 0x00, 0x00, 0x00],
+            &[],
+        )
+        .await
+        .unwrap();
+        let err = poll().await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
+
+        // no port 5
+        let err = testing::control(
+            &device,
+            &ctx,
+            [0xA3, 0x00, 0x00, 0x00, 0x05, 0x00, 0x04, 0x00],
+            &[],
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+    }
+}
@@ -1,4 +1,5 @@
 use super::*;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 /// Represent a USB interface
 #[derive(Clone)]
@@ -6,28 +7,240 @@ pub struct UsbInterface {
     pub interface_class: u8,
     pub interface_subclass: u8,
     pub interface_protocol: u8,
+    /// bAlternateSetting, a non-zero setting belongs to the preceding interface
+    pub alternate_setting: u8,
     pub endpoints: Vec<UsbEndpoint>,
     pub string_interface: u8,
     pub class_specific_descriptor: Vec<u8>,
+    /// Class specific descriptors following each endpoint descriptor, by endpoint address
+    pub class_specific_endpoint_descriptors: HashMap<u8, Vec<u8>>,
     pub handler: Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>>,
 }
 
+/// Represent a USB interface association, grouping interfaces into one function
+#[derive(Clone, Copy, Debug, Default)]
+pub struct UsbInterfaceAssociation {
+    /// bFirstInterface
+    pub first_interface: u8,
+    /// bInterfaceCount
+    pub interface_count: u8,
+    /// bFunctionClass
+    pub function_class: u8,
+    /// bFunctionSubClass
+    pub function_subclass: u8,
+    /// bFunctionProtocol
+    pub function_protocol: u8,
+    /// iFunction
+    pub string_function: u8,
+}
+
+impl UsbInterfaceAssociation {
+    /// Build the Interface Association Descriptor
+    pub fn descriptor(&self) -> Vec<u8> {
+        vec![
+            0x08,                                       // bLength
+            DescriptorType::InterfaceAssociation as u8, // bDescriptorType: IAD
+            self.first_interface,                       // bFirstInterface
+            self.interface_count,                       // bInterfaceCount
+            self.function_class,                        // bFunctionClass
+            self.function_subclass,                     // bFunctionSubClass
+            self.function_protocol,                     // bFunctionProtocol
+            self.string_function,                       // iFunction
+        ]
+    }
+}
+
+/// Identify the USB/IP connection a URB arrived on
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConnectionContext {
+    /// Unique id of the connection within this process
+    pub id: u64,
+    /// Address of the peer, if known
+    pub peer: Option<String>,
+    /// transfer_buffer_length of the URB being handled, if known
+    ///
+    /// Handlers of IN endpoints can size their reply with it, longer replies
+    /// are truncated to it anyway.
+    pub transfer_buffer_length: Option<u32>,
+    /// transfer_flags of the URB being handled, if known
+    ///
+    /// See [TransferFlags] for which flags the server honors itself.
+    pub transfer_flags: Option<TransferFlags>,
+    /// interval of the URB being handled, if known
+    ///
+    /// In frames at low and full speed, microframes at high speed. A NAKed
+    /// interrupt transfer is retried once per interval, like a host
+    /// controller polls the endpoint.
+    pub interval: Option<u32>,
+}
+
+impl ConnectionContext {
+    /// Create a context with a fresh connection id
+    pub fn new(peer: Option<String>) -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            peer,
+            transfer_buffer_length: None,
+            transfer_flags: None,
+            interval: None,
+        }
+    }
+}
+
+/// Completion of a URB, see [UsbInterfaceHandler::handle_urb_async]
+pub type UrbFuture = BoxFuture<'static, Result<Vec<u8>>>;
+
 /// A handler of a custom usb interface
 pub trait UsbInterfaceHandler {
     /// Return the class specific desciptor which is inserted between interface descriptor and endpoint descriptor
     fn get_class_specific_descriptor(&self) -> Vec<u8>;
 
+    /// Return the class specific descriptor inserted after the descriptor of `endpoint`
+    fn get_class_specific_endpoint_descriptor(&self, _endpoint: &UsbEndpoint) -> Vec<u8> {
+        vec![]
+    }
+
     /// Handle a URB(USB Request Block) targeting at this interface
     ///
     /// Can be one of: control transfer to ep0 or other types of transfer to its endpoint
+    ///
+    /// `setup` is the setup packet of a control transfer to ep0 and `None`
+    /// for transfers to the endpoints of the interface, where USB/IP sends
+    /// setup bytes that mean nothing.
+    ///
+    /// Both reach the same handler, so a control request can change what
+    /// the data endpoints return, e.g. a vendor request waking the device up,
+    /// see the vendor_init example.
+    ///
+    /// Return an error of kind [std::io::ErrorKind::WouldBlock] to NAK the
+    /// transfer when the device is not ready yet, e.g. a slow control write.
+    /// The server holds back the reply and calls the handler again with the
+    /// same request after [NAK_RETRY_INTERVAL], or the interval of the URB
+    /// on an interrupt endpoint, serving the other endpoints meanwhile. An interrupt IN handler without a report
+    /// yet should NAK rather than return no data, which completes the URB.
+    ///
+    /// Return an error of kind [std::io::ErrorKind::BrokenPipe] to STALL the
+    /// request, the client sees `-EPIPE` and the connection stays up. A
+    /// STALL of a data endpoint halts it: its transfers fail with `-EPIPE`
+    /// without reaching the handler until the host sends
+    /// CLEAR_FEATURE(ENDPOINT_HALT).
+    ///
+    /// Other errors fail the URB with the negative errno of
+    /// [UsbIpError::status], e.g. `-ETIMEDOUT` for
+    /// [std::io::ErrorKind::TimedOut] and `-EPROTO` otherwise, without data.
     fn handle_urb(
         &mut self,
         interface: &UsbInterface,
         ep: UsbEndpoint,
-        setup: SetupPacket,
+        setup: Option<SetupPacket>,
         req: &[u8],
     ) -> Result<Vec<u8>>;
 
+    /// Handle a URB like [UsbInterfaceHandler::handle_urb], knowing which connection sent it
+    ///
+    /// Override it to partition state or log per client when a device is
+    /// imported by several clients, or to act on the URB beyond its data:
+    /// `ctx` carries its transfer_buffer_length, transfer_flags and
+    /// interval, `ep` its endpoint address and direction. Defaults to
+    /// ignoring the context.
+    fn handle_urb_with_context(
+        &mut self,
+        _ctx: &ConnectionContext,
+        interface: &UsbInterface,
+        ep: UsbEndpoint,
+        setup: Option<SetupPacket>,
+        req: &[u8],
+    ) -> Result<Vec<u8>> {
+        self.handle_urb(interface, ep, setup, req)
+    }
+
+    /// Start handling a URB whose completion needs to `.await`, e.g. a socket
+    /// or another async service
+    ///
+    /// Called with the handler locked, the returned future runs once the lock
+    /// is released, so it has to own what it needs, like a clone of an `Arc`
+    /// to the backend. Errors mean the same as for
+    /// [UsbInterfaceHandler::handle_urb]. See [AsyncHandler] to write the
+    /// whole handler with `async` methods instead.
+    ///
+    /// Defaults to completing right away with
+    /// [UsbInterfaceHandler::handle_urb_with_context].
+    fn handle_urb_async(
+        &mut self,
+        ctx: &ConnectionContext,
+        interface: &UsbInterface,
+        ep: UsbEndpoint,
+        setup: Option<SetupPacket>,
+        req: &[u8],
+    ) -> UrbFuture {
+        let res = self.handle_urb_with_context(ctx, interface, ep, setup, req);
+        Box::pin(futures::future::ready(res))
+    }
+
+    /// Handle an isochronous URB of several packets, described by `packets`
+    ///
+    /// `req` is the transfer buffer of OUT URBs, the data of each packet
+    /// lies at its offset. Return one result per packet.
+    ///
+    /// Defaults to calling [UsbInterfaceHandler::handle_urb] once per packet.
+    /// A packet NAKed with [std::io::ErrorKind::WouldBlock] carries no data,
+    /// other errors fail only their packet.
+    fn handle_iso_urb(
+        &mut self,
+        interface: &UsbInterface,
+        ep: UsbEndpoint,
+        packets: &[IsoPacketDescriptor],
+        req: &[u8],
+    ) -> Result<Vec<IsoPacketResult>> {
+        let mut results = Vec::with_capacity(packets.len());
+        for packet in packets {
+            let data = match ep.direction() {
+                Direction::In => &[],
+                Direction::Out => packet.packet_data(req)?,
+            };
+            results.push(match self.handle_urb(interface, ep, None, data) {
+                Ok(data) => IsoPacketResult { data, status: 0 },
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
+                    IsoPacketResult::default()
+                }
+                Err(err) => {
+                    debug!("Iso packet failed: {}", err);
+                    IsoPacketResult {
+                        data: vec![],
+                        status: -errno::EPROTO,
+                    }
+                }
+            });
+        }
+        Ok(results)
+    }
+
+    /// Endpoints of the interface, as [UsbDevice::with_handler] adds them
+    ///
+    /// Defaults to none, handlers with endpoints are added with
+    /// [UsbDevice::with_interface] then.
+    fn endpoints(&self) -> Vec<UsbEndpoint> {
+        vec![]
+    }
+
+    /// Forget the state of the session of a client, called when it releases the device
+    ///
+    /// A client releases the device when it disconnects or imports a device
+    /// again, so each import starts from a clean slate: clear buffers, go
+    /// back to default settings and drain channels of data to the host. Not
+    /// called for devices with [UsbDevice::with_import_clones], whose
+    /// imports fork the handlers instead. Defaults to doing nothing.
+    fn reset(&mut self) {}
+
+    /// Create an independent handler for a new import, see [UsbDevice::with_import_clones]
+    ///
+    /// Share immutable data with `self` and start mutable state from the
+    /// template. Return `None`, the default, to share this handler instead.
+    fn fork(&self) -> Option<Box<dyn UsbInterfaceHandler + Send>> {
+        None
+    }
+
     /// Helper to downcast to actual struct
     ///
     /// Please implement it as:
@@ -38,3 +251,248 @@ pub trait UsbInterfaceHandler {
     /// ```
     fn as_any(&mut self) -> &mut dyn Any;
 }
+
+/// A handler of a custom usb interface which can `.await`, see [AsyncHandler]
+///
+/// Unlike [UsbInterfaceHandler::handle_urb], the request is passed by value
+/// and the handler stays locked while the returned future runs, so URBs to
+/// the interface are handled one at a time.
+pub trait AsyncUsbInterfaceHandler: Send + 'static {
+    /// Return the class specific desciptor which is inserted between interface descriptor and endpoint descriptor
+    fn get_class_specific_descriptor(&self) -> Vec<u8>;
+
+    /// Endpoints of the interface, see [UsbInterfaceHandler::endpoints]
+    fn endpoints(&self) -> Vec<UsbEndpoint> {
+        vec![]
+    }
+
+    /// Handle a URB targeting at this interface, see [UsbInterfaceHandler::handle_urb]
+    fn handle_urb(
+        &mut self,
+        ctx: ConnectionContext,
+        ep: UsbEndpoint,
+        setup: Option<SetupPacket>,
+        req: Vec<u8>,
+    ) -> BoxFuture<'_, Result<Vec<u8>>>;
+}
+
+/// Run an [AsyncUsbInterfaceHandler] as a [UsbInterfaceHandler]
+///
+/// Downcast to it with [UsbInterfaceHandler::as_any] and use
+/// [AsyncHandler::inner] to reach the wrapped handler.
+pub struct AsyncHandler<H> {
+    inner: Arc<tokio::sync::Mutex<H>>,
+    class_specific_descriptor: Vec<u8>,
+    endpoints: Vec<UsbEndpoint>,
+}
+
+impl<H: AsyncUsbInterfaceHandler> AsyncHandler<H> {
+    pub fn new(handler: H) -> Self {
+        Self {
+            class_specific_descriptor: handler.get_class_specific_descriptor(),
+            endpoints: handler.endpoints(),
+            inner: Arc::new(tokio::sync::Mutex::new(handler)),
+        }
+    }
+
+    /// The wrapped handler, locked while it handles a URB
+    pub fn inner(&self) -> Arc<tokio::sync::Mutex<H>> {
+        self.inner.clone()
+    }
+}
+
+impl<H: AsyncUsbInterfaceHandler> UsbInterfaceHandler for AsyncHandler<H> {
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        self.class_specific_descriptor.clone()
+    }
+
+    fn endpoints(&self) -> Vec<UsbEndpoint> {
+        self.endpoints.clone()
+    }
+
+    /// Only reached through defaults bypassing [UsbInterfaceHandler::handle_urb_async],
+    /// like isochronous transfers, fails
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        ep: UsbEndpoint,
+        _setup: Option<SetupPacket>,
+        _req: &[u8],
+    ) -> Result<Vec<u8>> {
+        Err(std::io::Error::other(format!(
+            "synchronous transfer to async handler on {:02x}",
+            ep.address
+        )))
+    }
+
+    fn handle_urb_async(
+        &mut self,
+        ctx: &ConnectionContext,
+        _interface: &UsbInterface,
+        ep: UsbEndpoint,
+        setup: Option<SetupPacket>,
+        req: &[u8],
+    ) -> UrbFuture {
+        let inner = self.inner.clone();
+        let ctx = ctx.clone();
+        let req = req.to_vec();
+        Box::pin(async move {
+            let mut handler = inner.lock().await;
+            handler.handle_urb(ctx, ep, setup, req).await
+        })
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// A [UsbInterfaceHandler] calling a closure for each URB
+///
+/// Handy for prototypes and tests answering a few vendor requests, e.g.
+/// ```ignore
+/// FnHandler::new(|_, _, setup, _| match setup {
+///     Some(setup) => Ok(vec![setup.request]),
+///     None => Ok(vec![]),
+/// })
+/// .into_handler()
+/// ```
+/// The closure returns errors like [UsbInterfaceHandler::handle_urb] does.
+pub struct FnHandler<F> {
+    handle: F,
+    class_specific_descriptor: Vec<u8>,
+}
+
+impl<F> FnHandler<F>
+where
+    F: FnMut(&UsbInterface, UsbEndpoint, Option<SetupPacket>, &[u8]) -> Result<Vec<u8>>
+        + Send
+        + 'static,
+{
+    pub fn new(handle: F) -> Self {
+        Self {
+            handle,
+            class_specific_descriptor: vec![],
+        }
+    }
+
+    /// Insert `descriptor` between the interface and endpoint descriptors
+    pub fn with_class_specific_descriptor(mut self, descriptor: Vec<u8>) -> Self {
+        self.class_specific_descriptor = descriptor;
+        self
+    }
+
+    /// Box and share it, as [UsbDevice::with_interface] takes it
+    pub fn into_handler(self) -> Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>> {
+        Arc::new(Mutex::new(Box::new(self)))
+    }
+}
+
+impl<F> UsbInterfaceHandler for FnHandler<F>
+where
+    F: FnMut(&UsbInterface, UsbEndpoint, Option<SetupPacket>, &[u8]) -> Result<Vec<u8>>
+        + Send
+        + 'static,
+{
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        self.class_specific_descriptor.clone()
+    }
+
+    fn handle_urb(
+        &mut self,
+        interface: &UsbInterface,
+        ep: UsbEndpoint,
+        setup: Option<SetupPacket>,
+        req: &[u8],
+    ) -> Result<Vec<u8>> {
+        (self.handle)(interface, ep, setup, req)
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn fn_handler() {
+        let mut calls = 0;
+        let device = UsbDevice::new(0).with_interface(
+            ClassCode::VendorSpecific as u8,
+            0x00,
+            0x00,
+            "Test Fn",
+            vec![],
+            FnHandler::new(move |_, _, setup, _| {
+                calls += 1;
+                match setup.map(|setup| setup.request) {
+                    Some(0x01) => Ok(vec![calls]),
+                    _ => Err(std::io::Error::new(ErrorKind::BrokenPipe, "unknown")),
+                }
+            })
+            .with_class_specific_descriptor(vec![0x03, 0x24, 0x00])
+            .into_handler(),
+        );
+        let ctx = ConnectionContext::default();
+
+        // vendor request to interface 0
+        let setup = [0xC1, 0x01, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00];
+        let res = device.handle_urb(&ctx, device.ep0_in, None, setup, &[]);
+        assert_eq!(res.await.unwrap(), [1]);
+        let res = device.handle_urb(&ctx, device.ep0_in, None, setup, &[]);
+        assert_eq!(res.await.unwrap(), [2]);
+        let setup = [0xC1, 0x02, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00];
+        let err = device
+            .handle_urb(&ctx, device.ep0_in, None, setup, &[])
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::BrokenPipe);
+
+        let desc = device.configuration_descriptor();
+        assert!(desc.windows(3).any(|w| w == [0x03, 0x24, 0x00]));
+    }
+
+    #[tokio::test]
+    async fn setup_only_for_control() {
+        let seen = Arc::new(Mutex::new(vec![]));
+        let handler_seen = seen.clone();
+        let ep = UsbEndpoint {
+            address: 0x81,
+            attributes: EndpointAttributes::Bulk as u8,
+            max_packet_size: 512,
+            interval: 0,
+        };
+        let device = UsbDevice::new(0).with_interface(
+            ClassCode::VendorSpecific as u8,
+            0x00,
+            0x00,
+            "Test Setup",
+            vec![ep],
+            FnHandler::new(move |_, _, setup, _| {
+                handler_seen
+                    .lock()
+                    .unwrap()
+                    .push(setup.map(|setup| setup.request));
+                Ok(vec![])
+            })
+            .into_handler(),
+        );
+        let ctx = ConnectionContext::default();
+
+        let setup = [0xC1, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+        device
+            .handle_urb(&ctx, device.ep0_in, None, setup, &[])
+            .await
+            .unwrap();
+        // garbage in the setup bytes of a bulk URB does not reach the handler
+        let intf = &device.interfaces[0];
+        device
+            .handle_urb(&ctx, ep, Some(intf), [0xFF; 8], &[])
+            .await
+            .unwrap();
+        assert_eq!(*seen.lock().unwrap(), [Some(0x01), None]);
+    }
+}
@@ -0,0 +1,235 @@
+use super::*;
+
+/// Size of one iso packet descriptor in the USB/IP stream
+pub const ISO_PACKET_DESCRIPTOR_SIZE: usize = 16;
+
+/// Value of number_of_packets in CMD_SUBMIT of non isochronous URBs
+pub const ISO_NO_PACKETS: u32 = 0xFFFF_FFFF;
+
+/// Most packets accepted in one isochronous URB
+///
+/// URBs with more packets, or more packets than bytes, complete with
+/// `-EINVAL` before their packet descriptors are buffered.
+pub const MAX_ISO_PACKETS: u32 = 1024;
+
+/// Describe one packet of an isochronous URB, `usbip_iso_packet_descriptor`
+///
+/// All fields are big endian in the USB/IP stream. The descriptors follow the
+/// transfer buffer in CMD_SUBMIT and RET_SUBMIT.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IsoPacketDescriptor {
+    /// Offset of the packet in the transfer buffer
+    pub offset: u32,
+    /// Expected length of the packet
+    pub length: u32,
+    /// Length actually transferred
+    pub actual_length: u32,
+    pub status: i32,
+}
+
+impl IsoPacketDescriptor {
+    pub async fn read<T: AsyncReadExt + Unpin>(socket: &mut T) -> Result<Self> {
+        Ok(Self {
+            offset: socket.read_u32().await?,
+            length: socket.read_u32().await?,
+            actual_length: socket.read_u32().await?,
+            status: socket.read_i32().await?,
+        })
+    }
+
+    pub async fn write<T: AsyncWriteExt + Unpin>(&self, socket: &mut T) -> Result<()> {
+        socket.write_u32(self.offset).await?;
+        socket.write_u32(self.length).await?;
+        socket.write_u32(self.actual_length).await?;
+        socket.write_i32(self.status).await?;
+        Ok(())
+    }
+
+    /// The `length` bytes of this packet in `buffer`, failing if they are out of bounds
+    pub fn packet_data<'a>(&self, buffer: &'a [u8]) -> Result<&'a [u8]> {
+        let start = self.offset as usize;
+        start
+            .checked_add(self.length as usize)
+            .and_then(|end| buffer.get(start..end))
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "iso packet {}+{} out of buffer of {} bytes",
+                        self.offset,
+                        self.length,
+                        buffer.len()
+                    ),
+                )
+            })
+    }
+}
+
+/// Outcome of one packet of an isochronous URB, see [UsbInterfaceHandler::handle_iso_urb]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IsoPacketResult {
+    /// Data of an IN packet, truncated to the packet length. Ignored for OUT.
+    pub data: Vec<u8>,
+    /// 0 or a negated errno, e.g. `-errno::EPROTO`
+    pub status: i32,
+}
+
+/// Fill actual_length and status of `packets` from `results`
+///
+/// Returns the data of IN packets back to back, the way RET_SUBMIT carries
+/// it. Data longer than its packet is cut and reported as `-EOVERFLOW`,
+/// OUT packets which succeeded consumed their whole length.
+pub fn complete_iso_packets(
+    direction: Direction,
+    packets: &mut [IsoPacketDescriptor],
+    results: Vec<IsoPacketResult>,
+) -> Vec<u8> {
+    if results.len() != packets.len() {
+        warn!(
+            "Got {} results for {} iso packets",
+            results.len(),
+            packets.len()
+        );
+    }
+    let mut data = vec![];
+    let mut results = results.into_iter();
+    for packet in packets.iter_mut() {
+        let mut result = results.next().unwrap_or_default();
+        packet.status = result.status;
+        packet.actual_length = match direction {
+            Direction::In => {
+                if result.data.len() > packet.length as usize {
+                    result.data.truncate(packet.length as usize);
+                    if packet.status == 0 {
+                        packet.status = -errno::EOVERFLOW;
+                    }
+                }
+                data.extend_from_slice(&result.data);
+                result.data.len() as u32
+            }
+            Direction::Out if result.status == 0 => packet.length,
+            Direction::Out => 0,
+        };
+    }
+    data
+}
+
+/// Read the `count` iso packet descriptors following an isochronous URB
+pub async fn read_iso_packet_descriptors<T: AsyncReadExt + Unpin>(
+    socket: &mut T,
+    count: usize,
+) -> Result<Vec<IsoPacketDescriptor>> {
+    // in one read, like the command headers
+    let mut bytes = vec![0u8; count * ISO_PACKET_DESCRIPTOR_SIZE];
+    socket.read_exact(&mut bytes).await?;
+    let word = |desc: &[u8], offset: usize| {
+        u32::from_be_bytes([
+            desc[offset],
+            desc[offset + 1],
+            desc[offset + 2],
+            desc[offset + 3],
+        ])
+    };
+    Ok(bytes
+        .chunks(ISO_PACKET_DESCRIPTOR_SIZE)
+        .map(|desc| IsoPacketDescriptor {
+            offset: word(desc, 0),
+            length: word(desc, 4),
+            actual_length: word(desc, 8),
+            status: word(desc, 12) as i32,
+        })
+        .collect())
+}
+
+/// Write iso packet descriptors after the data of an isochronous RET_SUBMIT
+pub async fn write_iso_packet_descriptors<T: AsyncWriteExt + Unpin>(
+    socket: &mut T,
+    descriptors: &[IsoPacketDescriptor],
+) -> Result<()> {
+    for desc in descriptors {
+        desc.write(socket).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn round_trip_descriptors() {
+        let descriptors = vec![
+            IsoPacketDescriptor {
+                offset: 0,
+                length: 3,
+                actual_length: 3,
+                status: 0,
+            },
+            IsoPacketDescriptor {
+                offset: 3,
+                length: 0x102,
+                actual_length: 2,
+                status: -errno::EPROTO,
+            },
+        ];
+        let mut socket = MockSocket::new(vec![]);
+        write_iso_packet_descriptors(&mut socket, &descriptors)
+            .await
+            .unwrap();
+        assert_eq!(socket.output.len(), 2 * ISO_PACKET_DESCRIPTOR_SIZE);
+        // big endian
+        assert_eq!(
+            socket.output[16..24],
+            [0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x01, 0x02]
+        );
+        assert_eq!(socket.output[28..32], [0xFF, 0xFF, 0xFF, 0xB9]);
+
+        let mut socket = MockSocket::new(socket.output);
+        let read = read_iso_packet_descriptors(&mut socket, 2).await.unwrap();
+        assert_eq!(read, descriptors);
+
+        let buffer = [1, 2, 3, 4, 5];
+        assert_eq!(read[0].packet_data(&buffer).unwrap(), [1, 2, 3]);
+        assert!(read[1].packet_data(&buffer).is_err());
+    }
+
+    #[test]
+    fn complete_packets() {
+        let packet = |offset, length| IsoPacketDescriptor {
+            offset,
+            length,
+            ..Default::default()
+        };
+        let result = |data: &[u8], status| IsoPacketResult {
+            data: data.to_vec(),
+            status,
+        };
+
+        // short, zero length, too long and missing packets
+        let mut packets = vec![packet(0, 4), packet(4, 0), packet(4, 2), packet(6, 4)];
+        let data = complete_iso_packets(
+            Direction::In,
+            &mut packets,
+            vec![result(&[1, 2], 0), result(&[], 0), result(&[3, 4, 5], 0)],
+        );
+        assert_eq!(data, [1, 2, 3, 4]);
+        let actual: Vec<_> = packets
+            .iter()
+            .map(|p| (p.actual_length, p.status))
+            .collect();
+        assert_eq!(actual, [(2, 0), (0, 0), (2, -errno::EOVERFLOW), (0, 0)]);
+
+        let mut packets = vec![packet(0, 4), packet(4, 4)];
+        let data = complete_iso_packets(
+            Direction::Out,
+            &mut packets,
+            vec![result(&[], 0), result(&[], -errno::EPROTO)],
+        );
+        assert!(data.is_empty());
+        assert_eq!(packets[0].actual_length, 4);
+        assert_eq!(
+            (packets[1].actual_length, packets[1].status),
+            (0, -errno::EPROTO)
+        );
+    }
+}
@@ -0,0 +1,66 @@
+use super::*;
+
+/// A random delay before each reply, modeling the variable latency of a real
+/// bus and network
+///
+/// Delays are uniform in `min..=max` with microsecond resolution. They come
+/// from a splitmix64 generator, so the same seed always gives the same
+/// sequence of delays.
+#[derive(Clone, Debug)]
+pub struct ResponseJitter {
+    min: Duration,
+    max: Duration,
+    state: u64,
+}
+
+impl ResponseJitter {
+    /// Create a generator of delays between `min` and `max` seeded with `seed`
+    pub fn new(min: Duration, max: Duration, seed: u64) -> Self {
+        assert!(min <= max, "jitter minimum above maximum");
+        Self {
+            min,
+            max,
+            state: seed,
+        }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Draw the next delay
+    pub fn next_delay(&mut self) -> Duration {
+        let span = (self.max - self.min).as_micros() as u64;
+        let offset = match span.checked_add(1) {
+            Some(range) => self.next_u64() % range,
+            None => self.next_u64(),
+        };
+        self.min + Duration::from_micros(offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays_in_range_and_reproducible() {
+        let min = Duration::from_micros(100);
+        let max = Duration::from_micros(300);
+        let mut jitter = ResponseJitter::new(min, max, 42);
+        let delays: Vec<_> = (0..1000).map(|_| jitter.next_delay()).collect();
+        assert!(delays.iter().all(|d| *d >= min && *d <= max));
+        // not stuck at one value
+        assert!(delays.iter().any(|d| *d != delays[0]));
+
+        let mut again = ResponseJitter::new(min, max, 42);
+        assert!(delays.iter().all(|d| *d == again.next_delay()));
+
+        let mut fixed = ResponseJitter::new(min, min, 7);
+        assert_eq!(fixed.next_delay(), min);
+    }
+}
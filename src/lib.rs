@@ -1,201 +1,1392 @@
 //! A library for running a USB/IP server
 
-use futures::stream::StreamExt;
+use futures::future::BoxFuture;
 use log::*;
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
-use rusb::*;
+use rusb::{DeviceHandle, GlobalContext};
 use std::any::Any;
-use std::collections::{HashMap, VecDeque};
-use std::io::Result;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::{ErrorKind, Result};
 use std::net::SocketAddr;
+use std::path::Path;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::io::AsyncReadExt;
 use tokio::io::AsyncWriteExt;
-use tokio::net::TcpListener;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::RwLock;
 
+pub mod audio;
 pub mod cdc;
+mod client;
 mod consts;
 mod device;
+pub mod dfu;
 mod endpoint;
+mod error;
+mod fat;
+mod framing;
 pub mod hid;
+mod history;
 mod host;
+mod hotplug;
+pub mod hub;
 mod interface;
+mod iso;
+#[cfg(feature = "fault-injection")]
+mod jitter;
+pub mod midi;
+pub mod msc;
+pub mod printer;
+mod replay;
+mod schedule;
 mod setup;
+mod sink;
+mod span;
+mod stats;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+mod throttle;
+mod transaction;
 mod util;
+pub mod uvc;
+mod validate;
+#[cfg(feature = "websocket")]
+pub mod websocket;
+pub use client::*;
 pub use consts::*;
 pub use device::*;
 pub use endpoint::*;
+pub use error::*;
+pub use framing::*;
+pub use history::*;
 pub use host::*;
+pub use hotplug::*;
 pub use interface::*;
+pub use iso::*;
+#[cfg(feature = "fault-injection")]
+pub use jitter::*;
+pub use replay::*;
+pub use schedule::*;
 pub use setup::*;
+pub use sink::*;
+pub use stats::*;
+pub use throttle::*;
+pub use transaction::*;
 pub use util::*;
+pub use validate::*;
+
+/// Handler of a custom OP command, see [UsbIpServer::with_custom_op]
+pub type CustomOpHandler =
+    Arc<dyn for<'a> Fn(&'a mut dyn UsbIpSocket) -> BoxFuture<'a, Result<()>> + Send + Sync>;
+
+/// Decides whether to serve a TCP peer, see [UsbIpServer::with_connection_filter]
+pub type ConnectionFilter = Arc<dyn Fn(SocketAddr) -> bool + Send + Sync>;
+
+/// Decides whether a connection may import a device, see
+/// [UsbIpServer::with_import_authorization]
+pub type ImportAuthorization = Arc<dyn Fn(&ConnectionContext, &UsbDevice) -> bool + Send + Sync>;
+
+/// What happens to TCP connections beyond the limit of
+/// [UsbIpServer::with_max_connections]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionLimitPolicy {
+    /// Leave them in the listen backlog until a connection closes
+    Wait,
+    /// Accept and close them right away
+    Reject,
+}
 
 /// Main struct of a USB/IP server
+///
+/// The exported devices form a registry which can change while the server
+/// runs, see [UsbIpServer::add_device], [UsbIpServer::remove_device] and
+/// [UsbIpServer::unplug_device]. Each device list reflects the registry at
+/// the time it is requested.
+///
+/// An import clones the device out of the registry. Unless the device was
+/// built with [UsbDevice::with_import_clones], the clone shares its interface
+/// handlers with the registry entry and with every other import of it: a
+/// handler is called from whichever connection task submits a URB, with its
+/// mutex held for the duration of the call. Handlers must therefore be
+/// `Send`, should not block, and must not lock the handler of another
+/// interface of the same device from within a call. Removing a device from
+/// the registry does not invalidate handlers still used by live imports.
 pub struct UsbIpServer {
-    devices: Vec<UsbDevice>,
+    devices: RwLock<Vec<UsbDevice>>,
+    custom_ops: HashMap<[u8; 4], CustomOpHandler>,
+    max_transfer_size: usize,
+    urb_history_len: usize,
+    urb_histories: Mutex<VecDeque<(ConnectionContext, Arc<Mutex<UrbHistory>>)>>,
+    validate_devices: bool,
+    listen_backlog: u32,
+    accept_concurrency: usize,
+    stats: Arc<ServerCounters>,
+    bus: Option<Bus>,
+    strict_submits: bool,
+    handshake_timeout: Option<Duration>,
+    connection_filter: Option<ConnectionFilter>,
+    import_authorization: Option<ImportAuthorization>,
+    connection_limit: Option<(Arc<tokio::sync::Semaphore>, ConnectionLimitPolicy)>,
+    imports: Arc<Mutex<BTreeMap<u64, (String, ConnectionContext)>>>,
+}
+
+/// Entry of an import in [UsbIpServer::imported_devices], removed when dropped
+struct ImportRecord {
+    imports: Arc<Mutex<BTreeMap<u64, (String, ConnectionContext)>>>,
+    connection_id: u64,
+}
+
+impl Drop for ImportRecord {
+    fn drop(&mut self) {
+        self.imports.lock().unwrap().remove(&self.connection_id);
+    }
 }
 
 impl UsbIpServer {
     /// Create a [UsbIpServer] with simulated devices
     pub fn new_simulated(devices: Vec<UsbDevice>) -> Self {
-        Self { devices }
+        Self {
+            devices: RwLock::new(devices),
+            custom_ops: HashMap::new(),
+            max_transfer_size: DEFAULT_MAX_TRANSFER_SIZE,
+            urb_history_len: 0,
+            urb_histories: Mutex::new(VecDeque::new()),
+            validate_devices: false,
+            listen_backlog: DEFAULT_LISTEN_BACKLOG,
+            accept_concurrency: 1,
+            stats: Arc::new(ServerCounters::default()),
+            bus: None,
+            strict_submits: false,
+            handshake_timeout: None,
+            connection_filter: None,
+            import_authorization: None,
+            connection_limit: None,
+            imports: Default::default(),
+        }
+    }
+
+    /// Serve only TCP peers for which `filter` returns true
+    ///
+    /// Consulted by [server], [server_with_shutdown] and [spawn_server] on
+    /// each accepted connection, a refused one is closed before any USB/IP
+    /// exchange.
+    /// Callers of [serve_stream] accept connections themselves and filter
+    /// them before.
+    pub fn with_connection_filter<F>(mut self, filter: F) -> Self
+    where
+        F: Fn(SocketAddr) -> bool + Send + Sync + 'static,
+    {
+        self.connection_filter = Some(Arc::new(filter));
+        self
+    }
+
+    /// Let a connection import a device only if `authorize` returns true
+    ///
+    /// Consulted on each OP_REQ_IMPORT with the context of the connection,
+    /// whose peer is the address of TCP clients. A refused import is answered
+    /// like one of an unknown device. OP_REQ_DEVLIST still lists every device.
+    pub fn with_import_authorization<F>(mut self, authorize: F) -> Self
+    where
+        F: Fn(&ConnectionContext, &UsbDevice) -> bool + Send + Sync + 'static,
+    {
+        self.import_authorization = Some(Arc::new(authorize));
+        self
+    }
+
+    /// Serve at most `max` connections at once
+    ///
+    /// Consulted by [server], [server_with_shutdown], [spawn_server] and
+    /// [server_unix], so a flood of connections cannot exhaust memory.
+    /// Connections beyond it wait or are rejected following `policy`.
+    /// Without it, there is no limit.
+    pub fn with_max_connections(mut self, max: usize, policy: ConnectionLimitPolicy) -> Self {
+        self.connection_limit = Some((Arc::new(tokio::sync::Semaphore::new(max)), policy));
+        self
+    }
+
+    /// Queue up to `backlog` connections not accepted yet, see [server]
+    ///
+    /// Connections arriving while the queue is full are dropped or retried
+    /// later by the client. The platform caps the value: Linux silently
+    /// limits it to `net.core.somaxconn`, macOS and the BSDs to
+    /// `kern.ipc.somaxconn`, Windows to about 200 unless `SOMAXCONN` is
+    /// passed. Defaults to [DEFAULT_LISTEN_BACKLOG].
+    pub fn with_listen_backlog(mut self, backlog: u32) -> Self {
+        self.listen_backlog = backlog;
+        self
+    }
+
+    /// Keep `concurrency` accepts pending on the listener at once
+    ///
+    /// Helps draining the backlog when many clients attach at the same
+    /// time. Defaults to 1, values below are treated as 1.
+    pub fn with_accept_concurrency(mut self, concurrency: usize) -> Self {
+        self.accept_concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Order and time URB completions of all connections with `scheduler`
+    ///
+    /// Without a scheduler, each completion is sent as soon as it is ready,
+    /// like with [FifoScheduler] on an idle bus.
+    pub fn with_urb_scheduler<S: UrbScheduler + 'static>(mut self, scheduler: S) -> Self {
+        self.bus = Some(Bus::new(Box::new(scheduler)));
+        self
+    }
+
+    /// Reject URBs a host controller driver would not have submitted
+    ///
+    /// A diagnostic mode for client and host driver bugs, which are
+    /// otherwise handled on a best effort basis. Each violation is logged as
+    /// a warning and fails the URB:
+    /// - endpoints the device does not have fail with `-ENOENT`, as do those
+    ///   of an unconfigured device or of an alternate setting not selected
+    /// - control transfers whose wLength is not the transfer length fail
+    ///   with `-EBADR`
+    /// - iso packets above the payload of their endpoint fail with `-EMSGSIZE`
+    pub fn with_strict_submits(mut self) -> Self {
+        self.strict_submits = true;
+        self
+    }
+
+    /// Check exported devices with [UsbDevice::validate] and log every problem
+    ///
+    /// Devices are checked when the server starts and when they are added.
+    /// Problems are only logged as warnings, the devices are still exported.
+    pub fn with_device_validation(mut self) -> Self {
+        self.validate_devices = true;
+        self
+    }
+
+    fn warn_invalid_device(&self, device: &UsbDevice) {
+        if !self.validate_devices {
+            return;
+        }
+        if let Err(errors) = device.validate() {
+            for error in errors {
+                warn!("Device {}: {}", device.bus_id, error);
+            }
+        }
+    }
+
+    async fn validate_all_devices(&self) {
+        if self.validate_devices {
+            for device in self.devices.read().await.iter() {
+                self.warn_invalid_device(device);
+            }
+        }
+    }
+
+    /// Reject URBs with a transfer_buffer_length above `max` bytes with `-EINVAL`
+    ///
+    /// Bounds the memory a client can make the server allocate per URB.
+    /// Defaults to [DEFAULT_MAX_TRANSFER_SIZE].
+    pub fn with_max_transfer_size(mut self, max: usize) -> Self {
+        self.max_transfer_size = max;
+        self
+    }
+
+    /// Drop connections which do not complete an OP request within `timeout`
+    ///
+    /// Until a device is imported, each request must arrive in full within
+    /// `timeout` of the connection or of the previous reply, otherwise the
+    /// connection ends with [ErrorKind::TimedOut]. A real client sends its
+    /// request at once, so a few seconds are plenty and keep clients which
+    /// trickle or withhold bytes from holding a task. Imported connections
+    /// may idle as long as they like. Disabled by default.
+    pub fn with_handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = Some(timeout);
+        self
+    }
+
+    /// Keep the last `len` URBs of each connection for post-mortem debugging
+    ///
+    /// Histories outlive their connection so they can be inspected after a
+    /// handler failed or a client dropped, see [UsbIpServer::urb_histories].
+    /// Only the last [URB_HISTORY_CONNECTIONS] connections are kept.
+    pub fn with_urb_history(mut self, len: usize) -> Self {
+        self.urb_history_len = len;
+        self
+    }
+
+    /// URB histories of recent connections, oldest connection first
+    pub fn urb_histories(&self) -> Vec<(ConnectionContext, Vec<UrbRecord>)> {
+        self.urb_histories
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(ctx, history)| (ctx.clone(), history.lock().unwrap().records()))
+            .collect()
+    }
+
+    /// URB history of the connection with id `connection_id`, if still kept
+    pub fn urb_history(&self, connection_id: u64) -> Option<Vec<UrbRecord>> {
+        self.urb_histories
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(ctx, _)| ctx.id == connection_id)
+            .map(|(_, history)| history.lock().unwrap().records())
+    }
+
+    /// Bus ids of the devices imported now, with the context of the
+    /// connection importing each, in the order connections were made
+    ///
+    /// The peer of the context is the address of TCP clients. A device built
+    /// with [UsbDevice::with_import_clones] shows up once per import.
+    pub fn imported_devices(&self) -> Vec<(String, ConnectionContext)> {
+        self.imports.lock().unwrap().values().cloned().collect()
+    }
+
+    fn record_import(&self, bus_id: &str, ctx: &ConnectionContext) -> ImportRecord {
+        self.imports
+            .lock()
+            .unwrap()
+            .insert(ctx.id, (bus_id.to_string(), ctx.clone()));
+        ImportRecord {
+            imports: self.imports.clone(),
+            connection_id: ctx.id,
+        }
+    }
+
+    /// Connection, URB and transfer counters of all connections so far
+    pub fn stats(&self) -> ServerStats {
+        self.stats.snapshot()
+    }
+
+    fn new_urb_history(&self, ctx: &ConnectionContext) -> Option<Arc<Mutex<UrbHistory>>> {
+        if self.urb_history_len == 0 {
+            return None;
+        }
+        let history = Arc::new(Mutex::new(UrbHistory::new(self.urb_history_len)));
+        let mut histories = self.urb_histories.lock().unwrap();
+        if histories.len() == URB_HISTORY_CONNECTIONS {
+            histories.pop_front();
+        }
+        histories.push_back((ctx.clone(), history.clone()));
+        Some(history)
+    }
+
+    /// Export a new device, it shows up in subsequent device lists
+    ///
+    /// Fails with [std::io::ErrorKind::AlreadyExists] if a device with the
+    /// same bus id is exported, clients could not tell them apart.
+    pub async fn add_device(&self, device: UsbDevice) -> Result<()> {
+        self.warn_invalid_device(&device);
+        let mut devices = self.devices.write().await;
+        if devices.iter().any(|d| d.bus_id == device.bus_id) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("device {} already exists", device.bus_id),
+            ));
+        }
+        devices.push(device);
+        Ok(())
+    }
+
+    /// Stop exporting the device with `bus_id`
+    ///
+    /// Clients that already imported it keep their own reference to it.
+    pub async fn remove_device(&self, bus_id: &str) -> Result<()> {
+        self.take_device(bus_id).await.map(|_| ())
+    }
+
+    /// Stop exporting the device with `bus_id`, as if it was unplugged
+    ///
+    /// Unlike [UsbIpServer::remove_device], clients that imported it see it
+    /// go: pending and subsequent URBs fail with `-ENODEV`, and connections
+    /// drop their reference to it, which releases the interfaces of a host
+    /// device once nothing else uses it.
+    pub async fn unplug_device(&self, bus_id: &str) -> Result<()> {
+        let device = self.take_device(bus_id).await?;
+        info!("Device {} unplugged", bus_id);
+        device.removal.remove();
+        Ok(())
+    }
+
+    async fn take_device(&self, bus_id: &str) -> Result<UsbDevice> {
+        let mut devices = self.devices.write().await;
+        if let Some(index) = devices.iter().position(|d| d.bus_id == bus_id) {
+            Ok(devices.remove(index))
+        } else {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("device {} not found", bus_id),
+            ))
+        }
+    }
+
+    /// Swap in devices which re-enumerated, see [Reenumeration]
+    ///
+    /// Only takes the device list for writing when a device re-enumerated, so
+    /// OP_REQ_DEVLIST and OP_REQ_IMPORT of other connections do not queue up
+    /// behind each other.
+    async fn apply_reenumerations(&self) {
+        let triggered = self.devices.read().await.iter().any(|device| {
+            device
+                .reenumeration
+                .as_ref()
+                .is_some_and(Reenumeration::is_triggered)
+        });
+        if !triggered {
+            return;
+        }
+        let mut devices = self.devices.write().await;
+        for device in devices.iter_mut() {
+            if let Some(mut next) = device.reenumeration.as_ref().and_then(|r| r.take()) {
+                info!("Device {} re-enumerated", device.bus_id);
+                next.path = device.path.clone();
+                next.bus_id = device.bus_id.clone();
+                next.bus_num = device.bus_num;
+                next.dev_num = device.dev_num;
+                *device = next;
+            }
+        }
+    }
+
+    /// Register a handler for a custom 4-byte OP command
+    ///
+    /// The handler is called after the command has been read and is
+    /// responsible for reading the rest of the request and writing the reply.
+    /// Standard USB/IP commands are always handled by the server and can not be
+    /// overridden: registering one of them panics.
+    pub fn with_custom_op<F>(mut self, command: [u8; 4], handler: F) -> Self
+    where
+        F: for<'a> Fn(&'a mut dyn UsbIpSocket) -> BoxFuture<'a, Result<()>> + Send + Sync + 'static,
+    {
+        assert!(
+            !STANDARD_COMMANDS.contains(&command),
+            "standard command {:02x?} can not be overridden",
+            command
+        );
+        self.custom_ops.insert(command, Arc::new(handler));
+        self
     }
 
     /// Create a [UsbIpServer] exposing devices in the host, and redirect all USB transfers to them using libusb
+    ///
+    /// Devices which can not be opened, e.g. for lack of permissions, are
+    /// still listed with their descriptors, but their transfers stall.
     pub fn new_from_host() -> Self {
         let mut devices = vec![];
         if let Ok(list) = rusb::devices() {
             for dev in list.iter() {
-                let handle = Arc::new(Mutex::new(dev.open().unwrap()));
-                let desc = dev.device_descriptor().unwrap();
-                let cfg = dev.active_config_descriptor().unwrap();
-                let mut interfaces = vec![];
-                handle
-                    .lock()
-                    .unwrap()
-                    .set_auto_detach_kernel_driver(true)
-                    .ok();
-                for intf in cfg.interfaces() {
-                    // ignore alternate settings
-                    let intf_desc = intf.descriptors().next().unwrap();
-                    handle
-                        .lock()
-                        .unwrap()
-                        .claim_interface(intf_desc.interface_number())
-                        .ok();
-                    let mut endpoints = vec![];
-
-                    for ep_desc in intf_desc.endpoint_descriptors() {
-                        endpoints.push(UsbEndpoint {
-                            address: ep_desc.address(),
-                            attributes: ep_desc.transfer_type() as u8,
-                            max_packet_size: ep_desc.max_packet_size(),
-                            interval: ep_desc.interval(),
-                        });
+                let mut device = match UsbDevice::from_host_device(&dev) {
+                    Ok(device) => device,
+                    Err(err) => {
+                        warn!("Skipping host device: {}", err);
+                        continue;
                     }
-
-                    let handler =
-                        Arc::new(Mutex::new(Box::new(UsbHostHandler::new(handle.clone()))
-                            as Box<dyn UsbInterfaceHandler + Send>));
-                    interfaces.push(UsbInterface {
-                        interface_class: intf_desc.class_code(),
-                        interface_subclass: intf_desc.sub_class_code(),
-                        interface_protocol: intf_desc.protocol_code(),
-                        endpoints,
-                        string_interface: intf_desc.description_string_index().unwrap_or(0),
-                        class_specific_descriptor: Vec::from(intf_desc.extra().unwrap_or(&[])),
-                        handler,
-                    });
-                }
-                let mut device = UsbDevice {
-                    path: format!(
-                        "/sys/bus/{}/{}/{}",
-                        dev.bus_number(),
-                        dev.address(),
-                        dev.port_number()
-                    ),
-                    bus_id: format!(
-                        "{}-{}-{}",
-                        dev.bus_number(),
-                        dev.address(),
-                        dev.port_number()
-                    ),
-                    bus_num: dev.bus_number() as u32,
-                    dev_num: dev.port_number() as u32,
-                    speed: dev.speed() as u32,
-                    vendor_id: desc.vendor_id(),
-                    product_id: desc.product_id(),
-                    device_class: desc.class_code(),
-                    device_subclass: desc.sub_class_code(),
-                    device_protocol: desc.protocol_code(),
-                    configuration_value: cfg.number(),
-                    num_configurations: desc.num_configurations(),
-                    ep0_in: UsbEndpoint {
-                        address: 0x80,
-                        attributes: EndpointAttributes::Control as u8,
-                        max_packet_size: desc.max_packet_size() as u16,
-                        interval: 0,
-                    },
-                    ep0_out: UsbEndpoint {
-                        address: 0x00,
-                        attributes: EndpointAttributes::Control as u8,
-                        max_packet_size: desc.max_packet_size() as u16,
-                        interval: 0,
-                    },
-                    interfaces,
-                    ..UsbDevice::default()
                 };
-
-                // set strings
-                if let Some(index) = desc.manufacturer_string_index() {
-                    device.string_manufacturer = device.new_string(
-                        &handle
-                            .lock()
-                            .unwrap()
-                            .read_string_descriptor_ascii(index)
-                            .unwrap(),
-                    )
+                match dev.open() {
+                    Ok(handle) => device.attach_host_handle(Arc::new(Mutex::new(handle))),
+                    Err(err) => warn!(
+                        "Can not open device {}, exporting descriptors only: {}",
+                        device.bus_id, err
+                    ),
                 }
-                if let Some(index) = desc.product_string_index() {
-                    device.string_product = device.new_string(
-                        &handle
-                            .lock()
-                            .unwrap()
-                            .read_string_descriptor_ascii(index)
-                            .unwrap(),
-                    )
+                devices.push(device);
+            }
+        }
+        Self::new_simulated(devices)
+    }
+}
+
+/// Default listen backlog, see [UsbIpServer::with_listen_backlog]
+pub const DEFAULT_LISTEN_BACKLOG: u32 = 1024;
+
+/// Default limit of transfer_buffer_length, see [UsbIpServer::with_max_transfer_size]
+pub const DEFAULT_MAX_TRANSFER_SIZE: usize = 16 * 1024 * 1024;
+
+/// Delay before retrying a URB whose handler returned [ErrorKind::WouldBlock]
+///
+/// URBs on an endpoint with an [InEndpointQueue] are retried as soon as data
+/// is pushed instead, interrupt URBs once per interval if that is longer.
+/// Later URBs to the same endpoint wait behind the NAKed one, those to other
+/// endpoints are served meanwhile.
+pub const NAK_RETRY_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Version of the USB/IP protocol spoken, 1.1.1, the first two bytes of OP requests
+pub const USBIP_VERSION: u16 = 0x0111;
+
+/// Status of an OP_REP_IMPORT refused to the client, ST_NA of Linux
+const OP_STATUS_NOT_AVAILABLE: u32 = 1;
+/// Status of an OP_REP_IMPORT of a device imported by another client, ST_DEV_BUSY of Linux
+const OP_STATUS_DEVICE_BUSY: u32 = 2;
+/// Status of an OP_REP_IMPORT of an unknown bus id, ST_NODEV of Linux
+const OP_STATUS_NO_DEVICE: u32 = 4;
+/// Status of an OP reply to a request the server can not handle, ST_ERROR of Linux
+const OP_STATUS_ERROR: u32 = 5;
+
+/// Commands defined by the USB/IP protocol
+const STANDARD_COMMANDS: [[u8; 4]; 4] = [
+    [0x01, 0x11, 0x80, 0x05], // OP_REQ_DEVLIST
+    [0x01, 0x11, 0x80, 0x03], // OP_REQ_IMPORT
+    [0x00, 0x00, 0x00, 0x01], // USBIP_CMD_SUBMIT
+    [0x00, 0x00, 0x00, 0x02], // USBIP_CMD_UNLINK
+];
+
+/// Command of USBIP_CMD_UNLINK
+const CMD_UNLINK: [u8; 4] = [0x00, 0x00, 0x00, 0x02];
+
+/// Length of USBIP_CMD_SUBMIT and USBIP_CMD_UNLINK headers after the command
+const CMD_HEADER_REST_LEN: usize = 0x30 - 4;
+
+/// Big endian word at `offset` of a header read in one go
+fn header_u32(bytes: &[u8], offset: usize) -> u32 {
+    let mut word = [0u8; 4];
+    word.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_be_bytes(word)
+}
+
+/// Read the rest of an OP request after its code: the status, then the bus
+/// id of OP_REQ_IMPORT
+async fn read_op_header<T: AsyncReadExt + Unpin>(
+    socket: &mut T,
+    code: u8,
+) -> Result<Option<[u8; 32]>> {
+    if code != 0x03 {
+        let _status = socket.read_u32().await?;
+        return Ok(None);
+    }
+    let mut bytes = [0u8; 4 + 32];
+    socket.read_exact(&mut bytes).await?;
+    let mut bus_id = [0u8; 32];
+    bus_id.copy_from_slice(&bytes[4..]);
+    Ok(Some(bus_id))
+}
+
+/// Header of USBIP_CMD_SUBMIT after the command
+struct SubmitRequest {
+    seq_num: u32,
+    dev_id: u32,
+    direction: u32,
+    ep: u32,
+    transfer_flags: u32,
+    transfer_buffer_length: u32,
+    start_frame: u32,
+    number_of_packets: u32,
+    interval: u32,
+    setup: [u8; 8],
+}
+
+impl SubmitRequest {
+    /// Read the whole fixed size header at once
+    async fn read<T: AsyncReadExt + Unpin>(socket: &mut T) -> Result<Self> {
+        let mut bytes = [0u8; CMD_HEADER_REST_LEN];
+        socket.read_exact(&mut bytes).await?;
+        let mut setup = [0u8; 8];
+        setup.copy_from_slice(&bytes[0x24..0x2C]);
+        Ok(Self {
+            seq_num: header_u32(&bytes, 0x00),
+            dev_id: header_u32(&bytes, 0x04),
+            direction: header_u32(&bytes, 0x08),
+            ep: header_u32(&bytes, 0x0C),
+            transfer_flags: header_u32(&bytes, 0x10),
+            transfer_buffer_length: header_u32(&bytes, 0x14),
+            start_frame: header_u32(&bytes, 0x18),
+            number_of_packets: header_u32(&bytes, 0x1C),
+            interval: header_u32(&bytes, 0x20),
+            setup,
+        })
+    }
+}
+
+/// Header of USBIP_CMD_UNLINK after the command
+struct UnlinkRequest {
+    seq_num: u32,
+    dev_id: u32,
+    direction: u32,
+    ep: u32,
+    seq_num_submit: u32,
+}
+
+impl UnlinkRequest {
+    /// Read the whole fixed size header at once, including 24 bytes of
+    /// struct padding
+    async fn read<T: AsyncReadExt + Unpin>(socket: &mut T) -> Result<Self> {
+        let mut bytes = [0u8; CMD_HEADER_REST_LEN];
+        socket.read_exact(&mut bytes).await?;
+        Ok(Self {
+            seq_num: header_u32(&bytes, 0x00),
+            dev_id: header_u32(&bytes, 0x04),
+            direction: header_u32(&bytes, 0x08),
+            ep: header_u32(&bytes, 0x0C),
+            seq_num_submit: header_u32(&bytes, 0x10),
+        })
+    }
+
+    /// Send USBIP_RET_UNLINK
+    async fn reply<T: AsyncWriteExt + Unpin>(&self, socket: &mut T, status: i32) -> Result<()> {
+        // command
+        socket.write_u32(0x4).await?;
+        socket.write_u32(self.seq_num).await?;
+        socket.write_u32(self.dev_id).await?;
+        socket.write_u32(self.direction).await?;
+        socket.write_u32(self.ep).await?;
+        socket.write_i32(status).await?;
+        // 24 bytes of struct padding
+        socket.write_all(&[0u8; 6 * 4]).await
+    }
+}
+
+/// The next command, possibly read in part while a URB was pending
+#[derive(Default)]
+struct CommandHeader {
+    bytes: [u8; 4],
+    len: usize,
+    eof: bool,
+}
+
+impl CommandHeader {
+    /// Read the rest of the header, None if the client closed the connection
+    /// before sending another command
+    async fn read<T: AsyncReadExt + Unpin>(&mut self, socket: &mut T) -> Result<Option<[u8; 4]>> {
+        while self.len < self.bytes.len() && !self.eof {
+            match socket.read(&mut self.bytes[self.len..]).await? {
+                0 => self.eof = true,
+                read => self.len += read,
+            }
+        }
+        match self.len {
+            0 => Ok(None),
+            len if len < self.bytes.len() => Err(std::io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "connection closed inside a command",
+            )),
+            _ => {
+                self.len = 0;
+                Ok(Some(self.bytes))
+            }
+        }
+    }
+}
+
+/// A USBIP_CMD_SUBMIT read in full, with its OUT data and iso packets
+struct Urb {
+    request: SubmitRequest,
+    ep: UsbEndpoint,
+    out_data: Vec<u8>,
+    iso_packets: Vec<IsoPacketDescriptor>,
+    iso: bool,
+    submitted: tokio::time::Instant,
+    /// When the handler is called again after a NAK
+    retry_at: tokio::time::Instant,
+}
+
+impl Urb {
+    fn setup_packet(&self) -> SetupPacket {
+        SetupPacket::parse(&self.request.setup)
+    }
+
+    /// The data stage of a control OUT is at most wLength bytes, a transfer
+    /// with only a status stage passes no data at all
+    fn handler_data(&self) -> &[u8] {
+        if self.ep.is_ep0() {
+            let limit = self
+                .setup_packet()
+                .data_limit(self.request.transfer_buffer_length);
+            &self.out_data[..limit.min(self.out_data.len())]
+        } else {
+            &self.out_data
+        }
+    }
+}
+
+/// URBs NAKed by their handlers, queued per endpoint
+///
+/// Only the oldest URB of an endpoint is retried, the others wait behind it,
+/// so each endpoint completes its URBs in order. URBs to other endpoints,
+/// ep0 in particular, are served meanwhile.
+#[derive(Default)]
+struct PendingUrbs {
+    endpoints: BTreeMap<u8, VecDeque<Urb>>,
+}
+
+impl PendingUrbs {
+    /// Both directions of ep0 are one control pipe
+    fn pipe(ep: UsbEndpoint) -> u8 {
+        if ep.is_ep0() {
+            0
+        } else {
+            ep.address
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.endpoints.is_empty()
+    }
+
+    /// Whether URBs to `ep` wait for a NAKed one
+    fn is_pending(&self, ep: UsbEndpoint) -> bool {
+        self.endpoints.contains_key(&Self::pipe(ep))
+    }
+
+    /// Queue `urb` behind the pending URBs of its endpoint
+    fn push(&mut self, urb: Urb) {
+        self.endpoints
+            .entry(Self::pipe(urb.ep))
+            .or_default()
+            .push_back(urb);
+    }
+
+    /// Put back `urb` NAKed by its handler, retried the way a host
+    /// controller would, see [NAK_RETRY_INTERVAL]
+    fn nak(&mut self, device: &UsbDevice, mut urb: Urb) {
+        let delay = if device.in_queues.contains_key(&urb.ep.address) {
+            NAK_RETRY_INTERVAL
+        } else if urb.ep.transfer_type() == Some(EndpointAttributes::Interrupt) {
+            // poll again in the next interval
+            device
+                .interval_duration(urb.request.interval)
+                .max(NAK_RETRY_INTERVAL)
+        } else {
+            NAK_RETRY_INTERVAL
+        };
+        urb.retry_at = tokio::time::Instant::now() + delay;
+        self.endpoints
+            .entry(Self::pipe(urb.ep))
+            .or_default()
+            .push_front(urb);
+    }
+
+    /// Take the oldest URB of `pipe` if it is due to be retried
+    fn pop_due(&mut self, device: &UsbDevice, pipe: u8) -> Option<Urb> {
+        let urbs = self.endpoints.get_mut(&pipe)?;
+        let urb = urbs.front()?;
+        let waiting = device
+            .in_queues
+            .get(&urb.ep.address)
+            .filter(|queue| !queue.is_ready())
+            .is_some();
+        if waiting || urb.retry_at > tokio::time::Instant::now() {
+            return None;
+        }
+        let urb = urbs.pop_front();
+        if urbs.is_empty() {
+            self.endpoints.remove(&pipe);
+        }
+        urb
+    }
+
+    /// Take URB `seq_num` out of its queue
+    fn unlink(&mut self, seq_num: u32) -> Option<Urb> {
+        let (&pipe, urbs) = self
+            .endpoints
+            .iter_mut()
+            .find(|(_, urbs)| urbs.iter().any(|urb| urb.request.seq_num == seq_num))?;
+        let index = urbs.iter().position(|urb| urb.request.seq_num == seq_num)?;
+        let urb = urbs.remove(index);
+        if urbs.is_empty() {
+            self.endpoints.remove(&pipe);
+        }
+        urb
+    }
+
+    fn take_all(&mut self) -> Vec<Urb> {
+        let mut urbs: Vec<Urb> = std::mem::take(&mut self.endpoints)
+            .into_values()
+            .flatten()
+            .collect();
+        urbs.sort_by_key(|urb| (urb.submitted, urb.request.seq_num));
+        urbs
+    }
+
+    /// Complete once the oldest URB of an endpoint may be due, an
+    /// [InEndpointQueue] got data or the device is unplugged
+    fn wait<'a>(&'a self, device: Option<&'a UsbDevice>) -> BoxFuture<'a, ()> {
+        let device = match device {
+            Some(device) if !self.is_empty() => device,
+            _ => return Box::pin(futures::future::pending()),
+        };
+        let mut wakes: Vec<BoxFuture<()>> = vec![Box::pin(device.removal.wait())];
+        for urbs in self.endpoints.values() {
+            let retry = tokio::time::sleep_until(urbs[0].retry_at);
+            wakes.push(match device.in_queues.get(&urbs[0].ep.address) {
+                Some(queue) => Box::pin(async move {
+                    queue.ready().await;
+                    retry.await
+                }),
+                None => Box::pin(retry),
+            });
+        }
+        Box::pin(futures::future::FutureExt::map(
+            futures::future::select_all(wakes),
+            |_| (),
+        ))
+    }
+}
+
+/// Pass `urb` to its handler, None if the handler NAKed it
+async fn attempt_urb(
+    device: &UsbDevice,
+    ctx: &ConnectionContext,
+    shutdown: &Shutdown,
+    urb: &Urb,
+) -> Option<(i32, Vec<u8>)> {
+    let intf = device.find_ep(urb.ep.address).and_then(|(_, intf)| intf);
+    let urb_ctx = ConnectionContext {
+        transfer_buffer_length: Some(urb.request.transfer_buffer_length),
+        transfer_flags: Some(TransferFlags(urb.request.transfer_flags)),
+        interval: Some(urb.request.interval),
+        ..ctx.clone()
+    };
+    let unplugged = || {
+        UsbIpError::Io(std::io::Error::new(
+            ErrorKind::NotConnected,
+            "device unplugged",
+        ))
+    };
+    let shutting_down = || {
+        UsbIpError::Io(std::io::Error::new(
+            ErrorKind::ConnectionAborted,
+            "server shutting down",
+        ))
+    };
+    let resp = if device.removal.is_removed() {
+        Err(unplugged())
+    } else if shutdown.is_requested() {
+        Err(shutting_down())
+    } else {
+        // a handler that takes long, e.g. a host device transfer, is
+        // abandoned once the device or the server goes away
+        let transfer = Box::pin(device.handle_urb(
+            &urb_ctx,
+            urb.ep,
+            intf,
+            urb.request.setup,
+            urb.handler_data(),
+        ));
+        let gone =
+            futures::future::select(Box::pin(device.removal.wait()), Box::pin(shutdown.wait()));
+        match futures::future::select(transfer, gone).await {
+            futures::future::Either::Left((res, _)) => res,
+            futures::future::Either::Right(_) if device.removal.is_removed() => Err(unplugged()),
+            futures::future::Either::Right(_) => Err(shutting_down()),
+        }
+    };
+    match resp {
+        Ok(resp) => Some((0, resp)),
+        Err(err) if err.kind() == ErrorKind::WouldBlock => None,
+        Err(err) if err.kind() == ErrorKind::NotConnected => {
+            debug!("Unplugged during transfer: {}", err);
+            Some((-errno::ENODEV, vec![]))
+        }
+        Err(err) if err.kind() == ErrorKind::ConnectionAborted => {
+            debug!("Shutdown during transfer: {}", err);
+            Some((-errno::ESHUTDOWN, vec![]))
+        }
+        Err(UsbIpError::Stall) => Some((-errno::EPIPE, vec![])),
+        Err(err) => {
+            // the connection stays up, the client sees the URB fail instead
+            // of completing without data
+            warn!("Transfer on {:02x} failed: {}", urb.ep.address, err);
+            Some((err.status(), vec![]))
+        }
+    }
+}
+
+/// Send the USBIP_RET_SUBMIT of `urb`, which completed with `status` and `resp`
+async fn complete_urb<T: AsyncWriteExt + Unpin>(
+    socket: &mut T,
+    server: &UsbIpServer,
+    device: &UsbDevice,
+    history: &Option<Arc<Mutex<UrbHistory>>>,
+    urb: Urb,
+    status: i32,
+    resp: Vec<u8>,
+) -> Result<()> {
+    let setup_packet = urb.setup_packet();
+    let control_limit = setup_packet.data_limit(urb.request.transfer_buffer_length);
+    let handler_len = urb.handler_data().len();
+    let Urb {
+        request,
+        ep: usb_ep,
+        out_data,
+        mut iso_packets,
+        iso,
+        submitted,
+        ..
+    } = urb;
+    let SubmitRequest {
+        seq_num,
+        dev_id,
+        direction,
+        ep,
+        transfer_flags,
+        transfer_buffer_length,
+        start_frame,
+        setup,
+        ..
+    } = request;
+    trace!("<-Resp {:02x?}", resp);
+
+    if status != 0 {
+        // the whole URB failed, so did each of its packets
+        for packet in iso_packets.iter_mut() {
+            packet.actual_length = 0;
+            packet.status = status;
+        }
+    }
+    let iso_error_count = iso_packets.iter().filter(|p| p.status != 0).count();
+
+    let overflow = status == 0
+        && !iso
+        && usb_ep.is_ep0()
+        && usb_ep.direction() == Direction::In
+        && setup_packet.length > 0
+        && resp.len() > control_limit;
+    // OUT transfers carry no data back, but report what they consumed
+    let (actual_length, resp) = match usb_ep.direction() {
+        _ if iso => {
+            let actual_length: u32 = iso_packets.iter().map(|p| p.actual_length).sum();
+            (actual_length as usize, resp)
+        }
+        Direction::Out if status == 0 => (handler_len, vec![]),
+        Direction::Out => (0, vec![]),
+        Direction::In if usb_ep.is_ep0() && setup_packet.length == 0 => {
+            // no data stage, the status stage carries nothing
+            (0, vec![])
+        }
+        Direction::In if !usb_ep.is_ep0() && resp.len() > transfer_buffer_length as usize => {
+            // more than the client asked for would overflow its
+            // buffer, the rest is served to the next URBs on the
+            // endpoint; control replies fail with -EOVERFLOW instead
+            debug!(
+                "Reply of {} bytes on endpoint {:02x} split at {} bytes",
+                resp.len(),
+                usb_ep.address,
+                transfer_buffer_length
+            );
+            let mut resp = resp;
+            let rest = resp.split_off(transfer_buffer_length as usize);
+            device.stash_in_remainder(usb_ep.address, rest);
+            (resp.len(), resp)
+        }
+        Direction::In if overflow => {
+            warn!(
+                "Truncated control reply of {} bytes to {} bytes",
+                resp.len(),
+                control_limit
+            );
+            let mut resp = resp;
+            resp.truncate(control_limit);
+            (resp.len(), resp)
+        }
+        Direction::In => (resp.len(), resp),
+    };
+    let status = if overflow {
+        -errno::EOVERFLOW
+    } else if status == 0
+        && !iso
+        && usb_ep.direction() == Direction::In
+        && TransferFlags(transfer_flags).contains(TransferFlags::SHORT_NOT_OK)
+        && actual_length < transfer_buffer_length as usize
+    {
+        debug!(
+            "Short reply of {} bytes on endpoint {:02x}, {} requested",
+            actual_length, usb_ep.address, transfer_buffer_length
+        );
+        -errno::EREMOTEIO
+    } else {
+        status
+    };
+
+    let delay = device.throttle(usb_ep.address, out_data.len() + resp.len());
+    if delay > Duration::from_secs(0) {
+        trace!("Throttled for {:?}", delay);
+        tokio::time::sleep(delay).await;
+    }
+    #[cfg(feature = "fault-injection")]
+    if let Some(jitter) = &device.response_jitter {
+        let delay = jitter.lock().unwrap().next_delay();
+        trace!("Jitter of {:?}", delay);
+        tokio::time::sleep(delay).await;
+    }
+    if let Some(bus) = &server.bus {
+        bus.complete(ScheduledUrb {
+            bus_id: device.bus_id.clone(),
+            ep: usb_ep,
+            length: out_data.len() + resp.len(),
+            ready: tokio::time::Instant::now(),
+        })
+        .await;
+    }
+    device
+        .stats
+        .endpoint(usb_ep.address)
+        .record(actual_length, status, submitted.elapsed());
+    server.stats.transferred(Direction::Out, out_data.len());
+    server.stats.transferred(Direction::In, resp.len());
+
+    // USBIP_RET_USBMIT
+    // command
+    socket.write_u32(0x3).await?;
+    socket.write_u32(seq_num).await?;
+    socket.write_u32(dev_id).await?;
+    socket.write_u32(direction).await?;
+    socket.write_u32(ep).await?;
+    // status
+    socket.write_i32(status).await?;
+    // actual length
+    socket.write_u32(actual_length as u32).await?;
+    if iso {
+        socket.write_u32(start_frame).await?;
+        socket.write_u32(iso_packets.len() as u32).await?;
+        socket.write_u32(iso_error_count as u32).await?;
+    } else {
+        // start frame
+        socket.write_u32(0).await?;
+        // number of packets
+        socket.write_u32(0).await?;
+        // error count
+        socket.write_u32(0).await?;
+    }
+    // 8 bytes of padding, where USBIP_CMD_SUBMIT has the setup
+    socket.write_all(&[0u8; 8]).await?;
+    // data
+    socket.write_all(&resp).await?;
+    write_iso_packet_descriptors(&mut *socket, &iso_packets).await?;
+
+    if let Some(history) = history {
+        let direction = usb_ep.direction();
+        history.lock().unwrap().push(UrbRecord {
+            timestamp: tokio::time::Instant::now(),
+            direction,
+            endpoint: usb_ep.address,
+            setup,
+            data: if direction == Direction::Out {
+                out_data
+            } else {
+                resp
+            },
+            status,
+        });
+    }
+    Ok(())
+}
+
+/// Retry the oldest URB of each endpoint once it is due, replying to those
+/// that complete
+async fn retry_pending_urbs<T: AsyncWriteExt + Unpin>(
+    socket: &mut T,
+    server: &UsbIpServer,
+    device: &UsbDevice,
+    history: &Option<Arc<Mutex<UrbHistory>>>,
+    ctx: &ConnectionContext,
+    shutdown: &Shutdown,
+    pending: &mut PendingUrbs,
+) -> Result<()> {
+    let pipes: Vec<u8> = pending.endpoints.keys().copied().collect();
+    for pipe in pipes {
+        while let Some(urb) = pending.pop_due(device, pipe) {
+            match attempt_urb(device, ctx, shutdown, &urb).await {
+                Some((status, resp)) => {
+                    let SubmitRequest {
+                        seq_num,
+                        ep,
+                        direction,
+                        ..
+                    } = urb.request;
+                    let complete = complete_urb(socket, server, device, history, urb, status, resp);
+                    span::in_urb(seq_num, ep, direction, complete).await?;
                 }
-                if let Some(index) = desc.serial_number_string_index() {
-                    device.string_serial = device.new_string(
-                        &handle
-                            .lock()
-                            .unwrap()
-                            .read_string_descriptor_ascii(index)
-                            .unwrap(),
-                    )
+                None => {
+                    pending.nak(device, urb);
+                    break;
                 }
-                devices.push(device);
             }
         }
-        Self { devices }
     }
+    Ok(())
+}
+
+/// End all pending URBs with `status`, e.g. once the device is unplugged
+async fn fail_pending_urbs<T: AsyncWriteExt + Unpin>(
+    socket: &mut T,
+    server: &UsbIpServer,
+    device: &UsbDevice,
+    history: &Option<Arc<Mutex<UrbHistory>>>,
+    pending: &mut PendingUrbs,
+    status: i32,
+) -> Result<()> {
+    for urb in pending.take_all() {
+        debug!("Ended pending URB {} with {}", urb.request.seq_num, status);
+        complete_urb(socket, server, device, history, urb, status, vec![]).await?;
+    }
+    Ok(())
+}
+
+/// Check a USBIP_CMD_SUBMIT to `ep` the way a host controller driver
+/// would, see [UsbIpServer::with_strict_submits]
+fn strict_submit_violation(
+    device: &UsbDevice,
+    ep: Option<UsbEndpoint>,
+    real_ep: u8,
+    setup: &[u8; 8],
+    transfer_buffer_length: u32,
+) -> Option<i32> {
+    let ep = match ep {
+        Some(ep) => ep,
+        None => {
+            if device.find_ep(real_ep ^ 0x80).is_some() {
+                warn!(
+                    "URB to endpoint {:02x}, which only exists as {:02x}",
+                    real_ep,
+                    real_ep ^ 0x80
+                );
+            } else {
+                warn!("URB to unknown endpoint {:02x}", real_ep);
+            }
+            return Some(-errno::ENOENT);
+        }
+    };
+    if ep.is_ep0() {
+        let setup = SetupPacket::parse(setup);
+        if setup.length as u32 != transfer_buffer_length {
+            warn!(
+                "Control transfer of {} bytes with wLength {}",
+                transfer_buffer_length, setup.length
+            );
+            return Some(-errno::EBADR);
+        }
+    } else if !device.is_endpoint_enabled(ep.address) {
+        warn!(
+            "URB to endpoint {:02x} while unconfigured or in another alternate setting",
+            ep.address
+        );
+        return Some(-errno::ENOENT);
+    }
+    None
+}
+
+/// Asks connections to stop after their current URB, see [server_with_shutdown]
+///
+/// A URB still waiting on its handler, e.g. a host device transfer, is
+/// abandoned and completes with `-ESHUTDOWN`.
+#[derive(Debug, Default)]
+struct Shutdown {
+    requested: std::sync::atomic::AtomicBool,
+    notify: tokio::sync::Notify,
+}
+
+impl Shutdown {
+    fn request(&self) {
+        self.requested
+            .store(true, std::sync::atomic::Ordering::SeqCst);
+        self.notify.notify_waiters();
+    }
+
+    fn is_requested(&self) -> bool {
+        self.requested.load(std::sync::atomic::Ordering::SeqCst)
+    }
+
+    /// Complete once shutdown is requested
+    async fn wait(&self) {
+        let notified = self.notify.notified();
+        if !self.is_requested() {
+            notified.await;
+        }
+    }
+}
+
+/// Finish `read` before `deadline`, see [UsbIpServer::with_handshake_timeout]
+async fn handshake_read<R>(
+    deadline: Option<tokio::time::Instant>,
+    read: impl std::future::Future<Output = Result<R>>,
+) -> Result<R> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, read)
+            .await
+            .map_err(|_| std::io::Error::new(ErrorKind::TimedOut, "handshake timed out"))?,
+        None => read.await,
+    }
+}
+
+async fn handler<T: AsyncReadExt + AsyncWriteExt + Unpin + Send>(
+    socket: &mut T,
+    server: Arc<UsbIpServer>,
+) -> std::result::Result<(), UsbIpError> {
+    handler_with_context(
+        socket,
+        server,
+        ConnectionContext::new(None),
+        &Shutdown::default(),
+    )
+    .await
+}
+
+async fn handler_with_context<T: AsyncReadExt + AsyncWriteExt + Unpin + Send>(
+    socket: &mut T,
+    server: Arc<UsbIpServer>,
+    ctx: ConnectionContext,
+    shutdown: &Shutdown,
+) -> std::result::Result<(), UsbIpError> {
+    let span = span::connection(&ctx);
+    span::instrument(span, serve_connection(socket, server, ctx, shutdown)).await
 }
 
-async fn handler<T: AsyncReadExt + AsyncWriteExt + Unpin>(
+async fn serve_connection<T: AsyncReadExt + AsyncWriteExt + Unpin + Send>(
     mut socket: &mut T,
     server: Arc<UsbIpServer>,
-) -> Result<()> {
-    let mut current_import_device = None;
+    ctx: ConnectionContext,
+    shutdown: &Shutdown,
+) -> std::result::Result<(), UsbIpError> {
+    let mut current_import_device: Option<UsbDevice> = None;
+    // keeps other clients from importing it, released with the connection
+    let mut import_lease: Option<ImportLease> = None;
+    let mut import_record: Option<ImportRecord> = None;
+    // the imported device was unplugged, its URBs fail until the next import
+    let mut import_removed = false;
+    let history = server.new_urb_history(&ctx);
+    let _connection = server.stats.connect();
+    let mut header = CommandHeader::default();
+    let mut pending = PendingUrbs::default();
+    let mut version_logged = false;
     loop {
-        let mut command = [0u8; 4];
-        socket.read_exact(&mut command).await?;
+        // deliver the replies so far before waiting, buffered transports
+        // like WebSocketStream send them as one message
+        socket.flush().await?;
+        if shutdown.is_requested() {
+            if let Some(device) = &current_import_device {
+                fail_pending_urbs(
+                    socket,
+                    &server,
+                    device,
+                    &history,
+                    &mut pending,
+                    -errno::ESHUTDOWN,
+                )
+                .await?;
+                socket.flush().await?;
+            }
+            info!("Closing connection for shutdown");
+            return Ok(());
+        }
+        if let Some(device) = &current_import_device {
+            if device.removal.is_removed() {
+                fail_pending_urbs(
+                    socket,
+                    &server,
+                    device,
+                    &history,
+                    &mut pending,
+                    -errno::ENODEV,
+                )
+                .await?;
+            }
+        }
+        let handshake_deadline = server
+            .handshake_timeout
+            .filter(|_| current_import_device.is_none())
+            .map(|timeout| tokio::time::Instant::now() + timeout);
+        let command = {
+            let read: BoxFuture<Result<Option<[u8; 4]>>> = if header.eof && !pending.is_empty() {
+                // the client sends nothing more, but waits for the pending URBs
+                Box::pin(futures::future::pending())
+            } else {
+                Box::pin(handshake_read(handshake_deadline, header.read(socket)))
+            };
+            let retry = pending.wait(current_import_device.as_ref());
+            let next = futures::future::select(read, retry);
+            match futures::future::select(Box::pin(shutdown.wait()), next).await {
+                futures::future::Either::Left(_) => continue,
+                futures::future::Either::Right((
+                    futures::future::Either::Left((command, _)),
+                    _,
+                )) => match command? {
+                    Some(command) => Some(command),
+                    None if !pending.is_empty() => None,
+                    None => {
+                        info!("Client disconnected");
+                        return Ok(());
+                    }
+                },
+                futures::future::Either::Right((futures::future::Either::Right(_), _)) => None,
+            }
+        };
+        let command = match (command, &current_import_device) {
+            (Some(command), _) => command,
+            // unplugged, the pending URBs fail above
+            (None, Some(device)) if !device.removal.is_removed() => {
+                retry_pending_urbs(
+                    socket,
+                    &server,
+                    device,
+                    &history,
+                    &ctx,
+                    shutdown,
+                    &mut pending,
+                )
+                .await?;
+                continue;
+            }
+            (None, _) => continue,
+        };
+        if !version_logged && command[..2] == USBIP_VERSION.to_be_bytes() {
+            info!("Client speaks USB/IP version {:04x}", USBIP_VERSION);
+            version_logged = true;
+        }
         match command {
             [0x01, 0x11, 0x80, 0x05] => {
                 trace!("Got OP_REQ_DEVLIST");
-                let _status = socket.read_u32().await?;
+                handshake_read(handshake_deadline, read_op_header(socket, 0x05)).await?;
+                server.apply_reenumerations().await;
 
                 // OP_REP_DEVLIST
                 socket.write_u32(0x01110005).await?;
                 socket.write_u32(0).await?;
-                socket.write_u32(server.devices.len() as u32).await?;
-                for dev in &server.devices {
+                // snapshot the list, the count and entries stay consistent
+                // and a slow client does not hold up attach and detach
+                let devices = server.devices.read().await.clone();
+                socket.write_u32(devices.len() as u32).await?;
+                for dev in devices.iter() {
                     dev.write_dev_with_interfaces(&mut socket).await?;
                 }
                 trace!("Sent OP_REP_DEVLIST");
             }
             [0x01, 0x11, 0x80, 0x03] => {
                 trace!("Got OP_REQ_IMPORT");
-                let _status = socket.read_u32().await?;
-                let mut bus_id = [0u8; 32];
-                socket.read_exact(&mut bus_id).await?;
+                let bus_id = handshake_read(handshake_deadline, read_op_header(socket, 0x03))
+                    .await?
+                    .unwrap_or_default();
+                server.apply_reenumerations().await;
+                // match against the live list, the device may have been
+                // removed since the client listed it
                 current_import_device = None;
-                for device in &server.devices {
+                if !pending.is_empty() {
+                    debug!("Dropped the URBs pending on the previous import");
+                    pending = PendingUrbs::default();
+                }
+                // release the device before claiming it again
+                drop(import_record.take());
+                drop(import_lease.take());
+                import_removed = false;
+                let mut busy = false;
+                let mut denied = false;
+                for device in server.devices.read().await.iter() {
                     let mut expected = device.bus_id.as_bytes().to_vec();
                     expected.resize(32, 0);
                     if expected == bus_id {
-                        current_import_device = Some(device);
-                        info!("Found device {:?}", device.path);
+                        if let Some(authorize) = &server.import_authorization {
+                            if !authorize(&ctx, device) {
+                                denied = true;
+                                break;
+                            }
+                        }
+                        import_lease = device.lease();
+                        if import_lease.is_some() {
+                            current_import_device = Some(device.import());
+                            import_record = Some(server.record_import(&device.bus_id, &ctx));
+                            info!("Found device {:?}", device.path);
+                        } else {
+                            busy = true;
+                        }
                         break;
                     }
                 }
@@ -203,117 +1394,694 @@ async fn handler<T: AsyncReadExt + AsyncWriteExt + Unpin>(
                 // OP_REP_IMPORT
                 trace!("Sent OP_REP_IMPORT");
                 socket.write_u32(0x01110003).await?;
-                if let Some(dev) = current_import_device {
+                if let Some(dev) = &current_import_device {
                     socket.write_u32(0).await?;
                     dev.write_dev(&mut socket).await?;
                 } else {
-                    socket.write_u32(1).await?;
+                    let bus_id = String::from_utf8_lossy(&bus_id);
+                    let bus_id = bus_id.trim_end_matches('\0');
+                    let status = if denied {
+                        warn!("Import of device {:?} not authorized", bus_id);
+                        OP_STATUS_NOT_AVAILABLE
+                    } else if busy {
+                        warn!("Import of device {:?} imported by another client", bus_id);
+                        OP_STATUS_DEVICE_BUSY
+                    } else {
+                        warn!("Import of unknown device {:?}", bus_id);
+                        OP_STATUS_NO_DEVICE
+                    };
+                    // the reply ends here, clients read a device only on success
+                    socket.write_u32(status).await?;
                 }
             }
+            [version_high, version_low, 0x80, code @ 0x03]
+            | [version_high, version_low, 0x80, code @ 0x05]
+                if !server.custom_ops.contains_key(&command) =>
+            {
+                let version = u16::from_be_bytes([version_high, version_low]);
+                warn!(
+                    "Got OP request {:02x} of USB/IP version {:04x}, only {:04x} is supported",
+                    code, version, USBIP_VERSION
+                );
+                // consume the rest of the request to stay in sync
+                handshake_read(handshake_deadline, read_op_header(socket, code)).await?;
+                // OP_REP_DEVLIST or OP_REP_IMPORT of the supported version,
+                // clients then report the mismatch
+                socket
+                    .write_u32((USBIP_VERSION as u32) << 16 | code as u32)
+                    .await?;
+                socket.write_u32(OP_STATUS_ERROR).await?;
+            }
             [0x00, 0x00, 0x00, 0x01] => {
                 trace!("Got USBIP_CMD_SUBMIT");
-                let seq_num = socket.read_u32().await?;
-                let dev_id = socket.read_u32().await?;
-                let direction = socket.read_u32().await?;
-                let ep = socket.read_u32().await?;
-                let _transfer_flags = socket.read_u32().await?;
-                let transfer_buffer_length = socket.read_u32().await?;
-                let _start_frame = socket.read_u32().await?;
-                let _number_of_packets = socket.read_u32().await?;
-                let _interval = socket.read_u32().await?;
-                let mut setup = [0u8; 8];
-                socket.read_exact(&mut setup).await?;
-                let device = current_import_device.unwrap();
-                let real_ep = if direction == 0 { ep } else { ep | 0x80 };
-                let (usb_ep, intf) = device.find_ep(real_ep as u8).unwrap();
-                trace!("->Endpoint {:02x?}", usb_ep);
-                trace!("->Setup {:02x?}", setup);
-                let resp = device
-                    .handle_urb(socket, usb_ep, intf, transfer_buffer_length, setup)
-                    .await?;
-                trace!("<-Resp {:02x?}", resp);
-
-                // USBIP_RET_USBMIT
-                // command
-                socket.write_u32(0x3).await?;
-                socket.write_u32(seq_num).await?;
-                socket.write_u32(dev_id).await?;
-                socket.write_u32(direction).await?;
-                socket.write_u32(ep).await?;
-                // status
-                socket.write_u32(0).await?;
-                // actual length
-                socket.write_u32(resp.len() as u32).await?;
-                // start frame
-                socket.write_u32(0).await?;
-                // number of packets
-                socket.write_u32(0).await?;
-                // error count
-                socket.write_u32(0).await?;
-                // setup
-                socket.write_all(&setup).await?;
-                // data
-                socket.write_all(&resp).await?;
+                server.stats.submitted();
+                let request = SubmitRequest::read(socket).await?;
+                let SubmitRequest {
+                    seq_num,
+                    dev_id,
+                    direction,
+                    ep,
+                    transfer_buffer_length,
+                    number_of_packets,
+                    setup,
+                    ..
+                } = request;
+                let submit = async {
+                    // iso packet descriptors follow the OUT data
+                    let iso_packet_count = if number_of_packets == ISO_NO_PACKETS {
+                        0
+                    } else {
+                        number_of_packets as usize
+                    };
+                    // checked before the descriptors are read into memory, each
+                    // packet carries at least a byte
+                    let iso_packets_invalid = iso_packet_count > MAX_ISO_PACKETS as usize
+                        || iso_packet_count > transfer_buffer_length as usize;
+                    let submitted = tokio::time::Instant::now();
+                    if let Some(device) = &current_import_device {
+                        if device.removal.is_removed() {
+                            info!("Imported device {} is gone", device.bus_id);
+                            fail_pending_urbs(
+                                socket,
+                                &server,
+                                device,
+                                &history,
+                                &mut pending,
+                                -errno::ENODEV,
+                            )
+                            .await?;
+                            current_import_device = None;
+                            import_removed = true;
+                        }
+                    }
+                    // the direction field frames the data stage, even of a
+                    // control transfer whose bmRequestType disagrees
+                    let data_direction = Direction::from_usbip(direction);
+                    let setup_direction = if setup[0] & 0x80 != 0 {
+                        Direction::In
+                    } else {
+                        Direction::Out
+                    };
+                    let real_ep = match data_direction {
+                        Direction::In => ep | 0x80,
+                        Direction::Out => ep,
+                    };
+                    let found = current_import_device
+                        .as_ref()
+                        .map(|device| device.find_ep(real_ep as u8));
+                    let rejected = if import_removed {
+                        Some(-errno::ENODEV)
+                    } else if found.is_none() {
+                        warn!("USBIP_CMD_SUBMIT without an imported device");
+                        Some(-errno::ENODEV)
+                    } else if ep == 0
+                        && data_direction != setup_direction
+                        && SetupPacket::parse(&setup).length > 0
+                    {
+                        warn!(
+                            "Control transfer {:?} with bmRequestType {:02x}",
+                            data_direction, setup[0]
+                        );
+                        Some(-errno::EINVAL)
+                    } else if iso_packets_invalid {
+                        warn!(
+                            "Rejected {} iso packets in a URB of {} bytes",
+                            number_of_packets, transfer_buffer_length
+                        );
+                        Some(-errno::EINVAL)
+                    } else if let (true, Some(device)) =
+                        (server.strict_submits, &current_import_device)
+                    {
+                        strict_submit_violation(
+                            device,
+                            found.flatten().map(|(ep, _)| ep),
+                            real_ep as u8,
+                            &setup,
+                            transfer_buffer_length,
+                        )
+                    } else if let Some(None) = found {
+                        warn!("URB to unknown endpoint {:02x}", real_ep);
+                        Some(-errno::EPIPE)
+                    } else {
+                        None
+                    };
+                    if let Some(status) = rejected {
+                        // consume the data stage of OUT transfers before failing
+                        let len = if data_direction == Direction::Out {
+                            transfer_buffer_length as u64
+                        } else {
+                            0
+                        } + iso_packet_count as u64 * ISO_PACKET_DESCRIPTOR_SIZE as u64;
+                        let mut data = (&mut *socket).take(len);
+                        tokio::io::copy(&mut data, &mut tokio::io::sink()).await?;
+                        // USBIP_RET_SUBMIT
+                        socket.write_u32(0x3).await?;
+                        socket.write_u32(seq_num).await?;
+                        socket.write_u32(dev_id).await?;
+                        socket.write_u32(direction).await?;
+                        socket.write_u32(ep).await?;
+                        socket.write_i32(status).await?;
+                        // actual length, start frame, number of packets, error
+                        // count and 8 bytes of padding
+                        socket.write_all(&[0u8; 4 * 4 + 8]).await?;
+                        return Ok(());
+                    }
+                    let (device, (usb_ep, intf)) = match (&current_import_device, found) {
+                        (Some(device), Some(Some(found))) => (device, found),
+                        _ => unreachable!("rejected above"),
+                    };
+                    trace!("->Endpoint {:02x?}", usb_ep);
+                    trace!("->Setup {:02x?}", setup);
+
+                    // read data from socket for OUT, always, even if no handler
+                    // consumes it, or the next command would be parsed from it
+                    let oversized = transfer_buffer_length as usize > server.max_transfer_size;
+                    let out_data = if let Direction::Out = usb_ep.direction() {
+                        if oversized {
+                            // drain without buffering it
+                            let mut data = (&mut *socket).take(transfer_buffer_length as u64);
+                            tokio::io::copy(&mut data, &mut tokio::io::sink()).await?;
+                            vec![]
+                        } else {
+                            let mut data = vec![0u8; transfer_buffer_length as usize];
+                            socket.read_exact(&mut data).await?;
+                            data
+                        }
+                    } else {
+                        vec![]
+                    };
+                    let mut iso_packets =
+                        read_iso_packet_descriptors(&mut *socket, iso_packet_count).await?;
+                    let iso = usb_ep.transfer_type() == Some(EndpointAttributes::Isochronous)
+                        && number_of_packets != ISO_NO_PACKETS;
+                    if !iso && !iso_packets.is_empty() {
+                        warn!(
+                            "Ignored {} iso packets to endpoint {:02x}",
+                            iso_packets.len(),
+                            usb_ep.address
+                        );
+                        iso_packets.clear();
+                    }
+
+                    let mut urb = Urb {
+                        request,
+                        ep: usb_ep,
+                        out_data,
+                        iso_packets,
+                        iso,
+                        submitted,
+                        retry_at: submitted,
+                    };
+
+                    // a reply longer than wLength is babble, whatever the handler does
+                    let setup_packet = urb.setup_packet();
+                    let control_limit = setup_packet.data_limit(transfer_buffer_length);
+                    if usb_ep.is_ep0() && control_limit != transfer_buffer_length as usize {
+                        warn!(
+                            "Control transfer with wLength {} and transfer_buffer_length {}",
+                            setup_packet.length, transfer_buffer_length
+                        );
+                    }
+
+                    let (status, resp) = if oversized {
+                        warn!(
+                            "Rejected transfer of {} bytes on endpoint {:02x}",
+                            transfer_buffer_length, usb_ep.address
+                        );
+                        (-errno::EINVAL, vec![])
+                    } else if let Some(packet) = urb.iso_packets.iter().find(|packet| {
+                        server.strict_submits && packet.length as usize > usb_ep.max_payload()
+                    }) {
+                        warn!(
+                            "Iso packet of {} bytes above the {} bytes of endpoint {:02x}",
+                            packet.length,
+                            usb_ep.max_payload(),
+                            usb_ep.address
+                        );
+                        (-errno::EMSGSIZE, vec![])
+                    } else if pending.is_pending(usb_ep) {
+                        // waits for the NAKed URBs to the endpoint to complete
+                        pending.push(urb);
+                        return Ok(());
+                    } else if let Some(status) = device.take_endpoint_error(usb_ep.address) {
+                        debug!(
+                            "Injected error {} on endpoint {:02x}",
+                            status, usb_ep.address
+                        );
+                        (status, vec![])
+                    } else if iso {
+                        match device.handle_iso_urb(usb_ep, intf, &urb.iso_packets, &urb.out_data) {
+                            Ok(results) => (
+                                0,
+                                complete_iso_packets(
+                                    usb_ep.direction(),
+                                    &mut urb.iso_packets,
+                                    results,
+                                ),
+                            ),
+                            Err(err) => {
+                                let err = UsbIpError::from_transfer(err);
+                                if !matches!(err, UsbIpError::Stall) {
+                                    warn!("Iso transfer on {:02x} failed: {}", usb_ep.address, err);
+                                }
+                                (err.status(), vec![])
+                            }
+                        }
+                    } else if let Some(resp) = (usb_ep.direction() == Direction::In
+                        && !usb_ep.is_ep0())
+                    .then(|| device.take_in_remainder(usb_ep.address, transfer_buffer_length))
+                    .flatten()
+                    {
+                        trace!(
+                            "Served {} bytes left over on endpoint {:02x}",
+                            resp.len(),
+                            usb_ep.address
+                        );
+                        (0, resp)
+                    } else if usb_ep.is_ep0()
+                        && usb_ep.direction() == Direction::In
+                        && setup_packet.length == 0
+                        && setup_packet.request_type & 0x60 != 0
+                    {
+                        // a class or vendor IN request without a data stage
+                        // has nothing for the handler to return, it completes
+                        // with its status stage alone; an OUT one still
+                        // reaches the handler, its setup packet is the request
+                        (0, vec![])
+                    } else {
+                        match attempt_urb(device, &ctx, shutdown, &urb).await {
+                            Some(completed) => completed,
+                            None => {
+                                // retried later, other URBs are served meanwhile
+                                pending.nak(device, urb);
+                                return Ok(());
+                            }
+                        }
+                    };
+                    complete_urb(socket, &server, device, &history, urb, status, resp).await?;
+                    Ok::<(), UsbIpError>(())
+                };
+                span::in_urb(seq_num, ep, direction, submit).await?;
             }
-            [0x00, 0x00, 0x00, 0x02] => {
+            CMD_UNLINK => {
                 trace!("Got USBIP_CMD_UNLINK");
-                let seq_num = socket.read_u32().await?;
-                let dev_id = socket.read_u32().await?;
-                let direction = socket.read_u32().await?;
-                let ep = socket.read_u32().await?;
-                let seq_num_submit = socket.read_u32().await?;
-                // 24 bytes of struct padding
-                let mut padding = [0u8; 6 * 4];
-                socket.read_exact(&mut padding).await?;
-
-                // USBIP_RET_UNLINK
-                // command
-                socket.write_u32(0x4).await?;
-                socket.write_u32(seq_num).await?;
-                socket.write_u32(dev_id).await?;
-                socket.write_u32(direction).await?;
-                socket.write_u32(ep).await?;
-                // status
-                socket.write_u32(0).await?;
+                let unlink = UnlinkRequest::read(socket).await?;
+                server.stats.unlinked();
+                // only a pending URB can be cancelled, any other has either
+                // completed already or never existed and the status is 0
+                let unlinked = async {
+                    match pending.unlink(unlink.seq_num_submit) {
+                        Some(urb) => {
+                            debug!("Unlinked pending URB {}", unlink.seq_num_submit);
+                            if let Some(device) = &current_import_device {
+                                device.stats.endpoint(urb.ep.address).record(
+                                    0,
+                                    -errno::ECONNRESET,
+                                    urb.submitted.elapsed(),
+                                );
+                            }
+                            unlink.reply(socket, -errno::ECONNRESET).await
+                        }
+                        None => {
+                            debug!("Unlink of completed URB {}", unlink.seq_num_submit);
+                            unlink.reply(socket, 0).await
+                        }
+                    }
+                };
+                span::in_unlink(unlink.seq_num, unlink.seq_num_submit, unlinked).await?;
+            }
+            _ => {
+                if let Some(custom_op) = server.custom_ops.get(&command) {
+                    trace!("Got custom command {:02x?}", command);
+                    custom_op(socket).await?;
+                } else {
+                    // its length is unknown, the next command cannot be found
+                    warn!("Got unknown command {:?}", command);
+                    return Err(UsbIpError::UnsupportedCommand(u32::from_be_bytes(command)));
+                }
             }
-            _ => warn!("Got unknown command {:?}", command),
         }
     }
 }
 
 /// Spawn a USB/IP server at `addr` using [TcpListener]
-pub async fn server(addr: SocketAddr, server: UsbIpServer) {
-    let mut listener = TcpListener::bind(addr).await.expect("bind to addr");
+///
+/// The listener sets `SO_REUSEADDR` on Unix platforms, so the port can be
+/// bound again right after a previous server exited, even while its old
+/// connections linger in `TIME_WAIT`.
+pub async fn server(addr: SocketAddr, server: Arc<UsbIpServer>) {
+    let listener = bind_tcp(addr, server.listen_backlog).expect("bind to addr");
+    serve_until(listener, server, futures::future::pending()).await
+}
 
-    let server = async move {
-        let usbip_server = Arc::new(server);
-        loop {
-            match listener.accept().await {
-                Ok((mut socket, _addr)) => {
-                    info!("Got connection from {:?}", socket.peer_addr());
-                    let new_server = usbip_server.clone();
-                    tokio::spawn(async move {
-                        let res = handler(&mut socket, new_server).await;
-                        info!("Handler ended with {:?}", res);
-                    });
+/// Like [server], but stop once `shutdown` completes or its sender is dropped
+///
+/// The listener is closed right away, so the port is free again. Each
+/// connection finishes the URB it is processing, URBs NAKed at that time
+/// complete with `-ESHUTDOWN`, then the connection is closed. Resolves once
+/// all connections are closed.
+pub async fn server_with_shutdown(
+    addr: SocketAddr,
+    server: Arc<UsbIpServer>,
+    shutdown: tokio::sync::oneshot::Receiver<()>,
+) {
+    let listener = bind_tcp(addr, server.listen_backlog).expect("bind to addr");
+    serve_until(
+        listener,
+        server,
+        futures::future::FutureExt::map(shutdown, |_| ()),
+    )
+    .await
+}
+
+/// A USB/IP server running in the background, see [spawn_server]
+///
+/// Stop it with [ServerHandle::stop] from wherever the embedding application
+/// decides to, and wait for it to finish with [ServerHandle::join], or do
+/// both with [ServerHandle::shutdown].
+pub struct ServerHandle {
+    local_addr: SocketAddr,
+    shutdown: Option<tokio::sync::oneshot::Sender<()>>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl ServerHandle {
+    /// Address the server listens on, with the actual port if bound to port 0
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Stop accepting connections and close the existing ones, without waiting
+    ///
+    /// See [server_with_shutdown]. Dropping the handle stops the server too.
+    pub fn stop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            shutdown.send(()).ok();
+        }
+    }
+
+    /// Wait until the server stopped and its connections are closed
+    ///
+    /// Only completes after [ServerHandle::stop], which can be called through
+    /// another handle to the same server, e.g. from a signal handler.
+    pub async fn join(self) {
+        // keep the server running until it is stopped
+        let _shutdown = self.shutdown;
+        if let Err(err) = self.task.await {
+            warn!("Server task failed: {:?}", err);
+        }
+    }
+
+    /// Stop the server and wait until its connections are closed
+    pub async fn shutdown(mut self) {
+        self.stop();
+        self.join().await
+    }
+}
+
+/// Bind `addr` and run a USB/IP server on it in a new task, see [ServerHandle]
+///
+/// The task runs on the tokio runtime of the caller, so embedders keep their
+/// own runtime. Await [server] instead to run the server in the current task.
+pub async fn spawn_server(addr: SocketAddr, server: Arc<UsbIpServer>) -> Result<ServerHandle> {
+    let listener = bind_tcp(addr, server.listen_backlog)?;
+    let local_addr = listener.local_addr()?;
+    let (shutdown, shutdown_rx) = tokio::sync::oneshot::channel();
+    let task = tokio::spawn(serve_until(
+        listener,
+        server,
+        futures::future::FutureExt::map(shutdown_rx, |_| ()),
+    ));
+    Ok(ServerHandle {
+        local_addr,
+        shutdown: Some(shutdown),
+        task,
+    })
+}
+
+/// Bind a [TcpListener] at `addr` queueing up to `backlog` connections
+fn bind_tcp(addr: SocketAddr, backlog: u32) -> Result<TcpListener> {
+    let socket = if addr.is_ipv4() {
+        tokio::net::TcpSocket::new_v4()?
+    } else {
+        tokio::net::TcpSocket::new_v6()?
+    };
+    // like TcpListener::bind
+    #[cfg(unix)]
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    socket.listen(backlog)
+}
+
+/// A listening socket [serve_until] accepts connections on
+trait Listener: Sync {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    /// Accept a connection, with the address of its peer if it is a TCP one
+    fn accept_connection(&self) -> BoxFuture<'_, Result<(Self::Stream, Option<SocketAddr>)>>;
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+
+    fn accept_connection(&self) -> BoxFuture<'_, Result<(TcpStream, Option<SocketAddr>)>> {
+        Box::pin(async move {
+            let (socket, addr) = self.accept().await?;
+            Ok((socket, Some(addr)))
+        })
+    }
+}
+
+#[cfg(unix)]
+impl Listener for UnixListener {
+    type Stream = UnixStream;
+
+    fn accept_connection(&self) -> BoxFuture<'_, Result<(UnixStream, Option<SocketAddr>)>> {
+        Box::pin(async move {
+            let (socket, _addr) = self.accept().await?;
+            Ok((socket, None))
+        })
+    }
+}
+
+/// Accept connections on `listener` until `shutdown` completes, then wait for
+/// them to close
+async fn serve_until<L, F>(listener: L, server: Arc<UsbIpServer>, shutdown: F)
+where
+    L: Listener,
+    F: std::future::Future<Output = ()> + Unpin,
+{
+    server.validate_all_devices().await;
+    let signal = Arc::new(Shutdown::default());
+    // every connection holds a sender, recv() returns None once all are gone
+    let (closed, mut all_closed) = tokio::sync::mpsc::channel::<()>(1);
+    let accept_loops = futures::future::join_all(
+        (0..server.accept_concurrency).map(|_| accept_loop(&listener, &server, &signal, &closed)),
+    );
+    // the accept loops only end by being dropped
+    futures::future::select(shutdown, Box::pin(accept_loops)).await;
+
+    info!("Shutting down server");
+    drop(listener);
+    signal.request();
+    drop(closed);
+    all_closed.recv().await;
+}
+
+/// Log how a connection ended, a client going away is not an error
+fn log_connection_end(res: std::result::Result<(), UsbIpError>) {
+    match res {
+        Ok(()) => info!("Connection closed"),
+        Err(UsbIpError::Io(err))
+            if matches!(
+                err.kind(),
+                ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset | ErrorKind::BrokenPipe
+            ) =>
+        {
+            info!("Client disconnected mid-command: {}", err)
+        }
+        Err(err) => warn!("Connection failed: {:?}", err),
+    }
+}
+
+/// Accept connections on `listener` and handle each in a new task
+///
+/// The connection filter only applies to TCP peers, access to a Unix socket
+/// is up to its file permissions.
+async fn accept_loop<L: Listener>(
+    listener: &L,
+    server: &Arc<UsbIpServer>,
+    signal: &Arc<Shutdown>,
+    closed: &tokio::sync::mpsc::Sender<()>,
+) {
+    loop {
+        // held by the connection task, frees its place once it ends
+        let mut permit = match &server.connection_limit {
+            Some((limit, ConnectionLimitPolicy::Wait)) => Some(limit.clone().acquire_owned().await),
+            _ => None,
+        };
+        match listener.accept_connection().await {
+            Ok((mut socket, addr)) => {
+                if let (Some(filter), Some(addr)) = (&server.connection_filter, addr) {
+                    if !filter(addr) {
+                        info!("Refused connection from {:?}", addr);
+                        continue;
+                    }
                 }
-                Err(err) => {
-                    warn!("Got error {:?}", err);
+                if let Some((limit, ConnectionLimitPolicy::Reject)) = &server.connection_limit {
+                    match limit.clone().try_acquire_owned() {
+                        Ok(acquired) => permit = Some(acquired),
+                        Err(_) => {
+                            warn!("Rejected connection from {:?}: too many connections", addr);
+                            continue;
+                        }
+                    }
                 }
+                info!("Got connection from {:?}", addr);
+                let new_server = server.clone();
+                let ctx = ConnectionContext::new(addr.map(|addr| addr.to_string()));
+                let signal = signal.clone();
+                let closed = closed.clone();
+                tokio::spawn(async move {
+                    let res = handler_with_context(&mut socket, new_server, ctx, &signal).await;
+                    log_connection_end(res);
+                    drop(permit);
+                    drop(closed);
+                });
+            }
+            Err(err) => {
+                warn!("Got error {:?}", err);
             }
         }
-    };
+    }
+}
+
+/// Speak USB/IP over `stream` until the client disconnects
+///
+/// Any transport works, e.g. a pipe or [tokio::io::duplex] in tests.
+pub async fn serve_stream<T: AsyncReadExt + AsyncWriteExt + Unpin + Send>(
+    mut stream: T,
+    server: Arc<UsbIpServer>,
+) -> std::result::Result<(), UsbIpError> {
+    let res = handler(&mut stream, server).await;
+    // deliver the last replies of buffered transports
+    stream.flush().await.ok();
+    res
+}
+
+/// Bytes buffered in each direction of a [duplex_pair], a few URBs worth
+const DUPLEX_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Two connected in-memory streams, to run a server and a client in one
+/// process without binding a port
+///
+/// Serve one end with [serve_stream] and speak to it through
+/// [UsbIpClient::new] on the other:
+/// ```ignore
+/// let (client, stream) = usbip::duplex_pair();
+/// tokio::spawn(usbip::serve_stream(stream, server));
+/// let device = usbip::UsbIpClient::new(client).import("0").await?;
+/// ```
+/// A side writing more than is buffered waits for the other to read.
+pub fn duplex_pair() -> (tokio::io::DuplexStream, tokio::io::DuplexStream) {
+    tokio::io::duplex(DUPLEX_BUFFER_SIZE)
+}
+
+/// Bind a [UnixListener] at `path`, removing a stale socket file left by a
+/// server that did not exit cleanly
+///
+/// A socket file nobody accepts connections on is stale. If a server is still
+/// listening on `path`, or `path` is anything but a socket, e.g. a regular
+/// file or a symlink, fail with [ErrorKind::AddrInUse] and leave it alone.
+#[cfg(unix)]
+pub async fn bind_unix(path: &Path) -> Result<UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(std::io::Error::new(
+                ErrorKind::AddrInUse,
+                format!("{:?} exists and is not a socket", path),
+            ));
+        }
+        if UnixStream::connect(path).await.is_ok() {
+            return Err(std::io::Error::new(
+                ErrorKind::AddrInUse,
+                format!("a server is listening on {:?}", path),
+            ));
+        }
+        info!("Removing stale socket file {:?}", path);
+        std::fs::remove_file(path)?;
+    }
+    UnixListener::bind(path)
+}
+
+/// Spawn a USB/IP server at Unix socket `path`, see [bind_unix]
+///
+/// Connections are served like those of [server], e.g. limited by
+/// [UsbIpServer::with_max_connections].
+#[cfg(unix)]
+pub async fn server_unix(path: impl AsRef<Path>, server: Arc<UsbIpServer>) {
+    let listener = bind_unix(path.as_ref()).await.expect("bind to path");
+    serve_until(listener, server, futures::future::pending()).await
+}
+
+/// Spawn a health check listener at `addr`, see [serve_health]
+pub async fn health_server(addr: SocketAddr, server: Arc<UsbIpServer>) {
+    let listener = TcpListener::bind(addr).await.expect("bind to addr");
+    serve_health(listener, server).await
+}
 
-    server.await
+/// Answer every connection on `listener` with a status line and the number of exported devices
+///
+/// Meant for load balancers and orchestrators, it does not speak USB/IP. The
+/// reply is a minimal HTTP response, so both plain TCP and HTTP checks work:
+///
+/// ```text
+/// HTTP/1.0 200 OK
+///
+/// ok devices=2
+/// ```
+pub async fn serve_health(listener: TcpListener, server: Arc<UsbIpServer>) {
+    loop {
+        match listener.accept().await {
+            Ok((mut socket, _addr)) => {
+                let server = server.clone();
+                tokio::spawn(async move {
+                    // consume an HTTP request if any, plain TCP checks send nothing
+                    let mut request = [0u8; 1024];
+                    tokio::time::timeout(HEALTH_REQUEST_TIMEOUT, socket.read(&mut request))
+                        .await
+                        .ok();
+                    let devices = server.devices.read().await.len();
+                    let body = format!("ok devices={}\n", devices);
+                    let response = format!(
+                        "HTTP/1.0 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}",
+                        body.len(),
+                        body
+                    );
+                    if let Err(err) = socket.write_all(response.as_bytes()).await {
+                        debug!("Health check reply failed: {:?}", err);
+                    }
+                    socket.shutdown(std::net::Shutdown::Write).ok();
+                });
+            }
+            Err(err) => {
+                warn!("Got error {:?}", err);
+            }
+        }
+    }
 }
 
+/// How long the health listener waits for a request before replying
+const HEALTH_REQUEST_TIMEOUT: Duration = Duration::from_millis(100);
+
 #[cfg(test)]
 mod test {
     use super::*;
 
     #[tokio::test]
     async fn req_empty_devlist() {
-        let server = UsbIpServer { devices: vec![] };
+        let server = UsbIpServer::new_simulated(vec![]);
 
         // OP_REQ_DEVLIST
         let mut mock_socket = MockSocket::new(vec![0x01, 0x11, 0x80, 0x05, 0x00, 0x00, 0x00, 0x00]);
@@ -326,20 +2094,19 @@ mod test {
     }
 
     #[tokio::test]
+    #[allow(clippy::identity_op)]
     async fn req_sample_devlist() {
         let intf_handler = Arc::new(Mutex::new(
             Box::new(cdc::UsbCdcAcmHandler::new()) as Box<dyn UsbInterfaceHandler + Send>
         ));
-        let server = UsbIpServer {
-            devices: vec![UsbDevice::new(0).with_interface(
-                ClassCode::CDC as u8,
-                cdc::CDC_ACM_SUBCLASS,
-                0x00,
-                "Test CDC ACM",
-                cdc::UsbCdcAcmHandler::endpoints(),
-                intf_handler.clone(),
-            )],
-        };
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0).with_interface(
+            ClassCode::CDC as u8,
+            cdc::CDC_ACM_SUBCLASS,
+            0x00,
+            "Test CDC ACM",
+            cdc::UsbCdcAcmHandler::endpoints(),
+            intf_handler.clone(),
+        )]);
 
         // OP_REQ_DEVLIST
         let mut mock_socket = MockSocket::new(vec![0x01, 0x11, 0x80, 0x05, 0x00, 0x00, 0x00, 0x00]);
@@ -356,16 +2123,14 @@ mod test {
         let intf_handler = Arc::new(Mutex::new(
             Box::new(cdc::UsbCdcAcmHandler::new()) as Box<dyn UsbInterfaceHandler + Send>
         ));
-        let server = UsbIpServer {
-            devices: vec![UsbDevice::new(0).with_interface(
-                ClassCode::CDC as u8,
-                cdc::CDC_ACM_SUBCLASS,
-                0x00,
-                "Test CDC ACM",
-                cdc::UsbCdcAcmHandler::endpoints(),
-                intf_handler.clone(),
-            )],
-        };
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0).with_interface(
+            ClassCode::CDC as u8,
+            cdc::CDC_ACM_SUBCLASS,
+            0x00,
+            "Test CDC ACM",
+            cdc::UsbCdcAcmHandler::endpoints(),
+            intf_handler.clone(),
+        )]);
 
         // OP_REQ_IMPORT
         let mut req = vec![0x01, 0x11, 0x80, 0x03, 0x00, 0x00, 0x00, 0x00];
@@ -383,16 +2148,14 @@ mod test {
         let intf_handler = Arc::new(Mutex::new(
             Box::new(cdc::UsbCdcAcmHandler::new()) as Box<dyn UsbInterfaceHandler + Send>
         ));
-        let server = UsbIpServer {
-            devices: vec![UsbDevice::new(0).with_interface(
-                ClassCode::CDC as u8,
-                cdc::CDC_ACM_SUBCLASS,
-                0x00,
-                "Test CDC ACM",
-                cdc::UsbCdcAcmHandler::endpoints(),
-                intf_handler.clone(),
-            )],
-        };
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0).with_interface(
+            ClassCode::CDC as u8,
+            cdc::CDC_ACM_SUBCLASS,
+            0x00,
+            "Test CDC ACM",
+            cdc::UsbCdcAcmHandler::endpoints(),
+            intf_handler.clone(),
+        )]);
 
         // OP_REQ_IMPORT
         let mut req = vec![0x01, 0x11, 0x80, 0x03, 0x00, 0x00, 0x00, 0x00];
@@ -415,7 +2178,3020 @@ mod test {
         ]);
         let mut mock_socket = MockSocket::new(req);
         handler(&mut mock_socket, Arc::new(server)).await.ok();
-        // OP_REQ_IMPORT + USBIP_CMD_SUBMIT + Device Descriptor
-        assert_eq!(mock_socket.output.len(), 0x140 + 0x30 + 0x12);
+        // OP_REQ_IMPORT + USBIP_CMD_SUBMIT, the descriptor does not fit in
+        // the empty transfer buffer
+        assert_eq!(mock_socket.output.len(), 0x140 + 0x30);
+        let ret = &mock_socket.output[0x140..];
+        assert_eq!(ret[0x14..0x18], (-errno::EOVERFLOW).to_be_bytes());
+    }
+
+    #[tokio::test]
+    async fn req_device_desc_short_buffer() {
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0)]);
+        // GetDescriptor to Device with wLength 0x40 into a buffer of 8 bytes
+        let get_descriptor = [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x40, 0x00];
+        let urbs = [testing::CmdSubmit {
+            transfer_buffer_length: 8,
+            ..testing::CmdSubmit::control(1, get_descriptor, &[])
+        }];
+        let rets = testing::round_trip(Arc::new(server), "0", &urbs)
+            .await
+            .unwrap();
+        // the buffer holds the first 8 bytes, the rest is babble
+        assert_eq!(rets[0].status, -errno::EOVERFLOW);
+        assert_eq!(rets[0].actual_length, 8);
+        assert_eq!(rets[0].data[..2], [0x12, DescriptorType::Device as u8]);
+    }
+
+    #[tokio::test]
+    async fn req_device_desc_longer_wlength() {
+        let intf_handler = Arc::new(Mutex::new(
+            Box::new(cdc::UsbCdcAcmHandler::new()) as Box<dyn UsbInterfaceHandler + Send>
+        ));
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0).with_interface(
+            ClassCode::CDC as u8,
+            cdc::CDC_ACM_SUBCLASS,
+            0x00,
+            "Test CDC ACM",
+            cdc::UsbCdcAcmHandler::endpoints(),
+            intf_handler.clone(),
+        )]);
+
+        // GetDescriptor to Device, wLength 64
+        let urbs = [testing::CmdSubmit::control(
+            1,
+            [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x40, 0x00],
+            &[],
+        )];
+        let rets = testing::round_trip(Arc::new(server), "0", &urbs)
+            .await
+            .unwrap();
+        // only the real 18 bytes are returned
+        assert_eq!(rets.len(), 1);
+        assert_eq!(rets[0].actual_length, 0x12);
+        assert_eq!(rets[0].data.len(), 0x12);
+        // bLength
+        assert_eq!(rets[0].data[0], 0x12);
+    }
+
+    #[tokio::test]
+    async fn custom_op() {
+        let server =
+            UsbIpServer::new_simulated(vec![]).with_custom_op([0x01, 0x11, 0x80, 0xAA], |socket| {
+                Box::pin(async move {
+                    let value = socket.read_u32().await?;
+                    socket.write_u32(value + 1).await
+                })
+            });
+
+        let mut mock_socket = MockSocket::new(vec![
+            0x01, 0x11, 0x80, 0xAA, // custom command
+            0x00, 0x00, 0x00, 0x41, // payload
+        ]);
+        handler(&mut mock_socket, Arc::new(server)).await.ok();
+        assert_eq!(mock_socket.output, [0x00, 0x00, 0x00, 0x42]);
+    }
+
+    #[tokio::test]
+    async fn unsupported_version() {
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0)]);
+        let mut req = vec![
+            0x01, 0x06, 0x80, 0x05, // OP_REQ_DEVLIST of version 1.0.6
+            0x00, 0x00, 0x00, 0x00, // status
+            0x01, 0x00, 0x80, 0x03, // OP_REQ_IMPORT of version 1.0.0
+            0x00, 0x00, 0x00, 0x00, // status
+        ];
+        let mut path = "0".as_bytes().to_vec();
+        path.resize(32, 0);
+        req.extend(path);
+        // still in sync: OP_REQ_DEVLIST of the supported version
+        req.extend([0x01, 0x11, 0x80, 0x05, 0x00, 0x00, 0x00, 0x00]);
+        let mut mock_socket = MockSocket::new(req);
+        handler(&mut mock_socket, Arc::new(server)).await.unwrap();
+
+        let output = &mock_socket.output;
+        assert_eq!(
+            output[..8],
+            [0x01, 0x11, 0x00, 0x05, 0x00, 0x00, 0x00, 0x05]
+        );
+        assert_eq!(
+            output[8..16],
+            [0x01, 0x11, 0x00, 0x03, 0x00, 0x00, 0x00, 0x05]
+        );
+        // OP_REP_DEVLIST with one device
+        assert_eq!(
+            output[16..24],
+            [0x01, 0x11, 0x00, 0x05, 0x00, 0x00, 0x00, 0x00]
+        );
+        assert_eq!(output[24..28], [0x00, 0x00, 0x00, 0x01]);
+    }
+
+    #[tokio::test]
+    async fn unknown_command_ends_connection() {
+        let server = UsbIpServer::new_simulated(vec![]);
+        let mut mock_socket = MockSocket::new(vec![
+            0x01, 0x11, 0x80, 0xAB, // unknown command
+            0x01, 0x11, 0x80, 0x05, // OP_REQ_DEVLIST, not parsed
+            0x00, 0x00, 0x00, 0x00,
+        ]);
+        let err = handler(&mut mock_socket, Arc::new(server))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, UsbIpError::UnsupportedCommand(0x011180AB)));
+        assert!(mock_socket.output.is_empty());
+    }
+
+    #[test]
+    #[should_panic]
+    fn custom_op_standard_command() {
+        UsbIpServer::new_simulated(vec![]).with_custom_op([0x01, 0x11, 0x80, 0x05], |_| {
+            Box::pin(async move { Ok(()) })
+        });
+    }
+
+    #[tokio::test]
+    async fn endpoint_error_injection() {
+        let intf_handler = Arc::new(Mutex::new(
+            Box::new(cdc::UsbCdcAcmHandler::new()) as Box<dyn UsbInterfaceHandler + Send>
+        ));
+        let device = UsbDevice::new(0).with_interface(
+            ClassCode::CDC as u8,
+            cdc::CDC_ACM_SUBCLASS,
+            0x00,
+            "Test CDC ACM",
+            cdc::UsbCdcAcmHandler::endpoints(),
+            intf_handler.clone(),
+        );
+        let server = Arc::new(UsbIpServer::new_simulated(vec![device.clone()]));
+
+        // bulk IN on ep 2
+        let urbs = [testing::CmdSubmit {
+            seq_num: 1,
+            endpoint: 0x82,
+            transfer_buffer_length: 0x200,
+            ..Default::default()
+        }];
+
+        device.set_endpoint_error(0x82, -errno::EPIPE);
+        let rets = testing::round_trip(server.clone(), "0", &urbs)
+            .await
+            .unwrap();
+        assert_eq!(rets[0].status, -errno::EPIPE);
+
+        device.clear_endpoint_error(0x82);
+        let rets = testing::round_trip(server, "0", &urbs).await.unwrap();
+        assert_eq!(rets[0].status, 0);
+    }
+
+    /// Completes a control write after NAKing it a few times
+    struct SlowControlHandler {
+        naks: usize,
+        received: Vec<u8>,
+    }
+
+    impl UsbInterfaceHandler for SlowControlHandler {
+        fn get_class_specific_descriptor(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn handle_urb(
+            &mut self,
+            _interface: &UsbInterface,
+            _ep: UsbEndpoint,
+            _setup: Option<SetupPacket>,
+            req: &[u8],
+        ) -> Result<Vec<u8>> {
+            if self.naks > 0 {
+                self.naks -= 1;
+                return Err(std::io::Error::new(ErrorKind::WouldBlock, "busy"));
+            }
+            self.received = req.to_vec();
+            Ok(vec![])
+        }
+
+        fn as_any(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    #[tokio::test]
+    async fn delayed_control_write() {
+        tokio::time::pause();
+        let intf_handler = Arc::new(Mutex::new(Box::new(SlowControlHandler {
+            naks: 3,
+            received: vec![],
+        })
+            as Box<dyn UsbInterfaceHandler + Send>));
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0).with_interface(
+            ClassCode::VendorSpecific as u8,
+            0x00,
+            0x00,
+            "Slow",
+            vec![],
+            intf_handler.clone(),
+        )]);
+
+        // vendor request to interface 0
+        let urbs = [testing::CmdSubmit::control(
+            1,
+            [0x41, 0x01, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00],
+            &[0xAB, 0xCD],
+        )];
+        let start = tokio::time::Instant::now();
+        let rets = testing::round_trip(Arc::new(server), "0", &urbs)
+            .await
+            .unwrap();
+        assert!(start.elapsed() >= NAK_RETRY_INTERVAL * 3);
+
+        // RET_SUBMIT is sent once the handler completes
+        assert_eq!(rets.len(), 1);
+        assert_eq!(rets[0].status, 0);
+        let mut intf_handler = intf_handler.lock().unwrap();
+        let slow = intf_handler
+            .as_any()
+            .downcast_mut::<SlowControlHandler>()
+            .unwrap();
+        assert_eq!(slow.naks, 0);
+        assert_eq!(slow.received, [0xAB, 0xCD]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn rebind_stale_unix_socket() {
+        let path = std::env::temp_dir().join(format!("usbip-test-{}.sock", std::process::id()));
+        std::fs::remove_file(&path).ok();
+
+        // a killed server leaves its socket file behind
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+        let listener = bind_unix(&path).await.unwrap();
+
+        // a live server is not replaced
+        let err = bind_unix(&path).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AddrInUse);
+
+        drop(listener);
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn bind_unix_keeps_other_files() {
+        let path = std::env::temp_dir().join(format!("usbip-test-{}.file", std::process::id()));
+        std::fs::write(&path, b"data").unwrap();
+
+        let err = bind_unix(&path).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AddrInUse);
+        assert_eq!(std::fs::read(&path).unwrap(), b"data");
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unix_connection_limit() {
+        let path =
+            std::env::temp_dir().join(format!("usbip-test-{}-limit.sock", std::process::id()));
+        std::fs::remove_file(&path).ok();
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0)])
+            .with_max_connections(1, ConnectionLimitPolicy::Reject);
+        tokio::spawn(server_unix(path.clone(), Arc::new(server)));
+        let mut stream = None;
+        for _ in 0..100 {
+            if let Ok(connected) = UnixStream::connect(&path).await {
+                stream = Some(connected);
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let mut first = UsbIpClient::new(stream.unwrap());
+        assert_eq!(first.list_devices().await.unwrap().len(), 1);
+        // closed without a reply, like a TCP connection
+        let mut second = UsbIpClient::new(UnixStream::connect(&path).await.unwrap());
+        assert!(second.list_devices().await.is_err());
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    /// Returns a fixed amount of data on every IN transfer
+    struct FixedDataHandler {
+        len: usize,
+    }
+
+    impl UsbInterfaceHandler for FixedDataHandler {
+        fn get_class_specific_descriptor(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn handle_urb(
+            &mut self,
+            _interface: &UsbInterface,
+            _ep: UsbEndpoint,
+            _setup: Option<SetupPacket>,
+            _req: &[u8],
+        ) -> Result<Vec<u8>> {
+            Ok(vec![0x55; self.len])
+        }
+
+        fn as_any(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    #[tokio::test]
+    async fn throttled_bulk_in() {
+        tokio::time::pause();
+        let intf_handler = Arc::new(Mutex::new(
+            Box::new(FixedDataHandler { len: 100 }) as Box<dyn UsbInterfaceHandler + Send>
+        ));
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0)
+            .with_interface(
+                ClassCode::VendorSpecific as u8,
+                0x00,
+                0x00,
+                "Fixed",
+                vec![UsbEndpoint::new(
+                    0x81,
+                    EndpointAttributes::Bulk,
+                    SyncType::NoSync,
+                    UsageType::Data,
+                    512,
+                    0,
+                )],
+                intf_handler,
+            )
+            .with_endpoint_throughput_limit(0x81, 1000)]);
+
+        // 21 bulk IN transfers of 100 bytes
+        let urb = testing::CmdSubmit {
+            seq_num: 1,
+            endpoint: 0x81,
+            transfer_buffer_length: 0x200,
+            ..Default::default()
+        };
+        let urbs = vec![urb; 21];
+        let start = tokio::time::Instant::now();
+        let rets = testing::round_trip(Arc::new(server), "0", &urbs)
+            .await
+            .unwrap();
+        // the first 100 bytes are a burst, the other 2000 bytes take 2s at 1000 bytes/s
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(1990), "{:?}", elapsed);
+        assert!(elapsed <= Duration::from_millis(2010), "{:?}", elapsed);
+        assert_eq!(rets.len(), 21);
+        assert!(rets.iter().all(|ret| ret.data.len() == 100));
+    }
+
+    #[cfg(feature = "fault-injection")]
+    #[tokio::test]
+    async fn response_jitter() {
+        tokio::time::pause();
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0).with_response_jitter(
+            Duration::from_millis(1),
+            Duration::from_millis(5),
+            1,
+        )]);
+
+        // 10 GetDescriptor to Device
+        let get_descriptor =
+            testing::CmdSubmit::control(1, [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00], &[]);
+        let start = tokio::time::Instant::now();
+        let rets = testing::round_trip(Arc::new(server), "0", &vec![get_descriptor; 10])
+            .await
+            .unwrap();
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(10), "{:?}", elapsed);
+        assert!(elapsed <= Duration::from_millis(50), "{:?}", elapsed);
+        assert_eq!(rets.len(), 10);
+        assert!(rets.iter().all(|ret| ret.actual_length == 0x12));
+    }
+
+    /// Records the connection id of every URB
+    struct ContextRecordingHandler {
+        ids: Vec<u64>,
+    }
+
+    impl UsbInterfaceHandler for ContextRecordingHandler {
+        fn get_class_specific_descriptor(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn handle_urb(
+            &mut self,
+            _interface: &UsbInterface,
+            _ep: UsbEndpoint,
+            _setup: Option<SetupPacket>,
+            _req: &[u8],
+        ) -> Result<Vec<u8>> {
+            unreachable!("context is always passed")
+        }
+
+        fn handle_urb_with_context(
+            &mut self,
+            ctx: &ConnectionContext,
+            _interface: &UsbInterface,
+            _ep: UsbEndpoint,
+            _setup: Option<SetupPacket>,
+            _req: &[u8],
+        ) -> Result<Vec<u8>> {
+            self.ids.push(ctx.id);
+            Ok(vec![])
+        }
+
+        fn as_any(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    #[tokio::test]
+    async fn connection_context_per_client() {
+        let intf_handler = Arc::new(Mutex::new(
+            Box::new(ContextRecordingHandler { ids: vec![] })
+                as Box<dyn UsbInterfaceHandler + Send>,
+        ));
+        let server = Arc::new(UsbIpServer::new_simulated(vec![UsbDevice::new(0)
+            .with_interface(
+                ClassCode::VendorSpecific as u8,
+                0x00,
+                0x00,
+                "Recording",
+                vec![],
+                intf_handler.clone(),
+            )]));
+
+        // vendor request to interface 0, from two connections
+        let urbs = [testing::CmdSubmit::control(
+            1,
+            [0x41, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+            &[],
+        )];
+        for _ in 0..2 {
+            testing::round_trip(server.clone(), "0", &urbs)
+                .await
+                .unwrap();
+        }
+
+        let mut intf_handler = intf_handler.lock().unwrap();
+        let recording = intf_handler
+            .as_any()
+            .downcast_mut::<ContextRecordingHandler>()
+            .unwrap();
+        assert_eq!(recording.ids.len(), 2);
+        assert_ne!(recording.ids[0], recording.ids[1]);
+    }
+
+    #[tokio::test]
+    async fn unhandled_control_out_keeps_stream_aligned() {
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0)]);
+
+        let urbs = [
+            // SET_LINE_CODING to a missing interface 5
+            testing::CmdSubmit::control(
+                1,
+                [0x21, 0x20, 0x00, 0x00, 0x05, 0x00, 0x07, 0x00],
+                &[0x80, 0x25, 0x00, 0x00, 0x00, 0x00, 0x08],
+            ),
+            // GetDescriptor to Device
+            testing::CmdSubmit::control(2, [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00], &[]),
+        ];
+        let rets = testing::round_trip(Arc::new(server), "0", &urbs)
+            .await
+            .unwrap();
+
+        // both URBs are answered, the first stalled, the second one with
+        // the device descriptor
+        assert_eq!(rets.len(), 2);
+        assert_eq!(rets[0].status, -errno::EPIPE);
+        assert_eq!(rets[1].seq_num, 2);
+        assert_eq!(rets[1].data[..2], [0x12, DescriptorType::Device as u8]);
+    }
+
+    /// A device whose interface 0 records the data of control requests and
+    /// replies with 4 bytes
+    fn recording_control_device() -> (UsbDevice, Arc<Mutex<Vec<Vec<u8>>>>) {
+        let received = Arc::new(Mutex::new(vec![]));
+        let recorder = received.clone();
+        let device = UsbDevice::new(0).with_interface(
+            ClassCode::VendorSpecific as u8,
+            0x00,
+            0x00,
+            "Recording",
+            vec![],
+            FnHandler::new(move |_, _, _, req| {
+                recorder.lock().unwrap().push(req.to_vec());
+                Ok(vec![0xAA; 4])
+            })
+            .into_handler(),
+        );
+        (device, received)
+    }
+
+    #[tokio::test]
+    async fn status_stage_only_control_transfers() {
+        let (device, received) = recording_control_device();
+        let server = UsbIpServer::new_simulated(vec![device]);
+
+        // vendor requests to interface 0 with wLength 0, as OUT and as IN
+        let urbs = [
+            testing::CmdSubmit::control(1, [0x41, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], &[]),
+            testing::CmdSubmit::control(2, [0xC1, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], &[]),
+        ];
+        let rets = testing::round_trip(Arc::new(server), "0", &urbs)
+            .await
+            .unwrap();
+
+        // the handler is not called for the IN one, which has no data stage
+        // to fill, the OUT one is its request and reaches it without data
+        assert_eq!(*received.lock().unwrap(), [vec![]]);
+        assert_eq!(rets.len(), 2);
+        for ret in rets {
+            assert_eq!(ret.status, 0);
+            assert_eq!(ret.actual_length, 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn control_out_data_stage_length() {
+        let (device, received) = recording_control_device();
+        let server = UsbIpServer::new_simulated(vec![device]);
+
+        // 4 bytes for a data stage of 2, vendor request to interface 0
+        let urb = testing::CmdSubmit::control(
+            1,
+            [0x41, 0x01, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00],
+            &[0x01, 0x02, 0x03, 0x04],
+        );
+        let rets = testing::round_trip(Arc::new(server), "0", &[urb])
+            .await
+            .unwrap();
+
+        assert_eq!(*received.lock().unwrap(), [vec![0x01, 0x02]]);
+        assert_eq!(rets.len(), 1);
+        assert_eq!(rets[0].status, 0);
+        assert_eq!(rets[0].actual_length, 2);
+    }
+
+    #[tokio::test]
+    async fn unsupported_control_requests_stall() {
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0)]);
+
+        let urbs = [
+            // vendor request to the device
+            testing::CmdSubmit::control(1, [0xC0, 0x42, 0x00, 0x00, 0x00, 0x00, 0x04, 0x00], &[]),
+            // GET_DESCRIPTOR of an unknown type
+            testing::CmdSubmit::control(2, [0x80, 0x06, 0x00, 0x42, 0x00, 0x00, 0x04, 0x00], &[]),
+        ];
+        let rets = testing::round_trip(Arc::new(server), "0", &urbs)
+            .await
+            .unwrap();
+
+        assert_eq!(rets.len(), 2);
+        for ret in rets {
+            assert_eq!(ret.status, -errno::EPIPE);
+            assert_eq!(ret.actual_length, 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn urb_history_keeps_last_urbs() {
+        let server =
+            Arc::new(UsbIpServer::new_simulated(vec![UsbDevice::new(0)]).with_urb_history(2));
+
+        // GetDescriptor to Device of 1, 2 and 3 bytes
+        let urbs: Vec<_> = (1..=3u8)
+            .map(|seq_num| {
+                let setup = [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, seq_num, 0x00];
+                testing::CmdSubmit::control(seq_num as u32, setup, &[])
+            })
+            .collect();
+        testing::round_trip(server.clone(), "0", &urbs)
+            .await
+            .unwrap();
+
+        // the history outlives the connection, the first URB was evicted
+        let histories = server.urb_histories();
+        assert_eq!(histories.len(), 1);
+        let records = server.urb_history(histories[0].0.id).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].setup[6], 2);
+        assert_eq!(records[1].setup[6], 3);
+        assert_eq!(records[1].direction, Direction::In);
+        assert_eq!(records[1].endpoint, 0x80);
+        assert_eq!(records[1].data, [0x12, DescriptorType::Device as u8, 0x10]);
+        assert_eq!(records[1].status, 0);
+    }
+
+    #[tokio::test]
+    async fn dfu_detach_reenumerates() {
+        let server = Arc::new(UsbIpServer::new_simulated(vec![dfu::with_dfu_runtime(
+            UsbDevice::new(0),
+        )]));
+
+        // DFU_DETACH to interface 0
+        let detach = [0x21, 0x00, 0xFF, 0x00, 0x00, 0x00, 0x00, 0x00];
+        let rets = testing::round_trip(
+            server.clone(),
+            "0",
+            &[testing::CmdSubmit::control(1, detach, &[])],
+        )
+        .await
+        .unwrap();
+        assert_eq!(rets.len(), 1);
+
+        // re-import sees the device in DFU mode
+        let mut req = testing::op_req_import("0");
+        // GetDescriptor to Configuration
+        let get_config = [0x80, 0x06, 0x00, 0x02, 0x00, 0x00, 0xFF, 0x00];
+        req.extend(testing::CmdSubmit::control(1, get_config, &[]).to_bytes());
+        let mut mock_socket = MockSocket::new(req);
+        handler(&mut mock_socket, server.clone()).await.ok();
+        let config = &mock_socket.output[0x140 + 0x30..];
+        // bus id is kept
+        assert_eq!(mock_socket.output[0x108], b'0');
+        // interface class, subclass and protocol
+        assert_eq!(
+            config[9 + 5..9 + 8],
+            [
+                ClassCode::ApplicationSpecific as u8,
+                dfu::DFU_SUBCLASS,
+                dfu::DFU_PROTOCOL_DFU_MODE
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn reject_oversized_transfer() {
+        let server =
+            UsbIpServer::new_simulated(vec![UsbDevice::new(0)]).with_max_transfer_size(0x10);
+
+        let urbs = [
+            // vendor request to device
+            testing::CmdSubmit::control(
+                1,
+                [0x40, 0x01, 0x00, 0x00, 0x00, 0x00, 0x20, 0x00],
+                &[0xAA; 0x20],
+            ),
+            // GetDescriptor to Device
+            testing::CmdSubmit::control(2, [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x08, 0x00], &[]),
+        ];
+        let rets = testing::round_trip(Arc::new(server), "0", &urbs)
+            .await
+            .unwrap();
+
+        assert_eq!(rets.len(), 2);
+        assert_eq!(rets[0].status, -errno::EINVAL);
+        assert_eq!(rets[0].actual_length, 0);
+        // the oversized data was drained, the next URB is served
+        assert_eq!(rets[1].status, 0);
+        assert_eq!(rets[1].actual_length, 0x08);
+    }
+
+    /// Returns data of an [InEndpointQueue], counting polls
+    struct QueueHandler {
+        queue: InEndpointQueue,
+        polls: usize,
+    }
+
+    impl UsbInterfaceHandler for QueueHandler {
+        fn get_class_specific_descriptor(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn handle_urb(
+            &mut self,
+            _interface: &UsbInterface,
+            _ep: UsbEndpoint,
+            _setup: Option<SetupPacket>,
+            _req: &[u8],
+        ) -> Result<Vec<u8>> {
+            self.polls += 1;
+            self.queue.pop()
+        }
+
+        fn as_any(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    /// Splits the data of an [InEndpointQueue] across URBs, counting polls
+    struct StreamHandler {
+        queue: InEndpointQueue,
+        polls: usize,
+    }
+
+    impl UsbInterfaceHandler for StreamHandler {
+        fn get_class_specific_descriptor(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn handle_urb(
+            &mut self,
+            _interface: &UsbInterface,
+            _ep: UsbEndpoint,
+            _setup: Option<SetupPacket>,
+            _req: &[u8],
+        ) -> Result<Vec<u8>> {
+            unreachable!("the server passes a context")
+        }
+
+        fn handle_urb_with_context(
+            &mut self,
+            ctx: &ConnectionContext,
+            _interface: &UsbInterface,
+            _ep: UsbEndpoint,
+            _setup: Option<SetupPacket>,
+            _req: &[u8],
+        ) -> Result<Vec<u8>> {
+            self.polls += 1;
+            self.queue
+                .pop_at_most(ctx.transfer_buffer_length.unwrap() as usize)
+        }
+
+        fn as_any(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    /// Answers bulk IN with a counter only after a vendor init request
+    #[derive(Default)]
+    struct InitGatedHandler {
+        initialized: bool,
+        counter: u8,
+    }
+
+    impl UsbInterfaceHandler for InitGatedHandler {
+        fn get_class_specific_descriptor(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn handle_urb(
+            &mut self,
+            _interface: &UsbInterface,
+            _ep: UsbEndpoint,
+            setup: Option<SetupPacket>,
+            _req: &[u8],
+        ) -> Result<Vec<u8>> {
+            if let Some(setup) = setup {
+                // vendor request 0x01 to the interface
+                if (setup.request_type, setup.request) == (0x41, 0x01) {
+                    self.initialized = true;
+                    return Ok(vec![]);
+                }
+                return Err(std::io::Error::new(
+                    ErrorKind::BrokenPipe,
+                    "unknown request",
+                ));
+            }
+            if !self.initialized {
+                return Ok(vec![]);
+            }
+            self.counter += 1;
+            Ok(vec![self.counter])
+        }
+
+        fn as_any(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    #[tokio::test]
+    async fn bulk_in_gated_by_init_request() {
+        let ep = UsbEndpoint::new(
+            0x81,
+            EndpointAttributes::Bulk,
+            SyncType::NoSync,
+            UsageType::Data,
+            512,
+            0,
+        );
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0).with_interface(
+            ClassCode::VendorSpecific as u8,
+            0x00,
+            0x00,
+            "Init Gated",
+            vec![ep],
+            Arc::new(Mutex::new(
+                Box::new(InitGatedHandler::default()) as Box<dyn UsbInterfaceHandler + Send>
+            )),
+        )]);
+
+        let bulk_in = |seq_num| testing::CmdSubmit {
+            seq_num,
+            endpoint: 0x81,
+            transfer_buffer_length: 512,
+            ..Default::default()
+        };
+        let urbs = [
+            // bulk IN before init
+            bulk_in(1),
+            // init vendor request to interface 0
+            testing::CmdSubmit::control(2, [0x41, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], &[]),
+            // bulk IN twice after init
+            bulk_in(3),
+            bulk_in(4),
+        ];
+        let rets = testing::round_trip(Arc::new(server), "0", &urbs)
+            .await
+            .unwrap();
+
+        assert_eq!(rets.len(), 4);
+        // empty before init
+        assert_eq!(rets[0].seq_num, 1);
+        assert_eq!(rets[0].actual_length, 0);
+        // init accepted
+        assert_eq!(rets[1].seq_num, 2);
+        assert_eq!(rets[1].status, 0);
+        // data after init
+        assert_eq!(rets[2].data, [1]);
+        assert_eq!(rets[3].seq_num, 4);
+        assert_eq!(rets[3].data, [2]);
+    }
+
+    #[tokio::test]
+    async fn pending_in_urb_woken_by_push() {
+        tokio::time::pause();
+        let queue = InEndpointQueue::new();
+        let intf_handler = Arc::new(Mutex::new(Box::new(QueueHandler {
+            queue: queue.clone(),
+            polls: 0,
+        })
+            as Box<dyn UsbInterfaceHandler + Send>));
+        let ep = UsbEndpoint {
+            address: 0x81,
+            attributes: EndpointAttributes::Interrupt as u8,
+            max_packet_size: 8,
+            interval: 10,
+        };
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0)
+            .with_interface(
+                ClassCode::VendorSpecific as u8,
+                0x00,
+                0x00,
+                "Queue",
+                vec![ep],
+                intf_handler.clone(),
+            )
+            .with_in_queue(0x81, &queue)]);
+
+        let urbs = [testing::CmdSubmit {
+            seq_num: 1,
+            endpoint: 0x81,
+            transfer_buffer_length: 8,
+            interval: 10,
+            ..Default::default()
+        }];
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            queue.push(vec![0x42]);
+        });
+        let start = tokio::time::Instant::now();
+        let rets = testing::round_trip(Arc::new(server), "0", &urbs)
+            .await
+            .unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(100));
+
+        assert_eq!(rets.len(), 1);
+        assert_eq!(rets[0].data, [0x42]);
+        // polled once when submitted and once when woken, not in between
+        let mut intf_handler = intf_handler.lock().unwrap();
+        let queue_handler = intf_handler
+            .as_any()
+            .downcast_mut::<QueueHandler>()
+            .unwrap();
+        assert_eq!(queue_handler.polls, 2);
+    }
+
+    #[tokio::test]
+    async fn one_push_completes_queued_urbs() {
+        tokio::time::pause();
+        let queue = InEndpointQueue::new();
+        let intf_handler = Arc::new(Mutex::new(Box::new(StreamHandler {
+            queue: queue.clone(),
+            polls: 0,
+        })
+            as Box<dyn UsbInterfaceHandler + Send>));
+        let ep = UsbEndpoint::new(
+            0x81,
+            EndpointAttributes::Bulk,
+            SyncType::NoSync,
+            UsageType::Data,
+            8,
+            0,
+        );
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0)
+            .with_interface(
+                ClassCode::VendorSpecific as u8,
+                0x00,
+                0x00,
+                "Stream",
+                vec![ep],
+                intf_handler.clone(),
+            )
+            .with_in_queue(0x81, &queue)]);
+
+        // three URBs of 8 bytes each
+        let urbs: Vec<_> = (1..=3)
+            .map(|seq_num| testing::CmdSubmit {
+                seq_num,
+                endpoint: 0x81,
+                transfer_buffer_length: 8,
+                ..Default::default()
+            })
+            .collect();
+        let data: Vec<u8> = (0..24).collect();
+        tokio::spawn({
+            let data = data.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                queue.push(data);
+            }
+        });
+        let rets = testing::round_trip(Arc::new(server), "0", &urbs)
+            .await
+            .unwrap();
+
+        assert_eq!(rets.len(), 3);
+        for (ret, chunk) in rets.iter().zip(data.chunks(8)) {
+            assert_eq!(ret.actual_length, 8);
+            assert_eq!(ret.data, chunk);
+        }
+        // a NAK, then one poll per URB without waiting
+        let mut intf_handler = intf_handler.lock().unwrap();
+        let stream_handler = intf_handler
+            .as_any()
+            .downcast_mut::<StreamHandler>()
+            .unwrap();
+        assert_eq!(stream_handler.polls, 4);
+    }
+
+    #[tokio::test]
+    async fn unlink_completed_urb() {
+        let server = Arc::new(UsbIpServer::new_simulated(vec![UsbDevice::new(0)]));
+
+        let mut req = testing::op_req_import("0");
+        // GetDescriptor to Device
+        let setup = [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00];
+        req.extend(testing::CmdSubmit::control(1, setup, &[]).to_bytes());
+        // USBIP_CMD_UNLINK of the completed URB
+        req.extend(vec![
+            0x00, 0x00, 0x00, 0x02, // command
+            0x00, 0x00, 0x00, 0x02, // seq num
+            0x00, 0x00, 0x00, 0x00, // dev id
+            0x00, 0x00, 0x00, 0x01, // IN
+            0x00, 0x00, 0x00, 0x00, // ep 0
+            0x00, 0x00, 0x00, 0x01, // seq num to unlink
+        ]);
+        req.extend(vec![0x00; 24]);
+        let mut mock_socket = MockSocket::new(req);
+        handler(&mut mock_socket, server.clone()).await.ok();
+
+        let ret_unlink = &mock_socket.output[0x140 + 0x30 + 0x12..];
+        assert_eq!(ret_unlink.len(), 0x30);
+        assert_eq!(ret_unlink[0x00..0x08], [0, 0, 0, 4, 0, 0, 0, 2]);
+        // status
+        assert_eq!(ret_unlink[0x14..0x18], [0x00; 4]);
+
+        assert_eq!(
+            server.stats(),
+            ServerStats {
+                connections: 1,
+                active_connections: 0,
+                urbs: 1,
+                unlinks: 1,
+                bytes_in: 0x12,
+                bytes_out: 0,
+            }
+        );
+    }
+
+    /// OP_REP_DEVLIST, OP_REP_IMPORT, USBIP_RET_SUBMIT and USBIP_RET_UNLINK
+    /// byte for byte, with every reserved field zero
+    #[tokio::test]
+    async fn reply_golden_bytes() {
+        let device = UsbDevice::new(0).with_vendor_id(0x1234).with_interface(
+            ClassCode::VendorSpecific as u8,
+            0x01,
+            0x02,
+            "Test",
+            vec![],
+            FnHandler::new(|_, _, _, _| Ok(vec![])).into_handler(),
+        );
+        let server = UsbIpServer::new_simulated(vec![device]);
+
+        // OP_REQ_DEVLIST
+        let mut req = vec![0x01, 0x11, 0x80, 0x05, 0x00, 0x00, 0x00, 0x00];
+        req.extend(testing::op_req_import("0"));
+        // GetDescriptor to Device
+        req.extend(
+            testing::CmdSubmit::control(1, [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x08, 0x00], &[])
+                .to_bytes(),
+        );
+        // USBIP_CMD_UNLINK
+        req.extend(vec![
+            0x00, 0x00, 0x00, 0x02, // command
+            0x00, 0x00, 0x00, 0x02, // seq num
+            0x00, 0x00, 0x00, 0x00, // dev id
+            0x00, 0x00, 0x00, 0x00, // OUT
+            0x00, 0x00, 0x00, 0x00, // ep 0
+            0x00, 0x00, 0x00, 0x01, // seq num to unlink
+        ]);
+        req.extend(vec![0xAA; 24]);
+        let mut mock_socket = MockSocket::new(req);
+        handler(&mut mock_socket, Arc::new(server)).await.ok();
+
+        let mut dev = b"/sys/device/usbip/0".to_vec();
+        dev.resize(256, 0);
+        dev.extend(b"0");
+        dev.resize(256 + 32, 0);
+        dev.extend(vec![
+            0x00, 0x00, 0x00, 0x00, // bus num
+            0x00, 0x00, 0x00, 0x00, // dev num
+            0x00, 0x00, 0x00, 0x03, // speed
+            0x12, 0x34, // vendor id
+            0x00, 0x00, // product id
+            0x00, 0x00, // bcdDevice
+            0x00, 0x00, 0x00, // class, subclass, protocol
+            0x01, 0x01, 0x01, // configuration value, configurations, interfaces
+        ]);
+        // OP_REP_DEVLIST
+        let mut expected = vec![
+            0x01, 0x11, 0x00, 0x05, // version, reply code
+            0x00, 0x00, 0x00, 0x00, // status
+            0x00, 0x00, 0x00, 0x01, // number of devices
+        ];
+        expected.extend(&dev);
+        // interface class, subclass, protocol, padding
+        expected.extend(vec![0xFF, 0x01, 0x02, 0x00]);
+        // OP_REP_IMPORT
+        expected.extend(vec![
+            0x01, 0x11, 0x00, 0x03, // version, reply code
+            0x00, 0x00, 0x00, 0x00, // status
+        ]);
+        expected.extend(&dev);
+        // USBIP_RET_SUBMIT
+        expected.extend(vec![
+            0x00, 0x00, 0x00, 0x03, // command
+            0x00, 0x00, 0x00, 0x01, // seq num
+            0x00, 0x00, 0x00, 0x00, // dev id
+            0x00, 0x00, 0x00, 0x01, // IN
+            0x00, 0x00, 0x00, 0x00, // ep 0
+            0x00, 0x00, 0x00, 0x00, // status
+            0x00, 0x00, 0x00, 0x08, // actual length
+            0x00, 0x00, 0x00, 0x00, // start frame
+            0x00, 0x00, 0x00, 0x00, // number of packets
+            0x00, 0x00, 0x00, 0x00, // error count
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // padding
+            0x12, 0x01, 0x10, 0x02, 0x00, 0x00, 0x00, 0x40, // device descriptor
+        ]);
+        // USBIP_RET_UNLINK
+        expected.extend(vec![
+            0x00, 0x00, 0x00, 0x04, // command
+            0x00, 0x00, 0x00, 0x02, // seq num
+            0x00, 0x00, 0x00, 0x00, // dev id
+            0x00, 0x00, 0x00, 0x00, // OUT
+            0x00, 0x00, 0x00, 0x00, // ep 0
+            0x00, 0x00, 0x00, 0x00, // status
+        ]);
+        expected.extend(vec![0x00; 24]);
+        assert_eq!(mock_socket.output, expected);
+    }
+
+    #[tokio::test]
+    async fn strict_submits() {
+        let device = UsbDevice::new(0).with_interface(
+            ClassCode::VendorSpecific as u8,
+            0x00,
+            0x00,
+            "Test",
+            vec![UsbEndpoint::new(
+                0x81,
+                EndpointAttributes::Bulk,
+                SyncType::NoSync,
+                UsageType::Data,
+                512,
+                0,
+            )],
+            FnHandler::new(|_, _, _, _| Ok(vec![0x55; 4])).into_handler(),
+        );
+        let server = UsbIpServer::new_simulated(vec![device]).with_strict_submits();
+
+        let get_device_descriptor = [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00];
+        let bulk = |seq_num, endpoint| testing::CmdSubmit {
+            seq_num,
+            endpoint,
+            transfer_buffer_length: 4,
+            ..Default::default()
+        };
+        let urbs = [
+            // bulk IN before SET_CONFIGURATION
+            bulk(1, 0x81),
+            // SET_CONFIGURATION(1)
+            testing::CmdSubmit::control(2, [0x00, 0x09, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00], &[]),
+            // GET_DESCRIPTOR as OUT
+            testing::CmdSubmit {
+                endpoint: 0x00,
+                ..testing::CmdSubmit::control(3, get_device_descriptor, &[])
+            },
+            // GET_DESCRIPTOR of 18 bytes in a buffer of 64
+            testing::CmdSubmit {
+                transfer_buffer_length: 64,
+                ..testing::CmdSubmit::control(4, get_device_descriptor, &[])
+            },
+            // bulk OUT to the IN endpoint
+            bulk(5, 0x01),
+            // bulk IN
+            bulk(6, 0x81),
+        ];
+        let rets = testing::round_trip(Arc::new(server), "0", &urbs)
+            .await
+            .unwrap();
+
+        let results: Vec<_> = rets
+            .iter()
+            .map(|ret| (ret.status, ret.actual_length))
+            .collect();
+        assert_eq!(
+            results,
+            [
+                (-errno::ENOENT, 0),
+                (0, 0),
+                (-errno::EINVAL, 0),
+                (-errno::EBADR, 0),
+                (-errno::ENOENT, 0),
+                (0, 4),
+            ]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn devlist_while_handler_busy() {
+        let intf_handler = FnHandler::new(|_, _, _, _| Ok(vec![])).into_handler();
+        let server = Arc::new(UsbIpServer::new_simulated(vec![UsbDevice::new(0)
+            .with_interface(
+                ClassCode::VendorSpecific as u8,
+                0x00,
+                0x00,
+                "Busy",
+                vec![],
+                intf_handler.clone(),
+            )]));
+        // a transfer in progress, e.g. on a passthrough device, holds the handler
+        let (locked_tx, locked_rx) = std::sync::mpsc::channel();
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        let transfer = std::thread::spawn(move || {
+            let _handler = intf_handler.lock().unwrap();
+            locked_tx.send(()).unwrap();
+            release_rx.recv().ok();
+        });
+        locked_rx.recv().unwrap();
+
+        // OP_REQ_DEVLIST is answered from the descriptors kept by the device
+        let mut mock_socket = MockSocket::new(vec![0x01, 0x11, 0x80, 0x05, 0x00, 0x00, 0x00, 0x00]);
+        let list = tokio::spawn(async move {
+            handler(&mut mock_socket, server).await.unwrap();
+            mock_socket.output
+        });
+        let output = tokio::time::timeout(Duration::from_secs(5), list)
+            .await
+            .expect("OP_REQ_DEVLIST waited for the handler")
+            .unwrap();
+        // one device with one interface
+        assert_eq!(output.len(), 12 + 0x138 + 4);
+        assert_eq!(output[0x0B], 1);
+        drop(release_tx);
+        transfer.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn health_check_reports_devices() {
+        let server = Arc::new(UsbIpServer::new_simulated(vec![
+            UsbDevice::new(0),
+            UsbDevice::new(1),
+        ]));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_health(listener, server));
+
+        let mut socket = tokio::net::TcpStream::connect(addr).await.unwrap();
+        socket
+            .write_all(b"GET /health HTTP/1.0\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        socket.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nok devices=2\n"));
+    }
+
+    #[tokio::test]
+    async fn control_direction_mismatch() {
+        let intf_handler = Arc::new(Mutex::new(Box::new(SlowControlHandler {
+            naks: 0,
+            received: vec![],
+        })
+            as Box<dyn UsbInterfaceHandler + Send>));
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0).with_interface(
+            ClassCode::VendorSpecific as u8,
+            0x00,
+            0x00,
+            "Control",
+            vec![],
+            intf_handler.clone(),
+        )]);
+
+        let get_descriptor = [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00];
+        let urbs = [
+            // control IN sent as OUT with its data stage
+            testing::CmdSubmit {
+                endpoint: 0x00,
+                data: vec![0x55; 0x12],
+                ..testing::CmdSubmit::control(1, get_descriptor, &[])
+            },
+            // control OUT sent as IN without its data stage, vendor request
+            // to interface 0
+            testing::CmdSubmit {
+                endpoint: 0x80,
+                transfer_buffer_length: 2,
+                ..testing::CmdSubmit::control(
+                    2,
+                    [0x41, 0x01, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00],
+                    &[],
+                )
+            },
+            testing::CmdSubmit::control(3, get_descriptor, &[]),
+        ];
+        let rets = testing::round_trip(Arc::new(server), "0", &urbs)
+            .await
+            .unwrap();
+
+        // both fail, their bytes framed by the direction field keep the
+        // stream in sync
+        assert_eq!(rets.len(), 3);
+        assert_eq!(rets[0].status, -errno::EINVAL);
+        assert_eq!(rets[1].status, -errno::EINVAL);
+        assert_eq!(rets[2].status, 0);
+        assert_eq!(rets[2].data[0], 0x12);
+        let mut intf_handler = intf_handler.lock().unwrap();
+        let control = intf_handler
+            .as_any()
+            .downcast_mut::<SlowControlHandler>()
+            .unwrap();
+        assert!(control.received.is_empty());
+    }
+
+    /// A memory written by control OUT and read by control IN, forking from a shared base
+    struct ForkingMemoryHandler {
+        base: Arc<Vec<u8>>,
+        memory: Vec<u8>,
+    }
+
+    impl UsbInterfaceHandler for ForkingMemoryHandler {
+        fn get_class_specific_descriptor(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn handle_urb(
+            &mut self,
+            _interface: &UsbInterface,
+            ep: UsbEndpoint,
+            _setup: Option<SetupPacket>,
+            req: &[u8],
+        ) -> Result<Vec<u8>> {
+            if ep.direction() == Direction::Out {
+                self.memory = req.to_vec();
+                Ok(vec![])
+            } else {
+                Ok(self.memory.clone())
+            }
+        }
+
+        fn fork(&self) -> Option<Box<dyn UsbInterfaceHandler + Send>> {
+            Some(Box::new(ForkingMemoryHandler {
+                base: self.base.clone(),
+                memory: self.base.to_vec(),
+            }))
+        }
+
+        fn as_any(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    #[tokio::test]
+    async fn import_clones_are_independent() {
+        let base = Arc::new(vec![0x11, 0x22]);
+        let server = Arc::new(UsbIpServer::new_simulated(vec![UsbDevice::new(0)
+            .with_interface(
+                ClassCode::VendorSpecific as u8,
+                0x00,
+                0x00,
+                "Memory",
+                vec![],
+                Arc::new(Mutex::new(Box::new(ForkingMemoryHandler {
+                    base: base.clone(),
+                    memory: base.to_vec(),
+                })
+                    as Box<dyn UsbInterfaceHandler + Send>)),
+            )
+            .with_import_clones()]));
+
+        // vendor write to and read from interface 0
+        let write = testing::CmdSubmit::control(
+            1,
+            [0x41, 0x01, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00],
+            &[0xAA, 0xBB],
+        );
+        let read =
+            testing::CmdSubmit::control(2, [0xC1, 0x01, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00], &[]);
+
+        // first client writes and reads back its own data
+        let rets = testing::round_trip(server.clone(), "0", &[write, read.clone()])
+            .await
+            .unwrap();
+        assert_eq!(rets[1].data, [0xAA, 0xBB]);
+
+        // second client starts from the base
+        let rets = testing::round_trip(server.clone(), "0", &[read])
+            .await
+            .unwrap();
+        assert_eq!(rets[0].data, [0x11, 0x22]);
+    }
+
+    #[tokio::test]
+    async fn req_import_pinned_bus_location() {
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0).with_bus_location(3, 5)]);
+
+        let mut mock_socket = MockSocket::new(testing::op_req_import("3-5"));
+        handler(&mut mock_socket, Arc::new(server)).await.ok();
+        assert_eq!(mock_socket.output.len(), 0x140);
+        // status
+        assert_eq!(mock_socket.output[4..8], [0, 0, 0, 0]);
+        // bus id, busnum and devnum
+        assert_eq!(mock_socket.output[0x108..0x10C], *b"3-5\0");
+        assert_eq!(mock_socket.output[0x128..0x12C], [0, 0, 0, 3]);
+        assert_eq!(mock_socket.output[0x12C..0x130], [0, 0, 0, 5]);
+    }
+
+    #[tokio::test]
+    async fn submit_to_unknown_endpoint() {
+        let server = Arc::new(UsbIpServer::new_simulated(vec![UsbDevice::new(0)]));
+        server.add_device(UsbDevice::new(1)).await.unwrap();
+
+        // OP_REQ_DEVLIST after the hotplug
+        let req = vec![0x01, 0x11, 0x80, 0x05, 0x00, 0x00, 0x00, 0x00];
+        let mut mock_socket = MockSocket::new(req);
+        handler(&mut mock_socket, server.clone()).await.ok();
+        assert_eq!(mock_socket.output[8..12], [0, 0, 0, 2]);
+
+        let urbs = [
+            // an endpoint the device does not have
+            testing::CmdSubmit {
+                seq_num: 1,
+                endpoint: 0x02,
+                data: vec![0x01, 0x02, 0x03, 0x04],
+                ..Default::default()
+            },
+            // GetDescriptor to Device
+            testing::CmdSubmit::control(2, [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00], &[]),
+        ];
+        let rets = testing::round_trip(server, "1", &urbs).await.unwrap();
+
+        // stalled instead of a panic, the stream is still in sync
+        assert_eq!(rets.len(), 2);
+        assert_eq!(rets[0].seq_num, 1);
+        assert_eq!(rets[0].status, -errno::EPIPE);
+        assert_eq!(rets[1].status, 0);
+        assert_eq!(rets[1].actual_length, 0x12);
+    }
+
+    #[tokio::test]
+    async fn add_duplicate_device() {
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0).with_bus_location(1, 1)]);
+
+        // another device at the same bus id
+        let device = UsbDevice::new(1)
+            .with_bus_location(1, 1)
+            .with_vendor_id(0x1234);
+        let err = server.add_device(device).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+        let devices = server.devices.read().await;
+        assert_eq!(devices.len(), 1);
+        assert_ne!(devices[0].vendor_id, 0x1234);
+        drop(devices);
+
+        // unless the first one is gone
+        server.remove_device("1-1").await.unwrap();
+        let device = UsbDevice::new(1).with_bus_location(1, 1);
+        server.add_device(device).await.unwrap();
+        assert_eq!(server.devices.read().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn req_import_removed_device() {
+        let server = Arc::new(UsbIpServer::new_simulated(vec![
+            UsbDevice::new(0),
+            UsbDevice::new(1),
+        ]));
+
+        // OP_REQ_DEVLIST
+        let req = vec![0x01, 0x11, 0x80, 0x05, 0x00, 0x00, 0x00, 0x00];
+        let mut mock_socket = MockSocket::new(req);
+        handler(&mut mock_socket, server.clone()).await.ok();
+        assert_eq!(mock_socket.output[8..12], [0, 0, 0, 2]);
+
+        server.remove_device("1").await.unwrap();
+
+        let mut req = testing::op_req_import("1");
+        // USBIP_CMD_SUBMIT sent anyway
+        let setup = [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00];
+        req.extend(testing::CmdSubmit::control(1, setup, &[]).to_bytes());
+        // OP_REQ_DEVLIST
+        req.extend(vec![0x01, 0x11, 0x80, 0x05, 0x00, 0x00, 0x00, 0x00]);
+        let mut mock_socket = MockSocket::new(req);
+        handler(&mut mock_socket, server).await.ok();
+        // OP_REP_IMPORT with ST_NODEV and no device
+        assert_eq!(
+            mock_socket.output[..8],
+            [0x01, 0x11, 0x00, 0x03, 0x00, 0x00, 0x00, 0x04]
+        );
+        // USBIP_RET_SUBMIT with -ENODEV
+        let ret_submit = &mock_socket.output[8..8 + 0x30];
+        assert_eq!(ret_submit[0x00..0x08], [0, 0, 0, 3, 0, 0, 0, 1]);
+        assert_eq!(ret_submit[0x14..0x18], (-errno::ENODEV).to_be_bytes());
+        // the connection goes on: OP_REP_DEVLIST of the remaining device
+        let rep_devlist = &mock_socket.output[8 + 0x30..];
+        assert_eq!(rep_devlist[0..4], [0x01, 0x11, 0x00, 0x05]);
+        assert_eq!(rep_devlist[8..12], [0, 0, 0, 1]);
+    }
+
+    struct InterruptOutHandler {
+        received: Vec<(u8, Vec<u8>)>,
+    }
+
+    impl UsbInterfaceHandler for InterruptOutHandler {
+        fn get_class_specific_descriptor(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn handle_urb(
+            &mut self,
+            _interface: &UsbInterface,
+            ep: UsbEndpoint,
+            _setup: Option<SetupPacket>,
+            req: &[u8],
+        ) -> Result<Vec<u8>> {
+            self.received.push((ep.address, req.to_vec()));
+            // not sent back for OUT transfers
+            Ok(vec![0xFF])
+        }
+
+        fn as_any(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    #[tokio::test]
+    async fn interrupt_out() {
+        let intf_handler = Arc::new(Mutex::new(
+            Box::new(InterruptOutHandler { received: vec![] })
+                as Box<dyn UsbInterfaceHandler + Send>,
+        ));
+        let interrupt = |address| UsbEndpoint {
+            address,
+            attributes: EndpointAttributes::Interrupt as u8,
+            max_packet_size: 8,
+            interval: 10,
+        };
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0).with_interface(
+            ClassCode::HID as u8,
+            0x00,
+            0x00,
+            "Interrupt",
+            vec![interrupt(0x81), interrupt(0x01)],
+            intf_handler.clone(),
+        )]);
+
+        let urbs = [testing::CmdSubmit {
+            seq_num: 1,
+            endpoint: 0x01,
+            interval: 10,
+            data: vec![0x01, 0x05],
+            ..Default::default()
+        }];
+        let rets = testing::round_trip(Arc::new(server), "0", &urbs)
+            .await
+            .unwrap();
+
+        // RET_SUBMIT without data, all of it consumed
+        assert_eq!(rets.len(), 1);
+        assert_eq!(rets[0].status, 0);
+        assert_eq!(rets[0].actual_length, 2);
+        assert!(rets[0].data.is_empty());
+        let mut intf_handler = intf_handler.lock().unwrap();
+        let out_handler = intf_handler
+            .as_any()
+            .downcast_mut::<InterruptOutHandler>()
+            .unwrap();
+        assert_eq!(out_handler.received, [(0x01, vec![0x01, 0x05])]);
+    }
+
+    #[tokio::test]
+    async fn endpoint_stats() {
+        tokio::time::pause();
+        let intf_handler = Arc::new(Mutex::new(
+            Box::new(FixedDataHandler { len: 100 }) as Box<dyn UsbInterfaceHandler + Send>
+        ));
+        let device = UsbDevice::new(0)
+            .with_interface(
+                ClassCode::VendorSpecific as u8,
+                0x00,
+                0x00,
+                "Fixed",
+                vec![UsbEndpoint::new(
+                    0x81,
+                    EndpointAttributes::Bulk,
+                    SyncType::NoSync,
+                    UsageType::Data,
+                    512,
+                    0,
+                )],
+                intf_handler,
+            )
+            .with_endpoint_throughput_limit(0x81, 1000);
+        device.set_endpoint_error_once(0x81, -errno::EPIPE);
+        let server = UsbIpServer::new_simulated(vec![device.clone()]);
+
+        // 4 bulk IN transfers, the first fails
+        let urb = testing::CmdSubmit {
+            seq_num: 1,
+            endpoint: 0x81,
+            transfer_buffer_length: 0x200,
+            ..Default::default()
+        };
+        testing::round_trip(Arc::new(server), "0", &vec![urb; 4])
+            .await
+            .unwrap();
+
+        let stats = device.endpoint_stats(0x81);
+        assert_eq!(stats.urbs, 4);
+        assert_eq!(stats.bytes, 300);
+        assert_eq!(stats.errors, 1);
+        // the first 100 bytes are a burst, the other two transfers wait 100ms each
+        let total = stats.total_latency;
+        assert!(total >= Duration::from_millis(190), "{:?}", total);
+        assert!(total <= Duration::from_millis(210), "{:?}", total);
+        assert_eq!(stats.average_latency(), total / 4);
+        assert_eq!(device.all_endpoint_stats(), [(0x81, stats)]);
+        assert_eq!(device.endpoint_stats(0x02), EndpointStats::default());
+    }
+
+    /// Answers interrupt IN with reports of the given lengths in turn
+    struct VariableReportHandler {
+        lengths: VecDeque<usize>,
+    }
+
+    impl UsbInterfaceHandler for VariableReportHandler {
+        fn get_class_specific_descriptor(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn handle_urb(
+            &mut self,
+            _interface: &UsbInterface,
+            _ep: UsbEndpoint,
+            _setup: Option<SetupPacket>,
+            _req: &[u8],
+        ) -> Result<Vec<u8>> {
+            let len = self.lengths.pop_front().unwrap();
+            Ok((0..len as u8).collect())
+        }
+
+        fn as_any(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    #[tokio::test]
+    async fn variable_length_interrupt_in() {
+        let lengths = [1usize, 8, 0, 5, 12];
+        let intf_handler = Arc::new(Mutex::new(Box::new(VariableReportHandler {
+            lengths: lengths.iter().copied().collect(),
+        })
+            as Box<dyn UsbInterfaceHandler + Send>));
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0).with_interface(
+            ClassCode::HID as u8,
+            0x00,
+            0x00,
+            "Variable",
+            vec![UsbEndpoint {
+                address: 0x81,
+                attributes: EndpointAttributes::Interrupt as u8,
+                max_packet_size: 8,
+                interval: 10,
+            }],
+            intf_handler,
+        )]);
+
+        let urb = testing::CmdSubmit {
+            seq_num: 1,
+            endpoint: 0x81,
+            transfer_buffer_length: 8,
+            interval: 10,
+            ..Default::default()
+        };
+        let rets = testing::round_trip(Arc::new(server), "0", &vec![urb; lengths.len()])
+            .await
+            .unwrap();
+
+        // each RET_SUBMIT carries exactly its report, the last one cut to the buffer
+        assert_eq!(rets.len(), lengths.len());
+        for (ret, len) in rets.iter().zip(lengths.iter().map(|len| (*len).min(8))) {
+            assert_eq!(ret.actual_length, len as u32);
+            assert_eq!(ret.data, (0..len as u8).collect::<Vec<_>>());
+        }
+    }
+
+    type ControlWrites = Arc<Mutex<Vec<(SetupPacket, Vec<u8>)>>>;
+    type BulkWrites = Arc<Mutex<Vec<Vec<u8>>>>;
+
+    struct MockHostHandle {
+        control_writes: ControlWrites,
+        bulk_in: Vec<u8>,
+        bulk_writes: BulkWrites,
+    }
+
+    impl HostDeviceHandle for MockHostHandle {
+        fn read_control(
+            &self,
+            _request_type: u8,
+            _request: u8,
+            _value: u16,
+            _index: u16,
+            _buf: &mut [u8],
+            _timeout: Duration,
+        ) -> rusb::Result<usize> {
+            Err(rusb::Error::Pipe)
+        }
+
+        fn write_control(
+            &self,
+            request_type: u8,
+            request: u8,
+            value: u16,
+            index: u16,
+            buf: &[u8],
+            _timeout: Duration,
+        ) -> rusb::Result<usize> {
+            let setup = SetupPacket {
+                request_type,
+                request,
+                value,
+                index,
+                length: buf.len() as u16,
+            };
+            self.control_writes
+                .lock()
+                .unwrap()
+                .push((setup, buf.to_vec()));
+            Ok(buf.len())
+        }
+
+        fn read_interrupt(
+            &self,
+            _endpoint: u8,
+            _buf: &mut [u8],
+            _timeout: Duration,
+        ) -> rusb::Result<usize> {
+            Err(rusb::Error::Timeout)
+        }
+
+        fn write_interrupt(
+            &self,
+            _endpoint: u8,
+            buf: &[u8],
+            _timeout: Duration,
+        ) -> rusb::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn read_bulk(
+            &self,
+            _endpoint: u8,
+            buf: &mut [u8],
+            _timeout: Duration,
+        ) -> rusb::Result<usize> {
+            let len = buf.len().min(self.bulk_in.len());
+            buf[..len].copy_from_slice(&self.bulk_in[..len]);
+            Ok(len)
+        }
+
+        fn write_bulk(&self, _endpoint: u8, buf: &[u8], _timeout: Duration) -> rusb::Result<usize> {
+            self.bulk_writes.lock().unwrap().push(buf.to_vec());
+            Ok(buf.len())
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_transfers_complete_with_errno() {
+        let host_handle = Arc::new(Mutex::new(MockHostHandle {
+            control_writes: ControlWrites::default(),
+            bulk_in: vec![0x55; 4],
+            bulk_writes: BulkWrites::default(),
+        }));
+        let failing = FnHandler::new(|_, _, _, _| Err(std::io::Error::other("backend gone")));
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0)
+            .with_interface(
+                ClassCode::VendorSpecific as u8,
+                0x00,
+                0x00,
+                "Passthrough",
+                vec![UsbEndpoint {
+                    address: 0x81,
+                    attributes: EndpointAttributes::Bulk as u8,
+                    max_packet_size: 512,
+                    interval: 0,
+                }],
+                Arc::new(Mutex::new(
+                    Box::new(UsbHostHandler::from_handle(host_handle))
+                        as Box<dyn UsbInterfaceHandler + Send>,
+                )),
+            )
+            .with_interface(
+                ClassCode::VendorSpecific as u8,
+                0x00,
+                0x00,
+                "Failing",
+                vec![UsbEndpoint {
+                    address: 0x02,
+                    attributes: EndpointAttributes::Bulk as u8,
+                    max_packet_size: 512,
+                    interval: 0,
+                }],
+                failing.into_handler(),
+            )]);
+        let urbs = [
+            // vendor control IN to the interface, stalled by the host device
+            testing::CmdSubmit::control(1, [0xC1, 0x01, 0x00, 0x00, 0x00, 0x00, 0x04, 0x00], &[]),
+            testing::CmdSubmit {
+                seq_num: 2,
+                endpoint: 0x02,
+                data: vec![1, 2, 3],
+                ..Default::default()
+            },
+            // the connection is still up
+            testing::CmdSubmit {
+                seq_num: 3,
+                endpoint: 0x81,
+                transfer_buffer_length: 4,
+                ..Default::default()
+            },
+        ];
+        let rets = testing::round_trip(Arc::new(server), "0", &urbs)
+            .await
+            .unwrap();
+        let statuses: Vec<_> = rets
+            .iter()
+            .map(|ret| (ret.status, ret.actual_length))
+            .collect();
+        assert_eq!(statuses, [(-errno::EPIPE, 0), (-errno::EPROTO, 0), (0, 4)]);
+        assert_eq!(rets[2].data, [0x55; 4]);
+    }
+
+    #[tokio::test]
+    async fn host_control_out_payload() {
+        let control_writes = ControlWrites::default();
+        let host_handle = Arc::new(Mutex::new(MockHostHandle {
+            control_writes: control_writes.clone(),
+            bulk_in: vec![],
+            bulk_writes: BulkWrites::default(),
+        }));
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0).with_interface(
+            ClassCode::VendorSpecific as u8,
+            0x00,
+            0x00,
+            "Passthrough",
+            vec![],
+            Arc::new(Mutex::new(
+                Box::new(UsbHostHandler::from_handle(host_handle))
+                    as Box<dyn UsbInterfaceHandler + Send>,
+            )),
+        )]);
+
+        let payload: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        // vendor request to interface 0
+        let setup = [0x41, 0x01, 0x34, 0x12, 0x00, 0x00, 0x2C, 0x01];
+        let rets = testing::round_trip(
+            Arc::new(server),
+            "0",
+            &[testing::CmdSubmit::control(1, setup, &payload)],
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(rets.len(), 1);
+        assert_eq!(rets[0].status, 0);
+        let control_writes = control_writes.lock().unwrap();
+        assert_eq!(control_writes.len(), 1);
+        let (setup, data) = &control_writes[0];
+        assert_eq!(
+            (setup.request_type, setup.request, setup.value, setup.index),
+            (0x41, 0x01, 0x1234, 0x0000)
+        );
+        assert_eq!(data, &payload);
+    }
+
+    #[tokio::test]
+    async fn short_control_in() {
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0)]);
+
+        // GetDescriptor to Device with a larger wLength, short replies are
+        // fine then not
+        let setup = [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0xFF, 0x00];
+        let urbs = [
+            testing::CmdSubmit::control(1, setup, &[]),
+            testing::CmdSubmit {
+                transfer_flags: USBIP_URB_SHORT_NOT_OK,
+                ..testing::CmdSubmit::control(2, setup, &[])
+            },
+        ];
+        let rets = testing::round_trip(Arc::new(server), "0", &urbs)
+            .await
+            .unwrap();
+
+        // actual length is the 18 bytes of the descriptor, not wLength
+        assert_eq!(rets.len(), 2);
+        for (ret, status) in rets.iter().zip([0, -errno::EREMOTEIO]) {
+            assert_eq!(ret.status, status);
+            assert_eq!(ret.actual_length, 18);
+            assert_eq!(ret.data[..2], [0x12, DescriptorType::Device as u8]);
+        }
+    }
+
+    #[tokio::test]
+    async fn control_in_overflow() {
+        let device = UsbDevice::new(0).with_interface(
+            ClassCode::VendorSpecific as u8,
+            0x00,
+            0x00,
+            "Test",
+            vec![],
+            // ignores wLength
+            FnHandler::new(|_, _, _, _| Ok(vec![0x55; 64])).into_handler(),
+        );
+        let server = UsbIpServer::new_simulated(vec![device]);
+
+        // vendor request to interface 0 with wLength 8, then with wLength
+        // 4096 in a buffer of 16 bytes
+        let urbs = [
+            testing::CmdSubmit::control(1, [0xC1, 0x01, 0x00, 0x00, 0x00, 0x00, 0x08, 0x00], &[]),
+            testing::CmdSubmit {
+                transfer_buffer_length: 16,
+                ..testing::CmdSubmit::control(
+                    2,
+                    [0xC1, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10],
+                    &[],
+                )
+            },
+        ];
+        let rets = testing::round_trip(Arc::new(server), "0", &urbs)
+            .await
+            .unwrap();
+
+        assert_eq!(rets.len(), 2);
+        for (ret, len) in rets.iter().zip([8usize, 16]) {
+            assert_eq!(ret.status, -errno::EOVERFLOW);
+            assert_eq!(ret.actual_length, len as u32);
+            assert_eq!(ret.data, vec![0x55; len]);
+        }
+    }
+
+    #[tokio::test]
+    async fn host_bulk_in_length() {
+        let bulk_in: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+        let host_handle = Arc::new(Mutex::new(MockHostHandle {
+            control_writes: ControlWrites::default(),
+            bulk_in: bulk_in.clone(),
+            bulk_writes: BulkWrites::default(),
+        }));
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0).with_interface(
+            ClassCode::VendorSpecific as u8,
+            0x00,
+            0x00,
+            "Passthrough",
+            vec![UsbEndpoint::new(
+                0x81,
+                EndpointAttributes::Bulk,
+                SyncType::NoSync,
+                UsageType::Data,
+                512,
+                0,
+            )],
+            Arc::new(Mutex::new(
+                Box::new(UsbHostHandler::from_handle(host_handle))
+                    as Box<dyn UsbInterfaceHandler + Send>,
+            )),
+        )]);
+
+        // 4096 bytes, short replies are fine then not
+        let urbs: Vec<_> = [(1, 0), (2, USBIP_URB_SHORT_NOT_OK)]
+            .iter()
+            .map(|&(seq_num, transfer_flags)| testing::CmdSubmit {
+                seq_num,
+                endpoint: 0x81,
+                transfer_flags,
+                transfer_buffer_length: 4096,
+                ..Default::default()
+            })
+            .collect();
+        let rets = testing::round_trip(Arc::new(server), "0", &urbs)
+            .await
+            .unwrap();
+
+        // the whole 3000 bytes are read in one transfer either way
+        assert_eq!(rets.len(), 2);
+        for (ret, status) in rets.iter().zip([0, -errno::EREMOTEIO]) {
+            assert_eq!(ret.status, status);
+            assert_eq!(ret.actual_length, 3000);
+            assert_eq!(ret.data, bulk_in);
+        }
+    }
+
+    #[tokio::test]
+    async fn host_bulk_out_zero_packet() {
+        let bulk_writes = BulkWrites::default();
+        let host_handle = Arc::new(Mutex::new(MockHostHandle {
+            control_writes: ControlWrites::default(),
+            bulk_in: vec![],
+            bulk_writes: bulk_writes.clone(),
+        }));
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0).with_interface(
+            ClassCode::VendorSpecific as u8,
+            0x00,
+            0x00,
+            "Passthrough",
+            vec![UsbEndpoint::new(
+                0x02,
+                EndpointAttributes::Bulk,
+                SyncType::NoSync,
+                UsageType::Data,
+                512,
+                0,
+            )],
+            Arc::new(Mutex::new(
+                Box::new(UsbHostHandler::from_handle(host_handle))
+                    as Box<dyn UsbInterfaceHandler + Send>,
+            )),
+        )]);
+
+        // only a full last packet needs termination
+        let urbs: Vec<_> = [
+            (1, 0, 512),
+            (2, TransferFlags::ZERO_PACKET.bits(), 512),
+            (3, TransferFlags::ZERO_PACKET.bits(), 100),
+        ]
+        .iter()
+        .map(|&(seq_num, transfer_flags, length)| testing::CmdSubmit {
+            seq_num,
+            endpoint: 0x02,
+            transfer_flags,
+            data: vec![0x55; length],
+            ..Default::default()
+        })
+        .collect();
+        let rets = testing::round_trip(Arc::new(server), "0", &urbs)
+            .await
+            .unwrap();
+
+        assert_eq!(rets.len(), 3);
+        let lengths: Vec<_> = bulk_writes.lock().unwrap().iter().map(Vec::len).collect();
+        assert_eq!(lengths, [512, 512, 0, 100]);
+    }
+
+    #[test]
+    fn host_interrupt_in_timeout_naks() {
+        let host_handle = Arc::new(Mutex::new(MockHostHandle {
+            control_writes: ControlWrites::default(),
+            bulk_in: vec![],
+            bulk_writes: BulkWrites::default(),
+        }));
+        let ep = UsbEndpoint {
+            address: 0x81,
+            attributes: EndpointAttributes::Interrupt as u8,
+            max_packet_size: 8,
+            interval: 10,
+        };
+        let intf = UsbInterface {
+            interface_class: ClassCode::HID as u8,
+            interface_subclass: 0,
+            interface_protocol: 0,
+            alternate_setting: 0,
+            endpoints: vec![ep],
+            string_interface: 0,
+            class_specific_descriptor: vec![],
+            class_specific_endpoint_descriptors: HashMap::new(),
+            handler: FnHandler::new(|_, _, _, _| Ok(vec![])).into_handler(),
+        };
+        let mut handler =
+            UsbHostHandler::from_handle(host_handle).with_timeout(Duration::from_millis(10));
+        let err = handler.handle_urb(&intf, ep, None, &[]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+    }
+
+    #[tokio::test]
+    async fn interrupt_nak_waits_interval() {
+        tokio::time::pause();
+        let mut naks = 3;
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0)
+            .with_speed(UsbSpeed::Full)
+            .with_interface(
+                ClassCode::VendorSpecific as u8,
+                0x00,
+                0x00,
+                "Polled",
+                vec![UsbEndpoint {
+                    address: 0x81,
+                    attributes: EndpointAttributes::Interrupt as u8,
+                    max_packet_size: 8,
+                    interval: 8,
+                }],
+                FnHandler::new(move |_, _, _, _| {
+                    if naks > 0 {
+                        naks -= 1;
+                        return Err(std::io::Error::new(ErrorKind::WouldBlock, "no report"));
+                    }
+                    Ok(vec![0x42])
+                })
+                .into_handler(),
+            )]);
+
+        let urb = testing::CmdSubmit {
+            seq_num: 1,
+            endpoint: 0x81,
+            transfer_buffer_length: 8,
+            interval: 8,
+            ..Default::default()
+        };
+        let start = tokio::time::Instant::now();
+        let rets = testing::round_trip(Arc::new(server), "0", &[urb])
+            .await
+            .unwrap();
+
+        // polled every 8 frames of 1ms, not every NAK_RETRY_INTERVAL
+        assert!(start.elapsed() >= Duration::from_millis(3 * 8));
+        assert_eq!(rets.len(), 1);
+        assert_eq!(rets[0].data, [0x42]);
+    }
+
+    #[tokio::test]
+    async fn long_in_reply_split_across_urbs() {
+        let calls = Arc::new(Mutex::new(0));
+        let handler_calls = calls.clone();
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0).with_interface(
+            ClassCode::VendorSpecific as u8,
+            0x00,
+            0x00,
+            "Bulk",
+            vec![UsbEndpoint {
+                address: 0x81,
+                attributes: EndpointAttributes::Bulk as u8,
+                max_packet_size: 512,
+                interval: 0,
+            }],
+            FnHandler::new(move |_, _, _, _| {
+                *handler_calls.lock().unwrap() += 1;
+                Ok((0..2048).map(|i| (i / 512) as u8).collect())
+            })
+            .into_handler(),
+        )]);
+        let urbs: Vec<_> = (1..=5)
+            .map(|seq_num| testing::CmdSubmit {
+                seq_num,
+                endpoint: 0x81,
+                transfer_buffer_length: 512,
+                ..testing::CmdSubmit::default()
+            })
+            .collect();
+        let rets = testing::round_trip(Arc::new(server), "0", &urbs)
+            .await
+            .unwrap();
+
+        for (i, ret) in rets[..4].iter().enumerate() {
+            assert_eq!(ret.status, 0);
+            assert_eq!(ret.actual_length, 512);
+            assert_eq!(ret.data, vec![i as u8; 512]);
+        }
+        // the remainder is used up, so the handler is asked again
+        assert_eq!(rets[4].data, vec![0; 512]);
+        assert_eq!(*calls.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn transfer_flags() {
+        let flags = TransferFlags(0x0241);
+        assert!(flags.contains(TransferFlags::SHORT_NOT_OK | TransferFlags::ZERO_PACKET));
+        assert!(!flags.contains(TransferFlags::NO_INTERRUPT));
+        assert_eq!(flags.direction(), Direction::In);
+        assert_eq!(TransferFlags::default().direction(), Direction::Out);
+    }
+
+    #[tokio::test]
+    async fn ep0_served_while_urb_pending() {
+        let queue = InEndpointQueue::new();
+        let intf_handler = Arc::new(Mutex::new(Box::new(QueueHandler {
+            queue: queue.clone(),
+            polls: 0,
+        })
+            as Box<dyn UsbInterfaceHandler + Send>));
+        let ep = UsbEndpoint {
+            address: 0x81,
+            attributes: EndpointAttributes::Interrupt as u8,
+            max_packet_size: 8,
+            interval: 10,
+        };
+        let server = Arc::new(UsbIpServer::new_simulated(vec![UsbDevice::new(0)
+            .with_interface(
+                ClassCode::VendorSpecific as u8,
+                0x00,
+                0x00,
+                "Queue",
+                vec![ep],
+                intf_handler,
+            )
+            .with_in_queue(0x81, &queue)]));
+        let (mut client, stream) = duplex_pair();
+        tokio::spawn(serve_stream(stream, server));
+
+        let mut req = testing::op_req_import("0");
+        // interrupt IN URB, NAKed as long as the queue stays empty
+        req.extend(
+            testing::CmdSubmit {
+                seq_num: 1,
+                endpoint: 0x81,
+                transfer_buffer_length: 8,
+                interval: 10,
+                ..Default::default()
+            }
+            .to_bytes(),
+        );
+        req.extend(
+            testing::CmdSubmit::control(2, [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00], &[])
+                .to_bytes(),
+        );
+        client.write_all(&req).await.unwrap();
+
+        // the control transfer completes, the IN URB stays pending
+        let mut reply = vec![0u8; 0x140 + 0x30 + 0x12];
+        tokio::time::timeout(Duration::from_secs(1), client.read_exact(&mut reply))
+            .await
+            .unwrap()
+            .unwrap();
+        let (ret, _) = testing::RetSubmit::parse(&reply[0x140..]).unwrap();
+        assert_eq!(ret.seq_num, 2);
+        assert_eq!(ret.actual_length, 0x12);
+
+        queue.push(vec![0x42]);
+        let mut reply = vec![0u8; 0x30 + 1];
+        client.read_exact(&mut reply).await.unwrap();
+        let (ret, _) = testing::RetSubmit::parse(&reply).unwrap();
+        assert_eq!(ret.seq_num, 1);
+        assert_eq!(ret.data, [0x42]);
+    }
+
+    #[tokio::test]
+    async fn unplug_imported_device() {
+        tokio::time::pause();
+        let queue = InEndpointQueue::new();
+        let intf_handler = Arc::new(Mutex::new(Box::new(QueueHandler {
+            queue: queue.clone(),
+            polls: 0,
+        })
+            as Box<dyn UsbInterfaceHandler + Send>));
+        let ep = UsbEndpoint {
+            address: 0x81,
+            attributes: EndpointAttributes::Interrupt as u8,
+            max_packet_size: 8,
+            interval: 10,
+        };
+        let server = Arc::new(UsbIpServer::new_simulated(vec![UsbDevice::new(0)
+            .with_interface(
+                ClassCode::VendorSpecific as u8,
+                0x00,
+                0x00,
+                "Queue",
+                vec![ep],
+                intf_handler.clone(),
+            )
+            .with_in_queue(0x81, &queue)]));
+
+        let interrupt_in = |seq_num| testing::CmdSubmit {
+            seq_num,
+            endpoint: 0x81,
+            transfer_buffer_length: 8,
+            interval: 10,
+            ..Default::default()
+        };
+        let urbs = [
+            // pending when the device is unplugged
+            interrupt_in(1),
+            // vendor request with an OUT data stage, after the removal
+            testing::CmdSubmit::control(
+                2,
+                [0x41, 0x01, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00],
+                &[0xAA, 0xBB],
+            ),
+            interrupt_in(3),
+        ];
+        let polls = |intf_handler: &Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>>| {
+            let mut intf_handler = intf_handler.lock().unwrap();
+            let queue_handler = intf_handler
+                .as_any()
+                .downcast_mut::<QueueHandler>()
+                .unwrap();
+            queue_handler.polls
+        };
+        let unplug_server = server.clone();
+        let unplug_handler = intf_handler.clone();
+        let unplug = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            let polls = polls(&unplug_handler);
+            unplug_server.unplug_device("0").await.unwrap();
+            polls
+        });
+        let rets = testing::round_trip(server.clone(), "0", &urbs)
+            .await
+            .unwrap();
+
+        assert_eq!(rets.len(), 3);
+        for (i, ret) in rets.iter().enumerate() {
+            assert_eq!(ret.seq_num, i as u32 + 1);
+            assert_eq!(ret.status, -errno::ENODEV);
+            assert_eq!(ret.actual_length, 0);
+        }
+        assert!(server.devices.read().await.is_empty());
+        // the pending URBs were not retried after the removal
+        assert_eq!(polls(&intf_handler), unplug.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn unlink_pending_urb() {
+        tokio::time::pause();
+        let queue = InEndpointQueue::new();
+        let intf_handler = Arc::new(Mutex::new(Box::new(QueueHandler {
+            queue: queue.clone(),
+            polls: 0,
+        })
+            as Box<dyn UsbInterfaceHandler + Send>));
+        let ep = UsbEndpoint {
+            address: 0x81,
+            attributes: EndpointAttributes::Interrupt as u8,
+            max_packet_size: 8,
+            interval: 10,
+        };
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0)
+            .with_interface(
+                ClassCode::VendorSpecific as u8,
+                0x00,
+                0x00,
+                "Queue",
+                vec![ep],
+                intf_handler.clone(),
+            )
+            .with_in_queue(0x81, &queue)]);
+
+        let mut req = testing::op_req_import("0");
+        // USBIP_CMD_SUBMIT, NAKed until unlinked
+        req.extend(
+            testing::CmdSubmit {
+                seq_num: 1,
+                endpoint: 0x81,
+                transfer_buffer_length: 8,
+                interval: 10,
+                ..Default::default()
+            }
+            .to_bytes(),
+        );
+        // USBIP_CMD_UNLINK of the pending URB
+        req.extend(vec![
+            0x00, 0x00, 0x00, 0x02, // command
+            0x00, 0x00, 0x00, 0x02, // seq num
+            0x00, 0x00, 0x00, 0x00, // dev id
+            0x00, 0x00, 0x00, 0x01, // IN
+            0x00, 0x00, 0x00, 0x01, // ep 1
+            0x00, 0x00, 0x00, 0x01, // seq num to unlink
+        ]);
+        req.extend(vec![0x00; 24]);
+        // GetDescriptor to Device
+        req.extend(
+            testing::CmdSubmit::control(3, [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00], &[])
+                .to_bytes(),
+        );
+        let mut mock_socket = MockSocket::new(req);
+        handler(&mut mock_socket, Arc::new(server)).await.ok();
+
+        // USBIP_RET_UNLINK instead of USBIP_RET_SUBMIT for the pending URB
+        let ret_unlink = &mock_socket.output[0x140..0x140 + 0x30];
+        assert_eq!(ret_unlink[0x00..0x08], [0, 0, 0, 4, 0, 0, 0, 2]);
+        assert_eq!(ret_unlink[0x14..0x18], (-errno::ECONNRESET).to_be_bytes());
+        // the stream is still in sync
+        let ret_submit = &mock_socket.output[0x140 + 0x30..];
+        assert_eq!(ret_submit.len(), 0x30 + 0x12);
+        assert_eq!(ret_submit[0x00..0x08], [0, 0, 0, 3, 0, 0, 0, 3]);
+        assert_eq!(ret_submit[0x14..0x18], [0x00; 4]);
+    }
+
+    #[tokio::test]
+    async fn command_during_pending_urb() {
+        tokio::time::pause();
+        let queue = InEndpointQueue::new();
+        let intf_handler = Arc::new(Mutex::new(Box::new(QueueHandler {
+            queue: queue.clone(),
+            polls: 0,
+        })
+            as Box<dyn UsbInterfaceHandler + Send>));
+        let ep = UsbEndpoint {
+            address: 0x81,
+            attributes: EndpointAttributes::Interrupt as u8,
+            max_packet_size: 8,
+            interval: 10,
+        };
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0)
+            .with_interface(
+                ClassCode::VendorSpecific as u8,
+                0x00,
+                0x00,
+                "Queue",
+                vec![ep],
+                intf_handler.clone(),
+            )
+            .with_in_queue(0x81, &queue)]);
+
+        let urbs = [
+            // NAKed until data is pushed
+            testing::CmdSubmit {
+                seq_num: 1,
+                endpoint: 0x81,
+                transfer_buffer_length: 8,
+                interval: 10,
+                ..Default::default()
+            },
+            // GetDescriptor to Device, sent while the first one is pending
+            testing::CmdSubmit::control(2, [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00], &[]),
+        ];
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            queue.push(vec![0x42]);
+        });
+        let rets = testing::round_trip(Arc::new(server), "0", &urbs)
+            .await
+            .unwrap();
+
+        // the control transfer does not wait for the pending IN URB, which
+        // completes once data is pushed
+        assert_eq!(rets.len(), 2);
+        assert_eq!(rets[0].seq_num, 2);
+        assert_eq!(rets[0].actual_length, 0x12);
+        assert_eq!(rets[1].seq_num, 1);
+        assert_eq!(rets[1].data, [0x42]);
+    }
+
+    #[tokio::test]
+    async fn iso_in_urb() {
+        let queue = InEndpointQueue::new();
+        queue.push(vec![1, 2]);
+        queue.push(vec![3, 4, 5, 6, 7]);
+        let intf_handler = Arc::new(Mutex::new(Box::new(QueueHandler {
+            queue: queue.clone(),
+            polls: 0,
+        })
+            as Box<dyn UsbInterfaceHandler + Send>));
+        let ep = UsbEndpoint {
+            address: 0x81,
+            attributes: EndpointAttributes::Isochronous as u8,
+            max_packet_size: 4,
+            interval: 1,
+        };
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0).with_interface(
+            ClassCode::VendorSpecific as u8,
+            0x00,
+            0x00,
+            "Iso",
+            vec![ep],
+            intf_handler.clone(),
+        )]);
+
+        let mut req = testing::op_req_import("0");
+        // USBIP_CMD_SUBMIT of three packets
+        let mut cmd = testing::CmdSubmit {
+            seq_num: 1,
+            endpoint: 0x81,
+            transfer_buffer_length: 12,
+            interval: 1,
+            ..Default::default()
+        }
+        .to_bytes();
+        // start frame, number of packets
+        cmd[0x1C..0x24].copy_from_slice(&[0, 0, 0, 0x10, 0, 0, 0, 3]);
+        req.extend(cmd);
+        for offset in [0u32, 4, 8] {
+            req.extend(offset.to_be_bytes());
+            req.extend(4u32.to_be_bytes());
+            req.extend([0x00; 8]);
+        }
+        let mut mock_socket = MockSocket::new(req);
+        handler(&mut mock_socket, Arc::new(server)).await.ok();
+
+        let ret_submit = &mock_socket.output[0x140..];
+        assert_eq!(ret_submit.len(), 0x30 + 6 + 3 * ISO_PACKET_DESCRIPTOR_SIZE);
+        // status, actual length, start frame, number of packets, error count
+        assert_eq!(ret_submit[0x14..0x18], [0x00; 4]);
+        assert_eq!(ret_submit[0x18..0x1C], [0, 0, 0, 6]);
+        assert_eq!(ret_submit[0x1C..0x20], [0, 0, 0, 0x10]);
+        assert_eq!(ret_submit[0x20..0x24], [0, 0, 0, 3]);
+        assert_eq!(ret_submit[0x24..0x28], [0, 0, 0, 1]);
+        // data of short and cut packets back to back
+        assert_eq!(ret_submit[0x30..0x36], [1, 2, 3, 4, 5, 6]);
+        let mut descriptors = &ret_submit[0x36..];
+        let packets = read_iso_packet_descriptors(&mut descriptors, 3)
+            .await
+            .unwrap();
+        let actual: Vec<_> = packets
+            .iter()
+            .map(|p| (p.actual_length, p.status))
+            .collect();
+        assert_eq!(actual, [(2, 0), (4, -errno::EOVERFLOW), (0, 0)]);
+    }
+
+    #[tokio::test]
+    async fn iso_packet_bounds() {
+        let intf_handler = Arc::new(Mutex::new(Box::new(QueueHandler {
+            queue: InEndpointQueue::new(),
+            polls: 0,
+        })
+            as Box<dyn UsbInterfaceHandler + Send>));
+        let ep = UsbEndpoint {
+            address: 0x81,
+            attributes: EndpointAttributes::Isochronous as u8,
+            max_packet_size: 4,
+            interval: 1,
+        };
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0).with_interface(
+            ClassCode::VendorSpecific as u8,
+            0x00,
+            0x00,
+            "Iso",
+            vec![ep],
+            intf_handler,
+        )]);
+        let submit = |seq_num, transfer_buffer_length, number_of_packets: u32| {
+            let mut cmd = testing::CmdSubmit {
+                seq_num,
+                endpoint: 0x81,
+                transfer_buffer_length,
+                interval: 1,
+                ..Default::default()
+            }
+            .to_bytes();
+            cmd[0x20..0x24].copy_from_slice(&number_of_packets.to_be_bytes());
+            cmd
+        };
+
+        let mut req = testing::op_req_import("0");
+        // more packets than bytes, with their descriptors
+        req.extend(submit(1, 3, 4));
+        req.extend(vec![0x00; 4 * ISO_PACKET_DESCRIPTOR_SIZE]);
+        // absurdly many packets, whose descriptors never come
+        req.extend(submit(2, 0x10000, 0x7FFF_FFFF));
+        let mut mock_socket = MockSocket::new(req);
+        handler(&mut mock_socket, Arc::new(server)).await.ok();
+
+        // both fail without data, the first one keeping the stream aligned
+        let ret_submits = &mock_socket.output[0x140..];
+        assert_eq!(ret_submits.len(), 2 * 0x30);
+        for (ret_submit, seq_num) in ret_submits.chunks(0x30).zip(1u32..) {
+            assert_eq!(ret_submit[0x04..0x08], seq_num.to_be_bytes());
+            assert_eq!(ret_submit[0x14..0x18], (-errno::EINVAL).to_be_bytes());
+            assert_eq!(ret_submit[0x18..0x1C], [0x00; 4]);
+        }
+    }
+
+    #[tokio::test]
+    async fn server_shutdown() {
+        let queue = InEndpointQueue::new();
+        let intf_handler = Arc::new(Mutex::new(Box::new(QueueHandler {
+            queue: queue.clone(),
+            polls: 0,
+        })
+            as Box<dyn UsbInterfaceHandler + Send>));
+        let ep = UsbEndpoint {
+            address: 0x81,
+            attributes: EndpointAttributes::Interrupt as u8,
+            max_packet_size: 8,
+            interval: 10,
+        };
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0)
+            .with_interface(
+                ClassCode::VendorSpecific as u8,
+                0x00,
+                0x00,
+                "Queue",
+                vec![ep],
+                intf_handler.clone(),
+            )
+            .with_in_queue(0x81, &queue)]);
+        let handle = spawn_server("127.0.0.1:0".parse().unwrap(), Arc::new(server))
+            .await
+            .unwrap();
+        let addr = handle.local_addr();
+
+        let mut req = testing::op_req_import("0");
+        // USBIP_CMD_SUBMIT, NAKed until the server shuts down
+        req.extend(
+            testing::CmdSubmit {
+                seq_num: 1,
+                endpoint: 0x81,
+                transfer_buffer_length: 8,
+                interval: 10,
+                ..Default::default()
+            }
+            .to_bytes(),
+        );
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        client.write_all(&req).await.unwrap();
+        let mut rep_import = [0u8; 0x140];
+        client.read_exact(&mut rep_import).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        handle.shutdown().await;
+
+        // the pending URB completes, then the connection closes
+        let mut output = vec![];
+        client.read_to_end(&mut output).await.unwrap();
+        let rets = testing::RetSubmit::parse_all(&output).unwrap();
+        assert_eq!(rets.len(), 1);
+        assert_eq!(rets[0].seq_num, 1);
+        assert_eq!(rets[0].status, -errno::ESHUTDOWN);
+        // the port is free again
+        TcpListener::bind(addr).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn exclusive_import() {
+        let server = UsbIpServer::new_simulated(vec![
+            UsbDevice::new(0),
+            UsbDevice::new(1).with_import_clones(),
+        ]);
+        let handle = spawn_server("127.0.0.1:0".parse().unwrap(), Arc::new(server))
+            .await
+            .unwrap();
+        let addr = handle.local_addr();
+
+        let first = UsbIpClient::connect(addr)
+            .await
+            .unwrap()
+            .import("0")
+            .await
+            .unwrap();
+        // OP_REP_IMPORT with ST_DEV_BUSY for a second client
+        let client = UsbIpClient::connect(addr).await.unwrap();
+        let err = client.import("0").await.err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::ResourceBusy);
+        // cloned devices serve everyone
+        for _ in 0..2 {
+            let client = UsbIpClient::connect(addr).await.unwrap();
+            client.import("1").await.unwrap();
+        }
+
+        // released once the first client disconnects
+        drop(first);
+        let mut attempts = 0;
+        loop {
+            let client = UsbIpClient::connect(addr).await.unwrap();
+            match client.import("0").await {
+                Ok(_) => break,
+                Err(_) if attempts < 100 => attempts += 1,
+                Err(err) => panic!("device not released: {}", err),
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        handle.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn access_control() {
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0), UsbDevice::new(1)])
+            .with_import_authorization(|ctx, device| {
+                ctx.peer
+                    .as_deref()
+                    .is_some_and(|peer| peer.starts_with("127.0.0.1:"))
+                    && device.bus_id == "0"
+            });
+        let handle = spawn_server("127.0.0.1:0".parse().unwrap(), Arc::new(server))
+            .await
+            .unwrap();
+        let addr = handle.local_addr();
+        let client = UsbIpClient::connect(addr).await.unwrap();
+        client.import("0").await.unwrap();
+        // OP_REP_IMPORT with ST_NA
+        let client = UsbIpClient::connect(addr).await.unwrap();
+        let err = client.import("1").await.err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        handle.shutdown().await;
+
+        // refused before OP_REQ_DEVLIST is answered
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0)])
+            .with_connection_filter(|addr| !addr.ip().is_loopback());
+        let handle = spawn_server("127.0.0.1:0".parse().unwrap(), Arc::new(server))
+            .await
+            .unwrap();
+        let mut client = tokio::net::TcpStream::connect(handle.local_addr())
+            .await
+            .unwrap();
+        client
+            .write_all(&[0x01, 0x11, 0x80, 0x05, 0x00, 0x00, 0x00, 0x00])
+            .await
+            .ok();
+        let mut reply = vec![];
+        client.read_to_end(&mut reply).await.ok();
+        assert!(reply.is_empty());
+        handle.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn imported_devices() {
+        let server = Arc::new(UsbIpServer::new_simulated(vec![
+            UsbDevice::new(0),
+            UsbDevice::new(1),
+        ]));
+        let handle = spawn_server("127.0.0.1:0".parse().unwrap(), server.clone())
+            .await
+            .unwrap();
+        let addr = handle.local_addr();
+        assert!(server.imported_devices().is_empty());
+        let mut client = UsbIpClient::connect(addr).await.unwrap();
+        client.list_devices().await.unwrap();
+        let imported = client.import("1").await.unwrap();
+        let imports = server.imported_devices();
+        assert_eq!(imports.len(), 1);
+        assert_eq!(imports[0].0, "1");
+        assert!(imports[0]
+            .1
+            .peer
+            .as_deref()
+            .is_some_and(|peer| peer.starts_with("127.0.0.1:")));
+
+        // released once the client disconnects
+        drop(imported);
+        for _ in 0..100 {
+            if server.imported_devices().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(server.imported_devices().is_empty());
+        handle.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn connection_limit() {
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0)])
+            .with_max_connections(1, ConnectionLimitPolicy::Reject);
+        let handle = spawn_server("127.0.0.1:0".parse().unwrap(), Arc::new(server))
+            .await
+            .unwrap();
+        let addr = handle.local_addr();
+        let mut first = UsbIpClient::connect(addr).await.unwrap();
+        assert_eq!(first.list_devices().await.unwrap().len(), 1);
+        // closed without a reply
+        let mut second = UsbIpClient::connect(addr).await.unwrap();
+        assert!(second.list_devices().await.is_err());
+        drop(first);
+        // the place is free once the first connection task ended
+        let mut listed = false;
+        for _ in 0..100 {
+            let mut client = UsbIpClient::connect(addr).await.unwrap();
+            if client.list_devices().await.is_ok() {
+                listed = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(listed);
+        handle.shutdown().await;
+
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0)])
+            .with_max_connections(1, ConnectionLimitPolicy::Wait);
+        let handle = spawn_server("127.0.0.1:0".parse().unwrap(), Arc::new(server))
+            .await
+            .unwrap();
+        let addr = handle.local_addr();
+        let mut first = UsbIpClient::connect(addr).await.unwrap();
+        first.list_devices().await.unwrap();
+        // answered only once the first connection closes
+        let mut second = UsbIpClient::connect(addr).await.unwrap();
+        let list = second.list_devices();
+        futures::pin_mut!(list);
+        let waited = tokio::time::timeout(Duration::from_millis(100), &mut list).await;
+        assert!(waited.is_err());
+        drop(first);
+        assert_eq!(list.await.unwrap().len(), 1);
+        handle.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn fragmented_stream() {
+        let server = Arc::new(UsbIpServer::new_simulated(vec![UsbDevice::new(0)]));
+        let mut request = vec![0x01, 0x11, 0x80, 0x05, 0x00, 0x00, 0x00, 0x00];
+        request.extend(testing::op_req_import("0"));
+        let get_descriptor = [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00];
+        request.extend(testing::CmdSubmit::control(1, get_descriptor, &[]).to_bytes());
+        // every read returns a single byte
+        let (client, stream) = tokio::io::duplex(1);
+        let serve = tokio::spawn(serve_stream(stream, server));
+        let (mut reader, mut writer) = tokio::io::split(client);
+        let write = async move {
+            writer.write_all(&request).await?;
+            writer.shutdown().await
+        };
+        let read = async move {
+            let mut reply = vec![];
+            reader.read_to_end(&mut reply).await?;
+            Ok(reply)
+        };
+        let (written, reply): (Result<()>, Result<Vec<u8>>) = futures::join!(write, read);
+        written.unwrap();
+        let reply = reply.unwrap();
+        serve.await.unwrap().unwrap();
+
+        // OP_REP_DEVLIST of one device without interfaces, OP_REP_IMPORT
+        let devlist_len = 0x0C + 0x138;
+        assert_eq!(reply[..8], [0x01, 0x11, 0x00, 0x05, 0, 0, 0, 0]);
+        let import = &reply[devlist_len..];
+        assert_eq!(import[..8], [0x01, 0x11, 0x00, 0x03, 0, 0, 0, 0]);
+        let rets = testing::RetSubmit::parse_all(&import[0x140..]).unwrap();
+        assert_eq!(rets.len(), 1);
+        assert_eq!(rets[0].status, 0);
+        assert_eq!(rets[0].data[..2], [0x12, DescriptorType::Device as u8]);
+    }
+
+    #[tokio::test]
+    async fn client_disconnect() {
+        let server = Arc::new(UsbIpServer::new_simulated(vec![UsbDevice::new(0)]));
+        // OP_REQ_DEVLIST, then EOF between commands is a clean disconnect
+        let mut mock_socket = MockSocket::new(vec![0x01, 0x11, 0x80, 0x05, 0x00, 0x00, 0x00, 0x00]);
+        handler(&mut mock_socket, server.clone()).await.unwrap();
+        assert_eq!(mock_socket.output[..4], [0x01, 0x11, 0x00, 0x05]);
+
+        // EOF inside a command is not
+        let mut mock_socket = MockSocket::new(vec![0x01, 0x11]);
+        let err = handler(&mut mock_socket, server).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    /// Never completes a URB, like a host device that does not answer
+    struct HungHandler;
+
+    impl UsbInterfaceHandler for HungHandler {
+        fn get_class_specific_descriptor(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn handle_urb(
+            &mut self,
+            _interface: &UsbInterface,
+            _ep: UsbEndpoint,
+            _setup: Option<SetupPacket>,
+            _req: &[u8],
+        ) -> Result<Vec<u8>> {
+            unreachable!("URBs are handled asynchronously")
+        }
+
+        fn handle_urb_async(
+            &mut self,
+            _ctx: &ConnectionContext,
+            _interface: &UsbInterface,
+            _ep: UsbEndpoint,
+            _setup: Option<SetupPacket>,
+            _req: &[u8],
+        ) -> UrbFuture {
+            Box::pin(futures::future::pending())
+        }
+
+        fn as_any(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_abandons_hung_transfer() {
+        tokio::time::pause();
+        let ep = UsbEndpoint::new(
+            0x81,
+            EndpointAttributes::Bulk,
+            SyncType::NoSync,
+            UsageType::Data,
+            512,
+            0,
+        );
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0).with_interface(
+            ClassCode::VendorSpecific as u8,
+            0x00,
+            0x00,
+            "Hung",
+            vec![ep],
+            Arc::new(Mutex::new(
+                Box::new(HungHandler) as Box<dyn UsbInterfaceHandler + Send>
+            )),
+        )]);
+
+        let mut req = testing::op_req_import("0");
+        req.extend(
+            testing::CmdSubmit {
+                seq_num: 1,
+                endpoint: 0x81,
+                transfer_buffer_length: 512,
+                ..Default::default()
+            }
+            .to_bytes(),
+        );
+        let signal = Arc::new(Shutdown::default());
+        tokio::spawn({
+            let signal = signal.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                signal.request();
+            }
+        });
+        let mut mock_socket = MockSocket::new(req);
+        handler_with_context(
+            &mut mock_socket,
+            Arc::new(server),
+            ConnectionContext::default(),
+            &signal,
+        )
+        .await
+        .unwrap();
+
+        let rets = testing::RetSubmit::parse_all(&mock_socket.output[0x140..]).unwrap();
+        assert_eq!(rets.len(), 1);
+        assert_eq!(rets[0].status, -errno::ESHUTDOWN);
+    }
+
+    #[tokio::test]
+    async fn handshake_timeout() {
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0)])
+            .with_handshake_timeout(Duration::from_millis(100));
+        let handle = spawn_server("127.0.0.1:0".parse().unwrap(), Arc::new(server))
+            .await
+            .unwrap();
+        let addr = handle.local_addr();
+
+        // OP_REQ_IMPORT stalled after its command
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        client.write_all(&[0x01, 0x11, 0x80, 0x03]).await.unwrap();
+        let start = tokio::time::Instant::now();
+        let mut rest = vec![];
+        tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut rest))
+            .await
+            .unwrap()
+            .ok();
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert!(rest.is_empty());
+
+        // an imported connection may idle past the timeout
+        let mut device = UsbIpClient::connect(addr)
+            .await
+            .unwrap()
+            .import("0")
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let urb = device
+            .control([0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00], &[])
+            .await
+            .unwrap();
+        assert_eq!(urb.data.len(), 0x12);
+        handle.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn server_stop_join() {
+        let server = UsbIpServer::new_simulated(vec![]);
+        let mut handle = spawn_server("127.0.0.1:0".parse().unwrap(), Arc::new(server))
+            .await
+            .unwrap();
+        let addr = handle.local_addr();
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        handle.stop();
+        handle.join().await;
+
+        // the idle connection is closed and the port is free again
+        let mut rest = vec![];
+        client.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+        TcpListener::bind(addr).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn serve_duplex_stream() {
+        let server = Arc::new(UsbIpServer::new_simulated(vec![UsbDevice::new(0)]));
+        let (client, stream) = duplex_pair();
+        let serve = tokio::spawn(serve_stream(stream, server));
+
+        let mut client = UsbIpClient::new(client);
+        let devices = client.list_devices().await.unwrap();
+        assert_eq!(devices.len(), 1);
+        let mut device = client.import("0").await.unwrap();
+        let urb = device
+            .control([0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00], &[])
+            .await
+            .unwrap();
+        assert_eq!(urb.data.len(), 0x12);
+        drop(device);
+        serve.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn devlist_snapshot() {
+        let server = Arc::new(UsbIpServer::new_simulated(vec![
+            UsbDevice::new(0).with_bus_location(1, 1),
+            UsbDevice::new(0).with_bus_location(1, 2),
+        ]));
+        // the reply does not fit, the server blocks until the client reads
+        let (mut client, mut socket) = tokio::io::duplex(64);
+        client
+            .write_all(&[0x01, 0x11, 0x80, 0x05, 0x00, 0x00, 0x00, 0x00])
+            .await
+            .unwrap();
+        let serve = {
+            let server = server.clone();
+            tokio::spawn(async move { handler(&mut socket, server).await })
+        };
+        let mut header = [0u8; 12];
+        client.read_exact(&mut header).await.unwrap();
+        assert_eq!(header[8..], 2u32.to_be_bytes());
+
+        // the list changes mid reply without waiting for the client
+        tokio::time::timeout(Duration::from_secs(1), async {
+            server
+                .add_device(UsbDevice::new(0).with_bus_location(1, 3))
+                .await
+                .unwrap();
+            server.remove_device("1-1").await.unwrap();
+        })
+        .await
+        .unwrap();
+
+        // both entries of the snapshot follow, without interfaces
+        let mut entries = vec![0u8; 2 * 0x138];
+        client.read_exact(&mut entries).await.unwrap();
+        assert_eq!(entries[0x100..0x103], *b"1-1");
+        assert_eq!(entries[0x138 + 0x100..0x138 + 0x103], *b"1-2");
+        drop(client);
+        serve.await.unwrap().ok();
+    }
+
+    #[tokio::test]
+    async fn concurrent_accepts() {
+        let server = UsbIpServer::new_simulated(vec![]).with_accept_concurrency(4);
+        let handle = spawn_server("127.0.0.1:0".parse().unwrap(), Arc::new(server))
+            .await
+            .unwrap();
+        let addr = handle.local_addr();
+
+        // OP_REQ_DEVLIST from many clients at once
+        let clients = (0..16).map(|_| async move {
+            let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+            client
+                .write_all(&[0x01, 0x11, 0x80, 0x05, 0x00, 0x00, 0x00, 0x00])
+                .await
+                .unwrap();
+            // OP_REP_DEVLIST without devices
+            let mut rep = [0u8; 12];
+            client.read_exact(&mut rep).await.unwrap();
+            rep
+        });
+        for rep in futures::future::join_all(clients).await {
+            assert_eq!(rep[..4], [0x01, 0x11, 0x00, 0x05]);
+        }
+        handle.shutdown().await;
+    }
+
+    /// Linux accepts backlog + 1 connections nobody accepted, then drops SYNs
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn listen_backlog() {
+        let listener = bind_tcp("127.0.0.1:0".parse().unwrap(), 1).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut clients = vec![];
+        for _ in 0..2 {
+            clients.push(tokio::net::TcpStream::connect(addr).await.unwrap());
+        }
+        let connect = tokio::net::TcpStream::connect(addr);
+        assert!(tokio::time::timeout(Duration::from_millis(200), connect)
+            .await
+            .is_err());
     }
 }
@@ -0,0 +1,668 @@
+//! Implement USB MIDI device, with MIDI 1.0 event packets or MIDI 2.0 Universal MIDI Packets
+//!
+//! The device has an Audio Control interface and a MIDI Streaming interface
+//! with one bulk endpoint in each direction. Both protocols move 32-bit words,
+//! little endian on the bus: a MIDI 1.0 event packet is a single word, a UMP
+//! is one to four words depending on its message type.
+//!
+//! The protocol is chosen at construction and exposed at alternate setting 0,
+//! hosts looking for MIDI 2.0 only at alternate setting 1 will not find it.
+use super::*;
+
+// reference:
+// MIDI 1.0: https://www.usb.org/sites/default/files/midi10.pdf
+// MIDI 2.0: https://www.usb.org/sites/default/files/USB%20MIDI%20v2_0.pdf
+
+/// Sub class code for Audio Control, with [ClassCode::Audio]
+pub const AUDIO_CONTROL_SUBCLASS: u8 = 0x01;
+/// Sub class code for MIDI Streaming, with [ClassCode::Audio]
+pub const MIDI_STREAMING_SUBCLASS: u8 = 0x03;
+
+/// Descriptor type of class specific interface descriptors
+const CS_INTERFACE: u8 = 0x24;
+/// Descriptor type of class specific endpoint descriptors
+const CS_ENDPOINT: u8 = 0x25;
+/// Descriptor type of group terminal block descriptors
+const CS_GR_TRM_BLOCK: u8 = 0x26;
+
+/// MIDI protocol spoken on the MIDI Streaming interface
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MidiProtocol {
+    /// USB MIDI 1.0 event packets
+    Midi1,
+    /// USB MIDI 2.0 Universal MIDI Packets
+    Midi2,
+}
+
+/// Receives the MIDI bytes of a USB MIDI 1.0 event packet with its cable number
+pub type MidiSink = Box<dyn FnMut(u8, &[u8]) + Send>;
+/// Gives the next packet to the host, if any
+pub type MidiSource = Box<dyn FnMut() -> Option<Vec<u32>> + Send>;
+
+/// Number of MIDI bytes in a USB MIDI 1.0 event packet, from its Code Index Number
+fn event_midi_len(cin: u8) -> usize {
+    match cin {
+        // reserved for future extensions
+        0x0 | 0x1 => 0,
+        // single byte system common, SysEx ending with one byte, single byte
+        0x5 | 0xF => 1,
+        // two byte system common, SysEx ending with two bytes, program and
+        // channel pressure
+        0x2 | 0x6 | 0xC | 0xD => 2,
+        _ => 3,
+    }
+}
+
+/// Split a USB MIDI 1.0 event packet into its cable number and MIDI bytes
+///
+/// Returns `None` for the reserved Code Index Numbers 0 and 1.
+pub fn parse_event_packet(packet: u32) -> Option<(u8, Vec<u8>)> {
+    let bytes = packet.to_le_bytes();
+    let len = event_midi_len(bytes[0] & 0x0F);
+    if len == 0 {
+        return None;
+    }
+    Some((bytes[0] >> 4, bytes[1..1 + len].to_vec()))
+}
+
+/// Build the USB MIDI 1.0 event packet of a MIDI message on `cable`
+///
+/// Takes a channel voice or system message, from its status byte, of one to
+/// three bytes. SysEx needs more than one packet and is not built here.
+pub fn event_packet(cable: u8, midi: &[u8]) -> Option<u32> {
+    let status = *midi.first()?;
+    let cin = match status {
+        0x80..=0xEF => status >> 4,
+        0xF1 | 0xF3 => 0x2,
+        0xF2 => 0x3,
+        0xF6 | 0xF8..=0xFF => 0x5,
+        _ => return None,
+    };
+    if midi.len() != event_midi_len(cin) {
+        return None;
+    }
+    let mut bytes = [(cable & 0x0F) << 4 | cin, 0, 0, 0];
+    bytes[1..1 + midi.len()].copy_from_slice(midi);
+    Some(u32::from_le_bytes(bytes))
+}
+
+/// Number of 32-bit words of a UMP, from the message type in its first word
+pub fn ump_word_count(first_word: u32) -> usize {
+    match first_word >> 28 {
+        0x0 | 0x1 | 0x2 | 0x6 | 0x7 => 1,
+        0x3 | 0x4 | 0x8 | 0x9 | 0xA => 2,
+        0xB | 0xC => 3,
+        _ => 4,
+    }
+}
+
+/// A handler of a MIDI Streaming interface
+///
+/// Packets received from the host are appended to `rx_packets`, packets in
+/// `tx_packets` are sent to the host. Each packet is a list of 32-bit words.
+///
+/// With a sink, MIDI 1.0 event packets from the host go to it as cable
+/// number and MIDI bytes instead. With a source, packets to the host are
+/// taken from it once `tx_packets` is empty. Bulk IN transfers are NAKed
+/// while there is no packet.
+pub struct UsbMidiHandler {
+    pub protocol: MidiProtocol,
+    pub rx_packets: VecDeque<Vec<u32>>,
+    pub tx_packets: VecDeque<Vec<u32>>,
+    sink: Option<MidiSink>,
+    source: Option<MidiSource>,
+}
+
+impl UsbMidiHandler {
+    pub fn new(protocol: MidiProtocol) -> Self {
+        Self {
+            protocol,
+            rx_packets: VecDeque::new(),
+            tx_packets: VecDeque::new(),
+            sink: None,
+            source: None,
+        }
+    }
+
+    /// Give MIDI 1.0 event packets from the host to `sink`
+    ///
+    /// Packets with a reserved Code Index Number are dropped. UMPs of MIDI
+    /// 2.0 still go to `rx_packets`.
+    pub fn with_sink<F: FnMut(u8, &[u8]) + Send + 'static>(mut self, sink: F) -> Self {
+        self.sink = Some(Box::new(sink));
+        self
+    }
+
+    /// Send packets from `source` to the host, e.g. built by [event_packet]
+    pub fn with_source<F: FnMut() -> Option<Vec<u32>> + Send + 'static>(
+        mut self,
+        source: F,
+    ) -> Self {
+        self.source = Some(Box::new(source));
+        self
+    }
+
+    /// Bulk endpoints, high speed sized for MIDI 2.0
+    pub fn endpoints(protocol: MidiProtocol) -> Vec<UsbEndpoint> {
+        let max_packet_size = match protocol {
+            MidiProtocol::Midi1 => 64,
+            MidiProtocol::Midi2 => 512,
+        };
+        vec![
+            // bulk out
+            UsbEndpoint {
+                address: 0x01,                              // OUT
+                attributes: EndpointAttributes::Bulk as u8, // Bulk
+                max_packet_size,
+                interval: 0,
+            },
+            // bulk in
+            UsbEndpoint {
+                address: 0x81,                              // IN
+                attributes: EndpointAttributes::Bulk as u8, // Bulk
+                max_packet_size,
+                interval: 0,
+            },
+        ]
+    }
+
+    /// Split received words into packets, dropping zero padding words
+    ///
+    /// Malformed data fails with [std::io::ErrorKind::BrokenPipe] to stall the endpoint.
+    fn parse_packets(&self, req: &[u8]) -> Result<Vec<Vec<u32>>> {
+        if !req.len().is_multiple_of(4) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "MIDI data not a multiple of 32-bit words",
+            ));
+        }
+        let words: Vec<u32> = req
+            .chunks(4)
+            .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+            .collect();
+        let mut packets = vec![];
+        let mut i = 0;
+        while i < words.len() {
+            if words[i] == 0 {
+                i += 1;
+                continue;
+            }
+            let count = match self.protocol {
+                MidiProtocol::Midi1 => 1,
+                MidiProtocol::Midi2 => ump_word_count(words[i]),
+            };
+            let packet = words.get(i..i + count).ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::BrokenPipe, "truncated UMP")
+            })?;
+            packets.push(packet.to_vec());
+            i += count;
+        }
+        Ok(packets)
+    }
+
+    /// Group terminal block descriptors, MIDI 2.0 only
+    fn group_terminal_blocks(&self) -> Vec<u8> {
+        let mut desc = vec![
+            0x05,            // bLength
+            CS_GR_TRM_BLOCK, // bDescriptorType: CS_GR_TRM_BLOCK
+            0x01,            // bDescriptorSubtype: GR_TRM_BLOCK_HEADER
+            0x00,
+            0x00, // wTotalLength: to be filled below
+        ];
+        desc.extend_from_slice(&[
+            0x0D,            // bLength
+            CS_GR_TRM_BLOCK, // bDescriptorType: CS_GR_TRM_BLOCK
+            0x02,            // bDescriptorSubtype: GR_TRM_BLOCK
+            0x01,            // bGrpTrmBlkID
+            0x00,            // bGrpTrmBlkType: bidirectional
+            0x00,            // nGroupTrm: group 1
+            0x01,            // nNumGroupTrm
+            0x00,            // iBlockItem
+            0x11,            // bMIDIProtocol: MIDI 2.0
+            0x00,
+            0x00, // wMaxInputBandwidth: unknown
+            0x00,
+            0x00, // wMaxOutputBandwidth: unknown
+        ]);
+        let len = desc.len() as u16;
+        desc[3] = len as u8;
+        desc[4] = (len >> 8) as u8;
+        desc
+    }
+}
+
+impl UsbInterfaceHandler for UsbMidiHandler {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        ep: UsbEndpoint,
+        setup: Option<SetupPacket>,
+        req: &[u8],
+    ) -> Result<Vec<u8>> {
+        if let Some(setup) = setup {
+            if self.protocol == MidiProtocol::Midi2
+                && setup.request_type == 0x81
+                && setup.request == StandardRequest::GetDescriptor as u8
+                && setup.value >> 8 == CS_GR_TRM_BLOCK as u16
+            {
+                let mut desc = self.group_terminal_blocks();
+                desc.truncate(setup.length as usize);
+                return Ok(desc);
+            }
+            warn!("MIDI unsupported control request {:x?}", setup);
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "unsupported MIDI request",
+            ));
+        }
+
+        match ep.direction() {
+            Direction::Out => {
+                let packets = self.parse_packets(req)?;
+                match &mut self.sink {
+                    Some(sink) if self.protocol == MidiProtocol::Midi1 => {
+                        for packet in packets {
+                            match parse_event_packet(packet[0]) {
+                                Some((cable, midi)) => sink(cable, &midi),
+                                None => debug!("Dropped MIDI event packet {:08x}", packet[0]),
+                            }
+                        }
+                    }
+                    _ => self.rx_packets.extend(packets),
+                }
+                Ok(vec![])
+            }
+            Direction::In => {
+                // send as many whole packets as fit in one max sized packet
+                if let Some(source) = &mut self.source {
+                    let mut words: usize = self.tx_packets.iter().map(Vec::len).sum();
+                    while words * 4 < ep.packet_size() {
+                        match source() {
+                            Some(packet) => {
+                                words += packet.len();
+                                self.tx_packets.push_back(packet);
+                            }
+                            None => break,
+                        }
+                    }
+                }
+                let mut resp = vec![];
+                while let Some(packet) = self.tx_packets.front() {
+                    if !resp.is_empty() && resp.len() + packet.len() * 4 > ep.packet_size() {
+                        break;
+                    }
+                    for word in packet {
+                        resp.extend_from_slice(&word.to_le_bytes());
+                    }
+                    self.tx_packets.pop_front();
+                }
+                if resp.is_empty() {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::WouldBlock,
+                        "no MIDI data",
+                    ));
+                }
+                Ok(resp)
+            }
+        }
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        match self.protocol {
+            MidiProtocol::Midi1 => {
+                let mut desc = vec![
+                    0x07,         // bLength
+                    CS_INTERFACE, // bDescriptorType: CS_INTERFACE
+                    0x01,         // bDescriptorSubtype: MS_HEADER
+                    0x00,
+                    0x01, // bcdMSC: 1.00
+                    0x00,
+                    0x00, // wTotalLength: to be filled below
+                    // embedded IN jack, data from the host
+                    0x06,         // bLength
+                    CS_INTERFACE, // bDescriptorType: CS_INTERFACE
+                    0x02,         // bDescriptorSubtype: MIDI_IN_JACK
+                    0x01,         // bJackType: EMBEDDED
+                    0x01,         // bJackID
+                    0x00,         // iJack
+                    // external IN jack
+                    0x06,         // bLength
+                    CS_INTERFACE, // bDescriptorType: CS_INTERFACE
+                    0x02,         // bDescriptorSubtype: MIDI_IN_JACK
+                    0x02,         // bJackType: EXTERNAL
+                    0x02,         // bJackID
+                    0x00,         // iJack
+                    // embedded OUT jack, data to the host
+                    0x09,         // bLength
+                    CS_INTERFACE, // bDescriptorType: CS_INTERFACE
+                    0x03,         // bDescriptorSubtype: MIDI_OUT_JACK
+                    0x01,         // bJackType: EMBEDDED
+                    0x03,         // bJackID
+                    0x01,         // bNrInputPins
+                    0x02,         // baSourceID: external IN jack
+                    0x01,         // baSourcePin
+                    0x00,         // iJack
+                    // external OUT jack
+                    0x09,         // bLength
+                    CS_INTERFACE, // bDescriptorType: CS_INTERFACE
+                    0x03,         // bDescriptorSubtype: MIDI_OUT_JACK
+                    0x02,         // bJackType: EXTERNAL
+                    0x04,         // bJackID
+                    0x01,         // bNrInputPins
+                    0x01,         // baSourceID: embedded IN jack
+                    0x01,         // baSourcePin
+                    0x00,         // iJack
+                ];
+                // including the endpoint descriptors following it
+                let len = desc.len() as u16 + 2 * (7 + 5);
+                desc[5] = len as u8;
+                desc[6] = (len >> 8) as u8;
+                desc
+            }
+            MidiProtocol::Midi2 => vec![
+                0x07,         // bLength
+                CS_INTERFACE, // bDescriptorType: CS_INTERFACE
+                0x01,         // bDescriptorSubtype: MS_HEADER
+                0x00,
+                0x02, // bcdMSC: 2.00
+                0x07,
+                0x00, // wTotalLength
+            ],
+        }
+    }
+
+    fn get_class_specific_endpoint_descriptor(&self, endpoint: &UsbEndpoint) -> Vec<u8> {
+        match self.protocol {
+            MidiProtocol::Midi1 => vec![
+                0x05,        // bLength
+                CS_ENDPOINT, // bDescriptorType: CS_ENDPOINT
+                0x01,        // bDescriptorSubtype: MS_GENERAL
+                0x01,        // bNumEmbMIDIJack
+                // baAssocJackID: embedded IN jack for OUT, embedded OUT jack for IN
+                if let Direction::Out = endpoint.direction() {
+                    0x01
+                } else {
+                    0x03
+                },
+            ],
+            MidiProtocol::Midi2 => vec![
+                0x05,        // bLength
+                CS_ENDPOINT, // bDescriptorType: CS_ENDPOINT
+                0x02,        // bDescriptorSubtype: MS_GENERAL_2_0
+                0x01,        // bNumGrpTrmBlock
+                0x01,        // baAssoGrpTrmBlkID
+            ],
+        }
+    }
+
+    /// Drop packets not sent or not taken yet, the source is not drained
+    fn reset(&mut self) {
+        self.rx_packets.clear();
+        self.tx_packets.clear();
+    }
+
+    fn endpoints(&self) -> Vec<UsbEndpoint> {
+        Self::endpoints(self.protocol)
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// A handler of the Audio Control interface preceding the MIDI Streaming one
+#[derive(Clone)]
+pub struct UsbAudioControlHandler {
+    streaming_interface: u8,
+}
+
+impl UsbAudioControlHandler {
+    pub fn new(streaming_interface: u8) -> Self {
+        Self {
+            streaming_interface,
+        }
+    }
+}
+
+impl UsbInterfaceHandler for UsbAudioControlHandler {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        _ep: UsbEndpoint,
+        setup: Option<SetupPacket>,
+        _req: &[u8],
+    ) -> Result<Vec<u8>> {
+        warn!("Audio control unsupported request {:x?}", setup);
+        Err(std::io::Error::new(
+            std::io::ErrorKind::BrokenPipe,
+            "unsupported audio control request",
+        ))
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        vec![
+            0x09,         // bLength
+            CS_INTERFACE, // bDescriptorType: CS_INTERFACE
+            0x01,         // bDescriptorSubtype: HEADER
+            0x00,
+            0x01, // bcdADC: 1.00
+            0x09,
+            0x00,                     // wTotalLength
+            0x01,                     // bInCollection
+            self.streaming_interface, // baInterfaceNr
+        ]
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// Add an Audio Control and a MIDI Streaming interface speaking `protocol` to `device`
+///
+/// Returns the device and the handler of the MIDI Streaming interface.
+pub fn with_midi(
+    device: UsbDevice,
+    protocol: MidiProtocol,
+) -> (UsbDevice, Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>>) {
+    let streaming_interface = device.num_interfaces() as u8 + 1;
+    let handler = Arc::new(Mutex::new(
+        Box::new(UsbMidiHandler::new(protocol)) as Box<dyn UsbInterfaceHandler + Send>
+    ));
+    let device = device
+        .with_interface(
+            ClassCode::Audio as u8,
+            AUDIO_CONTROL_SUBCLASS,
+            0x00,
+            "Audio Control",
+            vec![],
+            Arc::new(Mutex::new(
+                Box::new(UsbAudioControlHandler::new(streaming_interface))
+                    as Box<dyn UsbInterfaceHandler + Send>,
+            )),
+        )
+        .with_interface(
+            ClassCode::Audio as u8,
+            MIDI_STREAMING_SUBCLASS,
+            0x00,
+            "MIDI",
+            UsbMidiHandler::endpoints(protocol),
+            handler.clone(),
+        );
+    (device, handler)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn desc_verify() {
+        for protocol in [MidiProtocol::Midi1, MidiProtocol::Midi2].iter() {
+            let (device, _) = with_midi(UsbDevice::new(0), *protocol);
+            verify_descriptor(&device.configuration_descriptor());
+        }
+        verify_descriptor(&UsbMidiHandler::new(MidiProtocol::Midi2).group_terminal_blocks());
+    }
+
+    #[test]
+    fn midi2_descriptor_layout() {
+        let (device, _) = with_midi(UsbDevice::new(0), MidiProtocol::Midi2);
+        let config = device.configuration_descriptor();
+        // configuration, audio control interface and header
+        let ms = &config[9 + 9 + 9..];
+        assert_eq!(ms[1], DescriptorType::Interface as u8);
+        assert_eq!(ms[6], MIDI_STREAMING_SUBCLASS);
+        // MS_HEADER with bcdMSC 2.00
+        let header = &ms[9..];
+        assert_eq!(header[..7], [0x07, 0x24, 0x01, 0x00, 0x02, 0x07, 0x00]);
+        // each endpoint followed by MS_GENERAL_2_0 naming group terminal block 1
+        let ep = &header[7..];
+        assert_eq!(ep[1], DescriptorType::Endpoint as u8);
+        assert_eq!(ep[4..6], [0x00, 0x02]);
+        assert_eq!(ep[7..12], [0x05, 0x25, 0x02, 0x01, 0x01]);
+        assert_eq!(ep.len(), 2 * (7 + 5));
+    }
+
+    #[test]
+    fn event_packets() {
+        // note on, cable 1
+        let packet = event_packet(1, &[0x90, 0x3C, 0x40]).unwrap();
+        assert_eq!(packet.to_le_bytes(), [0x19, 0x90, 0x3C, 0x40]);
+        assert_eq!(
+            parse_event_packet(packet),
+            Some((1, vec![0x90, 0x3C, 0x40]))
+        );
+        // program change and timing clock are shorter
+        let packet = event_packet(0, &[0xC2, 0x05]).unwrap();
+        assert_eq!(parse_event_packet(packet), Some((0, vec![0xC2, 0x05])));
+        let packet = event_packet(2, &[0xF8]).unwrap();
+        assert_eq!(packet.to_le_bytes(), [0x25, 0xF8, 0x00, 0x00]);
+        // SysEx end with two bytes
+        assert_eq!(
+            parse_event_packet(u32::from_le_bytes([0x06, 0x01, 0xF7, 0x00])),
+            Some((0, vec![0x01, 0xF7]))
+        );
+        assert_eq!(event_packet(0, &[0x90, 0x3C]), None);
+        assert_eq!(event_packet(0, &[0xF0, 0x01, 0xF7]), None);
+        assert_eq!(parse_event_packet(0x0000_0001), None);
+    }
+
+    #[tokio::test]
+    async fn midi1_sink_and_source() {
+        let received = Arc::new(Mutex::new(vec![]));
+        let sink_received = received.clone();
+        let mut to_host: VecDeque<_> = vec![
+            event_packet(0, &[0x80, 0x3C, 0x00]).unwrap(),
+            event_packet(3, &[0xB0, 0x07, 0x64]).unwrap(),
+        ]
+        .into();
+        let midi = UsbMidiHandler::new(MidiProtocol::Midi1)
+            .with_sink(move |cable, midi| {
+                sink_received.lock().unwrap().push((cable, midi.to_vec()))
+            })
+            .with_source(move || to_host.pop_front().map(|packet| vec![packet]));
+        let device = UsbDevice::new(0).with_interface(
+            ClassCode::Audio as u8,
+            MIDI_STREAMING_SUBCLASS,
+            0x00,
+            "MIDI",
+            UsbMidiHandler::endpoints(MidiProtocol::Midi1),
+            Arc::new(Mutex::new(
+                Box::new(midi) as Box<dyn UsbInterfaceHandler + Send>
+            )),
+        );
+        let ctx = ConnectionContext::default();
+        let intf = &device.interfaces[0];
+        let endpoints = UsbMidiHandler::endpoints(MidiProtocol::Midi1);
+
+        // note on to cable 1 and a reserved packet
+        let out = [0x19, 0x90, 0x3C, 0x40, 0x01, 0x00, 0x00, 0x00];
+        device
+            .handle_urb(&ctx, endpoints[0], Some(intf), [0; 8], &out)
+            .await
+            .unwrap();
+        assert_eq!(*received.lock().unwrap(), [(1, vec![0x90, 0x3C, 0x40])]);
+
+        // both packets of the source in one transfer
+        let resp = device
+            .handle_urb(&ctx, endpoints[1], Some(intf), [0; 8], &[])
+            .await
+            .unwrap();
+        assert_eq!(resp, [0x08, 0x80, 0x3C, 0x00, 0x3B, 0xB0, 0x07, 0x64]);
+        let err = device
+            .handle_urb(&ctx, endpoints[1], Some(intf), [0; 8], &[])
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn midi1_jacks() {
+        let desc = UsbMidiHandler::new(MidiProtocol::Midi1).get_class_specific_descriptor();
+        // MS_HEADER then embedded and external IN jacks, each OUT jack fed by
+        // the IN jack of the other kind
+        let jacks: Vec<_> = desc[7..]
+            .chunks(6)
+            .take(2)
+            .map(|jack| (jack[2], jack[3], jack[4]))
+            .collect();
+        assert_eq!(jacks, [(0x02, 0x01, 0x01), (0x02, 0x02, 0x02)]);
+        let out_jacks = &desc[7 + 2 * 6..];
+        assert_eq!(out_jacks[2..7], [0x03, 0x01, 0x03, 0x01, 0x02]);
+        assert_eq!(out_jacks[9 + 2..9 + 7], [0x03, 0x02, 0x04, 0x01, 0x01]);
+    }
+
+    #[tokio::test]
+    async fn ump_round_trip() {
+        let (device, handler) = with_midi(UsbDevice::new(0), MidiProtocol::Midi2);
+        let ctx = ConnectionContext::default();
+        let intf = &device.interfaces[1];
+        let endpoints = UsbMidiHandler::endpoints(MidiProtocol::Midi2);
+
+        // group terminal blocks of the MIDI Streaming interface
+        let desc = device
+            .handle_urb(
+                &ctx,
+                device.ep0_in,
+                None,
+                [0x81, 0x06, 0x01, 0x26, 0x01, 0x00, 0xFF, 0x00],
+                &[],
+            )
+            .await
+            .unwrap();
+        assert_eq!(desc[..5], [0x05, 0x26, 0x01, 18, 0x00]);
+        // bMIDIProtocol
+        assert_eq!(desc[5 + 8], 0x11);
+
+        // a MIDI 2.0 note on (64 bits), padding and a MIDI 1.0 channel voice message (32 bits)
+        let packets = vec![vec![0x4090_3C00, 0xC000_0000], vec![0x2080_3C00]];
+        let mut out = vec![];
+        for word in [0x4090_3C00u32, 0xC000_0000, 0, 0x2080_3C00].iter() {
+            out.extend_from_slice(&word.to_le_bytes());
+        }
+        device
+            .handle_urb(&ctx, endpoints[0], Some(intf), [0; 8], &out)
+            .await
+            .unwrap();
+        {
+            let mut handler = handler.lock().unwrap();
+            let midi = handler.as_any().downcast_mut::<UsbMidiHandler>().unwrap();
+            assert_eq!(midi.rx_packets.iter().cloned().collect::<Vec<_>>(), packets);
+            // echo them back
+            midi.tx_packets = midi.rx_packets.drain(..).collect();
+        }
+        let resp = device
+            .handle_urb(&ctx, endpoints[1], Some(intf), [0; 8], &[])
+            .await
+            .unwrap();
+        assert_eq!(resp, [&out[..8], &out[12..]].concat());
+
+        // a UMP cut in the middle stalls
+        let err = device
+            .handle_urb(&ctx, endpoints[0], Some(intf), [0; 8], &out[..4])
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+    }
+}
@@ -0,0 +1,871 @@
+//! Implement Mass Storage device
+use super::*;
+use futures::future::BoxFuture;
+use tokio::sync::RwLock;
+
+/// A block storage backing a mass storage device
+///
+/// Reads and writes are async so that implementations can be backed by files
+/// or other async services, and may be shared with host code while exported.
+pub trait BlockDevice: Send + Sync {
+    /// Size of a logical block in bytes
+    fn block_size(&self) -> u32;
+
+    /// Number of logical blocks
+    fn num_blocks(&self) -> u64;
+
+    /// Read `count` blocks starting at `lba`
+    fn read_blocks(&self, lba: u64, count: u32) -> BoxFuture<'_, Result<Vec<u8>>>;
+
+    /// Write whole blocks in `data` starting at `lba`
+    fn write_blocks<'a>(&'a self, lba: u64, data: &'a [u8]) -> BoxFuture<'a, Result<()>>;
+
+    /// Whether the host must not write, reported as write protection
+    fn is_read_only(&self) -> bool {
+        false
+    }
+}
+
+/// A RAM disk, cloning it shares the underlying storage
+///
+/// Each read or write holds the lock over its whole block range, so a read
+/// never observes a partially applied write. Concurrent writers to the same
+/// blocks are not ordered: the last writer wins.
+#[derive(Clone)]
+pub struct RamDisk {
+    data: Arc<RwLock<Vec<u8>>>,
+    block_size: u32,
+    num_blocks: u64,
+    read_only: bool,
+}
+
+impl RamDisk {
+    /// Create a zeroed RAM disk of `num_blocks` blocks
+    pub fn new(block_size: u32, num_blocks: u64) -> Self {
+        Self::from_data(
+            block_size,
+            vec![0; block_size as usize * num_blocks as usize],
+        )
+    }
+
+    /// Create a RAM disk from an image, its length must be a multiple of `block_size`
+    pub fn from_data(block_size: u32, data: Vec<u8>) -> Self {
+        assert_eq!(data.len() % block_size as usize, 0);
+        Self {
+            num_blocks: (data.len() / block_size as usize) as u64,
+            data: Arc::new(RwLock::new(data)),
+            block_size,
+            read_only: false,
+        }
+    }
+
+    /// Create a RAM disk from the image file at `path`, padded with zeros to
+    /// whole blocks
+    pub fn from_file(block_size: u32, path: impl AsRef<Path>) -> Result<Self> {
+        let mut data = std::fs::read(path)?;
+        data.resize(
+            data.len().div_ceil(block_size as usize) * block_size as usize,
+            0,
+        );
+        Ok(Self::from_data(block_size, data))
+    }
+
+    /// Create a read only RAM disk of 512 byte blocks holding a FAT16
+    /// filesystem with the files under directory `path`
+    ///
+    /// Lets a host mount the files like a USB stick. The image is built once:
+    /// later changes to the directory are not seen. Symbolic links are
+    /// skipped, long names are kept as VFAT long file names.
+    pub fn from_dir(path: impl AsRef<Path>) -> Result<Self> {
+        let image = crate::fat::fat16_image(path.as_ref())?;
+        Ok(Self::from_data(512, image).with_read_only())
+    }
+
+    /// Reject writes, the host sees a write protected medium
+    pub fn with_read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+
+    /// Shared handle to the underlying storage, its length must not be changed
+    pub fn data(&self) -> Arc<RwLock<Vec<u8>>> {
+        self.data.clone()
+    }
+
+    fn range(&self, lba: u64, len: usize) -> Result<std::ops::Range<usize>> {
+        let start = lba as usize * self.block_size as usize;
+        let end = start + len;
+        if !len.is_multiple_of(self.block_size as usize)
+            || end > self.num_blocks as usize * self.block_size as usize
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "block range out of bounds",
+            ));
+        }
+        Ok(start..end)
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn block_size(&self) -> u32 {
+        self.block_size
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.num_blocks
+    }
+
+    fn read_blocks(&self, lba: u64, count: u32) -> BoxFuture<'_, Result<Vec<u8>>> {
+        Box::pin(async move {
+            let range = self.range(lba, count as usize * self.block_size as usize)?;
+            let data = self.data.read().await;
+            Ok(data[range].to_vec())
+        })
+    }
+
+    fn write_blocks<'a>(&'a self, lba: u64, data: &'a [u8]) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            if self.read_only {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::PermissionDenied,
+                    "read only disk",
+                ));
+            }
+            let range = self.range(lba, data.len())?;
+            let mut disk = self.data.write().await;
+            disk[range].copy_from_slice(data);
+            Ok(())
+        })
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+}
+
+/// Sub class code for SCSI transparent command set
+pub const MSC_SCSI_SUBCLASS: u8 = 0x06;
+/// Protocol code for Bulk-Only Transport
+pub const MSC_BULK_ONLY_PROTOCOL: u8 = 0x50;
+
+/// bRequest of Bulk-Only Mass Storage Reset
+pub const MSC_RESET: u8 = 0xFF;
+/// bRequest of Get Max LUN
+pub const MSC_GET_MAX_LUN: u8 = 0xFE;
+
+const CBW_SIGNATURE: u32 = 0x4342_5355;
+const CSW_SIGNATURE: u32 = 0x5342_5355;
+const CBW_LEN: usize = 31;
+
+const SCSI_TEST_UNIT_READY: u8 = 0x00;
+const SCSI_REQUEST_SENSE: u8 = 0x03;
+const SCSI_INQUIRY: u8 = 0x12;
+const SCSI_MODE_SENSE_6: u8 = 0x1A;
+const SCSI_START_STOP_UNIT: u8 = 0x1B;
+const SCSI_PREVENT_ALLOW_MEDIUM_REMOVAL: u8 = 0x1E;
+const SCSI_READ_CAPACITY_10: u8 = 0x25;
+const SCSI_READ_10: u8 = 0x28;
+const SCSI_WRITE_10: u8 = 0x2A;
+const SCSI_SYNCHRONIZE_CACHE_10: u8 = 0x35;
+const SCSI_MODE_SENSE_10: u8 = 0x5A;
+
+/// Sense key and additional sense code of a failed command
+type Sense = (u8, u8);
+const SENSE_MEDIUM_ERROR: u8 = 0x03;
+const SENSE_ILLEGAL_REQUEST: u8 = 0x05;
+const SENSE_DATA_PROTECT: u8 = 0x07;
+const ASC_WRITE_ERROR: u8 = 0x0C;
+const ASC_UNRECOVERED_READ_ERROR: u8 = 0x11;
+const ASC_INVALID_COMMAND: u8 = 0x20;
+const ASC_LBA_OUT_OF_RANGE: u8 = 0x21;
+const ASC_INVALID_FIELD_IN_CDB: u8 = 0x24;
+const ASC_WRITE_PROTECTED: u8 = 0x27;
+
+/// Command Block Wrapper sent by the host on the bulk out endpoint
+struct Cbw {
+    tag: u32,
+    data_length: u32,
+    data_in: bool,
+    cb: [u8; 16],
+}
+
+impl Cbw {
+    fn parse(req: &[u8]) -> Option<Cbw> {
+        if req.len() != CBW_LEN
+            || u32::from_le_bytes([req[0], req[1], req[2], req[3]]) != CBW_SIGNATURE
+        {
+            return None;
+        }
+        let mut cb = [0u8; 16];
+        cb.copy_from_slice(&req[15..31]);
+        Some(Cbw {
+            tag: u32::from_le_bytes([req[4], req[5], req[6], req[7]]),
+            data_length: u32::from_le_bytes([req[8], req[9], req[10], req[11]]),
+            data_in: req[12] & 0x80 != 0,
+            cb,
+        })
+    }
+}
+
+/// Command Status Wrapper closing a command
+fn csw(tag: u32, residue: u32, status: u8) -> Vec<u8> {
+    let mut csw = Vec::with_capacity(13);
+    csw.extend_from_slice(&CSW_SIGNATURE.to_le_bytes());
+    csw.extend_from_slice(&tag.to_le_bytes());
+    csw.extend_from_slice(&residue.to_le_bytes());
+    csw.push(status);
+    csw
+}
+
+/// Stage of the Bulk-Only Transport
+enum BotState {
+    /// Waiting for a CBW
+    Command,
+    /// Sending data to the host, then the CSW
+    DataIn {
+        data: Vec<u8>,
+        offset: usize,
+        csw: Vec<u8>,
+    },
+    /// Receiving data from the host, written at `lba` if any
+    DataOut {
+        cbw: Cbw,
+        data: Vec<u8>,
+        lba: Option<u64>,
+        sense: Option<Sense>,
+    },
+    /// Sending the CSW
+    Status(Vec<u8>),
+}
+
+/// A handler of a mass storage device of one LUN speaking SCSI over Bulk-Only Transport
+///
+/// Wrap it in an [AsyncHandler], blocks are read and written through a
+/// [BlockDevice]. Commands other than INQUIRY, READ CAPACITY(10), READ(10),
+/// WRITE(10), MODE SENSE, REQUEST SENSE and a few no-ops fail with ILLEGAL
+/// REQUEST.
+pub struct UsbMassStorageHandler {
+    disk: Arc<dyn BlockDevice>,
+    state: BotState,
+    sense: Option<Sense>,
+}
+
+impl UsbMassStorageHandler {
+    pub fn new(disk: Arc<dyn BlockDevice>) -> Self {
+        Self {
+            disk,
+            state: BotState::Command,
+            sense: None,
+        }
+    }
+
+    pub fn endpoints() -> Vec<UsbEndpoint> {
+        vec![
+            // bulk in
+            UsbEndpoint {
+                address: 0x81,                              // IN
+                attributes: EndpointAttributes::Bulk as u8, // Bulk
+                max_packet_size: 512,                       // 512 bytes
+                interval: 0,
+            },
+            // bulk out
+            UsbEndpoint {
+                address: 0x02,                              // OUT
+                attributes: EndpointAttributes::Bulk as u8, // Bulk
+                max_packet_size: 512,                       // 512 bytes
+                interval: 0,
+            },
+        ]
+    }
+
+    /// Blocks `lba..lba + count` of the command, if on the disk
+    fn block_range(&self, cb: &[u8; 16]) -> std::result::Result<(u64, u32), Sense> {
+        let lba = u32::from_be_bytes([cb[2], cb[3], cb[4], cb[5]]) as u64;
+        let count = u16::from_be_bytes([cb[7], cb[8]]) as u32;
+        if lba + count as u64 > self.disk.num_blocks() {
+            return Err((SENSE_ILLEGAL_REQUEST, ASC_LBA_OUT_OF_RANGE));
+        }
+        Ok((lba, count))
+    }
+
+    /// Device specific parameter of MODE SENSE
+    fn write_protect(&self) -> u8 {
+        if self.disk.is_read_only() {
+            0x80
+        } else {
+            0x00
+        }
+    }
+
+    /// Run a command without data out stage, returning its data in
+    async fn execute(&mut self, cbw: &Cbw) -> std::result::Result<Vec<u8>, Sense> {
+        let cb = &cbw.cb;
+        match cb[0] {
+            SCSI_TEST_UNIT_READY
+            | SCSI_START_STOP_UNIT
+            | SCSI_PREVENT_ALLOW_MEDIUM_REMOVAL
+            | SCSI_SYNCHRONIZE_CACHE_10 => Ok(vec![]),
+            SCSI_REQUEST_SENSE => {
+                let (key, asc) = self.sense.take().unwrap_or((0, 0));
+                // fixed format
+                let mut sense = vec![0u8; 18];
+                sense[0] = 0x70;
+                sense[2] = key;
+                sense[7] = 10; // additional sense length
+                sense[12] = asc;
+                Ok(sense)
+            }
+            SCSI_INQUIRY => {
+                let mut inquiry = vec![
+                    0x00, // direct access block device
+                    0x80, // removable
+                    0x04, // SPC-2
+                    0x02, // response data format
+                    31,   // additional length
+                    0x00, 0x00, 0x00,
+                ];
+                inquiry.extend_from_slice(b"usbip   "); // vendor
+                inquiry.extend_from_slice(b"Virtual Disk    "); // product
+                inquiry.extend_from_slice(b"0.1 "); // revision
+                Ok(inquiry)
+            }
+            SCSI_READ_CAPACITY_10 => {
+                let last_lba = self.disk.num_blocks().saturating_sub(1).min(0xFFFF_FFFF) as u32;
+                let mut capacity = last_lba.to_be_bytes().to_vec();
+                capacity.extend_from_slice(&self.disk.block_size().to_be_bytes());
+                Ok(capacity)
+            }
+            // header only: no pages, WP bit in the device specific parameter
+            SCSI_MODE_SENSE_6 => Ok(vec![0x03, 0x00, self.write_protect(), 0x00]),
+            SCSI_MODE_SENSE_10 => Ok(vec![
+                0x00,
+                0x06,
+                0x00,
+                self.write_protect(),
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+            ]),
+            SCSI_READ_10 => {
+                let (lba, count) = self.block_range(cb)?;
+                self.disk.read_blocks(lba, count).await.map_err(|err| {
+                    warn!("Read of {} blocks at {} failed: {}", count, lba, err);
+                    (SENSE_MEDIUM_ERROR, ASC_UNRECOVERED_READ_ERROR)
+                })
+            }
+            opcode => {
+                debug!("Unsupported SCSI command {:02x}", opcode);
+                Err((SENSE_ILLEGAL_REQUEST, ASC_INVALID_COMMAND))
+            }
+        }
+    }
+
+    async fn handle_cbw(&mut self, cbw: Cbw) {
+        trace!("SCSI command {:02x?}", cbw.cb);
+        if cbw.cb[0] == SCSI_WRITE_10 {
+            let target = self.block_range(&cbw.cb).and_then(|(lba, count)| {
+                let len = count as u64 * self.disk.block_size() as u64;
+                if cbw.data_in || (cbw.data_length as u64) < len {
+                    Err((SENSE_ILLEGAL_REQUEST, ASC_INVALID_FIELD_IN_CDB))
+                } else if self.disk.is_read_only() {
+                    Err((SENSE_DATA_PROTECT, ASC_WRITE_PROTECTED))
+                } else {
+                    Ok(lba)
+                }
+            });
+            if cbw.data_length == 0 {
+                let status = target.is_err() as u8;
+                self.sense = target.err();
+                self.state = BotState::Status(csw(cbw.tag, 0, status));
+                return;
+            }
+            if !cbw.data_in {
+                self.state = BotState::DataOut {
+                    lba: target.ok(),
+                    sense: target.err(),
+                    cbw,
+                    data: vec![],
+                };
+                return;
+            }
+        }
+
+        let result = if cbw.cb[0] == SCSI_WRITE_10 {
+            Err((SENSE_ILLEGAL_REQUEST, ASC_INVALID_FIELD_IN_CDB))
+        } else {
+            self.execute(&cbw).await
+        };
+        let status = result.is_err() as u8;
+        let (mut data, sense) = match result {
+            Ok(data) => (data, None),
+            Err(sense) => (vec![], Some(sense)),
+        };
+        self.sense = sense;
+        self.state = if cbw.data_length == 0 {
+            BotState::Status(csw(cbw.tag, 0, status))
+        } else if cbw.data_in {
+            data.truncate(cbw.data_length as usize);
+            let residue = cbw.data_length - data.len() as u32;
+            BotState::DataIn {
+                data,
+                offset: 0,
+                csw: csw(cbw.tag, residue, status),
+            }
+        } else {
+            // the host sends data nobody asked for, take it and discard it
+            BotState::DataOut {
+                lba: None,
+                sense,
+                cbw,
+                data: vec![],
+            }
+        };
+    }
+
+    /// Write what the data out stage received and close the command
+    async fn finish_data_out(
+        &mut self,
+        cbw: Cbw,
+        data: Vec<u8>,
+        lba: Option<u64>,
+        sense: Option<Sense>,
+    ) {
+        let mut residue = cbw.data_length;
+        let mut sense = sense;
+        if let Some(lba) = lba {
+            let count = u16::from_be_bytes([cbw.cb[7], cbw.cb[8]]) as usize;
+            let len = count * self.disk.block_size() as usize;
+            match self.disk.write_blocks(lba, &data[..len]).await {
+                Ok(()) => residue -= len as u32,
+                Err(err) => {
+                    warn!("Write of {} blocks at {} failed: {}", count, lba, err);
+                    sense = Some((SENSE_MEDIUM_ERROR, ASC_WRITE_ERROR));
+                }
+            }
+        }
+        self.sense = sense;
+        self.state = BotState::Status(csw(cbw.tag, residue, sense.is_some() as u8));
+    }
+}
+
+impl AsyncUsbInterfaceHandler for UsbMassStorageHandler {
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn endpoints(&self) -> Vec<UsbEndpoint> {
+        Self::endpoints()
+    }
+
+    fn handle_urb(
+        &mut self,
+        ctx: ConnectionContext,
+        ep: UsbEndpoint,
+        setup: Option<SetupPacket>,
+        req: Vec<u8>,
+    ) -> BoxFuture<'_, Result<Vec<u8>>> {
+        Box::pin(async move {
+            if let Some(setup) = setup {
+                // class requests to the interface
+                return match (setup.request_type, setup.request) {
+                    (0b00100001, MSC_RESET) => {
+                        debug!("Bulk-only mass storage reset");
+                        self.state = BotState::Command;
+                        Ok(vec![])
+                    }
+                    // a single LUN
+                    (0b10100001, MSC_GET_MAX_LUN) => Ok(vec![0]),
+                    _ => Err(std::io::Error::new(
+                        std::io::ErrorKind::BrokenPipe,
+                        format!("unhandled mass storage request {:x?}", setup),
+                    )),
+                };
+            }
+
+            match (
+                ep.direction(),
+                std::mem::replace(&mut self.state, BotState::Command),
+            ) {
+                (Direction::Out, BotState::Command) => {
+                    let cbw = Cbw::parse(&req).ok_or_else(|| {
+                        std::io::Error::new(
+                            std::io::ErrorKind::BrokenPipe,
+                            format!("invalid CBW of {} bytes", req.len()),
+                        )
+                    })?;
+                    self.handle_cbw(cbw).await;
+                    Ok(vec![])
+                }
+                (
+                    Direction::Out,
+                    BotState::DataOut {
+                        cbw,
+                        mut data,
+                        lba,
+                        sense,
+                    },
+                ) => {
+                    let wanted = cbw.data_length as usize - data.len();
+                    data.extend_from_slice(&req[..req.len().min(wanted)]);
+                    if data.len() == cbw.data_length as usize {
+                        self.finish_data_out(cbw, data, lba, sense).await;
+                    } else {
+                        self.state = BotState::DataOut {
+                            cbw,
+                            data,
+                            lba,
+                            sense,
+                        };
+                    }
+                    Ok(vec![])
+                }
+                (Direction::In, BotState::DataIn { data, offset, csw }) => {
+                    let len = ctx
+                        .transfer_buffer_length
+                        .map_or(data.len() - offset, |len| len as usize)
+                        .min(data.len() - offset);
+                    let chunk = data[offset..offset + len].to_vec();
+                    self.state = if offset + len == data.len() {
+                        BotState::Status(csw)
+                    } else {
+                        BotState::DataIn {
+                            data,
+                            offset: offset + len,
+                            csw,
+                        }
+                    };
+                    Ok(chunk)
+                }
+                (Direction::In, BotState::Status(csw)) => Ok(csw),
+                (Direction::In, state) => {
+                    // nothing to send until the next command
+                    self.state = state;
+                    Err(std::io::Error::new(
+                        std::io::ErrorKind::WouldBlock,
+                        "no command pending",
+                    ))
+                }
+                (Direction::Out, state) => {
+                    self.state = state;
+                    Err(std::io::Error::new(
+                        std::io::ErrorKind::BrokenPipe,
+                        "unexpected bulk out during data in",
+                    ))
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn ram_disk_concurrent_access() {
+        let disk = RamDisk::new(512, 4);
+        let other = disk.clone();
+        let new_data = vec![0xAA; 1024];
+        let (read1, write, read2) = futures::join!(
+            disk.read_blocks(1, 2),
+            other.write_blocks(1, &new_data),
+            disk.read_blocks(1, 2)
+        );
+        write.unwrap();
+        // each read sees either the old or the new content, never a mix
+        for read in [read1.unwrap(), read2.unwrap()].iter() {
+            assert!(read == &vec![0; 1024] || read == &new_data);
+        }
+        // the write is visible through every clone
+        assert_eq!(disk.read_blocks(1, 2).await.unwrap(), new_data);
+        assert_eq!(disk.read_blocks(0, 1).await.unwrap(), vec![0; 512]);
+        assert!(disk.read_blocks(3, 2).await.is_err());
+    }
+
+    fn cbw(tag: u32, data_length: u32, data_in: bool, cb: &[u8]) -> Vec<u8> {
+        let mut cbw = CBW_SIGNATURE.to_le_bytes().to_vec();
+        cbw.extend_from_slice(&tag.to_le_bytes());
+        cbw.extend_from_slice(&data_length.to_le_bytes());
+        cbw.push(if data_in { 0x80 } else { 0x00 });
+        cbw.push(0); // LUN
+        cbw.push(cb.len() as u8);
+        let mut block = [0u8; 16];
+        block[..cb.len()].copy_from_slice(cb);
+        cbw.extend_from_slice(&block);
+        cbw
+    }
+
+    async fn transfer(
+        handler: &mut UsbMassStorageHandler,
+        address: u8,
+        transfer_buffer_length: Option<u32>,
+        req: Vec<u8>,
+    ) -> Result<Vec<u8>> {
+        let ep = UsbMassStorageHandler::endpoints()
+            .into_iter()
+            .find(|ep| ep.address == address)
+            .unwrap();
+        let ctx = ConnectionContext {
+            transfer_buffer_length,
+            ..ConnectionContext::default()
+        };
+        handler.handle_urb(ctx, ep, None, req).await
+    }
+
+    #[tokio::test]
+    async fn scsi_read_write() {
+        let disk = RamDisk::new(512, 8);
+        let mut handler = UsbMassStorageHandler::new(Arc::new(disk.clone()));
+
+        // INQUIRY
+        transfer(
+            &mut handler,
+            0x02,
+            None,
+            cbw(1, 36, true, &[0x12, 0, 0, 0, 36, 0]),
+        )
+        .await
+        .unwrap();
+        let inquiry = transfer(&mut handler, 0x81, Some(36), vec![])
+            .await
+            .unwrap();
+        assert_eq!(inquiry.len(), 36);
+        assert_eq!(inquiry[1], 0x80);
+        assert_eq!(
+            transfer(&mut handler, 0x81, Some(13), vec![])
+                .await
+                .unwrap(),
+            csw(1, 0, 0)
+        );
+
+        // READ CAPACITY(10)
+        transfer(&mut handler, 0x02, None, cbw(2, 8, true, &[0x25; 1]))
+            .await
+            .unwrap();
+        assert_eq!(
+            transfer(&mut handler, 0x81, Some(8), vec![]).await.unwrap(),
+            [0, 0, 0, 7, 0, 0, 2, 0]
+        );
+        assert_eq!(
+            transfer(&mut handler, 0x81, Some(13), vec![])
+                .await
+                .unwrap(),
+            csw(2, 0, 0)
+        );
+
+        // WRITE(10) of blocks 1 and 2 in two URBs
+        let data: Vec<u8> = (0..1024).map(|i| (i % 251) as u8).collect();
+        let write = [0x2A, 0, 0, 0, 0, 1, 0, 0, 2, 0];
+        transfer(&mut handler, 0x02, None, cbw(3, 1024, false, &write))
+            .await
+            .unwrap();
+        for chunk in data.chunks(600) {
+            transfer(&mut handler, 0x02, None, chunk.to_vec())
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            transfer(&mut handler, 0x81, Some(13), vec![])
+                .await
+                .unwrap(),
+            csw(3, 0, 0)
+        );
+        assert_eq!(disk.read_blocks(1, 2).await.unwrap(), data);
+
+        // READ(10) of the same blocks, split by the URB size
+        let read = [0x28, 0, 0, 0, 0, 1, 0, 0, 2, 0];
+        transfer(&mut handler, 0x02, None, cbw(4, 1024, true, &read))
+            .await
+            .unwrap();
+        let mut received = vec![];
+        for _ in 0..2 {
+            received.extend(
+                transfer(&mut handler, 0x81, Some(512), vec![])
+                    .await
+                    .unwrap(),
+            );
+        }
+        assert_eq!(received, data);
+        assert_eq!(
+            transfer(&mut handler, 0x81, Some(13), vec![])
+                .await
+                .unwrap(),
+            csw(4, 0, 0)
+        );
+    }
+
+    #[tokio::test]
+    async fn scsi_errors() {
+        let mut handler = UsbMassStorageHandler::new(Arc::new(RamDisk::new(512, 8)));
+
+        // nothing to read before a command
+        let err = transfer(&mut handler, 0x81, Some(13), vec![])
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
+
+        // READ(10) past the end fails without data
+        let read = [0x28, 0, 0, 0, 0, 7, 0, 0, 2, 0];
+        transfer(&mut handler, 0x02, None, cbw(1, 1024, true, &read))
+            .await
+            .unwrap();
+        assert!(transfer(&mut handler, 0x81, Some(1024), vec![])
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            transfer(&mut handler, 0x81, Some(13), vec![])
+                .await
+                .unwrap(),
+            csw(1, 1024, 1)
+        );
+
+        // REQUEST SENSE tells why
+        transfer(
+            &mut handler,
+            0x02,
+            None,
+            cbw(2, 18, true, &[0x03, 0, 0, 0, 18, 0]),
+        )
+        .await
+        .unwrap();
+        let sense = transfer(&mut handler, 0x81, Some(18), vec![])
+            .await
+            .unwrap();
+        assert_eq!(
+            (sense[2], sense[12]),
+            (SENSE_ILLEGAL_REQUEST, ASC_LBA_OUT_OF_RANGE)
+        );
+        assert_eq!(
+            transfer(&mut handler, 0x81, Some(13), vec![])
+                .await
+                .unwrap(),
+            csw(2, 0, 0)
+        );
+
+        // unknown commands fail too
+        transfer(&mut handler, 0x02, None, cbw(3, 0, false, &[0xC0]))
+            .await
+            .unwrap();
+        assert_eq!(
+            transfer(&mut handler, 0x81, Some(13), vec![])
+                .await
+                .unwrap(),
+            csw(3, 0, 1)
+        );
+
+        // garbage instead of a CBW stalls
+        let err = transfer(&mut handler, 0x02, None, vec![0; 8])
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+    }
+
+    async fn read_10(handler: &mut UsbMassStorageHandler, lba: u32, count: u16) -> Vec<u8> {
+        let len = count as u32 * 512;
+        let [a, b, c, d] = lba.to_be_bytes();
+        let [e, f] = count.to_be_bytes();
+        let read = [0x28, 0, a, b, c, d, 0, e, f, 0];
+        transfer(handler, 0x02, None, cbw(lba, len, true, &read))
+            .await
+            .unwrap();
+        let data = transfer(handler, 0x81, Some(len), vec![]).await.unwrap();
+        assert_eq!(
+            transfer(handler, 0x81, Some(13), vec![]).await.unwrap(),
+            csw(lba, 0, 0)
+        );
+        data
+    }
+
+    #[tokio::test]
+    async fn fat_image_from_dir() {
+        let dir = std::env::temp_dir().join(format!("usbip-fat-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("docs")).unwrap();
+        std::fs::write(dir.join("hello.txt"), b"Hello, world!\n").unwrap();
+        let long: Vec<u8> = (0..2000).map(|i| (i % 251) as u8).collect();
+        std::fs::write(dir.join("A rather long name.bin"), &long).unwrap();
+        std::fs::write(dir.join("docs").join("NOTE.TXT"), b"note").unwrap();
+        let disk = RamDisk::from_dir(&dir).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let mut handler = UsbMassStorageHandler::new(Arc::new(disk));
+
+        // boot sector
+        let boot = read_10(&mut handler, 0, 1).await;
+        assert_eq!(boot[510..], [0x55, 0xAA]);
+        assert_eq!(&boot[54..62], b"FAT16   ");
+        let sectors_per_cluster = boot[13] as u32;
+        let sectors_per_fat = u16::from_le_bytes([boot[22], boot[23]]) as u32;
+        let root_lba = 1 + 2 * sectors_per_fat;
+        let data_lba = root_lba + 32;
+        let cluster_lba = |entry: &[u8]| {
+            let cluster = u16::from_le_bytes([entry[26], entry[27]]) as u32;
+            data_lba + (cluster - 2) * sectors_per_cluster
+        };
+
+        // root directory: long name entries precede each short entry
+        let root = read_10(&mut handler, root_lba, 1).await;
+        let entries: Vec<&[u8]> = root.chunks(32).take_while(|e| e[0] != 0).collect();
+        let short = |name: &[u8; 11]| entries.iter().position(|e| &e[..11] == name).unwrap();
+        let long_name = |i: usize| {
+            let mut chars = vec![];
+            for entry in entries[..i].iter().rev().take_while(|e| e[11] == 0x0F) {
+                let offsets = (1..11).step_by(2).chain((14..26).step_by(2));
+                for offset in offsets.chain((28..32).step_by(2)) {
+                    chars.push(u16::from_le_bytes([entry[offset], entry[offset + 1]]));
+                }
+            }
+            let end = chars.iter().position(|c| *c == 0).unwrap_or(chars.len());
+            String::from_utf16(&chars[..end]).unwrap()
+        };
+
+        let hello = short(b"HELLO   TXT");
+        assert_eq!(long_name(hello), "hello.txt");
+        let size = u16::from_le_bytes([entries[hello][28], entries[hello][29]]);
+        let data = read_10(&mut handler, cluster_lba(entries[hello]), 1).await;
+        assert_eq!(&data[..size as usize], b"Hello, world!\n");
+
+        let bin = short(b"ARATHE~1BIN");
+        assert_eq!(long_name(bin), "A rather long name.bin");
+        let data = read_10(&mut handler, cluster_lba(entries[bin]), 4).await;
+        assert_eq!(data[..2000], long[..]);
+
+        // 8.3 uppercase names need no long name
+        let docs = short(b"DOCS       ");
+        assert_eq!(entries[docs][11], 0x10);
+        let docs = read_10(&mut handler, cluster_lba(entries[docs]), 1).await;
+        assert_eq!(&docs[..11], b".          ");
+        assert_eq!(&docs[64..75], b"NOTE    TXT");
+        assert_eq!(docs[64 - 32 + 11], 0x10);
+
+        // writes fail, the medium is write protected
+        transfer(
+            &mut handler,
+            0x02,
+            None,
+            cbw(1, 4, true, &[0x1A, 0, 0x3F, 0, 4, 0]),
+        )
+        .await
+        .unwrap();
+        let mode = transfer(&mut handler, 0x81, Some(4), vec![]).await.unwrap();
+        assert_eq!(mode[2], 0x80);
+        transfer(&mut handler, 0x81, Some(13), vec![])
+            .await
+            .unwrap();
+        let write = [0x2A, 0, 0, 0, 0, 0, 0, 0, 1, 0];
+        transfer(&mut handler, 0x02, None, cbw(2, 512, false, &write))
+            .await
+            .unwrap();
+        transfer(&mut handler, 0x02, None, vec![0; 512])
+            .await
+            .unwrap();
+        assert_eq!(
+            transfer(&mut handler, 0x81, Some(13), vec![])
+                .await
+                .unwrap(),
+            csw(2, 512, 1)
+        );
+        assert_eq!(read_10(&mut handler, 0, 1).await, boot);
+    }
+}
@@ -0,0 +1,274 @@
+//! Implement USB printer device, capturing what the host prints
+//!
+//! The printer interface has a bulk OUT endpoint for data to print and, if
+//! bidirectional, a bulk IN endpoint for status the printer reports back.
+use super::*;
+
+// reference: https://www.usb.org/sites/default/files/usbprint11a021811.pdf
+
+/// Sub class code for printers, with [ClassCode::Printer]
+pub const PRINTER_SUBCLASS: u8 = 0x01;
+/// Protocol code of a unidirectional printer interface, bulk OUT only
+pub const PRINTER_PROTOCOL_UNIDIRECTIONAL: u8 = 0x01;
+/// Protocol code of a bidirectional printer interface, bulk OUT and IN
+pub const PRINTER_PROTOCOL_BIDIRECTIONAL: u8 = 0x02;
+
+/// bRequest of GET_DEVICE_ID
+pub const PRINTER_GET_DEVICE_ID: u8 = 0x00;
+/// bRequest of GET_PORT_STATUS
+pub const PRINTER_GET_PORT_STATUS: u8 = 0x01;
+/// bRequest of SOFT_RESET
+pub const PRINTER_SOFT_RESET: u8 = 0x02;
+
+/// Bit of the port status: the printer is not in an error state
+pub const PRINTER_STATUS_NOT_ERROR: u8 = 0x08;
+/// Bit of the port status: the printer is selected
+pub const PRINTER_STATUS_SELECT: u8 = 0x10;
+/// Bit of the port status: the printer is out of paper
+pub const PRINTER_STATUS_PAPER_EMPTY: u8 = 0x20;
+
+/// Receives the data written to the bulk OUT endpoint
+pub type PrinterSink = Box<dyn FnMut(&[u8]) + Send>;
+
+/// A handler of a printer interface
+///
+/// Data from the host is appended to `received`, or goes to the sink if
+/// there is one. Data in `tx_buffer` is sent to the host on the bulk IN
+/// endpoint of a bidirectional printer, which is NAKed while it is empty.
+pub struct UsbPrinterHandler {
+    /// IEEE 1284 device ID, e.g. `MFG:Acme;MDL:Printer;CMD:PCL;`
+    pub device_id: String,
+    /// Answer to GET_PORT_STATUS, see [PRINTER_STATUS_SELECT] and others
+    pub port_status: u8,
+    pub received: Vec<u8>,
+    pub tx_buffer: Vec<u8>,
+    sink: Option<PrinterSink>,
+}
+
+impl UsbPrinterHandler {
+    /// A selected printer without errors reporting `device_id`
+    pub fn new(device_id: &str) -> Self {
+        Self {
+            device_id: device_id.to_string(),
+            port_status: PRINTER_STATUS_NOT_ERROR | PRINTER_STATUS_SELECT,
+            received: vec![],
+            tx_buffer: vec![],
+            sink: None,
+        }
+    }
+
+    /// Pass data from the host to `sink` instead of `received`
+    pub fn with_sink<F: FnMut(&[u8]) + Send + 'static>(mut self, sink: F) -> Self {
+        self.sink = Some(Box::new(sink));
+        self
+    }
+
+    /// Pass data from the host through a channel, e.g. to a thread saving print jobs
+    pub fn channel(device_id: &str) -> (Self, std::sync::mpsc::Receiver<Vec<u8>>) {
+        let (sink, from_host) = std::sync::mpsc::channel();
+        let handler = Self::new(device_id).with_sink(move |data| {
+            // the receiver is gone, nobody wants the data
+            sink.send(data.to_vec()).ok();
+        });
+        (handler, from_host)
+    }
+
+    /// Bulk endpoints, with bulk IN if `protocol` is [PRINTER_PROTOCOL_BIDIRECTIONAL]
+    pub fn endpoints(protocol: u8) -> Vec<UsbEndpoint> {
+        let mut endpoints = vec![
+            // bulk out
+            UsbEndpoint {
+                address: 0x01,                              // OUT
+                attributes: EndpointAttributes::Bulk as u8, // Bulk
+                max_packet_size: 512,                       // 512 bytes
+                interval: 0,
+            },
+        ];
+        if protocol == PRINTER_PROTOCOL_BIDIRECTIONAL {
+            // bulk in
+            endpoints.push(UsbEndpoint {
+                address: 0x81,                              // IN
+                attributes: EndpointAttributes::Bulk as u8, // Bulk
+                max_packet_size: 512,                       // 512 bytes
+                interval: 0,
+            });
+        }
+        endpoints
+    }
+
+    /// The device ID prefixed with its length, big endian, as GET_DEVICE_ID returns it
+    fn device_id_bytes(&self) -> Vec<u8> {
+        let len = (self.device_id.len() + 2).min(u16::MAX as usize);
+        let mut resp = (len as u16).to_be_bytes().to_vec();
+        resp.extend_from_slice(self.device_id.as_bytes());
+        resp.truncate(len);
+        resp
+    }
+}
+
+impl UsbInterfaceHandler for UsbPrinterHandler {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        ep: UsbEndpoint,
+        setup: Option<SetupPacket>,
+        req: &[u8],
+    ) -> Result<Vec<u8>> {
+        if let Some(setup) = setup {
+            // class requests to the interface
+            match (setup.request_type, setup.request) {
+                (0b10100001, PRINTER_GET_DEVICE_ID) => {
+                    let mut resp = self.device_id_bytes();
+                    resp.truncate(setup.length as usize);
+                    return Ok(resp);
+                }
+                (0b10100001, PRINTER_GET_PORT_STATUS) => {
+                    return Ok(vec![self.port_status]);
+                }
+                // recipient other, as in printer class 1.0, or interface
+                (0b00100011, PRINTER_SOFT_RESET) | (0b00100001, PRINTER_SOFT_RESET) => {
+                    debug!("Printer soft reset");
+                    self.tx_buffer.clear();
+                }
+                _ => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::BrokenPipe,
+                        format!("unhandled printer request {:x?}", setup),
+                    ));
+                }
+            }
+            return Ok(vec![]);
+        }
+
+        match ep.direction() {
+            Direction::Out => {
+                match &mut self.sink {
+                    Some(sink) => sink(req),
+                    None => self.received.extend_from_slice(req),
+                }
+                Ok(vec![])
+            }
+            Direction::In => {
+                if self.tx_buffer.is_empty() {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::WouldBlock,
+                        "no printer data",
+                    ));
+                }
+                let len = self.tx_buffer.len().min(ep.packet_size());
+                Ok(self.tx_buffer.drain(..len).collect())
+            }
+        }
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    /// Drop data not sent to the host yet, what was printed stays in `received`
+    fn reset(&mut self) {
+        self.tx_buffer.clear();
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn printer_requests() {
+        let device_id = "MFG:Acme;MDL:Capture;CMD:PCL;";
+        let (printer, from_host) = UsbPrinterHandler::channel(device_id);
+        let endpoints = UsbPrinterHandler::endpoints(PRINTER_PROTOCOL_BIDIRECTIONAL);
+        let device = UsbDevice::new(0)
+            .with_interface(
+                ClassCode::CDCData as u8,
+                0x00,
+                0x00,
+                "Other",
+                vec![],
+                Arc::new(Mutex::new(
+                    Box::new(cdc::UsbCdcAcmHandler::new()) as Box<dyn UsbInterfaceHandler + Send>
+                )),
+            )
+            .with_interface(
+                ClassCode::Printer as u8,
+                PRINTER_SUBCLASS,
+                PRINTER_PROTOCOL_BIDIRECTIONAL,
+                "Printer",
+                endpoints.clone(),
+                Arc::new(Mutex::new(
+                    Box::new(printer) as Box<dyn UsbInterfaceHandler + Send>
+                )),
+            );
+        let ctx = ConnectionContext::default();
+        let ep0 = device.ep0_in;
+
+        // GET_DEVICE_ID of interface 1, alternate setting 0
+        let resp = device
+            .handle_urb(
+                &ctx,
+                ep0,
+                None,
+                [0xA1, 0x00, 0x00, 0x00, 0x00, 0x01, 0xFF, 0x00],
+                &[],
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp[..2], [0x00, device_id.len() as u8 + 2]);
+        assert_eq!(&resp[2..], device_id.as_bytes());
+        // GET_PORT_STATUS of interface 1
+        let resp = device
+            .handle_urb(
+                &ctx,
+                ep0,
+                None,
+                [0xA1, 0x01, 0x00, 0x00, 0x01, 0x00, 0x01, 0x00],
+                &[],
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp, [PRINTER_STATUS_NOT_ERROR | PRINTER_STATUS_SELECT]);
+
+        let intf = &device.interfaces[1];
+        device
+            .handle_urb(&ctx, endpoints[0], Some(intf), [0; 8], b"%!PS")
+            .await
+            .unwrap();
+        assert_eq!(from_host.try_recv().unwrap(), b"%!PS");
+        let err = device
+            .handle_urb(&ctx, endpoints[1], Some(intf), [0; 8], &[])
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
+
+        // SOFT_RESET with recipient other drops pending data to the host
+        {
+            let mut handler = intf.handler.lock().unwrap();
+            let printer = handler
+                .as_any()
+                .downcast_mut::<UsbPrinterHandler>()
+                .unwrap();
+            printer.tx_buffer = b"status".to_vec();
+        }
+        device
+            .handle_urb(
+                &ctx,
+                device.ep0_out,
+                None,
+                [0x23, 0x02, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00],
+                &[],
+            )
+            .await
+            .unwrap();
+        let err = device
+            .handle_urb(&ctx, endpoints[1], Some(intf), [0; 8], &[])
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
+    }
+}
@@ -0,0 +1,211 @@
+//! Feed URBs to a device without USB/IP framing, e.g. from a fuzzer
+use super::*;
+
+/// A URB passed to [UsbDevice::replay]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TraceUrb {
+    /// Endpoint address, including the direction bit: 0x80 for control IN
+    pub endpoint: u8,
+    pub setup: [u8; 8],
+    pub transfer_buffer_length: u32,
+    /// OUT data, ignored for IN transfers
+    pub data: Vec<u8>,
+}
+
+impl TraceUrb {
+    /// Decode URBs from arbitrary bytes, e.g. the input of a fuzzer
+    ///
+    /// Each URB is its endpoint address, 8 bytes of setup, transfer_buffer_length
+    /// as 2 little endian bytes, the length of its data as 1 byte, then the
+    /// data. Trailing bytes which do not make up a whole URB are ignored.
+    pub fn parse_all(bytes: &[u8]) -> Vec<Self> {
+        let mut urbs = vec![];
+        let mut rest = bytes;
+        while rest.len() >= 12 {
+            let data_len = rest[11] as usize;
+            if rest.len() < 12 + data_len {
+                break;
+            }
+            let mut setup = [0u8; 8];
+            setup.copy_from_slice(&rest[1..9]);
+            urbs.push(Self {
+                endpoint: rest[0],
+                setup,
+                transfer_buffer_length: u16::from_le_bytes([rest[9], rest[10]]) as u32,
+                data: rest[12..12 + data_len].to_vec(),
+            });
+            rest = &rest[12 + data_len..];
+        }
+        urbs
+    }
+}
+
+/// Rebuild a URB from a connection history, see [UsbIpServer::urb_histories]
+///
+/// The history keeps at most [URB_HISTORY_DATA_LEN] bytes, so long OUT data
+/// is cut. IN transfers ask for as many bytes as were returned.
+impl From<&UrbRecord> for TraceUrb {
+    fn from(record: &UrbRecord) -> Self {
+        match record.direction {
+            Direction::In => Self {
+                endpoint: record.endpoint,
+                setup: record.setup,
+                transfer_buffer_length: record.data.len() as u32,
+                data: vec![],
+            },
+            Direction::Out => Self {
+                endpoint: record.endpoint,
+                setup: record.setup,
+                transfer_buffer_length: record.data.len() as u32,
+                data: record.data.clone(),
+            },
+        }
+    }
+}
+
+/// Completion of a [TraceUrb]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TraceReply {
+    /// 0 or a negated errno, as in RET_SUBMIT
+    pub status: i32,
+    /// IN data, cut to transfer_buffer_length
+    pub data: Vec<u8>,
+}
+
+impl UsbDevice {
+    /// Pass `urbs` in order through the dispatch of URBs from a client
+    ///
+    /// Standard requests are answered by the device, the others reach the
+    /// handlers, but no socket and no USB/IP framing is involved, so handlers
+    /// can be fuzzed in isolation. The device is imported once for the
+    /// whole sequence. A handler error becomes the status a client would
+    /// see, see [UsbIpError::status]: `-EPIPE` for a STALL, `-ENODEV` after
+    /// an unplug and `-EPROTO` otherwise. A NAK is not retried and reported
+    /// as `-ETIMEDOUT`.
+    pub async fn replay(&self, urbs: impl IntoIterator<Item = TraceUrb>) -> Vec<TraceReply> {
+        let device = self.import();
+        let ctx = ConnectionContext::new(None);
+        let mut replies = vec![];
+        for urb in urbs {
+            let (ep, intf) = match device.find_ep(urb.endpoint) {
+                Some(found) => found,
+                None => {
+                    replies.push(TraceReply {
+                        status: UsbIpError::EndpointNotFound(urb.endpoint).status(),
+                        data: vec![],
+                    });
+                    continue;
+                }
+            };
+            let urb_ctx = ConnectionContext {
+                transfer_buffer_length: Some(urb.transfer_buffer_length),
+                ..ctx.clone()
+            };
+            let out_data: &[u8] = match ep.direction() {
+                // a control OUT carries at most wLength bytes
+                Direction::Out if ep.is_ep0() => {
+                    let limit =
+                        SetupPacket::parse(&urb.setup).data_limit(urb.transfer_buffer_length);
+                    &urb.data[..limit.min(urb.data.len())]
+                }
+                Direction::Out => &urb.data,
+                Direction::In => &[],
+            };
+            let reply = match device
+                .handle_urb(&urb_ctx, ep, intf, urb.setup, out_data)
+                .await
+            {
+                Ok(mut data) => {
+                    match ep.direction() {
+                        Direction::In => data.truncate(urb.transfer_buffer_length as usize),
+                        Direction::Out => data.clear(),
+                    }
+                    TraceReply { status: 0, data }
+                }
+                Err(err) => TraceReply {
+                    status: err.status(),
+                    data: vec![],
+                },
+            };
+            replies.push(reply);
+        }
+        replies
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cdc_device() -> UsbDevice {
+        UsbDevice::new(0).with_handler(
+            ClassCode::CDC as u8,
+            cdc::CDC_ACM_SUBCLASS,
+            0x00,
+            "Serial",
+            cdc::UsbCdcAcmHandler::new(),
+        )
+    }
+
+    #[tokio::test]
+    async fn replay_cdc() {
+        let line_coding = vec![0x80, 0x25, 0x00, 0x00, 0x00, 0x00, 0x08];
+        let urbs = vec![
+            // GET_DESCRIPTOR(device), cut to 8 bytes
+            TraceUrb {
+                endpoint: 0x80,
+                setup: [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00],
+                transfer_buffer_length: 8,
+                data: vec![],
+            },
+            // SET_LINE_CODING then GET_LINE_CODING
+            TraceUrb {
+                endpoint: 0x00,
+                setup: [0x21, 0x20, 0x00, 0x00, 0x00, 0x00, 0x07, 0x00],
+                transfer_buffer_length: 7,
+                data: line_coding.clone(),
+            },
+            TraceUrb {
+                endpoint: 0x80,
+                setup: [0xA1, 0x21, 0x00, 0x00, 0x00, 0x00, 0x07, 0x00],
+                transfer_buffer_length: 7,
+                data: vec![],
+            },
+            // unknown class request and unknown endpoint
+            TraceUrb {
+                endpoint: 0x00,
+                setup: [0x21, 0xFF, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+                ..TraceUrb::default()
+            },
+            TraceUrb {
+                endpoint: 0x0F,
+                ..TraceUrb::default()
+            },
+        ];
+        let replies = cdc_device().replay(urbs).await;
+        let statuses: Vec<_> = replies.iter().map(|reply| reply.status).collect();
+        assert_eq!(statuses, [0, 0, 0, -errno::EPIPE, -errno::EPIPE]);
+        assert_eq!(replies[0].data[..2], [0x12, DescriptorType::Device as u8]);
+        assert_eq!(replies[0].data.len(), 8);
+        assert_eq!(replies[2].data, line_coding);
+    }
+
+    #[test]
+    fn parse_fuzz_input() {
+        let mut bytes = vec![0x02];
+        bytes.extend([0u8; 8]);
+        bytes.extend([0x03, 0x00, 0x03, b'a', b'b', b'c']);
+        // truncated second URB
+        bytes.extend([0x81, 0x00]);
+        let urbs = TraceUrb::parse_all(&bytes);
+        assert_eq!(
+            urbs,
+            [TraceUrb {
+                endpoint: 0x02,
+                setup: [0; 8],
+                transfer_buffer_length: 3,
+                data: b"abc".to_vec(),
+            }]
+        );
+    }
+}
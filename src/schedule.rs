@@ -0,0 +1,186 @@
+//! Ordering of URB completions across connections, see [UrbScheduler]
+use super::*;
+use tokio::sync::oneshot;
+
+/// A URB completion waiting for the bus, see [UrbScheduler]
+#[derive(Clone, Debug)]
+pub struct ScheduledUrb {
+    /// Bus id of the device the URB was submitted to
+    pub bus_id: String,
+    pub ep: UsbEndpoint,
+    /// Bytes moved in either direction
+    pub length: usize,
+    /// When the handler completed the URB
+    pub ready: tokio::time::Instant,
+}
+
+/// Decide the order and timing of URB completions of all connections
+///
+/// The server models a single bus shared by every imported device: each
+/// completion waits for its turn before USBIP_RET_SUBMIT is sent, and keeps
+/// the bus busy for [UrbScheduler::transfer_time]. Whenever the bus is free
+/// and completions wait, [UrbScheduler::next] picks the one to send, e.g.
+/// interrupt before bulk, or reserves bandwidth for isochronous URBs by
+/// delaying the others. See [UsbIpServer::with_urb_scheduler].
+pub trait UrbScheduler: Send {
+    /// Index of the completion in `ready` to send next, in arrival order
+    fn next(&mut self, ready: &[ScheduledUrb]) -> usize;
+
+    /// How long sending `urb` keeps the bus busy, defaults to no time
+    fn transfer_time(&mut self, _urb: &ScheduledUrb) -> Duration {
+        Duration::from_secs(0)
+    }
+}
+
+/// Send completions in the order they are ready, the default
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FifoScheduler;
+
+impl UrbScheduler for FifoScheduler {
+    fn next(&mut self, _ready: &[ScheduledUrb]) -> usize {
+        0
+    }
+}
+
+/// The bus shared by the connections of a server, granting it to one
+/// completion at a time
+pub(crate) struct Bus {
+    state: Mutex<BusState>,
+}
+
+struct BusState {
+    scheduler: Box<dyn UrbScheduler>,
+    busy: bool,
+    waiting: Vec<(ScheduledUrb, oneshot::Sender<Duration>)>,
+}
+
+impl BusState {
+    /// Grant the bus to the next waiting completion, or mark it free
+    fn dispatch(&mut self) {
+        while !self.waiting.is_empty() {
+            let ready: Vec<_> = self.waiting.iter().map(|(urb, _)| urb.clone()).collect();
+            let index = self.scheduler.next(&ready).min(ready.len() - 1);
+            let (urb, grant) = self.waiting.remove(index);
+            let time = self.scheduler.transfer_time(&urb);
+            // the connection may have gone meanwhile
+            if grant.send(time).is_ok() {
+                self.busy = true;
+                return;
+            }
+        }
+        self.busy = false;
+    }
+}
+
+/// Frees the bus when dropped, even if the connection task is cancelled
+struct BusTurn<'a>(&'a Bus);
+
+impl Drop for BusTurn<'_> {
+    fn drop(&mut self) {
+        self.0.state.lock().unwrap().dispatch();
+    }
+}
+
+impl Bus {
+    pub(crate) fn new(scheduler: Box<dyn UrbScheduler>) -> Self {
+        Self {
+            state: Mutex::new(BusState {
+                scheduler,
+                busy: false,
+                waiting: vec![],
+            }),
+        }
+    }
+
+    /// Wait for the turn of `urb` and its transfer time
+    pub(crate) async fn complete(&self, urb: ScheduledUrb) {
+        let (grant, granted) = oneshot::channel();
+        {
+            let mut state = self.state.lock().unwrap();
+            state.waiting.push((urb, grant));
+            if !state.busy {
+                state.dispatch();
+            }
+        }
+        if let Ok(time) = granted.await {
+            let _turn = BusTurn(self);
+            if time > Duration::from_secs(0) {
+                tokio::time::sleep(time).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Interrupt URBs first, each URB keeps the bus busy for 100ms
+    struct InterruptFirst {
+        order: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl UrbScheduler for InterruptFirst {
+        fn next(&mut self, ready: &[ScheduledUrb]) -> usize {
+            ready
+                .iter()
+                .position(|urb| urb.ep.transfer_type() == Some(EndpointAttributes::Interrupt))
+                .unwrap_or(0)
+        }
+
+        fn transfer_time(&mut self, urb: &ScheduledUrb) -> Duration {
+            self.order.lock().unwrap().push(urb.bus_id.clone());
+            Duration::from_millis(100)
+        }
+    }
+
+    fn device(dev_num: u32, attributes: EndpointAttributes) -> UsbDevice {
+        UsbDevice::new(0)
+            .with_bus_location(1, dev_num)
+            .with_interface(
+                ClassCode::VendorSpecific as u8,
+                0x00,
+                0x00,
+                "Test",
+                vec![UsbEndpoint {
+                    address: 0x81,
+                    attributes: attributes as u8,
+                    max_packet_size: 64,
+                    interval: 1,
+                }],
+                FnHandler::new(|_, _, _, _| Ok(vec![0; 8])).into_handler(),
+            )
+    }
+
+    #[tokio::test]
+    async fn interrupt_before_bulk() {
+        let order = Arc::new(Mutex::new(vec![]));
+        let server = UsbIpServer::new_simulated(vec![
+            device(1, EndpointAttributes::Bulk),
+            device(2, EndpointAttributes::Bulk),
+            device(3, EndpointAttributes::Interrupt),
+        ])
+        .with_urb_scheduler(InterruptFirst {
+            order: order.clone(),
+        });
+        let handle = spawn_server("127.0.0.1:0".parse().unwrap(), Arc::new(server))
+            .await
+            .unwrap();
+
+        let mut tasks = vec![];
+        for bus_id in &["1-1", "1-2", "1-3"] {
+            let client = UsbIpClient::connect(handle.local_addr()).await.unwrap();
+            let mut device = client.import(bus_id).await.unwrap();
+            tasks.push(tokio::spawn(async move {
+                device.submit(0x81, [0; 8], 0, 8, &[]).await.unwrap()
+            }));
+            // the first bulk URB holds the bus while the others arrive
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        for task in tasks {
+            assert_eq!(task.await.unwrap().status, 0);
+        }
+        assert_eq!(*order.lock().unwrap(), ["1-1", "1-3", "1-2"]);
+        handle.shutdown().await;
+    }
+}
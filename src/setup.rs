@@ -1,5 +1,3 @@
-use super::*;
-
 /// Parse the SETUP packet of control transfers
 #[derive(Clone, Copy, Debug, Default)]
 pub struct SetupPacket {
@@ -26,4 +24,59 @@ impl SetupPacket {
             length: (setup[7] as u16) << 8 | (setup[6] as u16),
         }
     }
+
+    /// Bytes the data stage may carry: wLength, bounded by the
+    /// transfer_buffer_length of the URB in case the client sent them apart
+    pub fn data_limit(&self, transfer_buffer_length: u32) -> usize {
+        (self.length as usize).min(transfer_buffer_length as usize)
+    }
+}
+
+/// Match control requests by any of their bmRequestType, bRequest, wValue and wIndex
+///
+/// Fields left as `None` match anything.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ControlRequestMatcher {
+    pub request_type: Option<u8>,
+    pub request: Option<u8>,
+    pub value: Option<u16>,
+    pub index: Option<u16>,
 }
+
+impl ControlRequestMatcher {
+    /// Match every control request
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_request_type(mut self, request_type: u8) -> Self {
+        self.request_type = Some(request_type);
+        self
+    }
+
+    pub fn with_request(mut self, request: u8) -> Self {
+        self.request = Some(request);
+        self
+    }
+
+    pub fn with_value(mut self, value: u16) -> Self {
+        self.value = Some(value);
+        self
+    }
+
+    pub fn with_index(mut self, index: u16) -> Self {
+        self.index = Some(index);
+        self
+    }
+
+    pub fn matches(&self, setup: &SetupPacket) -> bool {
+        self.request_type.is_none_or(|v| v == setup.request_type)
+            && self.request.is_none_or(|v| v == setup.request)
+            && self.value.is_none_or(|v| v == setup.value)
+            && self.index.is_none_or(|v| v == setup.index)
+    }
+}
+
+/// Called with the SETUP packet and OUT data stage of a matching control request
+pub type ControlRequestCallback =
+    std::sync::Arc<dyn Fn(&SetupPacket, &[u8]) + Send + Sync + 'static>;
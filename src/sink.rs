@@ -0,0 +1,206 @@
+use super::*;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// When a [FileSink] forces written data to disk
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// fsync after every write, slow but nothing is lost on a crash
+    PerWrite,
+    /// fsync on the first write after the interval has passed since the last one
+    Periodic(Duration),
+    /// fsync only when the sink is closed
+    OnClose,
+}
+
+/// Write received data to a file, syncing it according to a [FsyncPolicy]
+///
+/// Writes are buffered, so data is only guaranteed to be on disk after a sync.
+/// Dropping the sink closes it, but errors are then only logged; call
+/// [FileSink::close] to check them.
+#[derive(Debug)]
+pub struct FileSink {
+    file: Option<BufWriter<File>>,
+    policy: FsyncPolicy,
+    last_sync: Instant,
+    syncs: usize,
+}
+
+impl FileSink {
+    /// Create or truncate the file at `path`
+    pub fn create<P: AsRef<Path>>(path: P, policy: FsyncPolicy) -> Result<Self> {
+        Ok(Self::from_file(File::create(path)?, policy))
+    }
+
+    pub fn from_file(file: File, policy: FsyncPolicy) -> Self {
+        Self {
+            file: Some(BufWriter::new(file)),
+            policy,
+            last_sync: Instant::now(),
+            syncs: 0,
+        }
+    }
+
+    /// Append `data`, syncing if the policy asks for it
+    pub fn write(&mut self, data: &[u8]) -> Result<()> {
+        let file = self.file.as_mut().ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::BrokenPipe, "file sink closed")
+        })?;
+        file.write_all(data)?;
+        let sync = match self.policy {
+            FsyncPolicy::PerWrite => true,
+            FsyncPolicy::Periodic(interval) => self.last_sync.elapsed() >= interval,
+            FsyncPolicy::OnClose => false,
+        };
+        if sync {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Flush buffered data and fsync it now, regardless of the policy
+    pub fn sync(&mut self) -> Result<()> {
+        if let Some(file) = self.file.as_mut() {
+            file.flush()?;
+            file.get_ref().sync_data()?;
+            self.last_sync = Instant::now();
+            self.syncs += 1;
+        }
+        Ok(())
+    }
+
+    /// Flush and fsync the file and close it, later writes fail
+    pub fn close(&mut self) -> Result<()> {
+        if let Some(mut file) = self.file.take() {
+            file.flush()?;
+            file.get_ref().sync_all()?;
+            self.syncs += 1;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for FileSink {
+    fn drop(&mut self) {
+        if let Err(err) = self.close() {
+            warn!("Failed to close file sink: {}", err);
+        }
+    }
+}
+
+/// A handler writing data of its bulk OUT endpoints to a [FileSink]
+///
+/// Useful to capture print jobs or raw disk writes. The file is written from
+/// the connection task, so a slow disk or [FsyncPolicy::PerWrite] throttles the
+/// host. IN transfers and control requests are stalled.
+#[derive(Debug)]
+pub struct UsbFileSinkHandler {
+    pub sink: FileSink,
+}
+
+impl UsbFileSinkHandler {
+    pub fn new(sink: FileSink) -> Self {
+        Self { sink }
+    }
+
+    pub fn endpoints() -> Vec<UsbEndpoint> {
+        vec![UsbEndpoint {
+            address: 0x01,                              // OUT
+            attributes: EndpointAttributes::Bulk as u8, // Bulk
+            max_packet_size: 512,                       // 512 bytes
+            interval: 0,
+        }]
+    }
+}
+
+impl UsbInterfaceHandler for UsbFileSinkHandler {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        ep: UsbEndpoint,
+        setup: Option<SetupPacket>,
+        req: &[u8],
+    ) -> Result<Vec<u8>> {
+        if ep.transfer_type() == Some(EndpointAttributes::Bulk) {
+            if let Direction::Out = ep.direction() {
+                self.sink.write(req)?;
+                return Ok(vec![]);
+            }
+        }
+        warn!("File sink unsupported transfer {:x?} {:x?}", ep, setup);
+        Err(std::io::Error::new(
+            std::io::ErrorKind::BrokenPipe,
+            "unsupported transfer",
+        ))
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn endpoints(&self) -> Vec<UsbEndpoint> {
+        Self::endpoints()
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("usbip-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn durable_after_close() {
+        let path = temp_path("sink-on-close");
+        let mut handler =
+            UsbFileSinkHandler::new(FileSink::create(&path, FsyncPolicy::OnClose).unwrap());
+        let intf = UsbDevice::new(0)
+            .with_interface(
+                ClassCode::VendorSpecific as u8,
+                0,
+                0,
+                "",
+                vec![],
+                Arc::new(Mutex::new(
+                    Box::new(cdc::UsbCdcAcmHandler::new()) as Box<dyn UsbInterfaceHandler + Send>
+                )),
+            )
+            .interfaces
+            .remove(0);
+        let ep = UsbFileSinkHandler::endpoints()[0];
+        for chunk in [&b"hello "[..], &b"printer"[..]].iter() {
+            handler.handle_urb(&intf, ep, None, chunk).unwrap();
+        }
+        // buffered, not synced yet
+        assert_eq!(handler.sink.syncs, 0);
+
+        handler.sink.close().unwrap();
+        assert_eq!(handler.sink.syncs, 1);
+        assert_eq!(std::fs::read(&path).unwrap(), b"hello printer");
+        // closed sinks refuse further data
+        assert!(handler.handle_urb(&intf, ep, None, b"!").is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn sync_per_write() {
+        let path = temp_path("sink-per-write");
+        let mut sink = FileSink::create(&path, FsyncPolicy::PerWrite).unwrap();
+        sink.write(b"a").unwrap();
+        sink.write(b"b").unwrap();
+        assert_eq!(sink.syncs, 2);
+        // already on disk before close
+        assert_eq!(std::fs::read(&path).unwrap(), b"ab");
+        drop(sink);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,148 @@
+//! Spans of connections and URBs, with the `tracing` feature
+//!
+//! A connection runs in a `connection` span carrying its id and peer, each
+//! URB in an `urb` span carrying seq_num, ep and direction, so everything
+//! logged for one URB can be told apart from the URBs of other connections.
+//! The crate logs through `log`: install `tracing_log::LogTracer` for its
+//! records to become events in these spans. Without the feature, spans are
+//! no-ops and the log is the same as before.
+use super::*;
+use std::future::Future;
+
+#[cfg(feature = "tracing")]
+pub(crate) use tracing::Span;
+
+/// Stands in for a span without the `tracing` feature
+#[cfg(not(feature = "tracing"))]
+pub(crate) struct Span;
+
+/// Span of a connection
+#[cfg(feature = "tracing")]
+pub(crate) fn connection(ctx: &ConnectionContext) -> Span {
+    tracing::info_span!(
+        "connection",
+        id = ctx.id,
+        peer = ctx.peer.as_deref().unwrap_or("-")
+    )
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn connection(_ctx: &ConnectionContext) -> Span {
+    Span
+}
+
+/// Span of a USBIP_CMD_SUBMIT, fields as the client sent them
+#[cfg(feature = "tracing")]
+fn urb(seq_num: u32, ep: u32, direction: u32) -> Span {
+    tracing::info_span!(
+        "urb",
+        seq_num,
+        ep,
+        direction = ?Direction::from_usbip(direction)
+    )
+}
+
+#[cfg(not(feature = "tracing"))]
+fn urb(_seq_num: u32, _ep: u32, _direction: u32) -> Span {
+    Span
+}
+
+/// Span of a USBIP_CMD_UNLINK of URB `seq_num_submit`
+#[cfg(feature = "tracing")]
+fn unlink(seq_num: u32, seq_num_submit: u32) -> Span {
+    tracing::info_span!("unlink", seq_num, seq_num_submit)
+}
+
+#[cfg(not(feature = "tracing"))]
+fn unlink(_seq_num: u32, _seq_num_submit: u32) -> Span {
+    Span
+}
+
+/// Run `fut` in `span`, entered each time it is polled
+#[cfg(feature = "tracing")]
+pub(crate) fn instrument<F: Future>(span: Span, fut: F) -> impl Future<Output = F::Output> {
+    tracing::Instrument::instrument(fut, span)
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn instrument<F: Future>(_span: Span, fut: F) -> F {
+    fut
+}
+
+/// Run the handling of a submitted URB in its span
+pub(crate) fn in_urb<F: Future>(
+    seq_num: u32,
+    ep: u32,
+    direction: u32,
+    fut: F,
+) -> impl Future<Output = F::Output> {
+    instrument(urb(seq_num, ep, direction), fut)
+}
+
+/// Run the handling of an unlink in its span
+pub(crate) fn in_unlink<F: Future>(
+    seq_num: u32,
+    seq_num_submit: u32,
+    fut: F,
+) -> impl Future<Output = F::Output> {
+    instrument(unlink(seq_num, seq_num_submit), fut)
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use super::*;
+    use testing::*;
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+
+    /// Records the spans created, as their name and fields
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    struct Fields(String);
+
+    impl Visit for Fields {
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            self.0.push_str(&format!(" {}={:?}", field.name(), value));
+        }
+    }
+
+    impl Subscriber for Recorder {
+        fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &Attributes<'_>) -> Id {
+            let mut spans = self.0.lock().unwrap();
+            let mut fields = Fields(span.metadata().name().to_string());
+            span.record(&mut fields);
+            spans.push(fields.0);
+            Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, _span: &Id, _values: &Record<'_>) {}
+
+        fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+        fn event(&self, _event: &Event<'_>) {}
+
+        fn enter(&self, _span: &Id) {}
+
+        fn exit(&self, _span: &Id) {}
+    }
+
+    #[tokio::test]
+    async fn urb_spans() {
+        let spans = Arc::new(Mutex::new(vec![]));
+        let _guard = tracing::subscriber::set_default(Recorder(spans.clone()));
+        let server = Arc::new(UsbIpServer::new_simulated(vec![UsbDevice::new(0)]));
+        let get_descriptor = [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00];
+        let urbs = [CmdSubmit::control(5, get_descriptor, &[])];
+        round_trip(server, "0", &urbs).await.unwrap();
+
+        let spans = spans.lock().unwrap();
+        assert!(spans[0].starts_with("connection id="));
+        assert!(spans[0].ends_with("peer=\"-\""));
+        assert_eq!(spans[1], "urb seq_num=5 ep=0 direction=In");
+    }
+}
@@ -0,0 +1,173 @@
+use super::*;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Transfer counters of one endpoint, updated by the connection tasks
+#[derive(Debug, Default)]
+pub(crate) struct EndpointCounters {
+    urbs: AtomicU64,
+    bytes: AtomicU64,
+    errors: AtomicU64,
+    latency_us: AtomicU64,
+}
+
+impl EndpointCounters {
+    /// Count a URB which moved `bytes` and took `latency` from submit to reply
+    pub(crate) fn record(&self, bytes: usize, status: i32, latency: Duration) {
+        self.urbs.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        if status != 0 {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        self.latency_us
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> EndpointStats {
+        EndpointStats {
+            urbs: self.urbs.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            total_latency: Duration::from_micros(self.latency_us.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Transfer statistics of an endpoint, see [UsbDevice::endpoint_stats]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EndpointStats {
+    /// URBs answered
+    pub urbs: u64,
+    /// Bytes transferred in either direction
+    pub bytes: u64,
+    /// URBs answered with a non-zero status or failing the connection
+    pub errors: u64,
+    /// Sum of the time from CMD_SUBMIT to RET_SUBMIT, including NAK retries
+    pub total_latency: Duration,
+}
+
+impl EndpointStats {
+    /// Mean time from CMD_SUBMIT to RET_SUBMIT
+    pub fn average_latency(&self) -> Duration {
+        if self.urbs == 0 {
+            return Duration::from_secs(0);
+        }
+        self.total_latency / self.urbs as u32
+    }
+}
+
+/// Counters of all endpoints of a device, shared by its clones
+#[derive(Clone, Debug, Default)]
+pub(crate) struct DeviceStats {
+    endpoints: Arc<Mutex<HashMap<u8, Arc<EndpointCounters>>>>,
+}
+
+impl DeviceStats {
+    pub(crate) fn endpoint(&self, address: u8) -> Arc<EndpointCounters> {
+        self.endpoints
+            .lock()
+            .unwrap()
+            .entry(address)
+            .or_default()
+            .clone()
+    }
+
+    pub(crate) fn snapshot(&self, address: u8) -> EndpointStats {
+        self.endpoints
+            .lock()
+            .unwrap()
+            .get(&address)
+            .map(|counters| counters.snapshot())
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn snapshot_all(&self) -> Vec<(u8, EndpointStats)> {
+        let mut stats: Vec<_> = self
+            .endpoints
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(address, counters)| (*address, counters.snapshot()))
+            .collect();
+        stats.sort_by_key(|(address, _)| *address);
+        stats
+    }
+}
+
+/// Counters of all connections of a server, updated by the connection tasks
+#[derive(Debug, Default)]
+pub(crate) struct ServerCounters {
+    connections: AtomicU64,
+    active_connections: AtomicU64,
+    urbs: AtomicU64,
+    unlinks: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+}
+
+impl ServerCounters {
+    /// Count a new connection, active until the returned guard is dropped
+    pub(crate) fn connect(self: &Arc<Self>) -> ActiveConnection {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+        ActiveConnection(self.clone())
+    }
+
+    /// Count a USBIP_CMD_SUBMIT
+    pub(crate) fn submitted(&self) {
+        self.urbs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a USBIP_CMD_UNLINK
+    pub(crate) fn unlinked(&self) {
+        self.unlinks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count the data of a USBIP_RET_SUBMIT
+    pub(crate) fn transferred(&self, direction: Direction, bytes: usize) {
+        let counter = match direction {
+            Direction::In => &self.bytes_in,
+            Direction::Out => &self.bytes_out,
+        };
+        counter.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> ServerStats {
+        ServerStats {
+            connections: self.connections.load(Ordering::Relaxed),
+            active_connections: self.active_connections.load(Ordering::Relaxed),
+            urbs: self.urbs.load(Ordering::Relaxed),
+            unlinks: self.unlinks.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A connection counted by [ServerCounters::connect]
+pub(crate) struct ActiveConnection(Arc<ServerCounters>);
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.0.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Statistics of all connections of a server, see [UsbIpServer::stats]
+///
+/// Bytes of each endpoint are in [UsbDevice::endpoint_stats], whose
+/// addresses tell the direction.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ServerStats {
+    /// Connections accepted since the server started
+    pub connections: u64,
+    /// Connections currently open
+    pub active_connections: u64,
+    /// USBIP_CMD_SUBMIT received, including rejected ones
+    pub urbs: u64,
+    /// USBIP_CMD_UNLINK received, whether or not the URB was still pending
+    pub unlinks: u64,
+    /// Bytes sent to clients in IN transfers
+    pub bytes_in: u64,
+    /// Bytes received from clients in OUT transfers
+    pub bytes_out: u64,
+}
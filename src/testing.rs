@@ -0,0 +1,333 @@
+//! Build USB/IP requests and parse replies to test handlers end to end
+//!
+//! Enabled by the `testing` feature. [round_trip] imports a device of a
+//! server, submits URBs to it and collects their RET_SUBMIT, going through
+//! the same parsing and dispatch as a real client connection.
+use super::*;
+
+/// A USBIP_CMD_SUBMIT, see [CmdSubmit::to_bytes]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CmdSubmit {
+    pub seq_num: u32,
+    /// Endpoint address, including the direction bit: 0x80 for control IN
+    pub endpoint: u8,
+    pub transfer_flags: u32,
+    /// Bytes asked for by an IN transfer, OUT transfers send all of `data`
+    pub transfer_buffer_length: u32,
+    pub interval: u32,
+    pub setup: [u8; 8],
+    /// OUT data, ignored for IN transfers
+    pub data: Vec<u8>,
+}
+
+impl CmdSubmit {
+    /// A control transfer on ep0, IN or OUT as bmRequestType says
+    ///
+    /// IN transfers ask for wLength bytes.
+    pub fn control(seq_num: u32, setup: [u8; 8], data: &[u8]) -> Self {
+        let (endpoint, transfer_buffer_length) = if setup[0] & 0x80 != 0 {
+            (0x80, u16::from_le_bytes([setup[6], setup[7]]) as u32)
+        } else {
+            (0x00, data.len() as u32)
+        };
+        Self {
+            seq_num,
+            endpoint,
+            transfer_buffer_length,
+            setup,
+            data: data.to_vec(),
+            ..Self::default()
+        }
+    }
+
+    /// The command as sent by a client, not isochronous
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let direction = if self.endpoint & 0x80 != 0 {
+            Direction::In
+        } else {
+            Direction::Out
+        };
+        let transfer_buffer_length = match direction {
+            Direction::In => self.transfer_buffer_length,
+            Direction::Out => self.data.len() as u32,
+        };
+        let mut bytes = vec![];
+        bytes.extend_from_slice(&0x1u32.to_be_bytes()); // command
+        bytes.extend_from_slice(&self.seq_num.to_be_bytes());
+        bytes.extend_from_slice(&0u32.to_be_bytes()); // dev id
+        bytes.extend_from_slice(&direction.to_usbip().to_be_bytes());
+        bytes.extend_from_slice(&((self.endpoint & 0x7F) as u32).to_be_bytes());
+        bytes.extend_from_slice(&self.transfer_flags.to_be_bytes());
+        bytes.extend_from_slice(&transfer_buffer_length.to_be_bytes());
+        bytes.extend_from_slice(&0u32.to_be_bytes()); // start frame
+        bytes.extend_from_slice(&ISO_NO_PACKETS.to_be_bytes()); // number of packets
+        bytes.extend_from_slice(&self.interval.to_be_bytes());
+        bytes.extend_from_slice(&self.setup);
+        if direction == Direction::Out {
+            bytes.extend_from_slice(&self.data);
+        }
+        bytes
+    }
+}
+
+/// An OP_REQ_IMPORT of `bus_id`
+pub fn op_req_import(bus_id: &str) -> Vec<u8> {
+    let mut bytes = vec![0x01, 0x11, 0x80, 0x03, 0x00, 0x00, 0x00, 0x00];
+    let mut path = bus_id.as_bytes().to_vec();
+    path.resize(32, 0);
+    bytes.extend(path);
+    bytes
+}
+
+/// A USBIP_RET_SUBMIT, see [RetSubmit::parse]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RetSubmit {
+    pub seq_num: u32,
+    /// 0 or a negative errno, e.g. `-errno::EPIPE` for a STALL
+    pub status: i32,
+    pub actual_length: u32,
+    /// Data of IN transfers
+    pub data: Vec<u8>,
+}
+
+impl RetSubmit {
+    /// Parse the reply at the start of `bytes`, returning it and the bytes it took
+    ///
+    /// Fails on other replies, e.g. USBIP_RET_UNLINK, or if `bytes` ends
+    /// before the reply does. Iso packet descriptors are not parsed.
+    pub fn parse(bytes: &[u8]) -> std::result::Result<(Self, usize), UsbIpError> {
+        let truncated = || UsbIpError::Protocol("truncated RET_SUBMIT".to_string());
+        let word = |offset: usize| -> std::result::Result<u32, UsbIpError> {
+            let mut word = [0u8; 4];
+            word.copy_from_slice(bytes.get(offset..offset + 4).ok_or_else(truncated)?);
+            Ok(u32::from_be_bytes(word))
+        };
+        let command = word(0x00)?;
+        if command != 0x3 {
+            return Err(UsbIpError::UnsupportedCommand(command));
+        }
+        let direction = Direction::from_usbip(word(0x0C)?);
+        let actual_length = word(0x18)?;
+        let len = match direction {
+            Direction::In => 0x30 + actual_length as usize,
+            Direction::Out => 0x30,
+        };
+        let data = bytes.get(0x30..len).ok_or_else(truncated)?.to_vec();
+        let ret = Self {
+            seq_num: word(0x04)?,
+            status: word(0x14)? as i32,
+            actual_length,
+            data,
+        };
+        Ok((ret, len))
+    }
+
+    /// Parse replies back to back until `bytes` ends
+    pub fn parse_all(bytes: &[u8]) -> std::result::Result<Vec<Self>, UsbIpError> {
+        let mut rets = vec![];
+        let mut rest = bytes;
+        while !rest.is_empty() {
+            let (ret, len) = Self::parse(rest)?;
+            rets.push(ret);
+            rest = &rest[len..];
+        }
+        Ok(rets)
+    }
+}
+
+/// Import `bus_id` of `server`, submit `urbs` and collect their replies
+///
+/// The commands are written in one go, as a pipelining client would, and
+/// the connection is closed after them. A NAKed URB is still retried until
+/// it completes, but a URB never completing hangs the round trip.
+pub async fn round_trip(
+    server: Arc<UsbIpServer>,
+    bus_id: &str,
+    urbs: &[CmdSubmit],
+) -> std::result::Result<Vec<RetSubmit>, UsbIpError> {
+    let mut request = op_req_import(bus_id);
+    for urb in urbs {
+        request.extend(urb.to_bytes());
+    }
+    let (client, stream) = duplex_pair();
+    let serve = tokio::spawn(serve_stream(stream, server));
+    let (mut reader, mut writer) = tokio::io::split(client);
+    let write = async move {
+        writer.write_all(&request).await?;
+        writer.shutdown().await
+    };
+    let read = async move {
+        let mut reply = vec![];
+        reader.read_to_end(&mut reply).await?;
+        Ok(reply)
+    };
+    let (written, reply): (Result<()>, Result<Vec<u8>>) = futures::join!(write, read);
+    written?;
+    let reply = reply?;
+    serve.await.map_err(std::io::Error::other)??;
+
+    // OP_REP_IMPORT
+    if reply.len() < 8 || reply[4..8] != [0; 4] {
+        return Err(UsbIpError::DeviceNotFound(bus_id.to_string()));
+    }
+    RetSubmit::parse_all(reply.get(0x140..).unwrap_or_default())
+}
+
+/// Submit a control transfer to ep0 of `device`, IN or OUT as bmRequestType says
+///
+/// Calls the device as the server does once a command is parsed, without a
+/// connection, so that transfers share `ctx` and errors keep their kind.
+pub async fn control(
+    device: &UsbDevice,
+    ctx: &ConnectionContext,
+    setup: [u8; 8],
+    data: &[u8],
+) -> std::result::Result<Vec<u8>, UsbIpError> {
+    let ep0 = if setup[0] & 0x80 != 0 {
+        device.ep0_in
+    } else {
+        device.ep0_out
+    };
+    device.handle_urb(ctx, ep0, None, setup, data).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn get_device_descriptor() {
+        let server = Arc::new(UsbIpServer::new_simulated(vec![UsbDevice::new(0)
+            .with_vendor_id(0x1234)
+            .with_product_id(0x5678)]));
+        let get_descriptor = [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00];
+        let urbs = [
+            CmdSubmit::control(1, get_descriptor, &[]),
+            // vendor request to the device, stalled
+            CmdSubmit::control(2, [0x40, 0x42, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], &[]),
+        ];
+        let rets = round_trip(server, "0", &urbs).await.unwrap();
+
+        assert_eq!(rets.len(), 2);
+        assert_eq!(rets[0].seq_num, 1);
+        assert_eq!(rets[0].status, 0);
+        assert_eq!(rets[0].actual_length, 0x12);
+        assert_eq!(rets[0].data[..2], [0x12, DescriptorType::Device as u8]);
+        // idVendor and idProduct
+        assert_eq!(rets[0].data[8..12], [0x34, 0x12, 0x78, 0x56]);
+        assert_eq!(rets[1].seq_num, 2);
+        assert_eq!(rets[1].status, -errno::EPIPE);
+        assert!(rets[1].data.is_empty());
+    }
+
+    /// Endpoint address, transfer_buffer_length, transfer_flags and interval of a URB
+    type UrbMetadata = (u8, Option<u32>, Option<TransferFlags>, Option<u32>);
+
+    /// Records the URB metadata it is called with
+    struct MetadataHandler {
+        seen: Arc<Mutex<Vec<UrbMetadata>>>,
+    }
+
+    impl UsbInterfaceHandler for MetadataHandler {
+        fn get_class_specific_descriptor(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn handle_urb(
+            &mut self,
+            _interface: &UsbInterface,
+            _ep: UsbEndpoint,
+            _setup: Option<SetupPacket>,
+            _req: &[u8],
+        ) -> Result<Vec<u8>> {
+            unreachable!("the server passes a context")
+        }
+
+        fn handle_urb_with_context(
+            &mut self,
+            ctx: &ConnectionContext,
+            _interface: &UsbInterface,
+            ep: UsbEndpoint,
+            _setup: Option<SetupPacket>,
+            _req: &[u8],
+        ) -> Result<Vec<u8>> {
+            self.seen.lock().unwrap().push((
+                ep.address,
+                ctx.transfer_buffer_length,
+                ctx.transfer_flags,
+                ctx.interval,
+            ));
+            Ok(vec![0xAA; 64])
+        }
+
+        fn as_any(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    #[tokio::test]
+    async fn urb_metadata_reaches_handler() {
+        let seen = Arc::new(Mutex::new(vec![]));
+        let handler = MetadataHandler { seen: seen.clone() };
+        let endpoints = vec![
+            UsbEndpoint {
+                address: 0x81,
+                attributes: EndpointAttributes::Interrupt as u8,
+                max_packet_size: 64,
+                interval: 4,
+            },
+            UsbEndpoint {
+                address: 0x02,
+                attributes: EndpointAttributes::Bulk as u8,
+                max_packet_size: 512,
+                interval: 0,
+            },
+        ];
+        let server = Arc::new(UsbIpServer::new_simulated(vec![UsbDevice::new(0)
+            .with_interface(
+                ClassCode::VendorSpecific as u8,
+                0x00,
+                0x00,
+                "Metadata",
+                endpoints,
+                Arc::new(Mutex::new(
+                    Box::new(handler) as Box<dyn UsbInterfaceHandler + Send>
+                )),
+            )]));
+        let urbs = [
+            CmdSubmit {
+                seq_num: 1,
+                endpoint: 0x81,
+                transfer_flags: TransferFlags::SHORT_NOT_OK.bits(),
+                transfer_buffer_length: 13,
+                interval: 4,
+                ..CmdSubmit::default()
+            },
+            CmdSubmit {
+                seq_num: 2,
+                endpoint: 0x02,
+                transfer_flags: TransferFlags::ZERO_PACKET.bits(),
+                data: vec![1, 2, 3],
+                ..CmdSubmit::default()
+            },
+        ];
+        let rets = round_trip(server, "0", &urbs).await.unwrap();
+
+        // the reply is cut to what the host asked for
+        assert_eq!(rets[0].data, [0xAA; 13]);
+        assert_eq!(
+            *seen.lock().unwrap(),
+            [
+                (0x81, Some(13), Some(TransferFlags::SHORT_NOT_OK), Some(4)),
+                (0x02, Some(3), Some(TransferFlags::ZERO_PACKET), Some(0)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_bus_id() {
+        let server = Arc::new(UsbIpServer::new_simulated(vec![UsbDevice::new(0)]));
+        let err = round_trip(server, "1-1", &[]).await.unwrap_err();
+        assert!(matches!(err, UsbIpError::DeviceNotFound(bus_id) if bus_id == "1-1"));
+    }
+}
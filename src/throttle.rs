@@ -0,0 +1,61 @@
+use super::*;
+use tokio::time::Instant;
+
+/// A token bucket limiting throughput to a number of bytes per second
+///
+/// The bucket holds up to a tenth of a second worth of bytes, so short bursts
+/// pass undelayed. A transfer larger than the remaining tokens is not split,
+/// it puts the bucket into debt and is delayed until the debt is repaid.
+#[derive(Clone, Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// Create a full bucket refilling at `bytes_per_sec`
+    pub fn new(bytes_per_sec: u64) -> Self {
+        assert!(bytes_per_sec > 0, "throughput limit must be positive");
+        let rate = bytes_per_sec as f64;
+        let capacity = (rate / 10.0).max(1.0);
+        Self {
+            rate,
+            capacity,
+            tokens: capacity,
+            last: Instant::now(),
+        }
+    }
+
+    /// Take `bytes` out of the bucket, returning how long to wait before they may be sent
+    pub fn consume(&mut self, bytes: usize) -> Duration {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.tokens -= bytes as f64;
+        if self.tokens < 0.0 {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        } else {
+            Duration::from_secs(0)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn token_bucket_rate() {
+        tokio::time::pause();
+        let mut bucket = TokenBucket::new(1000);
+        // burst within capacity
+        assert_eq!(bucket.consume(100), Duration::from_secs(0));
+        // in debt
+        assert_eq!(bucket.consume(500), Duration::from_millis(500));
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(bucket.consume(0), Duration::from_secs(0));
+    }
+}
@@ -0,0 +1,180 @@
+use super::*;
+
+/// Build the IN data answering a request, given the request payload
+pub type TransactionResponder = Box<dyn FnOnce(&[u8]) -> Vec<u8> + Send>;
+
+/// Match requests of a vendor protocol to responses by an embedded transaction id
+///
+/// Some bulk protocols correlate a request and its response by an id carried in
+/// the payload rather than by URB. Register the expected transactions with
+/// [TransactionTable::expect]; when the handler receives an OUT request it
+/// extracts the id and calls [TransactionTable::dispatch], which queues the
+/// response for the next IN transfer. Cloning the table shares it, and
+/// [TransactionTable::queue] can be registered with [UsbDevice::with_in_queue]
+/// so that a pending IN URB completes as soon as a response is queued.
+#[derive(Clone, Default)]
+pub struct TransactionTable {
+    pending: Arc<Mutex<HashMap<u32, TransactionResponder>>>,
+    responses: InEndpointQueue,
+}
+
+impl TransactionTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answer the request with transaction `id` using `responder`, replacing
+    /// any responder already registered for it
+    pub fn expect<F>(&self, id: u32, responder: F)
+    where
+        F: FnOnce(&[u8]) -> Vec<u8> + Send + 'static,
+    {
+        self.pending.lock().unwrap().insert(id, Box::new(responder));
+    }
+
+    /// Whether a response is still expected for transaction `id`
+    pub fn is_pending(&self, id: u32) -> bool {
+        self.pending.lock().unwrap().contains_key(&id)
+    }
+
+    /// Queue the response to the `request` with transaction `id`
+    ///
+    /// Fails with [std::io::ErrorKind::BrokenPipe] when no response is
+    /// registered, so that a handler returning the error stalls the endpoint.
+    pub fn dispatch(&self, id: u32, request: &[u8]) -> Result<()> {
+        let responder = self.pending.lock().unwrap().remove(&id).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                format!("unexpected transaction id {}", id),
+            )
+        })?;
+        self.responses.push(responder(request));
+        Ok(())
+    }
+
+    /// Queue of responses in the order their requests arrived
+    pub fn queue(&self) -> &InEndpointQueue {
+        &self.responses
+    }
+
+    /// Take the oldest response, or fail with [std::io::ErrorKind::WouldBlock] to NAK
+    pub fn pop(&self) -> Result<Vec<u8>> {
+        self.responses.pop()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Requests and responses are a little endian transaction id and a payload
+    struct PingHandler {
+        table: TransactionTable,
+    }
+
+    impl UsbInterfaceHandler for PingHandler {
+        fn get_class_specific_descriptor(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn handle_urb(
+            &mut self,
+            _interface: &UsbInterface,
+            ep: UsbEndpoint,
+            _setup: Option<SetupPacket>,
+            req: &[u8],
+        ) -> Result<Vec<u8>> {
+            match ep.direction() {
+                Direction::Out => {
+                    if req.len() < 4 {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::BrokenPipe,
+                            "short request",
+                        ));
+                    }
+                    let id = u32::from_le_bytes([req[0], req[1], req[2], req[3]]);
+                    self.table.dispatch(id, &req[4..])?;
+                    Ok(vec![])
+                }
+                Direction::In => self.table.pop(),
+            }
+        }
+
+        fn as_any(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    fn pong(id: u32) -> impl FnOnce(&[u8]) -> Vec<u8> + Send {
+        move |req: &[u8]| {
+            assert_eq!(req, b"PING");
+            let mut resp = id.to_le_bytes().to_vec();
+            resp.extend_from_slice(b"PONG");
+            resp
+        }
+    }
+
+    #[tokio::test]
+    async fn ping_pong() {
+        let table = TransactionTable::new();
+        let bulk = |address| UsbEndpoint {
+            address,
+            attributes: EndpointAttributes::Bulk as u8,
+            max_packet_size: 512,
+            interval: 0,
+        };
+        let device = UsbDevice::new(0)
+            .with_interface(
+                ClassCode::VendorSpecific as u8,
+                0x00,
+                0x00,
+                "Ping",
+                vec![bulk(0x81), bulk(0x01)],
+                Arc::new(Mutex::new(Box::new(PingHandler {
+                    table: table.clone(),
+                })
+                    as Box<dyn UsbInterfaceHandler + Send>)),
+            )
+            .with_in_queue(0x81, table.queue());
+        table.expect(1, pong(1));
+        table.expect(2, pong(2));
+
+        let ctx = ConnectionContext::default();
+        let intf = &device.interfaces[0];
+        let ping = |id: u32| {
+            let mut req = id.to_le_bytes().to_vec();
+            req.extend_from_slice(b"PING");
+            req
+        };
+        // nothing to read before a request
+        let err = device
+            .handle_urb(&ctx, bulk(0x81), Some(intf), [0; 8], &[])
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
+
+        // answered in request order, not registration order
+        for id in [2, 1].iter() {
+            device
+                .handle_urb(&ctx, bulk(0x01), Some(intf), [0; 8], &ping(*id))
+                .await
+                .unwrap();
+        }
+        assert!(!table.is_pending(1));
+        for id in [2u32, 1].iter() {
+            let resp = device
+                .handle_urb(&ctx, bulk(0x81), Some(intf), [0; 8], &[])
+                .await
+                .unwrap();
+            assert_eq!(resp[..4], id.to_le_bytes());
+            assert_eq!(&resp[4..], b"PONG");
+        }
+
+        // each transaction is answered once, unknown ids stall
+        let err = device
+            .handle_urb(&ctx, bulk(0x01), Some(intf), [0; 8], &ping(1))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+    }
+}
@@ -2,24 +2,71 @@ use super::*;
 
 pub(crate) async fn socket_write_fixed_string<T: AsyncReadExt + AsyncWriteExt + Unpin>(
     socket: &mut T,
-    s: &String,
+    s: &str,
     len: usize,
 ) -> Result<()> {
-    let mut path = s.clone().into_bytes();
+    let mut path = s.as_bytes().to_vec();
     assert!(path.len() <= len);
     path.resize(len, 0);
     socket.write_all(&path).await
 }
 
+pub(crate) async fn socket_read_fixed_string<T: AsyncReadExt + Unpin>(
+    socket: &mut T,
+    len: usize,
+) -> Result<String> {
+    let mut s = vec![0u8; len];
+    socket.read_exact(&mut s).await?;
+    let end = s.iter().position(|b| *b == 0).unwrap_or(len);
+    s.truncate(end);
+    String::from_utf8(s).map_err(|err| std::io::Error::new(ErrorKind::InvalidData, err))
+}
+
+/// A bidirectional byte stream carrying the USB/IP protocol
+pub trait UsbIpSocket: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> UsbIpSocket for T {}
+
 /// Check validity of a USB descriptor
+///
+/// The descriptors must add up to the length of `desc`, and a configuration
+/// descriptor must count all of them in wTotalLength and its interfaces in
+/// bNumInterfaces.
 pub fn verify_descriptor(desc: &[u8]) {
     let mut offset = 0;
     while offset < desc.len() {
         offset += desc[offset] as usize; // length
     }
     assert_eq!(offset, desc.len());
+    if desc.len() >= 9 && desc[1] == DescriptorType::Configuration as u8 {
+        assert_eq!(u16::from_le_bytes([desc[2], desc[3]]) as usize, desc.len());
+        assert_eq!(desc[4] as usize, count_interfaces(desc));
+    }
+}
+
+/// Count distinct bInterfaceNumber values in a configuration descriptor
+///
+/// Alternate settings of an interface are counted once, so the result is what
+/// bNumInterfaces of the configuration descriptor must be.
+pub fn count_interfaces(config_desc: &[u8]) -> usize {
+    let mut numbers = std::collections::BTreeSet::new();
+    let mut offset = 0;
+    while offset + 4 <= config_desc.len() {
+        let len = config_desc[offset] as usize;
+        if len < 2 {
+            break;
+        }
+        if len >= 9 && config_desc[offset + 1] == DescriptorType::Interface as u8 {
+            numbers.insert(config_desc[offset + 2]);
+        }
+        offset += len;
+    }
+    numbers.len()
 }
 
+#[cfg(test)]
+pub(crate) use tests::*;
+
 #[cfg(test)]
 mod tests {
     use std::{
@@ -74,6 +121,3 @@ mod tests {
         }
     }
 }
-
-#[cfg(test)]
-pub use tests::*;
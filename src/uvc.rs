@@ -0,0 +1,705 @@
+//! Implement UVC(USB Video Class) device
+//!
+//! A minimal camera: a VideoControl interface with a camera terminal, a
+//! processing unit and a streaming output terminal, and a VideoStreaming
+//! interface offering one uncompressed YUY2 frame size. Frames are delivered
+//! on an isochronous endpoint at alternate setting 1 of the VideoStreaming
+//! interface. Setting 0 has no endpoint, hosts select it while not streaming
+//! and negotiate the stream with probe and commit controls to it.
+use super::*;
+
+// reference:
+// UVC 1.1: https://www.usb.org/document-library/video-class-v11-document-set
+
+/// Sub class code for UVC VideoControl interface
+pub const UVC_SUBCLASS_VIDEO_CONTROL: u8 = 0x01;
+/// Sub class code for UVC VideoStreaming interface
+pub const UVC_SUBCLASS_VIDEO_STREAMING: u8 = 0x02;
+/// Sub class code for UVC Video Interface Collection, used in the IAD
+pub const UVC_SUBCLASS_VIDEO_INTERFACE_COLLECTION: u8 = 0x03;
+
+const CS_INTERFACE: u8 = 0x24;
+const UVC_CLOCK_FREQUENCY: u32 = 48_000_000;
+const VS_PROBE_CONTROL: u8 = 0x01;
+const VS_COMMIT_CONTROL: u8 = 0x02;
+const PROBE_COMMIT_LEN: usize = 34;
+
+/// Bits of bmHeaderInfo in the payload header
+const UVC_HEADER_FID: u8 = 0x01;
+const UVC_HEADER_EOF: u8 = 0x02;
+const UVC_HEADER_EOH: u8 = 0x80;
+
+/// A list of defined UVC class specific requests
+#[derive(Copy, Clone, Debug, FromPrimitive)]
+pub enum UvcRequest {
+    SetCur = 0x01,
+    GetCur = 0x81,
+    GetMin = 0x82,
+    GetMax = 0x83,
+    GetRes = 0x84,
+    GetLen = 0x85,
+    GetInfo = 0x86,
+    GetDef = 0x87,
+}
+
+/// A source of video frames for [UsbVideoStreamingHandler]
+pub trait UvcFrameSource: Send {
+    /// Produce the next frame in YUY2 format, `width * height * 2` bytes
+    fn next_frame(&mut self, width: u16, height: u16) -> Vec<u8>;
+}
+
+impl<F: FnMut(u16, u16) -> Vec<u8> + Send> UvcFrameSource for F {
+    fn next_frame(&mut self, width: u16, height: u16) -> Vec<u8> {
+        self(width, height)
+    }
+}
+
+/// Color bars scrolling by one pixel per frame
+#[derive(Clone, Debug, Default)]
+pub struct TestPatternSource {
+    frame: usize,
+}
+
+impl TestPatternSource {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl UvcFrameSource for TestPatternSource {
+    fn next_frame(&mut self, width: u16, height: u16) -> Vec<u8> {
+        // (Y, U, V) of white, yellow, cyan, green, magenta, red, blue, black
+        const BARS: [(u8, u8, u8); 8] = [
+            (235, 128, 128),
+            (210, 16, 146),
+            (170, 166, 16),
+            (145, 54, 34),
+            (106, 202, 222),
+            (81, 90, 240),
+            (41, 240, 110),
+            (16, 128, 128),
+        ];
+        let width = width as usize;
+        let mut frame = Vec::with_capacity(width * height as usize * 2);
+        for _ in 0..height {
+            // YUY2 packs two pixels in 4 bytes: Y0 U Y1 V
+            for x in (0..width).step_by(2) {
+                let bar = ((x + self.frame) % width) * BARS.len() / width;
+                let (y, u, v) = BARS[bar];
+                frame.extend_from_slice(&[y, u, y, v]);
+            }
+        }
+        self.frame += 1;
+        frame
+    }
+}
+
+/// A handler of the UVC VideoControl interface
+#[derive(Clone)]
+pub struct UsbVideoControlHandler {
+    /// Interface number of the VideoStreaming interface
+    pub streaming_interface: u8,
+}
+
+impl UsbVideoControlHandler {
+    pub fn new(streaming_interface: u8) -> Self {
+        Self {
+            streaming_interface,
+        }
+    }
+}
+
+impl UsbInterfaceHandler for UsbVideoControlHandler {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        _ep: UsbEndpoint,
+        setup: Option<SetupPacket>,
+        _req: &[u8],
+    ) -> Result<Vec<u8>> {
+        // no controls are implemented on the terminals and units
+        debug!("UVC control request {:x?}", setup);
+        Err(std::io::Error::new(
+            std::io::ErrorKind::BrokenPipe,
+            "no UVC controls",
+        ))
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        let mut desc = vec![
+            // VC Interface Header
+            0x0D,         // bLength
+            CS_INTERFACE, // bDescriptorType
+            0x01,         // bDescriptorSubtype: VC_HEADER
+            0x10,
+            0x01, // bcdUVC: 1.1
+            0x00,
+            0x00, // wTotalLength: to be filled below
+            UVC_CLOCK_FREQUENCY as u8,
+            (UVC_CLOCK_FREQUENCY >> 8) as u8,
+            (UVC_CLOCK_FREQUENCY >> 16) as u8,
+            (UVC_CLOCK_FREQUENCY >> 24) as u8, // dwClockFrequency
+            0x01,                              // bInCollection
+            self.streaming_interface,          // baInterfaceNr(1)
+            // Input Terminal: camera
+            0x12,         // bLength
+            CS_INTERFACE, // bDescriptorType
+            0x02,         // bDescriptorSubtype: VC_INPUT_TERMINAL
+            0x01,         // bTerminalID
+            0x01,
+            0x02, // wTerminalType: ITT_CAMERA
+            0x00, // bAssocTerminal
+            0x00, // iTerminal
+            0x00,
+            0x00, // wObjectiveFocalLengthMin
+            0x00,
+            0x00, // wObjectiveFocalLengthMax
+            0x00,
+            0x00, // wOcularFocalLength
+            0x03, // bControlSize
+            0x00,
+            0x00,
+            0x00, // bmControls
+            // Processing Unit
+            0x0C,         // bLength
+            CS_INTERFACE, // bDescriptorType
+            0x05,         // bDescriptorSubtype: VC_PROCESSING_UNIT
+            0x02,         // bUnitID
+            0x01,         // bSourceID
+            0x00,
+            0x00, // wMaxMultiplier
+            0x02, // bControlSize
+            0x00,
+            0x00, // bmControls
+            0x00, // iProcessing
+            0x00, // bmVideoStandards
+            // Output Terminal: streaming
+            0x09,         // bLength
+            CS_INTERFACE, // bDescriptorType
+            0x03,         // bDescriptorSubtype: VC_OUTPUT_TERMINAL
+            0x03,         // bTerminalID
+            0x01,
+            0x01, // wTerminalType: TT_STREAMING
+            0x00, // bAssocTerminal
+            0x02, // bSourceID
+            0x00, // iTerminal
+        ];
+        let len = desc.len() as u16;
+        desc[5] = len as u8;
+        desc[6] = (len >> 8) as u8;
+        desc
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// A handler of the UVC VideoStreaming interface
+///
+/// Handles the controls at alternate setting 0, and produces the payloads of
+/// the isochronous endpoint at setting 1.
+pub struct UsbVideoStreamingHandler {
+    pub width: u16,
+    pub height: u16,
+    /// Frame interval in 100ns units
+    pub frame_interval: u32,
+    source: Box<dyn UvcFrameSource>,
+    committed: bool,
+    frame: Vec<u8>,
+    offset: usize,
+    fid: u8,
+    high_speed: bool,
+}
+
+impl UsbVideoStreamingHandler {
+    /// Stream frames of `width` x `height` at `fps` from `source`
+    ///
+    /// Panics if `fps` is 0.
+    pub fn new(width: u16, height: u16, fps: u32, source: Box<dyn UvcFrameSource>) -> Self {
+        assert!(fps > 0, "fps must not be 0");
+        Self {
+            width,
+            height,
+            frame_interval: 10_000_000 / fps,
+            source,
+            committed: false,
+            frame: vec![],
+            offset: 0,
+            fid: 0,
+            high_speed: true,
+        }
+    }
+
+    /// The video data endpoint, a packet every (micro)frame
+    fn endpoints(&self) -> Vec<UsbEndpoint> {
+        vec![UsbEndpoint::new(
+            0x81,
+            EndpointAttributes::Isochronous,
+            SyncType::Async,
+            UsageType::Data,
+            self.max_payload_size() as u16,
+            0x01,
+        )]
+    }
+
+    /// Largest payload of a packet, including the payload header
+    fn max_payload_size(&self) -> u32 {
+        if self.high_speed {
+            1024
+        } else {
+            1023
+        }
+    }
+
+    fn frame_size(&self) -> u32 {
+        let size = self.width as u64 * self.height as u64 * 2;
+        size.min(u32::MAX as u64) as u32
+    }
+
+    fn bit_rate(&self) -> u32 {
+        let bit_rate =
+            self.frame_size() as u64 * 8 * 10_000_000 / self.frame_interval.max(1) as u64;
+        bit_rate.min(u32::MAX as u64) as u32
+    }
+
+    /// The only supported streaming parameters, for VS_PROBE_CONTROL and VS_COMMIT_CONTROL
+    fn probe_commit(&self) -> Vec<u8> {
+        let mut ctrl = vec![0u8; PROBE_COMMIT_LEN];
+        ctrl[2] = 1; // bFormatIndex
+        ctrl[3] = 1; // bFrameIndex
+        ctrl[4..8].copy_from_slice(&self.frame_interval.to_le_bytes()); // dwFrameInterval
+        ctrl[18..22].copy_from_slice(&self.frame_size().to_le_bytes()); // dwMaxVideoFrameSize
+        ctrl[22..26].copy_from_slice(&self.max_payload_size().to_le_bytes()); // dwMaxPayloadTransferSize
+        ctrl[26..30].copy_from_slice(&UVC_CLOCK_FREQUENCY.to_le_bytes()); // dwClockFrequency
+        ctrl[30] = 0x03; // bmFramingInfo: FID and EOF used
+        ctrl
+    }
+
+    /// Next payload of at most `max_len` bytes: a header and the next chunk
+    /// of the current frame
+    fn next_payload(&mut self, max_len: usize) -> Vec<u8> {
+        if self.offset >= self.frame.len() {
+            self.frame = self.source.next_frame(self.width, self.height);
+            self.offset = 0;
+        }
+        let chunk_len = max_len
+            .saturating_sub(2)
+            .min(self.frame.len() - self.offset);
+        let end = self.offset + chunk_len;
+        let mut info = UVC_HEADER_EOH | self.fid;
+        if end == self.frame.len() {
+            info |= UVC_HEADER_EOF;
+        }
+        let mut payload = vec![
+            0x02, // bHeaderLength
+            info, // bmHeaderInfo
+        ];
+        payload.extend_from_slice(&self.frame[self.offset..end]);
+        self.offset = end;
+        if info & UVC_HEADER_EOF != 0 {
+            // frame id toggles between frames
+            self.fid ^= UVC_HEADER_FID;
+        }
+        payload
+    }
+}
+
+impl UsbInterfaceHandler for UsbVideoStreamingHandler {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        _ep: UsbEndpoint,
+        setup: Option<SetupPacket>,
+        req: &[u8],
+    ) -> Result<Vec<u8>> {
+        if let Some(setup) = setup {
+            let selector = (setup.value >> 8) as u8;
+            if selector != VS_PROBE_CONTROL && selector != VS_COMMIT_CONTROL {
+                warn!("UVC unknown streaming control {:x?}", setup);
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::BrokenPipe,
+                    "unknown UVC streaming control",
+                ));
+            }
+            match FromPrimitive::from_u8(setup.request) {
+                Some(UvcRequest::SetCur) => {
+                    // only one set of parameters is supported, ignore the values
+                    if selector == VS_COMMIT_CONTROL {
+                        info!("UVC streaming committed");
+                        self.committed = true;
+                        // start over at a frame boundary
+                        self.frame.clear();
+                        self.offset = 0;
+                    }
+                    debug!("UVC set {:x?}", req);
+                    Ok(vec![])
+                }
+                Some(UvcRequest::GetCur)
+                | Some(UvcRequest::GetMin)
+                | Some(UvcRequest::GetMax)
+                | Some(UvcRequest::GetDef) => {
+                    let mut resp = self.probe_commit();
+                    resp.truncate(setup.length as usize);
+                    Ok(resp)
+                }
+                Some(UvcRequest::GetLen) => Ok(vec![PROBE_COMMIT_LEN as u8, 0]),
+                Some(UvcRequest::GetInfo) => Ok(vec![0x03]), // supports GET and SET
+                _ => {
+                    warn!("UVC unknown request {:x?}", setup);
+                    Err(std::io::Error::new(
+                        std::io::ErrorKind::BrokenPipe,
+                        "unknown UVC request",
+                    ))
+                }
+            }
+        } else if self.committed {
+            Ok(self.next_payload(self.max_payload_size() as usize))
+        } else {
+            Ok(vec![])
+        }
+    }
+
+    fn handle_iso_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        _ep: UsbEndpoint,
+        packets: &[IsoPacketDescriptor],
+        _req: &[u8],
+    ) -> Result<Vec<IsoPacketResult>> {
+        // nothing to send before commit
+        let max_payload_size = self.max_payload_size();
+        Ok(packets
+            .iter()
+            .map(|packet| IsoPacketResult {
+                data: if self.committed {
+                    self.next_payload(packet.length.min(max_payload_size) as usize)
+                } else {
+                    vec![]
+                },
+                status: 0,
+            })
+            .collect())
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        let frame_size = self.frame_size();
+        let bit_rate = self.bit_rate();
+        let mut desc = vec![
+            // VS Input Header
+            0x0E,         // bLength
+            CS_INTERFACE, // bDescriptorType
+            0x01,         // bDescriptorSubtype: VS_INPUT_HEADER
+            0x01,         // bNumFormats
+            0x00,
+            0x00, // wTotalLength: to be filled below
+            0x81, // bEndpointAddress
+            0x00, // bmInfo
+            0x03, // bTerminalLink: output terminal
+            0x00, // bStillCaptureMethod
+            0x00, // bTriggerSupport
+            0x00, // bTriggerUsage
+            0x01, // bControlSize
+            0x00, // bmaControls(1)
+            // Uncompressed Video Format
+            0x1B,         // bLength
+            CS_INTERFACE, // bDescriptorType
+            0x04,         // bDescriptorSubtype: VS_FORMAT_UNCOMPRESSED
+            0x01,         // bFormatIndex
+            0x01,         // bNumFrameDescriptors
+            // guidFormat: YUY2
+            0x59,
+            0x55,
+            0x59,
+            0x32,
+            0x00,
+            0x00,
+            0x10,
+            0x00,
+            0x80,
+            0x00,
+            0x00,
+            0xAA,
+            0x00,
+            0x38,
+            0x9B,
+            0x71,
+            0x10, // bBitsPerPixel
+            0x01, // bDefaultFrameIndex
+            0x00, // bAspectRatioX
+            0x00, // bAspectRatioY
+            0x00, // bmInterlaceFlags
+            0x00, // bCopyProtect
+            // Uncompressed Video Frame
+            0x1E,         // bLength
+            CS_INTERFACE, // bDescriptorType
+            0x05,         // bDescriptorSubtype: VS_FRAME_UNCOMPRESSED
+            0x01,         // bFrameIndex
+            0x00,         // bmCapabilities
+        ];
+        desc.extend_from_slice(&self.width.to_le_bytes()); // wWidth
+        desc.extend_from_slice(&self.height.to_le_bytes()); // wHeight
+        desc.extend_from_slice(&bit_rate.to_le_bytes()); // dwMinBitRate
+        desc.extend_from_slice(&bit_rate.to_le_bytes()); // dwMaxBitRate
+        desc.extend_from_slice(&frame_size.to_le_bytes()); // dwMaxVideoFrameBufferSize
+        desc.extend_from_slice(&self.frame_interval.to_le_bytes()); // dwDefaultFrameInterval
+        desc.push(0x01); // bFrameIntervalType: one discrete interval
+        desc.extend_from_slice(&self.frame_interval.to_le_bytes()); // dwFrameInterval(1)
+
+        let len = desc.len() as u16;
+        desc[4] = len as u8;
+        desc[5] = (len >> 8) as u8;
+        desc
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// Alternate setting 1 of the VideoStreaming interface, passing transfers on
+/// to the [UsbVideoStreamingHandler] of setting 0
+struct UvcStreamingSetting {
+    streaming: Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>>,
+}
+
+impl UsbInterfaceHandler for UvcStreamingSetting {
+    fn handle_urb(
+        &mut self,
+        interface: &UsbInterface,
+        ep: UsbEndpoint,
+        setup: Option<SetupPacket>,
+        req: &[u8],
+    ) -> Result<Vec<u8>> {
+        let mut streaming = self.streaming.lock().unwrap();
+        streaming.handle_urb(interface, ep, setup, req)
+    }
+
+    fn handle_iso_urb(
+        &mut self,
+        interface: &UsbInterface,
+        ep: UsbEndpoint,
+        packets: &[IsoPacketDescriptor],
+        req: &[u8],
+    ) -> Result<Vec<IsoPacketResult>> {
+        let mut streaming = self.streaming.lock().unwrap();
+        streaming.handle_iso_urb(interface, ep, packets, req)
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// Add a UVC camera function streaming frames from `source` to `device`
+///
+/// Adds the VideoControl and VideoStreaming interfaces grouped by an IAD.
+/// Panics if `fps` is 0.
+pub fn with_uvc_camera(
+    device: UsbDevice,
+    width: u16,
+    height: u16,
+    fps: u32,
+    source: Box<dyn UvcFrameSource>,
+) -> UsbDevice {
+    let first_interface = device.num_interfaces() as u8;
+    let control = Arc::new(Mutex::new(
+        Box::new(UsbVideoControlHandler::new(first_interface + 1))
+            as Box<dyn UsbInterfaceHandler + Send>,
+    ));
+    let mut handler = UsbVideoStreamingHandler::new(width, height, fps, source);
+    handler.high_speed = device.speed == UsbSpeed::High as u32;
+    let endpoints = handler.endpoints();
+    let streaming = Arc::new(Mutex::new(
+        Box::new(handler) as Box<dyn UsbInterfaceHandler + Send>
+    ));
+    let setting = Arc::new(Mutex::new(Box::new(UvcStreamingSetting {
+        streaming: streaming.clone(),
+    }) as Box<dyn UsbInterfaceHandler + Send>));
+    device
+        .with_interface(
+            ClassCode::Video as u8,
+            UVC_SUBCLASS_VIDEO_CONTROL,
+            0x00,
+            "Video Control",
+            vec![],
+            control,
+        )
+        // zero bandwidth setting
+        .with_interface(
+            ClassCode::Video as u8,
+            UVC_SUBCLASS_VIDEO_STREAMING,
+            0x00,
+            "Video Streaming",
+            vec![],
+            streaming,
+        )
+        .with_alternate_setting(
+            ClassCode::Video as u8,
+            UVC_SUBCLASS_VIDEO_STREAMING,
+            0x00,
+            "Video Streaming",
+            endpoints,
+            setting,
+        )
+        .with_interface_association(
+            first_interface,
+            2,
+            ClassCode::Video as u8,
+            UVC_SUBCLASS_VIDEO_INTERFACE_COLLECTION,
+            0x00,
+            "Camera",
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn camera(speed: UsbSpeed) -> UsbDevice {
+        with_uvc_camera(
+            UsbDevice::new(0).with_speed(speed),
+            320,
+            240,
+            30,
+            Box::new(TestPatternSource::new()),
+        )
+    }
+
+    fn iso_packets(count: usize, length: u32) -> Vec<IsoPacketDescriptor> {
+        (0..count)
+            .map(|i| IsoPacketDescriptor {
+                offset: i as u32 * length,
+                length,
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn desc_verify() {
+        let handler = UsbVideoControlHandler::new(1);
+        verify_descriptor(&handler.get_class_specific_descriptor());
+        let handler = UsbVideoStreamingHandler::new(64, 48, 30, Box::new(TestPatternSource::new()));
+        verify_descriptor(&handler.get_class_specific_descriptor());
+        for speed in [UsbSpeed::Full, UsbSpeed::High] {
+            let device = camera(speed);
+            verify_descriptor(&device.configuration_descriptor());
+            assert_eq!(device.validate(), Ok(()));
+            // setting 0 has no endpoint, setting 1 the iso endpoint
+            assert!(device.interfaces[1].endpoints.is_empty());
+            let intf = &device.interfaces[2];
+            assert_eq!(intf.alternate_setting, 1);
+            assert_eq!(
+                intf.endpoints[0].transfer_type(),
+                Some(EndpointAttributes::Isochronous)
+            );
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "fps")]
+    fn zero_fps() {
+        UsbVideoStreamingHandler::new(64, 48, 0, Box::new(TestPatternSource::new()));
+    }
+
+    #[test]
+    fn bit_rate() {
+        // 4K at 60 fps is above what dwMaxBitRate holds
+        let mut handler =
+            UsbVideoStreamingHandler::new(3840, 2160, 60, Box::new(TestPatternSource::new()));
+        let desc = handler.get_class_specific_descriptor();
+        // dwMinBitRate and dwMaxBitRate of the frame descriptor
+        let frame = &desc[0x0E + 0x1B..];
+        assert_eq!(frame[9..17], [0xFF; 8]);
+
+        handler.frame_interval = 0;
+        verify_descriptor(&handler.get_class_specific_descriptor());
+    }
+
+    #[tokio::test]
+    async fn stream_frames() {
+        let device = camera(UsbSpeed::High);
+        let ctx = ConnectionContext::default();
+
+        // probe and commit at setting 0
+        let probe = [
+            0xA1,
+            UvcRequest::GetCur as u8,
+            0x00,
+            VS_PROBE_CONTROL,
+            0x01,
+            0x00,
+            PROBE_COMMIT_LEN as u8,
+            0x00,
+        ];
+        let ctrl = testing::control(&device, &ctx, probe, &[]).await.unwrap();
+        assert_eq!(ctrl.len(), PROBE_COMMIT_LEN);
+        assert_eq!(ctrl[18..22], (320u32 * 240 * 2).to_le_bytes());
+        assert_eq!(ctrl[22..26], 1024u32.to_le_bytes());
+        let commit = [
+            0x21,
+            UvcRequest::SetCur as u8,
+            0x00,
+            VS_COMMIT_CONTROL,
+            0x01,
+            0x00,
+            PROBE_COMMIT_LEN as u8,
+            0x00,
+        ];
+        testing::control(&device, &ctx, commit, &ctrl)
+            .await
+            .unwrap();
+
+        // SET_INTERFACE(1) enables the iso endpoint
+        let set_interface = [0x01, 0x0B, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00];
+        testing::control(&device, &ctx, set_interface, &[])
+            .await
+            .unwrap();
+        let (ep, intf) = device.find_ep(0x81).unwrap();
+        assert_eq!(intf.unwrap().alternate_setting, 1);
+
+        // a frame spans several packets, the last one has EOF and FID toggles after it
+        let mut received = 0;
+        'frame: loop {
+            let results = device
+                .handle_iso_urb(ep, intf, &iso_packets(32, 1024), &[])
+                .unwrap();
+            for result in results {
+                assert_eq!(result.status, 0);
+                let payload = result.data;
+                assert!(payload.len() <= 1024);
+                assert_eq!(payload[1] & UVC_HEADER_FID, 0);
+                received += payload.len() - 2;
+                if payload[1] & UVC_HEADER_EOF != 0 {
+                    break 'frame;
+                }
+            }
+        }
+        assert_eq!(received, 320 * 240 * 2);
+        let results = device
+            .handle_iso_urb(ep, intf, &iso_packets(1, 1024), &[])
+            .unwrap();
+        assert_eq!(results[0].data[1] & UVC_HEADER_FID, UVC_HEADER_FID);
+    }
+
+    #[tokio::test]
+    async fn no_data_before_commit() {
+        let device = camera(UsbSpeed::Full);
+        let ctx = ConnectionContext::default();
+        let set_interface = [0x01, 0x0B, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00];
+        testing::control(&device, &ctx, set_interface, &[])
+            .await
+            .unwrap();
+        let (ep, intf) = device.find_ep(0x81).unwrap();
+        assert_eq!(ep.max_packet_size, 1023);
+        let results = device
+            .handle_iso_urb(ep, intf, &iso_packets(4, 1023), &[])
+            .unwrap();
+        assert!(results.iter().all(|result| result.data.is_empty()));
+    }
+}
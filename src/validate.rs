@@ -0,0 +1,651 @@
+//! Consistency checks of device descriptors, see [UsbDevice::validate]
+use super::*;
+
+/// A descriptor inconsistency found by [UsbDevice::validate]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ValidationError {
+    /// bMaxPacketSize0 not allowed at the speed of the device
+    MaxPacketSize0 { speed: u32, max_packet_size: u16 },
+    /// Super speed needs a SuperSpeed capability in BOS and endpoint companion
+    /// descriptors, which are not generated
+    UnsupportedSpeed { speed: u32 },
+    /// Endpoint address 0 or with reserved bits set
+    EndpointAddress { interface: usize, address: u8 },
+    /// Endpoint address used by more than one interface
+    EndpointConflict { address: u8 },
+    /// Endpoint address declared twice by the same interface
+    EndpointDuplicate { interface: usize, address: u8 },
+    /// Endpoint of the opposite direction to the one its handler declares
+    EndpointDirection { address: u8, expected: u8 },
+    /// Transfer type not available at the speed of the device
+    EndpointType { address: u8, speed: u32 },
+    /// wMaxPacketSize above the limit of the transfer type and speed
+    EndpointMaxPacketSize {
+        address: u8,
+        max_packet_size: u16,
+        limit: u16,
+    },
+    /// wMaxPacketSize of a high speed bulk endpoint other than 512
+    BulkMaxPacketSize { address: u8, max_packet_size: u16 },
+    /// bInterval out of the range of the transfer type and speed
+    EndpointInterval { address: u8, interval: u8 },
+    /// Interface association covering no or missing interfaces
+    AssociationRange { first_interface: u8, count: u8 },
+    /// Interface in more than one association
+    AssociationOverlap { interface: u8 },
+    /// Interface associations without the Multi-Interface Function class triple
+    AssociationDeviceClass {
+        class: u8,
+        subclass: u8,
+        protocol: u8,
+    },
+}
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use ValidationError::*;
+        match self {
+            MaxPacketSize0 {
+                speed,
+                max_packet_size,
+            } => write!(
+                f,
+                "bMaxPacketSize0 {} is not allowed at speed {}",
+                max_packet_size, speed
+            ),
+            UnsupportedSpeed { speed } => write!(
+                f,
+                "speed {} needs a SuperSpeed capability and endpoint companions, which are not generated",
+                speed
+            ),
+            EndpointAddress { interface, address } => write!(
+                f,
+                "interface {} has invalid endpoint address {:02x}",
+                interface, address
+            ),
+            EndpointConflict { address } => write!(
+                f,
+                "endpoint {:02x} is used by several interfaces",
+                address
+            ),
+            EndpointDuplicate { interface, address } => write!(
+                f,
+                "interface {} declares endpoint {:02x} more than once",
+                interface, address
+            ),
+            EndpointDirection { address, expected } => write!(
+                f,
+                "endpoint {:02x} has the opposite direction of {:02x} declared by its handler",
+                address, expected
+            ),
+            EndpointType { address, speed } => write!(
+                f,
+                "endpoint {:02x} has a transfer type not available at speed {}",
+                address, speed
+            ),
+            EndpointMaxPacketSize {
+                address,
+                max_packet_size,
+                limit,
+            } => write!(
+                f,
+                "endpoint {:02x} has wMaxPacketSize {}, at most {} is allowed",
+                address, max_packet_size, limit
+            ),
+            BulkMaxPacketSize {
+                address,
+                max_packet_size,
+            } => write!(
+                f,
+                "high speed bulk endpoint {:02x} has wMaxPacketSize {}, expected 512",
+                address, max_packet_size
+            ),
+            EndpointInterval { address, interval } => write!(
+                f,
+                "endpoint {:02x} has bInterval {} out of range",
+                address, interval
+            ),
+            AssociationRange {
+                first_interface,
+                count,
+            } => write!(
+                f,
+                "interface association of {} interfaces from {} does not match the interfaces",
+                count, first_interface
+            ),
+            AssociationOverlap { interface } => write!(
+                f,
+                "interface {} is in several interface associations",
+                interface
+            ),
+            AssociationDeviceClass {
+                class,
+                subclass,
+                protocol,
+            } => write!(
+                f,
+                "device class {:02x}/{:02x}/{:02x} with interface associations, expected ef/02/01",
+                class, subclass, protocol
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+impl UsbDevice {
+    /// Check that the descriptors of this device are consistent
+    ///
+    /// Hosts tend to reject inconsistent descriptors without telling why, so
+    /// run this on custom devices before exporting them. Returns every
+    /// problem found, see [UsbIpServer::with_device_validation] to log them
+    /// when serving.
+    pub fn validate(&self) -> std::result::Result<(), Vec<ValidationError>> {
+        use ValidationError::*;
+        let mut errors = vec![];
+        let speed = self.speed;
+        let low = speed == UsbSpeed::Low as u32;
+        let full = speed == UsbSpeed::Full as u32;
+        let high = speed == UsbSpeed::High as u32;
+
+        if speed == UsbSpeed::Super as u32 || speed == UsbSpeed::SuperPlus as u32 {
+            errors.push(UnsupportedSpeed { speed });
+        }
+        let max_packet_size = self.max_packet_size0();
+        if (low && max_packet_size != 8)
+            || (full && ![8, 16, 32, 64].contains(&max_packet_size))
+            || (high && max_packet_size != 64)
+        {
+            errors.push(MaxPacketSize0 {
+                speed,
+                max_packet_size,
+            });
+        }
+
+        let configurations = std::iter::once(&self.interfaces).chain(
+            self.other_configurations
+                .iter()
+                .map(|config| &config.interfaces),
+        );
+        for interfaces in configurations {
+            errors.extend(check_endpoints(interfaces, speed));
+        }
+
+        let num_interfaces = self.num_interfaces();
+        let mut associated = vec![false; num_interfaces];
+        for iad in &self.interface_associations {
+            let first = iad.first_interface as usize;
+            let end = first + iad.interface_count as usize;
+            if iad.interface_count == 0 || end > num_interfaces {
+                errors.push(AssociationRange {
+                    first_interface: iad.first_interface,
+                    count: iad.interface_count,
+                });
+                continue;
+            }
+            for (interface, seen) in associated[first..end].iter_mut().enumerate() {
+                if *seen {
+                    errors.push(AssociationOverlap {
+                        interface: (first + interface) as u8,
+                    });
+                }
+                *seen = true;
+            }
+        }
+        if !self.interface_associations.is_empty()
+            && (
+                self.device_class,
+                self.device_subclass,
+                self.device_protocol,
+            ) != (
+                ClassCode::Misc as u8,
+                MISC_SUBCLASS_COMMON,
+                MISC_PROTOCOL_IAD,
+            )
+        {
+            errors.push(AssociationDeviceClass {
+                class: self.device_class,
+                subclass: self.device_subclass,
+                protocol: self.device_protocol,
+            });
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+impl UsbEndpoint {
+    /// Create an endpoint like [UsbEndpoint::new], checking it against `speed`
+    ///
+    /// Fails with the same errors as [UsbDevice::validate] would report for
+    /// the endpoint, e.g. a high speed interrupt endpoint with a bInterval
+    /// above 16.
+    pub fn try_new(
+        address: u8,
+        transfer_type: EndpointAttributes,
+        sync_type: SyncType,
+        usage_type: UsageType,
+        max_packet_size: u16,
+        interval: u8,
+        speed: UsbSpeed,
+    ) -> std::result::Result<Self, Vec<ValidationError>> {
+        let ep = Self::new(
+            address,
+            transfer_type,
+            sync_type,
+            usage_type,
+            max_packet_size,
+            interval,
+        );
+        if ep.is_ep0() || address & 0x70 != 0 {
+            return Err(vec![ValidationError::EndpointAddress {
+                interface: 0,
+                address,
+            }]);
+        }
+        let errors = check_endpoint(&ep, speed as u32);
+        if errors.is_empty() {
+            Ok(ep)
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Address of the endpoint in `declared` of the same number and
+    /// transfer type but the other direction, unless `declared` has this one
+    ///
+    /// `declared` are the endpoints a handler reports, see
+    /// [UsbInterfaceHandler::endpoints].
+    pub(crate) fn declared_opposite(&self, declared: &[UsbEndpoint]) -> Option<u8> {
+        if declared.iter().any(|other| other.address == self.address) {
+            return None;
+        }
+        declared
+            .iter()
+            .find(|other| {
+                other.address & 0x0F == self.address & 0x0F
+                    && other.transfer_type() == self.transfer_type()
+            })
+            .map(|other| other.address)
+    }
+
+    /// bInterval to put in the endpoint descriptor at `speed`
+    ///
+    /// Intervals out of range are mapped to the closest valid one with a
+    /// warning, much like Linux does when parsing them, and are still
+    /// reported by [UsbDevice::validate]. A high speed interrupt bInterval
+    /// above 16 is taken as a number of frames, as it would be at full
+    /// speed, and turned into the exponent of as many microframes.
+    pub(crate) fn descriptor_interval(&self, speed: u32) -> u8 {
+        use EndpointAttributes::*;
+        let high = speed == UsbSpeed::High as u32;
+        let interval = match self.transfer_type() {
+            Some(Interrupt) if high && self.interval > 16 => {
+                // 8 microframes per frame, 2^(n-1) <= interval * 8
+                (32 - (self.interval as u32 * 8).leading_zeros()).min(16) as u8
+            }
+            Some(Isochronous) => self.interval.clamp(1, 16),
+            Some(Interrupt) => self.interval.max(1),
+            _ => self.interval,
+        };
+        if interval != self.interval {
+            warn!(
+                "Endpoint {:02x} bInterval {} is out of range, using {}",
+                self.address, self.interval, interval
+            );
+        }
+        interval
+    }
+}
+
+/// Check the endpoint addresses of the interfaces of a configuration and
+/// each endpoint with [check_endpoint]
+///
+/// Alternate settings of an interface may reuse its addresses, other
+/// interfaces may not.
+fn check_endpoints(interfaces: &[UsbInterface], speed: u32) -> Vec<ValidationError> {
+    use ValidationError::*;
+    let mut errors = vec![];
+    let mut owners: HashMap<u8, usize> = HashMap::new();
+    let mut number = 0;
+    for (i, intf) in interfaces.iter().enumerate() {
+        if i > 0 && intf.alternate_setting == 0 {
+            number += 1;
+        }
+        let declared = intf
+            .handler
+            .lock()
+            .map(|handler| handler.endpoints())
+            .unwrap_or_default();
+        for (j, ep) in intf.endpoints.iter().enumerate() {
+            if ep.is_ep0() || ep.address & 0x70 != 0 {
+                errors.push(EndpointAddress {
+                    interface: number,
+                    address: ep.address,
+                });
+                continue;
+            }
+            if intf.endpoints[..j]
+                .iter()
+                .any(|other| other.address == ep.address)
+            {
+                errors.push(EndpointDuplicate {
+                    interface: number,
+                    address: ep.address,
+                });
+            } else if *owners.entry(ep.address).or_insert(number) != number {
+                errors.push(EndpointConflict {
+                    address: ep.address,
+                });
+            }
+            if let Some(expected) = ep.declared_opposite(&declared) {
+                errors.push(EndpointDirection {
+                    address: ep.address,
+                    expected,
+                });
+            }
+            errors.extend(check_endpoint(ep, speed));
+        }
+    }
+    errors
+}
+
+/// Check packet size and interval of `ep` against USB 2.0 chapter 5 and 9
+fn check_endpoint(ep: &UsbEndpoint, speed: u32) -> Vec<ValidationError> {
+    use EndpointAttributes::*;
+    use ValidationError::*;
+    let low = speed == UsbSpeed::Low as u32;
+    let high = speed == UsbSpeed::High as u32;
+    let mut errors = vec![];
+    // bits 12:11 of high speed periodic endpoints are additional transactions
+    let size = if high {
+        ep.max_packet_size & 0x7FF
+    } else {
+        ep.max_packet_size
+    };
+    let limit = match ep.transfer_type() {
+        Some(Bulk) | Some(Isochronous) if low => {
+            errors.push(EndpointType {
+                address: ep.address,
+                speed,
+            });
+            return errors;
+        }
+        Some(Bulk) if high => {
+            if size != 512 {
+                errors.push(BulkMaxPacketSize {
+                    address: ep.address,
+                    max_packet_size: ep.max_packet_size,
+                });
+            }
+            512
+        }
+        Some(Bulk) => 64,
+        Some(Interrupt) if low => 8,
+        Some(Interrupt) | Some(Isochronous) if high => 1024,
+        Some(Interrupt) => 64,
+        Some(Isochronous) => 1023,
+        Some(Control) | None => 64,
+    };
+    if size > limit {
+        errors.push(EndpointMaxPacketSize {
+            address: ep.address,
+            max_packet_size: ep.max_packet_size,
+            limit,
+        });
+    }
+    let interval_ok = match ep.transfer_type() {
+        // 2^(bInterval-1) (micro)frames
+        Some(Isochronous) => (1..=16).contains(&ep.interval),
+        Some(Interrupt) if high => (1..=16).contains(&ep.interval),
+        Some(Interrupt) => ep.interval >= 1,
+        _ => true,
+    };
+    if !interval_ok {
+        errors.push(EndpointInterval {
+            address: ep.address,
+            interval: ep.interval,
+        });
+    }
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handler() -> Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>> {
+        Arc::new(Mutex::new(
+            Box::new(cdc::UsbCdcAcmHandler::new()) as Box<dyn UsbInterfaceHandler + Send>
+        ))
+    }
+
+    fn device_with_endpoint(ep: UsbEndpoint) -> UsbDevice {
+        UsbDevice::new(0).with_interface(
+            ClassCode::VendorSpecific as u8,
+            0x00,
+            0x00,
+            "Test",
+            vec![ep],
+            handler(),
+        )
+    }
+
+    #[test]
+    fn valid_devices() {
+        assert_eq!(UsbDevice::new(0).validate(), Ok(()));
+        let device = UsbDevice::new(0)
+            .with_interface(
+                ClassCode::CDC as u8,
+                cdc::CDC_ACM_SUBCLASS,
+                0x00,
+                "Test CDC ACM",
+                cdc::UsbCdcAcmHandler::endpoints(),
+                handler(),
+            )
+            .with_interface_association(
+                0,
+                1,
+                ClassCode::CDC as u8,
+                cdc::CDC_ACM_SUBCLASS,
+                0x00,
+                "Test CDC Function",
+            );
+        assert_eq!(device.validate(), Ok(()));
+    }
+
+    #[test]
+    fn endpoint_misconfigurations() {
+        // full speed bulk endpoint at high speed
+        let bulk = UsbEndpoint::new(
+            0x81,
+            EndpointAttributes::Bulk,
+            SyncType::NoSync,
+            UsageType::Data,
+            64,
+            0,
+        );
+        let errors = device_with_endpoint(bulk).validate().unwrap_err();
+        assert_eq!(
+            errors,
+            [ValidationError::BulkMaxPacketSize {
+                address: 0x81,
+                max_packet_size: 64
+            }]
+        );
+        assert_eq!(
+            errors[0].to_string(),
+            "high speed bulk endpoint 81 has wMaxPacketSize 64, expected 512"
+        );
+
+        // interrupt endpoint polled never
+        let interrupt = UsbEndpoint {
+            address: 0x82,
+            attributes: EndpointAttributes::Interrupt as u8,
+            max_packet_size: 8,
+            interval: 0,
+        };
+        assert_eq!(
+            device_with_endpoint(interrupt).validate(),
+            Err(vec![ValidationError::EndpointInterval {
+                address: 0x82,
+                interval: 0
+            }])
+        );
+
+        // bulk at low speed, which also needs bMaxPacketSize0 of 8
+        let mut device = device_with_endpoint(bulk);
+        device.speed = UsbSpeed::Low as u32;
+        assert_eq!(
+            device.validate(),
+            Err(vec![
+                ValidationError::MaxPacketSize0 {
+                    speed: UsbSpeed::Low as u32,
+                    max_packet_size: 64
+                },
+                ValidationError::EndpointType {
+                    address: 0x81,
+                    speed: UsbSpeed::Low as u32
+                }
+            ])
+        );
+    }
+
+    #[test]
+    fn endpoint_intervals() {
+        let interrupt = |interval| {
+            UsbEndpoint::try_new(
+                0x81,
+                EndpointAttributes::Interrupt,
+                SyncType::NoSync,
+                UsageType::Data,
+                8,
+                interval,
+                UsbSpeed::High,
+            )
+        };
+        assert!(interrupt(4).is_ok());
+        assert_eq!(
+            interrupt(32).unwrap_err(),
+            [ValidationError::EndpointInterval {
+                address: 0x81,
+                interval: 32
+            }]
+        );
+        let iso = UsbEndpoint::try_new(
+            0x02,
+            EndpointAttributes::Isochronous,
+            SyncType::Async,
+            UsageType::Data,
+            1023,
+            1,
+            UsbSpeed::Full,
+        );
+        assert!(iso.is_ok());
+
+        // 32 frames become 2^8 microframes in the descriptor at high speed
+        let mut ep = UsbEndpoint::new(
+            0x81,
+            EndpointAttributes::Interrupt,
+            SyncType::NoSync,
+            UsageType::Data,
+            8,
+            32,
+        );
+        let config = device_with_endpoint(ep).configuration_descriptor();
+        assert_eq!(config[config.len() - 1], 9);
+        assert_eq!(ep.descriptor_interval(UsbSpeed::Full as u32), 32);
+        ep.interval = 0;
+        assert_eq!(ep.descriptor_interval(UsbSpeed::Full as u32), 1);
+    }
+
+    #[test]
+    fn endpoint_address_misconfigurations() {
+        let endpoints = cdc::UsbCdcAcmHandler::endpoints();
+        let add = |endpoints: Vec<UsbEndpoint>| {
+            UsbDevice::new(0).try_with_interface(
+                ClassCode::CDC as u8,
+                cdc::CDC_ACM_SUBCLASS,
+                0x00,
+                "Test CDC ACM",
+                endpoints,
+                handler(),
+            )
+        };
+        let mut interrupt_out = endpoints[0];
+        interrupt_out.address = 0x01;
+        let err = add(vec![endpoints[1], endpoints[1]]).err().unwrap();
+        assert_eq!(err.to_string(), "endpoint 82 declared twice");
+        let err = add(vec![interrupt_out]).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "endpoint 01 has the opposite direction of 81 declared by its handler"
+        );
+
+        // interfaces changed after they were added
+        let mut device = add(endpoints.clone()).unwrap();
+        device.interfaces[0].endpoints.push(endpoints[1]);
+        device.interfaces[0].endpoints[0] = interrupt_out;
+        assert_eq!(
+            device.validate(),
+            Err(vec![
+                ValidationError::EndpointDirection {
+                    address: 0x01,
+                    expected: 0x81
+                },
+                ValidationError::EndpointDuplicate {
+                    interface: 0,
+                    address: 0x82
+                },
+            ])
+        );
+
+        // ep0 in another configuration
+        let mut ep0 = endpoints[0];
+        ep0.address = 0x80;
+        let mut interfaces = device_with_endpoint(endpoints[0]).interfaces;
+        interfaces[0].endpoints[0] = ep0;
+        let device = UsbDevice::new(0).with_other_configuration("Other", interfaces);
+        assert_eq!(
+            device.validate(),
+            Err(vec![ValidationError::EndpointAddress {
+                interface: 0,
+                address: 0x80
+            }])
+        );
+    }
+
+    #[test]
+    fn association_misconfigurations() {
+        let device = UsbDevice::new(0)
+            .with_interface(
+                ClassCode::CDC as u8,
+                cdc::CDC_ACM_SUBCLASS,
+                0x00,
+                "Test CDC ACM",
+                cdc::UsbCdcAcmHandler::endpoints(),
+                handler(),
+            )
+            .with_interface_association(0, 1, ClassCode::CDC as u8, 0x02, 0x00, "A")
+            .with_interface_association(0, 2, ClassCode::CDC as u8, 0x02, 0x00, "B")
+            .with_device_class(0x00, 0x00, 0x00);
+        assert_eq!(
+            device.validate(),
+            Err(vec![
+                ValidationError::AssociationRange {
+                    first_interface: 0,
+                    count: 2
+                },
+                ValidationError::AssociationDeviceClass {
+                    class: 0,
+                    subclass: 0,
+                    protocol: 0
+                }
+            ])
+        );
+    }
+}
@@ -0,0 +1,381 @@
+//! Carry USB/IP over WebSocket, for relays reachable from browsers
+//!
+//! The USB/IP byte stream is split into WebSocket binary messages (RFC 6455,
+//! opcode 0x2). Message boundaries carry no meaning: the receiver
+//! concatenates the payloads and parses USB/IP from the result, so a command
+//! may span several messages and a message may hold several commands. The
+//! server sends one unmasked message per flush, which the server does after
+//! each batch of replies. Clients must mask their
+//! frames as RFC 6455 requires. Pings are answered with pongs, a close frame
+//! is echoed and ends the stream, text messages are a protocol error.
+//!
+//! The HTTP upgrade handshake is left to the web server accepting the
+//! connection, [WebSocketStream] takes over the upgraded stream.
+use super::*;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::ReadBuf;
+
+/// Largest payload accepted in one frame
+pub const WEBSOCKET_MAX_PAYLOAD: usize = DEFAULT_MAX_TRANSFER_SIZE + 0x10000;
+
+/// Written data buffered before writes wait for the peer
+const WEBSOCKET_WRITE_BUFFER: usize = 0x10000;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+/// Server side of a WebSocket connection, exposing the payload of binary
+/// messages as a byte stream
+pub struct WebSocketStream<S> {
+    inner: S,
+    // received bytes not parsed into frames yet
+    read_buf: Vec<u8>,
+    // payload of received frames not read yet
+    payload: VecDeque<u8>,
+    // written payload not framed yet, sent as one message on flush
+    pending_payload: Vec<u8>,
+    // encoded frames not written yet
+    write_buf: Vec<u8>,
+    closed: bool,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> WebSocketStream<S> {
+    /// Wrap a stream on which the WebSocket handshake already completed
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            read_buf: vec![],
+            payload: VecDeque::new(),
+            pending_payload: vec![],
+            write_buf: vec![],
+            closed: false,
+        }
+    }
+
+    /// Return the underlying stream, dropping buffered data
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    /// Frame the payload written since the last flush as one binary message
+    fn frame_pending_payload(&mut self) {
+        if !self.pending_payload.is_empty() {
+            encode_frame(OPCODE_BINARY, &self.pending_payload, &mut self.write_buf);
+            self.pending_payload.clear();
+        }
+    }
+
+    /// Write out buffered frames, as far as the inner stream accepts them
+    fn poll_write_buf(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        while !self.write_buf.is_empty() {
+            let written = match Pin::new(&mut self.inner).poll_write(cx, &self.write_buf) {
+                Poll::Ready(res) => res?,
+                Poll::Pending => return Poll::Pending,
+            };
+            if written == 0 {
+                return Poll::Ready(Err(std::io::Error::new(
+                    ErrorKind::WriteZero,
+                    "websocket peer gone",
+                )));
+            }
+            self.write_buf.drain(..written);
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Parse one complete frame off `read_buf`, if there is one
+    fn parse_frame(&mut self) -> Result<Option<(u8, Vec<u8>)>> {
+        let buf = &self.read_buf;
+        if buf.len() < 2 {
+            return Ok(None);
+        }
+        let opcode = buf[0] & 0x0F;
+        if buf[1] & 0x80 == 0 {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "unmasked websocket frame from client",
+            ));
+        }
+        let (len, mut offset) = match buf[1] & 0x7F {
+            126 if buf.len() >= 4 => (u16::from_be_bytes([buf[2], buf[3]]) as u64, 4),
+            127 if buf.len() >= 10 => {
+                let mut len = [0u8; 8];
+                len.copy_from_slice(&buf[2..10]);
+                (u64::from_be_bytes(len), 10)
+            }
+            126 | 127 => return Ok(None),
+            len => (len as u64, 2),
+        };
+        if len > WEBSOCKET_MAX_PAYLOAD as u64 {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("websocket frame of {} bytes", len),
+            ));
+        }
+        let len = len as usize;
+        if buf.len() < offset + 4 + len {
+            return Ok(None);
+        }
+        let mut mask = [0u8; 4];
+        mask.copy_from_slice(&buf[offset..offset + 4]);
+        offset += 4;
+        let payload = buf[offset..offset + len]
+            .iter()
+            .enumerate()
+            .map(|(i, b)| b ^ mask[i % 4])
+            .collect();
+        self.read_buf.drain(..offset + len);
+        Ok(Some((opcode, payload)))
+    }
+}
+
+/// Encode an unmasked frame with FIN set
+fn encode_frame(opcode: u8, payload: &[u8], out: &mut Vec<u8>) {
+    out.push(0x80 | opcode);
+    if payload.len() < 126 {
+        out.push(payload.len() as u8);
+    } else if payload.len() <= u16::MAX as usize {
+        out.push(126);
+        out.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    } else {
+        out.push(127);
+        out.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+    }
+    out.extend_from_slice(payload);
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WebSocketStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        // send pongs while the handler waits for a command
+        if let Poll::Ready(Err(err)) = this.poll_write_buf(cx) {
+            return Poll::Ready(Err(err));
+        }
+        loop {
+            if !this.payload.is_empty() {
+                let len = this.payload.len().min(buf.remaining());
+                let data: Vec<u8> = this.payload.drain(..len).collect();
+                buf.put_slice(&data);
+                return Poll::Ready(Ok(()));
+            }
+            if this.closed {
+                return Poll::Ready(Ok(()));
+            }
+            if let Some((opcode, payload)) = this.parse_frame()? {
+                match opcode {
+                    OPCODE_BINARY | OPCODE_CONTINUATION => this.payload.extend(payload),
+                    OPCODE_PING => {
+                        encode_frame(OPCODE_PONG, &payload, &mut this.write_buf);
+                        if let Poll::Ready(Err(err)) = this.poll_write_buf(cx) {
+                            return Poll::Ready(Err(err));
+                        }
+                    }
+                    OPCODE_PONG => {}
+                    OPCODE_CLOSE => {
+                        debug!("Got websocket close");
+                        encode_frame(OPCODE_CLOSE, &payload, &mut this.write_buf);
+                        this.closed = true;
+                        if let Poll::Ready(Err(err)) = this.poll_write_buf(cx) {
+                            return Poll::Ready(Err(err));
+                        }
+                    }
+                    OPCODE_TEXT => {
+                        return Poll::Ready(Err(std::io::Error::new(
+                            ErrorKind::InvalidData,
+                            "websocket text message",
+                        )))
+                    }
+                    _ => {
+                        return Poll::Ready(Err(std::io::Error::new(
+                            ErrorKind::InvalidData,
+                            format!("websocket opcode {:x}", opcode),
+                        )))
+                    }
+                }
+                continue;
+            }
+            let mut chunk = [0u8; 4096];
+            let mut chunk_buf = ReadBuf::new(&mut chunk);
+            match Pin::new(&mut this.inner).poll_read(cx, &mut chunk_buf) {
+                Poll::Ready(Ok(())) => {
+                    if chunk_buf.filled().is_empty() {
+                        // end of stream without a close frame
+                        this.closed = true;
+                    }
+                    this.read_buf.extend_from_slice(chunk_buf.filled());
+                }
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WebSocketStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        let this = self.get_mut();
+        if this.pending_payload.len() >= WEBSOCKET_WRITE_BUFFER {
+            // a large reply goes out in several messages
+            this.frame_pending_payload();
+        }
+        if this.write_buf.len() >= WEBSOCKET_WRITE_BUFFER {
+            match this.poll_write_buf(cx) {
+                Poll::Ready(res) => res?,
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        this.pending_payload.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        this.frame_pending_payload();
+        match this.poll_write_buf(cx) {
+            Poll::Ready(res) => res?,
+            Poll::Pending => return Poll::Pending,
+        }
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        this.frame_pending_payload();
+        match this.poll_write_buf(cx) {
+            Poll::Ready(res) => res?,
+            Poll::Pending => return Poll::Pending,
+        }
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+/// Serve USB/IP on a connection upgraded to WebSocket, see [WebSocketStream]
+pub async fn serve_websocket<S>(
+    stream: S,
+    server: Arc<UsbIpServer>,
+) -> std::result::Result<(), UsbIpError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let mut stream = WebSocketStream::new(stream);
+    let res = handler(&mut stream, server).await;
+    // deliver the last replies, e.g. those before an error
+    stream.flush().await.ok();
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encode a masked client frame
+    fn client_frame(opcode: u8, fin: bool, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![(fin as u8) << 7 | opcode];
+        if payload.len() < 126 {
+            frame.push(0x80 | payload.len() as u8);
+        } else {
+            frame.push(0x80 | 126);
+            frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    /// Split unmasked server frames into (opcode, payload)
+    fn server_frames(mut data: &[u8]) -> Vec<(u8, Vec<u8>)> {
+        let mut frames = vec![];
+        while !data.is_empty() {
+            assert_eq!(data[1] & 0x80, 0, "server frames are unmasked");
+            let (len, offset) = match data[1] {
+                126 => (u16::from_be_bytes([data[2], data[3]]) as usize, 4),
+                len => (len as usize, 2),
+            };
+            frames.push((data[0] & 0x0F, data[offset..offset + len].to_vec()));
+            data = &data[offset + len..];
+        }
+        frames
+    }
+
+    #[tokio::test]
+    async fn devlist_over_websocket() {
+        let server = Arc::new(UsbIpServer::new_simulated(vec![UsbDevice::new(0)]));
+
+        // OP_REQ_DEVLIST split over a binary and a continuation frame,
+        // with a ping in between, then a close
+        let mut input = client_frame(OPCODE_BINARY, false, &[0x01, 0x11, 0x80]);
+        input.extend(client_frame(OPCODE_PING, true, b"hi"));
+        input.extend(client_frame(
+            OPCODE_CONTINUATION,
+            true,
+            &[0x05, 0x00, 0x00, 0x00, 0x00],
+        ));
+        input.extend(client_frame(OPCODE_CLOSE, true, &[0x03, 0xE8]));
+        let mut mock_socket = MockSocket::new(input);
+        serve_websocket(&mut mock_socket, server).await.ok();
+
+        let frames = server_frames(&mock_socket.output);
+        assert_eq!(frames[0], (OPCODE_PONG, b"hi".to_vec()));
+        let close = frames
+            .iter()
+            .position(|(op, _)| *op == OPCODE_CLOSE)
+            .unwrap();
+        assert_eq!(frames[close].1, [0x03, 0xE8]);
+        // OP_REP_DEVLIST of one device without interfaces, in one message
+        assert_eq!(close, 2);
+        let (opcode, reply) = &frames[1];
+        assert_eq!(*opcode, OPCODE_BINARY);
+        assert_eq!(reply.len(), 0xC + 0x138);
+        assert_eq!(
+            reply[..12],
+            [0x01, 0x11, 0x00, 0x05, 0, 0, 0, 0, 0, 0, 0, 1]
+        );
+    }
+
+    #[tokio::test]
+    async fn one_message_per_reply() {
+        let server = Arc::new(UsbIpServer::new_simulated(vec![UsbDevice::new(0)]));
+
+        // OP_REQ_IMPORT and two URBs in a single frame
+        let mut request = testing::op_req_import("0");
+        let get_descriptor = [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00];
+        for seq_num in 1..=2 {
+            request.extend(testing::CmdSubmit::control(seq_num, get_descriptor, &[]).to_bytes());
+        }
+        let mut mock_socket = MockSocket::new(client_frame(OPCODE_BINARY, true, &request));
+        serve_websocket(&mut mock_socket, server).await.ok();
+
+        // OP_REP_IMPORT, then a RET_SUBMIT each, not a message per write
+        let lengths: Vec<_> = server_frames(&mock_socket.output)
+            .iter()
+            .map(|(opcode, payload)| (*opcode, payload.len()))
+            .collect();
+        assert_eq!(
+            lengths,
+            [
+                (OPCODE_BINARY, 0x140),
+                (OPCODE_BINARY, 0x30 + 0x12),
+                (OPCODE_BINARY, 0x30 + 0x12)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn reject_unmasked_frame() {
+        let mut mock_socket = MockSocket::new(vec![0x82, 0x01, 0x00]);
+        let mut stream = WebSocketStream::new(&mut mock_socket);
+        let mut data = [0u8; 1];
+        let err = stream.read_exact(&mut data).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}